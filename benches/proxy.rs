@@ -0,0 +1,109 @@
+//! End-to-end benchmarks of the proxy binary.
+//!
+//! Starts `proxy --mock-upstream` on a free port and measures requests sent
+//! to it over loopback, so regressions anywhere in the stack, body buffering
+//! included, show up here. `proxy bench`, built with the `bench` feature,
+//! measures the stack in-process, with p99 latency and allocations.
+
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hyper::{Body, Client, Method, Request};
+
+const CONFIG: &str = r#"{
+    "server": { "listen": "LISTEN" },
+    "mock_upstream": [
+        { "body": { "d": [{ "id": 1, "uuid": "0123456789abcdef", "is_online": true }] } }
+    ]
+}"#;
+
+/// A proxy process, killed on drop.
+struct Proxy {
+    child: Child,
+    config: PathBuf,
+    addr: SocketAddr,
+}
+
+impl Proxy {
+    fn start() -> Proxy {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("free port");
+        let config = std::env::temp_dir().join(format!("proxy-bench-{}.json", std::process::id()));
+        std::fs::write(&config, CONFIG.replace("LISTEN", &addr.to_string())).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_proxy"))
+            .arg("--mock-upstream")
+            .env("PROXY_CONFIG", &config)
+            .env_remove("BALENA_API_KEY")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("proxy binary");
+        let proxy = Proxy {
+            child,
+            config,
+            addr,
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(proxy.addr).is_err() {
+            assert!(Instant::now() < deadline, "proxy did not start listening");
+            thread::sleep(Duration::from_millis(50));
+        }
+        proxy
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config);
+    }
+}
+
+fn bench_proxy(c: &mut Criterion) {
+    let proxy = Proxy::start();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let client = Client::new();
+    let send = |method: Method, body: Bytes| {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}/v6/device", proxy.addr))
+            .header("authorization", "Bearer bench")
+            .body(Body::from(body))
+            .unwrap();
+        runtime.block_on(async {
+            let res = client.request(req).await.unwrap();
+            assert!(res.status().is_success(), "{}", res.status());
+            hyper::body::to_bytes(res.into_body()).await.unwrap()
+        })
+    };
+
+    c.bench_function("get", |b| b.iter(|| send(Method::GET, Bytes::new())));
+
+    // request bodies are read whole before being forwarded
+    let mut group = c.benchmark_group("post");
+    for size in [1024, 64 * 1024, 1024 * 1024] {
+        let body = Bytes::from(
+            serde_json::json!({ "data": "x".repeat(size) })
+                .to_string()
+                .into_bytes(),
+        );
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_function(size.to_string(), |b| {
+            b.iter(|| send(Method::POST, body.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_proxy);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+//! Build facts for `/version`: the git commit and when it was built.
+//!
+//! `PROXY_GIT_SHA` may be set for builds outside a checkout, such as in a
+//! container, and `SOURCE_DATE_EPOCH` for reproducible ones.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=PROXY_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("PROXY_GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+    });
+    println!(
+        "cargo:rustc-env=PROXY_GIT_SHA={}",
+        sha.unwrap_or_else(|| "unknown".to_owned())
+    );
+
+    let built = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    println!("cargo:rustc-env=PROXY_BUILD_EPOCH={}", built);
+}
@@ -0,0 +1,220 @@
+//! Method and path allowlists.
+//!
+//! [`AccessLayer`] narrows what callers may reach through the proxy. The
+//! first rule whose route matches a request decides: the request must use
+//! one of its methods and match one of its path patterns, or it is refused
+//! with a 403 before being forwarded. Requests matching no rule follow
+//! `default_allow`, so the proxy can expose only the listed capabilities.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderValue, Request, Response, StatusCode};
+use regex::Regex;
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::route::RouteMatcher;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// The first rule matching the request applies.
+    pub rules: Vec<AccessRule>,
+    /// Let through requests no rule matches.
+    #[serde(default = "default_allow")]
+    pub default_allow: bool,
+}
+
+fn default_allow() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Allowed methods, any method when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Regular expressions the whole path must match one of, any path when
+    /// empty.
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+struct Rule {
+    route: RouteMatcher,
+    methods: Vec<String>,
+    paths: Vec<Regex>,
+}
+
+impl Rule {
+    fn allows<B>(&self, req: &Request<B>) -> bool {
+        let method = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(req.method().as_str()));
+        let path = self.paths.is_empty() || self.paths.iter().any(|p| p.is_match(req.uri().path()));
+        method && path
+    }
+}
+
+struct Policy {
+    rules: Vec<Rule>,
+    default_allow: bool,
+}
+
+impl Policy {
+    fn allows<B>(&self, req: &Request<B>) -> bool {
+        match self.rules.iter().find(|rule| rule.route.matches(req)) {
+            Some(rule) => rule.allows(req),
+            None => self.default_allow,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLayer {
+    policy: Arc<Policy>,
+}
+
+impl AccessLayer {
+    /// Compile the path patterns of `config`.
+    pub fn new(config: AccessConfig) -> Result<Self, BoxError> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let paths = rule
+                    .paths
+                    .iter()
+                    .map(|p| {
+                        Regex::new(&format!("^(?:{})$", p))
+                            .map_err(|err| format!("access path {}: {}", p, err))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Rule {
+                    route: rule.route,
+                    methods: rule.methods,
+                    paths,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            policy: Arc::new(Policy {
+                rules,
+                default_allow: config.default_allow,
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for AccessLayer {
+    type Service = Access<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Access {
+            inner: service,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Access<S> {
+    inner: S,
+    policy: Arc<Policy>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Access<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        if self.policy.allows(&req) {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        tracing::warn!(method = %req.method(), path = req.uri().path(), "request denied");
+        let body = serde_json::json!({
+            "error": "method or path not allowed",
+            "method": req.method().as_str(),
+            "path": req.uri().path(),
+        });
+        let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+        *res.status_mut() = StatusCode::FORBIDDEN;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_layer(default_allow: bool) -> AccessLayer {
+        AccessLayer::new(
+            serde_json::from_value(serde_json::json!({
+                "rules": [
+                    { "route": { "path_prefix": "/v6/application" }, "methods": ["GET", "PATCH"] },
+                    { "route": { "path_prefix": "/v6/device" }, "paths": [r"/v6/device\(\d+\)"] },
+                ],
+                "default_allow": default_allow,
+            }))
+            .expect("valid config"),
+        )
+        .expect("valid patterns")
+    }
+
+    fn allows(layer: &AccessLayer, method: &str, path: &str) -> bool {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap();
+        layer.policy.allows(&req)
+    }
+
+    #[test]
+    fn test_policy() {
+        let layer = access_layer(true);
+        assert!(allows(&layer, "GET", "/v6/application(1)"));
+        assert!(!allows(&layer, "DELETE", "/v6/application(1)"));
+        assert!(allows(&layer, "DELETE", "/v6/device(12)"));
+        assert!(!allows(&layer, "GET", "/v6/device"));
+        assert!(allows(&layer, "GET", "/v6/release"));
+        assert!(!allows(&access_layer(false), "GET", "/v6/release"));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let config = serde_json::from_value(serde_json::json!({
+            "rules": [{ "paths": ["("] }],
+        }))
+        .unwrap();
+        assert!(AccessLayer::new(config).is_err());
+    }
+}
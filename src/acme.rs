@@ -0,0 +1,524 @@
+//! ACME (RFC 8555) certificate provisioning.
+//!
+//! [`Acme`] obtains a certificate for the configured domains from Let's
+//! Encrypt, or another ACME directory, answering HTTP-01 challenges on a
+//! plain HTTP listener that must be reachable on port 80 of every domain.
+//! The account key, the certificate and its key are kept in `cache_dir`, so
+//! restarts reuse them instead of ordering again.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http::{header::CONTENT_TYPE, HeaderMap, Request, Response, StatusCode};
+use hyper::{
+    client::HttpConnector,
+    service::{make_service_fn, service_fn},
+    Body, Client, Server,
+};
+use hyper_tls::HttpsConnector;
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509Req, X509},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tower::BoxError;
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Public hostnames of the certificate, the first one as its subject.
+    pub domains: Vec<String>,
+    /// Contact emails of the account, told about expiring certificates.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// ACME directory, Let's Encrypt production when unset.
+    #[serde(default = "default_directory")]
+    pub directory_url: String,
+    /// Where the account key and certificate are kept.
+    pub cache_dir: PathBuf,
+    /// Listener answering HTTP-01 challenges.
+    #[serde(default = "default_challenge_listen")]
+    pub challenge_listen: SocketAddr,
+    /// Renew certificates expiring within this many days.
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u32,
+}
+
+fn default_directory() -> String {
+    LETS_ENCRYPT.to_owned()
+}
+
+fn default_challenge_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 80))
+}
+
+fn default_renew_before_days() -> u32 {
+    30
+}
+
+/// A certificate chain and its private key, PEM encoded.
+#[derive(Clone)]
+pub struct Certificate {
+    pub chain: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// Key authorizations served to the ACME server, by token.
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+pub struct Acme {
+    config: AcmeConfig,
+    client: Client<HttpsConnector<HttpConnector>>,
+    key: EcKey<Private>,
+    challenges: Challenges,
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn new_key() -> Result<EcKey<Private>, BoxError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(EcKey::generate(&group)?)
+}
+
+impl Acme {
+    /// Load or create the account key in the cache directory.
+    pub fn new(config: AcmeConfig) -> Result<Self, BoxError> {
+        if config.domains.is_empty() {
+            return Err("acme: no domains".into());
+        }
+        let dir = &config.cache_dir;
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+        let path = dir.join("account.pem");
+        let key = match fs::read(&path) {
+            Ok(pem) => EcKey::private_key_from_pem(&pem)
+                .map_err(|err| format!("{}: {}", path.display(), err))?,
+            Err(_) => {
+                let key = new_key()?;
+                fs::write(&path, key.private_key_to_pem()?)
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+                key
+            }
+        };
+        Ok(Self {
+            config,
+            client: Client::builder().build(HttpsConnector::new()),
+            key,
+            challenges: Arc::default(),
+        })
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.config.cache_dir.join("cert.pem")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.config.cache_dir.join("key.pem")
+    }
+
+    /// The cached certificate, if any.
+    pub fn cached(&self) -> Option<Certificate> {
+        Some(Certificate {
+            chain: fs::read(self.cert_path()).ok()?,
+            key: fs::read(self.key_path()).ok()?,
+        })
+    }
+
+    /// Whether `certificate` is missing, unreadable or about to expire.
+    pub fn needs_renewal(&self, certificate: Option<&Certificate>) -> bool {
+        let Some(cert) = certificate.and_then(|c| X509::from_pem(&c.chain).ok()) else {
+            return true;
+        };
+        match Asn1Time::days_from_now(self.config.renew_before_days) {
+            Ok(renew_at) => cert.not_after() <= renew_at,
+            Err(_) => true,
+        }
+    }
+
+    /// Serve the HTTP-01 challenges on the challenge listener.
+    pub async fn serve_challenges(&self) -> Result<(), BoxError> {
+        let challenges = self.challenges.clone();
+        let make_service = make_service_fn(move |_| {
+            let challenges = challenges.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let answer = req
+                        .uri()
+                        .path()
+                        .strip_prefix(CHALLENGE_PREFIX)
+                        .and_then(|token| challenges.lock().unwrap().get(token).cloned());
+                    async move {
+                        let mut res = Response::new(Body::from(answer.clone().unwrap_or_default()));
+                        if answer.is_none() {
+                            *res.status_mut() = StatusCode::NOT_FOUND;
+                        }
+                        Ok::<_, Infallible>(res)
+                    }
+                }))
+            }
+        });
+        let addr = self.config.challenge_listen;
+        tracing::info!(%addr, "serving ACME challenges");
+        Server::try_bind(&addr)
+            .map_err(|err| format!("acme challenge_listen {}: {}", addr, err))?
+            .serve(make_service)
+            .await?;
+        Ok(())
+    }
+
+    /// The public key of the account, as a JWK with its members in order.
+    fn jwk(&self) -> Result<String, BoxError> {
+        let mut ctx = BigNumContext::new()?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        self.key
+            .public_key()
+            .affine_coordinates(self.key.group(), &mut x, &mut y, &mut ctx)?;
+        Ok(format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64(x.to_vec_padded(32)?),
+            b64(y.to_vec_padded(32)?),
+        ))
+    }
+
+    fn key_authorization(&self, token: &str) -> Result<String, BoxError> {
+        let thumbprint = Sha256::digest(self.jwk()?.as_bytes());
+        Ok(format!("{}.{}", token, b64(thumbprint)))
+    }
+
+    /// A flattened JWS of `payload`, signed with ES256. A `None` payload
+    /// makes a POST-as-GET.
+    fn jws(
+        &self,
+        url: &str,
+        nonce: &str,
+        kid: Option<&str>,
+        payload: Option<&Value>,
+    ) -> Result<String, BoxError> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match kid {
+            Some(kid) => protected["kid"] = kid.into(),
+            None => protected["jwk"] = serde_json::from_str(&self.jwk()?)?,
+        }
+        let protected = b64(protected.to_string());
+        let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+        let digest = Sha256::digest(format!("{}.{}", protected, payload).as_bytes());
+        let signature = EcdsaSig::sign(&digest, &self.key)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+        Ok(
+            json!({ "protected": protected, "payload": payload, "signature": b64(raw) })
+                .to_string(),
+        )
+    }
+
+    /// Order a certificate, answering its challenges, and cache it.
+    pub async fn order(&self) -> Result<Certificate, BoxError> {
+        let res = self.client.get(self.config.directory_url.parse()?).await?;
+        let directory: Directory = serde_json::from_slice(&hyper::body::to_bytes(res).await?)
+            .map_err(|err| format!("acme directory: {}", err))?;
+        let mut session = Session {
+            acme: self,
+            nonce: None,
+            new_nonce: directory.new_nonce,
+            kid: None,
+        };
+
+        let contact: Vec<String> = self
+            .config
+            .contact
+            .iter()
+            .map(|email| format!("mailto:{}", email))
+            .collect();
+        let (_, headers, _) = session
+            .post(
+                &directory.new_account,
+                Some(&json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        session.kid = Some(location(&headers)?);
+
+        let identifiers: Vec<Value> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let (_, headers, body) = session
+            .post(
+                &directory.new_order,
+                Some(&json!({ "identifiers": identifiers })),
+            )
+            .await?;
+        let order_url = location(&headers)?;
+        let order: Order = serde_json::from_slice(&body)?;
+
+        let result = self.authorize(&mut session, &order).await;
+        self.challenges.lock().unwrap().clear();
+        result?;
+
+        let key = PKey::from_ec_key(new_key()?)?;
+        let csr = self.csr(&key)?;
+        session
+            .post(&order.finalize, Some(&json!({ "csr": b64(csr) })))
+            .await?;
+        let mut certificate_url = None;
+        for _ in 0..30 {
+            let (_, _, body) = session.post(&order_url, None).await?;
+            let order: Order = serde_json::from_slice(&body)?;
+            match order.status.as_str() {
+                "valid" => {
+                    certificate_url = order.certificate;
+                    break;
+                }
+                "invalid" => return Err("acme: order invalid".into()),
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+        let certificate_url = certificate_url.ok_or("acme: order not ready in time")?;
+        let (_, _, chain) = session.post(&certificate_url, None).await?;
+
+        let certificate = Certificate {
+            chain: chain.to_vec(),
+            key: key.private_key_to_pem_pkcs8()?,
+        };
+        fs::write(self.key_path(), &certificate.key)?;
+        fs::write(self.cert_path(), &certificate.chain)?;
+        tracing::info!(domains = ?self.config.domains, "ACME certificate issued");
+        Ok(certificate)
+    }
+
+    async fn authorize(&self, session: &mut Session<'_>, order: &Order) -> Result<(), BoxError> {
+        for url in &order.authorizations {
+            let (_, _, body) = session.post(url, None).await?;
+            let authorization: Authorization = serde_json::from_slice(&body)?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|c| c.kind == "http-01")
+                .ok_or("acme: no http-01 challenge offered")?;
+            let key_authorization = self.key_authorization(&challenge.token)?;
+            self.challenges
+                .lock()
+                .unwrap()
+                .insert(challenge.token.clone(), key_authorization);
+            session.post(&challenge.url, Some(&json!({}))).await?;
+
+            let mut status = authorization.status;
+            for _ in 0..30 {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                let (_, _, body) = session.post(url, None).await?;
+                status = serde_json::from_slice::<Authorization>(&body)?.status;
+                if status != "pending" {
+                    break;
+                }
+            }
+            if status != "valid" {
+                return Err(format!("acme: authorization {}: {}", url, status).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn csr(&self, key: &PKey<Private>) -> Result<Vec<u8>, BoxError> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &self.config.domains[0])?;
+        let mut req = X509Req::builder()?;
+        req.set_subject_name(&name.build())?;
+        req.set_pubkey(key)?;
+        let mut san = SubjectAlternativeName::new();
+        for domain in &self.config.domains {
+            san.dns(domain);
+        }
+        let mut extensions = Stack::new()?;
+        extensions.push(san.build(&req.x509v3_context(None))?)?;
+        req.add_extensions(&extensions)?;
+        req.sign(key, MessageDigest::sha256())?;
+        Ok(req.build().to_der()?)
+    }
+}
+
+fn location(headers: &HeaderMap) -> Result<String, BoxError> {
+    Ok(headers
+        .get(http::header::LOCATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("acme: missing Location")?
+        .to_owned())
+}
+
+/// Requests of one order, sharing nonces and the account URL.
+struct Session<'a> {
+    acme: &'a Acme,
+    nonce: Option<String>,
+    new_nonce: String,
+    kid: Option<String>,
+}
+
+impl Session<'_> {
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes), BoxError> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let req = Request::head(self.new_nonce.as_str()).body(Body::empty())?;
+                    let res = self.acme.client.request(req).await?;
+                    replay_nonce(res.headers()).ok_or("acme: no nonce")?
+                }
+            };
+            let body = self.acme.jws(url, &nonce, self.kid.as_deref(), payload)?;
+            let req = Request::post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body))?;
+            let (parts, body) = self.acme.client.request(req).await?.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            self.nonce = replay_nonce(&parts.headers);
+            if parts.status.is_success() {
+                return Ok((parts.status, parts.headers, body));
+            }
+            // nonces expire, the server sends a fresh one along
+            let bad_nonce = serde_json::from_slice::<Value>(&body)
+                .is_ok_and(|problem| problem["type"] == "urn:ietf:params:acme:error:badNonce");
+            if bad_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!(
+                "acme {}: {} {}",
+                url,
+                parts.status,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+    }
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    Some(headers.get("replay-nonce")?.to_str().ok()?.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509Builder;
+
+    fn acme(dir: &std::path::Path) -> Acme {
+        Acme::new(AcmeConfig {
+            domains: vec!["proxy.example.com".into(), "alt.example.com".into()],
+            contact: Vec::new(),
+            directory_url: LETS_ENCRYPT.into(),
+            cache_dir: dir.to_owned(),
+            challenge_listen: default_challenge_listen(),
+            renew_before_days: 30,
+        })
+        .unwrap()
+    }
+
+    fn certificate(days: u32) -> Certificate {
+        let key = PKey::from_ec_key(new_key().unwrap()).unwrap();
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        Certificate {
+            chain: cert.build().to_pem().unwrap(),
+            key: key.private_key_to_pem_pkcs8().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_account_and_renewal() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("proxy-acme-{}", std::process::id()));
+        let acme = acme(&dir);
+        // the account key survives restarts
+        assert_eq!(acme.jwk()?, self::acme(&dir).jwk()?);
+
+        let jws: Value = serde_json::from_str(&acme.jws("https://ca/x", "n1", None, None)?)?;
+        assert_eq!(jws["payload"], "");
+        let protected: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(jws["protected"].as_str().unwrap())?)?;
+        assert_eq!(protected["jwk"]["kty"], "EC");
+        assert_eq!(
+            URL_SAFE_NO_PAD
+                .decode(jws["signature"].as_str().unwrap())?
+                .len(),
+            64
+        );
+        let key_authorization = acme.key_authorization("tok")?;
+        let (token, thumbprint) = key_authorization.split_once('.').unwrap();
+        assert_eq!((token, thumbprint.len()), ("tok", 43));
+
+        assert!(acme.needs_renewal(None));
+        assert!(acme.needs_renewal(Some(&certificate(10))));
+        assert!(!acme.needs_renewal(Some(&certificate(60))));
+
+        let csr = X509Req::from_der(&acme.csr(&PKey::from_ec_key(new_key()?)?)?)?;
+        let public_key = csr.public_key()?;
+        assert!(csr.verify(&public_key)?);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
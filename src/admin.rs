@@ -0,0 +1,476 @@
+//! Admin API, served on its own listener.
+
+use std::{convert::Infallible, fmt::Write, net::SocketAddr, time::Instant};
+
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Server,
+};
+use serde::Deserialize;
+
+#[cfg(feature = "auth")]
+use crate::auth::KeyPool;
+use crate::{
+    context::FORWARDED, dns::CONNECT_FAILURES, maintenance::Maintenance, outlier::EJECTED,
+    reload::Reloader, route_docs::RouteDocs, server::ACTIVE, store_forward::DurableQueue,
+};
+#[cfg(feature = "cache")]
+use crate::{response_cache::ResponseCache, sanitize::percent_decode};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+}
+
+/// Handles to the components the admin API inspects and controls.
+#[derive(Clone, Default)]
+pub struct Admin {
+    pub queue: Option<DurableQueue>,
+    pub webhooks: Option<DurableQueue>,
+    pub reloader: Option<Reloader>,
+    pub routes: Option<RouteDocs>,
+    pub maintenance: Option<Maintenance>,
+    #[cfg(feature = "cache")]
+    pub cache: Option<ResponseCache>,
+    #[cfg(feature = "auth")]
+    pub keys: Option<KeyPool>,
+    /// When the proxy started, for the uptime on `/status`.
+    pub started: Option<Instant>,
+}
+
+pub fn json(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    let mut res = Response::new(Body::from(value.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+fn not_found() -> Response<Body> {
+    json(
+        StatusCode::NOT_FOUND,
+        serde_json::json!({ "error": "not found" }),
+    )
+}
+
+fn list(queue: &Option<DurableQueue>) -> Response<Body> {
+    match queue {
+        Some(queue) => json(StatusCode::OK, serde_json::json!(queue.list())),
+        None => not_found(),
+    }
+}
+
+fn remove(queue: &Option<DurableQueue>, id: &str) -> Response<Body> {
+    match (queue, id.parse()) {
+        (Some(queue), Ok(id)) if queue.remove(id) => {
+            json(StatusCode::OK, serde_json::json!({ "removed": id }))
+        }
+        _ => not_found(),
+    }
+}
+
+fn config_version(reloader: &Option<Reloader>) -> Response<Body> {
+    match reloader {
+        Some(reloader) => json(
+            StatusCode::OK,
+            serde_json::json!({ "version": reloader.version() }),
+        ),
+        None => not_found(),
+    }
+}
+
+/// Whether maintenance mode is on, after switching it to `enabled` if set.
+fn maintenance(maintenance: &Option<Maintenance>, enabled: Option<bool>) -> Response<Body> {
+    let Some(maintenance) = maintenance else {
+        return not_found();
+    };
+    if let Some(enabled) = enabled {
+        maintenance.set(enabled);
+    }
+    json(
+        StatusCode::OK,
+        serde_json::json!({ "enabled": maintenance.is_enabled() }),
+    )
+}
+
+/// The decoded value of query parameter `name` of `req`.
+#[cfg(feature = "cache")]
+fn query_param<B>(req: &Request<B>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        (key == name).then(|| {
+            String::from_utf8_lossy(&percent_decode(&value.replace('+', " "))).into_owned()
+        })
+    })
+}
+
+/// Drop the cached responses selected by the `key`, `prefix` or `tag`
+/// parameter, or all of them without any.
+#[cfg(feature = "cache")]
+fn purge_cache<B>(cache: &Option<ResponseCache>, req: &Request<B>) -> Response<Body> {
+    let Some(cache) = cache else {
+        return not_found();
+    };
+    let selectors = ["key", "prefix", "tag"].map(|name| query_param(req, name));
+    let purged = match selectors {
+        [Some(key), None, None] => cache.purge_key(&key),
+        [None, Some(prefix), None] => cache.purge_prefix(&prefix),
+        [None, None, Some(tag)] => cache.purge_tag(&tag),
+        [None, None, None] => cache.purge_all(),
+        _ => {
+            return json(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "error": "give one of key, prefix or tag" }),
+            )
+        }
+    };
+    json(StatusCode::OK, serde_json::json!({ "purged": purged }))
+}
+
+fn routes(routes: &Option<RouteDocs>) -> Response<Body> {
+    match routes {
+        Some(routes) => json(StatusCode::OK, routes.get()),
+        None => not_found(),
+    }
+}
+
+async fn reload(reloader: &Option<Reloader>) -> Response<Body> {
+    let Some(reloader) = reloader.clone() else {
+        return not_found();
+    };
+    match tokio::task::spawn_blocking(move || reloader.reload()).await {
+        Ok(Ok(version)) => json(StatusCode::OK, serde_json::json!({ "version": version })),
+        Ok(Err(err)) => json(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            serde_json::json!({ "error": err.to_string() }),
+        ),
+        Err(err) => json(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({ "error": err.to_string() }),
+        ),
+    }
+}
+
+/// The keys of the pool by label, never the keys themselves.
+#[cfg(feature = "auth")]
+fn keys(keys: &Option<KeyPool>) -> Response<Body> {
+    let Some(keys) = keys else {
+        return not_found();
+    };
+    let active = keys.active_key();
+    let list: Vec<_> = keys
+        .keys()
+        .iter()
+        .map(|key| {
+            let stats = keys.stats(key);
+            serde_json::json!({
+                "label": keys.label(key),
+                "active": active.as_ref() == Some(key),
+                "requests": stats.map_or(0, |stats| stats.requests),
+                "errors": stats.map_or(0, |stats| stats.errors),
+                "success_ratio": stats.map(|stats| stats.success_ratio),
+                "latency_ms": stats.map(|stats| stats.latency.as_millis() as u64),
+            })
+        })
+        .collect();
+    json(StatusCode::OK, serde_json::json!(list))
+}
+
+impl Admin {
+    /// What `/status` shows: uptime, forwarded requests and their error
+    /// rate, connections, the key pool by label, and upstream endpoints
+    /// ejected by outlier detection, which is what stands in for circuit
+    /// breakers here.
+    fn status(&self) -> serde_json::Value {
+        let class = |class: &str| FORWARDED.sum(&[("class", class)]) as u64;
+        let total = FORWARDED.total() as u64;
+        let failed = class("5xx") + class("error");
+        #[cfg(feature = "auth")]
+        let keys = self.keys.as_ref().map(|keys| {
+            let active = keys.active_key();
+            keys.keys()
+                .iter()
+                .map(|key| {
+                    serde_json::json!({
+                        "label": keys.label(key),
+                        "active": active.as_ref() == Some(key),
+                        "quarantined": keys.is_quarantined(key),
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        #[cfg(not(feature = "auth"))]
+        let keys: Option<Vec<serde_json::Value>> = None;
+        serde_json::json!({
+            "uptime_secs": self.started.map(|started| started.elapsed().as_secs()),
+            "config_version": self.reloader.as_ref().map(Reloader::version),
+            "maintenance": self.maintenance.as_ref().map(Maintenance::is_enabled),
+            "connections_active": ACTIVE.get(&[]) as u64,
+            "requests": {
+                "total": total,
+                "2xx": class("2xx"),
+                "3xx": class("3xx"),
+                "4xx": class("4xx"),
+                "5xx": class("5xx"),
+                "error": class("error"),
+                "error_rate": (total > 0).then(|| failed as f64 / total as f64),
+            },
+            "keys": keys,
+            "upstream": {
+                "endpoints_ejected": EJECTED.get(&[]) as u64,
+                "connect_failures": CONNECT_FAILURES.total() as u64,
+            },
+        })
+    }
+}
+
+/// `status` as a page of tables, one per section.
+fn status_html(status: &serde_json::Value) -> String {
+    fn escape(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+    fn cell(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::String(value) => escape(value),
+            serde_json::Value::Null => "-".to_owned(),
+            value => escape(&value.to_string()),
+        }
+    }
+    fn rows(out: &mut String, object: &serde_json::Map<String, serde_json::Value>) {
+        for (name, value) in object {
+            let _ = write!(
+                out,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(name),
+                cell(value)
+            );
+        }
+    }
+
+    let mut out = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>proxy status</title>\
+         <style>body{font-family:sans-serif}th{text-align:left;padding-right:1em}</style>\
+         </head><body><h1>proxy status</h1><table>",
+    );
+    let empty = serde_json::Map::new();
+    let object = status.as_object().unwrap_or(&empty);
+    let scalars = object
+        .iter()
+        .filter(|(_, value)| !value.is_object() && !value.is_array())
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    rows(&mut out, &scalars);
+    out.push_str("</table>");
+    for (name, value) in object {
+        match value {
+            serde_json::Value::Object(section) => {
+                let _ = write!(out, "<h2>{}</h2><table>", escape(name));
+                rows(&mut out, section);
+                out.push_str("</table>");
+            }
+            serde_json::Value::Array(items) => {
+                let _ = write!(out, "<h2>{}</h2><table>", escape(name));
+                let items: Vec<_> = items
+                    .iter()
+                    .filter_map(serde_json::Value::as_object)
+                    .collect();
+                if let Some(first) = items.first() {
+                    out.push_str("<tr>");
+                    for column in first.keys() {
+                        let _ = write!(out, "<th>{}</th>", escape(column));
+                    }
+                    out.push_str("</tr>");
+                }
+                for item in items {
+                    out.push_str("<tr>");
+                    for value in item.values() {
+                        let _ = write!(out, "<td>{}</td>", cell(value));
+                    }
+                    out.push_str("</tr>");
+                }
+                out.push_str("</table>");
+            }
+            _ => {}
+        }
+    }
+    out.push_str("</body></html>");
+    out
+}
+
+/// Metrics in the OpenMetrics format, with exemplars, for scrapers asking
+/// for it, or in the Prometheus one.
+#[cfg(feature = "metrics")]
+fn metrics<B>(req: &Request<B>) -> Response<Body> {
+    let openmetrics = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let (body, content_type) = if openmetrics {
+        (
+            crate::metrics::render_openmetrics(),
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )
+    } else {
+        (crate::metrics::render(), "text/plain; version=0.0.4")
+    };
+    let mut res = Response::new(Body::from(body));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res
+}
+
+impl Admin {
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().trim_end_matches('/');
+        let segments: Vec<&str> = path.split('/').skip(1).collect();
+
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["queue"]) => list(&self.queue),
+            (&Method::DELETE, ["queue", id]) => remove(&self.queue, id),
+            (&Method::GET, ["webhooks"]) => list(&self.webhooks),
+            (&Method::DELETE, ["webhooks", id]) => remove(&self.webhooks, id),
+            (&Method::GET, ["config"]) => config_version(&self.reloader),
+            (&Method::POST, ["reload"]) => reload(&self.reloader).await,
+            (&Method::GET, ["routes"]) => routes(&self.routes),
+            #[cfg(feature = "cache")]
+            (&Method::GET, ["cache"]) => match &self.cache {
+                Some(cache) => json(StatusCode::OK, serde_json::json!(cache.list())),
+                None => not_found(),
+            },
+            #[cfg(feature = "cache")]
+            (&Method::DELETE, ["cache"]) => purge_cache(&self.cache, &req),
+            (&Method::GET, ["version"]) => json(StatusCode::OK, crate::version::info()),
+            (&Method::GET, ["maintenance"]) => maintenance(&self.maintenance, None),
+            (&Method::POST, ["maintenance"]) => maintenance(&self.maintenance, Some(true)),
+            (&Method::DELETE, ["maintenance"]) => maintenance(&self.maintenance, Some(false)),
+            #[cfg(feature = "auth")]
+            (&Method::GET, ["keys"]) => keys(&self.keys),
+            (&Method::GET, ["status"]) => {
+                let status = self.status();
+                let html = req
+                    .headers()
+                    .get(ACCEPT)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|accept| accept.contains("text/html"));
+                if !html {
+                    return json(StatusCode::OK, status);
+                }
+                let mut res = Response::new(Body::from(status_html(&status)));
+                res.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                res
+            }
+            #[cfg(feature = "metrics")]
+            (&Method::GET, ["metrics"]) => metrics(&req),
+            _ => not_found(),
+        }
+    }
+
+    /// Serve the admin API until the process exits.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        tracing::info!(%addr, "admin API listening");
+        let make_service = make_service_fn(move |_| {
+            let admin = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let admin = admin.clone();
+                    async move { Ok::<_, Infallible>(admin.handle(req).await) }
+                }))
+            }
+        });
+        Server::bind(&addr).serve(make_service).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_status() -> Result<(), tower::BoxError> {
+        let admin = Admin {
+            started: Some(Instant::now()),
+            maintenance: Some(Maintenance::new(true)),
+            ..Default::default()
+        };
+        let res = admin
+            .handle(Request::get("/status").body(Body::empty())?)
+            .await;
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let status: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(status["uptime_secs"], 0);
+        assert_eq!(status["maintenance"], true);
+        assert!(status["requests"]["total"].is_u64());
+
+        let req = Request::get("/status/")
+            .header(ACCEPT, "text/html,*/*")
+            .body(Body::empty())?;
+        let res = admin.handle(req).await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+
+        // labels come from the config, and are escaped
+        let page = status_html(&serde_json::json!({
+            "keys": [{ "label": "<fleet>", "active": true }],
+        }));
+        assert!(page.contains("<tr><th>active</th><th>label</th></tr>"));
+        assert!(page.contains("<tr><td>true</td><td>&lt;fleet&gt;</td></tr>"));
+        Ok(())
+    }
+
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn test_purge_cache() -> Result<(), tower::BoxError> {
+        let config = serde_json::from_value(serde_json::json!({ "routes": [] }))?;
+        let admin = Admin {
+            cache: Some(crate::response_cache::CacheLayer::new(config).cache()),
+            ..Default::default()
+        };
+        let purge = |uri: &str| {
+            let req = Request::delete(uri).body(Body::empty()).unwrap();
+            admin.handle(req)
+        };
+
+        let res = purge("/cache?key=ab12cd34+%2Fv6%2Fdevice(1)").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body, r#"{"purged":0}"#);
+        let res = purge("/cache?prefix=/v6&tag=device").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::get("/cache?key=a+b").body(Body::empty())?;
+        assert_eq!(query_param(&req, "key").as_deref(), Some("a b"));
+        let res = Admin::default().handle(req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version() -> Result<(), tower::BoxError> {
+        let res = Admin::default()
+            .handle(Request::get("/version").body(Body::empty())?)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let version: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!version["git_sha"].as_str().unwrap().is_empty());
+        assert!(version["built_at"].is_string());
+        assert_eq!(
+            version["features"].as_array().unwrap().len(),
+            crate::version::features().len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! Request metadata for fleet analytics.
+//!
+//! [`AnalyticsSink`] is a [`ProxyPlugin`] that sends one datagram per sampled
+//! request to a local collector, over UDP or a unix datagram socket. A record
+//! holds the method, the path without its query, the final status, the time
+//! the request took and the device UUID when the request names one. Sends
+//! never wait: when the collector is down or its socket buffer is full, the
+//! record is dropped and counted.
+//!
+//! Records are JSON objects, or with `format: binary`, big-endian:
+//!
+//! | bytes | field                                   |
+//! |-------|-----------------------------------------|
+//! | 1     | version, 1                              |
+//! | 2     | status, 0 when the request failed       |
+//! | 4     | elapsed microseconds                    |
+//! | 1 + n | method length and method                |
+//! | 2 + n | path length and path                    |
+//! | 1 + n | device UUID length and UUID, 0 if none  |
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{os::unix::net::UnixDatagram, path::PathBuf};
+
+use http::{Method, StatusCode, Uri};
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::{
+    metrics::Metric,
+    odata::ODataQuery,
+    plugin::ProxyPlugin,
+    rng::{HasherRng, Rng},
+    route::RouteMatcher,
+};
+
+const DROPPED: Metric = Metric::counter(
+    "proxy_analytics_dropped_total",
+    "Analytics records the collector did not take.",
+);
+
+/// Paths are cut to this many bytes so a record fits a datagram.
+const MAX_PATH: usize = 2048;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsFormat {
+    #[default]
+    Json,
+    Binary,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    /// `udp://host:port` or `unix:///path/to/socket`.
+    pub collector: String,
+    /// Fraction of requests reported.
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    #[serde(default)]
+    pub format: AnalyticsFormat,
+    /// Requests reported, all when empty.
+    #[serde(default)]
+    pub routes: Vec<RouteMatcher>,
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+enum Collector {
+    Udp(UdpSocket, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixDatagram, PathBuf),
+}
+
+impl Collector {
+    fn new(address: &str) -> Result<Self, BoxError> {
+        if let Some(addr) = address.strip_prefix("udp://") {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| format!("collector {} does not resolve", address))?;
+            let local = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.set_nonblocking(true)?;
+            return Ok(Collector::Udp(socket, addr));
+        }
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix://") {
+            let socket = UnixDatagram::unbound()?;
+            socket.set_nonblocking(true)?;
+            return Ok(Collector::Unix(socket, path.into()));
+        }
+        Err(format!("collector {} is neither udp:// nor unix://", address).into())
+    }
+
+    fn send(&self, record: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Collector::Udp(socket, addr) => socket.send_to(record, addr),
+            #[cfg(unix)]
+            Collector::Unix(socket, path) => socket.send_to(record, path),
+        }
+    }
+}
+
+/// What is reported of a request.
+#[derive(Debug, PartialEq)]
+struct Record<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: Option<StatusCode>,
+    elapsed: Duration,
+    device: Option<&'a str>,
+}
+
+impl Record<'_> {
+    fn encode(&self, format: AnalyticsFormat) -> Vec<u8> {
+        let elapsed = u32::try_from(self.elapsed.as_micros()).unwrap_or(u32::MAX);
+        match format {
+            AnalyticsFormat::Json => serde_json::json!({
+                "method": self.method,
+                "path": self.path,
+                "status": self.status.map(|status| status.as_u16()),
+                "elapsed_us": elapsed,
+                "device": self.device,
+            })
+            .to_string()
+            .into_bytes(),
+            AnalyticsFormat::Binary => {
+                let method = truncate(self.method, u8::MAX.into());
+                let device = truncate(self.device.unwrap_or_default(), u8::MAX.into());
+                let mut record = vec![1];
+                record.extend(
+                    self.status
+                        .map_or(0, |status| status.as_u16())
+                        .to_be_bytes(),
+                );
+                record.extend(elapsed.to_be_bytes());
+                record.push(method.len() as u8);
+                record.extend(method.as_bytes());
+                record.extend((self.path.len() as u16).to_be_bytes());
+                record.extend(self.path.as_bytes());
+                record.push(device.len() as u8);
+                record.extend(device.as_bytes());
+                record
+            }
+        }
+    }
+}
+
+/// The longest prefix of `s` of at most `max` bytes.
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub struct AnalyticsSink {
+    collector: Collector,
+    config: AnalyticsConfig,
+    rng: Mutex<HasherRng>,
+}
+
+impl AnalyticsSink {
+    /// Open a socket to the collector of `config`, which need not be up yet.
+    pub fn new(config: AnalyticsConfig) -> Result<Self, BoxError> {
+        Ok(Self {
+            collector: Collector::new(&config.collector)?,
+            config,
+            rng: Mutex::new(HasherRng::new()),
+        })
+    }
+
+    fn sample(&self, method: &Method, uri: &Uri) -> bool {
+        (self.config.routes.is_empty()
+            || self
+                .config
+                .routes
+                .iter()
+                .any(|route| route.matches_parts(method, uri.path())))
+            && (self.config.rate >= 1.0 || self.rng.lock().unwrap().next_f64() < self.config.rate)
+    }
+}
+
+impl ProxyPlugin for AnalyticsSink {
+    fn name(&self) -> &str {
+        "analytics"
+    }
+
+    fn on_complete(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        status: Option<StatusCode>,
+        elapsed: Duration,
+    ) {
+        if !self.sample(method, uri) {
+            return;
+        }
+        let query = ODataQuery::parse(uri);
+        let record = Record {
+            method: method.as_str(),
+            path: truncate(uri.path(), MAX_PATH),
+            status,
+            elapsed,
+            device: query.as_ref().and_then(ODataQuery::uuid),
+        };
+        if let Err(err) = self.collector.send(&record.encode(self.config.format)) {
+            tracing::trace!(%err, "analytics record dropped");
+            DROPPED.increment(&[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analytics_sink() -> Result<(), BoxError> {
+        let collector = UdpSocket::bind("127.0.0.1:0")?;
+        collector.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let sink = AnalyticsSink::new(serde_json::from_value(serde_json::json!({
+            "collector": format!("udp://{}", collector.local_addr()?),
+            "routes": [{ "path_prefix": "/v6/device" }],
+        }))?)?;
+
+        let device = Uri::from_static("/v6/device?$filter=uuid%20eq%20'abc'&$select=id");
+        sink.on_complete(&Method::GET, &device, None, Duration::from_millis(2));
+        // unmatched routes are not reported
+        let release = Uri::from_static("/v6/release");
+        sink.on_complete(&Method::GET, &release, None, Duration::ZERO);
+        sink.on_complete(
+            &Method::PATCH,
+            &device,
+            Some(StatusCode::OK),
+            Duration::ZERO,
+        );
+
+        let mut buf = [0; 1024];
+        let n = collector.recv(&mut buf)?;
+        let record: serde_json::Value = serde_json::from_slice(&buf[..n])?;
+        assert_eq!(
+            record,
+            serde_json::json!({
+                "method": "GET", "path": "/v6/device", "status": null,
+                "elapsed_us": 2000, "device": "abc",
+            })
+        );
+        let n = collector.recv(&mut buf)?;
+        let record: serde_json::Value = serde_json::from_slice(&buf[..n])?;
+        assert_eq!(record["method"], "PATCH");
+        assert_eq!(record["status"], 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_record() {
+        let record = Record {
+            method: "GET",
+            path: "/v6/device",
+            status: Some(StatusCode::NOT_FOUND),
+            elapsed: Duration::from_micros(258),
+            device: Some("ab"),
+        };
+        let mut expected = vec![1, 1, 148, 0, 0, 1, 2, 3];
+        expected.extend(b"GET");
+        expected.extend([0, 10]);
+        expected.extend(b"/v6/device");
+        expected.extend([2]);
+        expected.extend(b"ab");
+        assert_eq!(record.encode(AnalyticsFormat::Binary), expected);
+        assert!(AnalyticsSink::new(
+            serde_json::from_value(serde_json::json!({ "collector": "tcp://127.0.0.1:1" }))
+                .unwrap()
+        )
+        .is_err());
+    }
+}
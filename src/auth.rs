@@ -1,49 +1,694 @@
 use std::{
-    sync::{Arc, RwLock},
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use futures_core::{ready, Future};
-use http::{header::AUTHORIZATION, HeaderValue, Request, Response, StatusCode};
+use http::{
+    header::{AUTHORIZATION, RETRY_AFTER},
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
+};
 use pin_project_lite::pin_project;
+use serde::Deserialize;
+use tokio::sync::{broadcast, Notify};
 use tower::{Layer, Service};
 
+use crate::{
+    context::{key_id, ProxyContext},
+    metrics::Metric,
+    redirect::ForeignHost,
+    secret::ApiKey,
+};
+
+pub const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+pub const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
+const KEY_REQUESTS: Metric = Metric::counter(
+    "proxy_key_requests_total",
+    "Upstream requests made with each pooled key.",
+);
+const KEY_ERRORS: Metric = Metric::counter(
+    "proxy_key_errors_total",
+    "Upstream requests with each pooled key that failed, were throttled or rejected.",
+);
+const KEY_SUCCESS_RATIO: Metric = Metric::gauge(
+    "proxy_key_success_ratio",
+    "Recent share of successful upstream requests with each pooled key.",
+);
+const KEY_LATENCY: Metric = Metric::gauge(
+    "proxy_key_latency_seconds",
+    "Recent upstream latency with each pooled key.",
+);
+
+/// Weight of the latest request in the recent success ratio and latency.
+const RECENT_WEIGHT: f64 = 0.1;
+
+const KEY_IN_FLIGHT: Metric = Metric::gauge(
+    "proxy_key_in_flight",
+    "Upstream requests in flight with each pooled key.",
+);
+
+const KEY_QUARANTINES: Metric = Metric::counter(
+    "proxy_key_quarantines_total",
+    "Pooled keys set aside after a response mapped to quarantine.",
+);
+
+/// Period of the key budgets.
+const BUDGET_PERIOD: Duration = Duration::from_secs(3600);
+
+/// How long quarantined keys sit out by default.
+const QUARANTINE_SECS: u64 = 600;
+
+/// How long requests wait for a key with room by default.
+const IN_FLIGHT_WAIT_MS: u64 = 1000;
+
+/// What the pool does with a key that got a given upstream status.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAction {
+    /// Drop the key from the pool, as it was revoked.
+    Remove,
+    /// Move on from the key, as it is rate limited.
+    Rotate,
+    /// Set the key aside for a while and alert, e.g. as it lacks a scope.
+    Quarantine,
+    /// Keep using the key.
+    Ignore,
+}
+
+/// The actions on statuses not configured otherwise.
+fn default_action(status: StatusCode) -> Option<KeyAction> {
+    match status {
+        StatusCode::UNAUTHORIZED => Some(KeyAction::Remove),
+        StatusCode::TOO_MANY_REQUESTS => Some(KeyAction::Rotate),
+        _ => None,
+    }
+}
+
+/// How the pool uses its keys. Read at startup only, like the keys: reloads
+/// changing it are refused.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyPoolConfig {
+    /// Requests per hour each key may make before the pool moves on from
+    /// it, unlimited when unset.
+    pub requests_per_hour: Option<u64>,
+    /// Requests per hour of single keys, by their name.
+    pub budgets: HashMap<String, u64>,
+    /// Names of the keys in logs, metrics and the admin API, by the hash
+    /// they go by otherwise. The hash of a key is the `key` label of its
+    /// metrics until it is named.
+    pub labels: HashMap<String, String>,
+    /// Seconds after which the active key is rotated, errors or not.
+    pub rotate_every_secs: Option<u64>,
+    /// Requests after which the active key is rotated, errors or not.
+    pub rotate_every_requests: Option<u64>,
+    /// Actions on upstream statuses, e.g. `{"403": "quarantine"}`, on top of
+    /// removing keys on 401 and rotating on 429.
+    pub statuses: HashMap<u16, KeyAction>,
+    /// Seconds quarantined keys sit out, 600 by default.
+    pub quarantine_secs: Option<u64>,
+    /// Requests each key may have in flight at once, unlimited when unset.
+    /// Requests spill over to the next key with room, and wait for one
+    /// when every key is saturated.
+    pub max_in_flight: Option<usize>,
+    /// Milliseconds a request waits for a key with room, 1000 by default,
+    /// before it goes with the active key regardless.
+    pub in_flight_wait_ms: Option<u64>,
+}
+
+/// Requests made with one key in the current budget period.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    since: Instant,
+    used: u64,
+}
+
+/// Upstream quota of one key, as last reported by the upstream.
+#[derive(Debug, Clone, Copy)]
+struct Quota {
+    limit: u64,
+    remaining: u64,
+    reset_at: Instant,
+}
+
+/// Quota left across the pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolQuota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the next key gets its quota back.
+    pub reset_secs: u64,
+}
+
+/// Upstream outcomes of one key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyStats {
+    pub requests: u64,
+    pub errors: u64,
+    /// Share of successful requests, weighted towards the recent ones.
+    pub success_ratio: f64,
+    /// Latency, weighted towards the recent requests.
+    pub latency: Duration,
+}
+
+/// Whether a response with `status` counts against the key that got it.
+fn is_key_error(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Seconds until the reset time in `value`, which may be a delay in seconds
+/// or a unix timestamp.
+fn reset_secs(value: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if value > 1_000_000_000 {
+        value.saturating_sub(now)
+    } else {
+        value
+    }
+}
+
+/// A change the upstream made us make to the pool.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyEvent {
+    /// The key got a status mapped to removal, 401 by default, and left the
+    /// pool.
+    Removed(ApiKey),
+    /// The key got a status mapped to rotation, 429 by default, and is no
+    /// longer the active one.
+    Rotated(ApiKey),
+}
+
 #[derive(Clone)]
 pub struct KeyPool {
-    data: Arc<RwLock<(Vec<String>, usize)>>,
+    data: Arc<RwLock<(Vec<ApiKey>, usize)>>,
+    quotas: Arc<Mutex<HashMap<ApiKey, Quota>>>,
+    stats: Arc<Mutex<HashMap<ApiKey, KeyStats>>>,
+    /// Names of the keys, by key.
+    names: Arc<HashMap<ApiKey, String>>,
+    /// Requests per hour of each key, by key.
+    budgets: Arc<HashMap<ApiKey, u64>>,
+    usage: Arc<Mutex<HashMap<ApiKey, Usage>>>,
+    /// Quarantined keys, until when.
+    quarantined: Arc<Mutex<HashMap<ApiKey, Instant>>>,
+    statuses: Arc<HashMap<u16, KeyAction>>,
+    quarantine: Duration,
+    rotate_every_requests: Option<u64>,
+    taken: Arc<AtomicU64>,
+    max_in_flight: Option<usize>,
+    in_flight_wait: Duration,
+    /// Requests in flight with each key, counted with `max_in_flight` only.
+    in_flight: Arc<Mutex<HashMap<ApiKey, usize>>>,
+    /// Woken when a request in flight completes.
+    in_flight_done: Arc<Notify>,
+    events: broadcast::Sender<KeyEvent>,
 }
 
 impl From<Vec<&str>> for KeyPool {
     fn from(value: Vec<&str>) -> Self {
-        let keys = value.iter().map(|s| s.to_string()).collect();
+        let keys = value.into_iter().map(ApiKey::from).collect();
         KeyPool::new(keys)
     }
 }
 
 impl KeyPool {
-    pub fn new(keys: Vec<String>) -> KeyPool {
+    pub fn new(keys: Vec<ApiKey>) -> KeyPool {
         KeyPool {
             data: Arc::new(RwLock::new((keys, 0))),
+            quotas: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            names: Arc::new(HashMap::new()),
+            budgets: Arc::new(HashMap::new()),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            quarantined: Arc::new(Mutex::new(HashMap::new())),
+            statuses: Arc::new(HashMap::new()),
+            quarantine: Duration::from_secs(QUARANTINE_SECS),
+            rotate_every_requests: None,
+            taken: Arc::new(AtomicU64::new(0)),
+            max_in_flight: None,
+            in_flight_wait: Duration::from_millis(IN_FLIGHT_WAIT_MS),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            in_flight_done: Arc::new(Notify::new()),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    /// Use the keys as `config` says.
+    pub fn with_config(mut self, config: &KeyPoolConfig) -> KeyPool {
+        let names: HashMap<ApiKey, String> = self
+            .keys()
+            .into_iter()
+            .map(|key| {
+                let id = key_id(&key);
+                let name = config.labels.get(&id).cloned().unwrap_or(id);
+                (key, name)
+            })
+            .collect();
+        let budgets = names
+            .iter()
+            .filter_map(|(key, name)| {
+                let budget = config
+                    .budgets
+                    .get(name)
+                    .copied()
+                    .or(config.requests_per_hour)?;
+                Some((key.clone(), budget))
+            })
+            .collect();
+        self.names = Arc::new(names);
+        self.budgets = Arc::new(budgets);
+        self.rotate_every_requests = config.rotate_every_requests.filter(|&n| n > 0);
+        self.statuses = Arc::new(config.statuses.clone());
+        self.quarantine = Duration::from_secs(config.quarantine_secs.unwrap_or(QUARANTINE_SECS));
+        self.max_in_flight = config.max_in_flight.filter(|&n| n > 0);
+        self.in_flight_wait =
+            Duration::from_millis(config.in_flight_wait_ms.unwrap_or(IN_FLIGHT_WAIT_MS));
+        self
+    }
+
+    /// What to do with a key that got `status`, if anything.
+    pub fn action(&self, status: StatusCode) -> Option<KeyAction> {
+        match self.statuses.get(&status.as_u16()) {
+            Some(action) => Some(*action),
+            None => default_action(status),
+        }
+    }
+
+    /// Set `key` aside, moving on from it if it is the active one. It is
+    /// used again once the quarantine is over, or when no other key is left.
+    pub fn quarantine_key(&self, key: &str, status: StatusCode) {
+        if !self.data.read().unwrap().0.iter().any(|k| k == key) {
+            return;
+        }
+        let until = Instant::now() + self.quarantine;
+        self.quarantined
+            .lock()
+            .unwrap()
+            .insert(ApiKey::from(key), until);
+        let name = self.label(key);
+        KEY_QUARANTINES.increment(&[("key", &name)]);
+        tracing::error!(
+            key = %name,
+            status = status.as_u16(),
+            secs = self.quarantine.as_secs(),
+            "key quarantined, check its scopes"
+        );
+        self.rotate_key(key);
+    }
+
+    /// Whether `key` is in quarantine.
+    pub fn is_quarantined(&self, key: &str) -> bool {
+        self.quarantined
+            .lock()
+            .unwrap()
+            .get(key)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    pub fn keys(&self) -> Vec<ApiKey> {
+        self.data.read().unwrap().0.clone()
+    }
+
+    /// The name `key` goes by in logs and metrics: its label, or its hash.
+    pub fn label(&self, key: &str) -> String {
+        self.names.get(key).cloned().unwrap_or_else(|| key_id(key))
+    }
+
+    /// Removals and rotations made from now on, not including those made
+    /// with [`KeyPool::remove_key`] and [`KeyPool::rotate_key`].
+    pub fn events(&self) -> broadcast::Receiver<KeyEvent> {
+        self.events.subscribe()
+    }
+
+    /// Remove `key` wherever it is in the pool, e.g. as another replica found
+    /// it revoked. Returns whether it was there.
+    pub fn remove_key(&self, key: &str) -> bool {
+        let mut data = self.data.write().unwrap();
+        let Some(index) = data.0.iter().position(|k| k == key) else {
+            return false;
+        };
+        data.0.remove(index);
+        self.quotas.lock().unwrap().remove(key);
+        self.stats.lock().unwrap().remove(key);
+        // keep the active key active
+        if index < data.1 {
+            data.1 -= 1;
+        }
+        if data.1 >= data.0.len() {
+            data.1 = 0;
+        }
+        true
+    }
+
+    /// Move on to the next key, e.g. on schedule. Unlike
+    /// [`KeyPool::shift_active_key`] this says nothing about the key, so it
+    /// is not an event.
+    pub fn rotate(&self) {
+        let mut data = self.data.write().unwrap();
+        let len = data.0.len();
+        if len > 1 {
+            data.1 = (data.1 + 1) % len;
+            tracing::debug!("active key rotated on schedule");
+        }
+    }
+
+    /// Move on from `key` if it is the active one.
+    pub fn rotate_key(&self, key: &str) {
+        let mut data = self.data.write().unwrap();
+        let len = data.0.len();
+        if len > 1 && data.0[data.1] == key {
+            data.1 = (data.1 + 1) % len;
+        }
+    }
+
+    /// Remember the quota the upstream reported for `key` in `headers`.
+    /// Keys outside the pool, e.g. ones sent by clients, are not tracked.
+    pub fn record_quota(&self, key: &str, status: StatusCode, headers: &HeaderMap) {
+        if !self.data.read().unwrap().0.iter().any(|k| k == key) {
+            return;
+        }
+        let mut quotas = self.quotas.lock().unwrap();
+        let known = quotas.get(key).copied();
+        let mut limit = header_u64(headers, X_RATELIMIT_LIMIT).or(known.map(|q| q.limit));
+        let mut remaining = header_u64(headers, X_RATELIMIT_REMAINING);
+        let mut reset = header_u64(headers, X_RATELIMIT_RESET).map(reset_secs);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // exhausted even if the upstream never told the limit
+            limit = limit.or(Some(0));
+            remaining = Some(0);
+            reset = reset.or(header_u64(headers, RETRY_AFTER.as_str()));
+        }
+        if let (Some(limit), Some(remaining)) = (limit, remaining) {
+            let reset_at = Instant::now() + Duration::from_secs(reset.unwrap_or(60));
+            quotas.insert(
+                ApiKey::from(key),
+                Quota {
+                    limit,
+                    remaining,
+                    reset_at,
+                },
+            );
+        }
+    }
+
+    /// Account an upstream request made with `key` that took `latency`, and
+    /// `failed` if it errored or its response counts against the key. Keys
+    /// outside the pool are not tracked.
+    pub fn record_outcome(&self, key: &str, failed: bool, latency: Duration) {
+        if !self.data.read().unwrap().0.iter().any(|k| k == key) {
+            return;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(ApiKey::from(key)).or_insert(KeyStats {
+            requests: 0,
+            errors: 0,
+            success_ratio: 1.0,
+            latency,
+        });
+        let success = if failed { 0.0 } else { 1.0 };
+        stats.requests += 1;
+        stats.errors += failed as u64;
+        stats.success_ratio += RECENT_WEIGHT * (success - stats.success_ratio);
+        stats.latency = stats.latency.mul_f64(1.0 - RECENT_WEIGHT) + latency.mul_f64(RECENT_WEIGHT);
+
+        let name = self.label(key);
+        let labels = [("key", name.as_str())];
+        KEY_REQUESTS.increment(&labels);
+        if failed {
+            KEY_ERRORS.increment(&labels);
+        }
+        KEY_SUCCESS_RATIO.set(&labels, stats.success_ratio);
+        KEY_LATENCY.set(&labels, stats.latency.as_secs_f64());
+    }
+
+    /// The upstream outcomes of `key`, if any request was made with it.
+    pub fn stats(&self, key: &str) -> Option<KeyStats> {
+        self.stats.lock().unwrap().get(key).copied()
+    }
+
+    /// Aggregate the known quotas of the keys in the pool.
+    pub fn quota(&self) -> Option<PoolQuota> {
+        let keys = self.data.read().unwrap().0.clone();
+        let quotas = self.quotas.lock().unwrap();
+        let now = Instant::now();
+        let mut total: Option<PoolQuota> = None;
+        for quota in keys.iter().filter_map(|key| quotas.get(key)) {
+            let expired = quota.reset_at <= now;
+            let remaining = if expired {
+                quota.limit
+            } else {
+                quota.remaining
+            };
+            let reset_secs = quota.reset_at.saturating_duration_since(now).as_secs();
+            let total = total.get_or_insert(PoolQuota {
+                limit: 0,
+                remaining: 0,
+                reset_secs,
+            });
+            total.limit += quota.limit;
+            total.remaining += remaining;
+            total.reset_secs = total.reset_secs.min(reset_secs);
+        }
+        total
+    }
+
+    /// Order the keys by health, making the first the active one: keys
+    /// whose requests failed go last, then those out of quota, and the rest
+    /// by the quota they have left, keys without a reported quota after
+    /// those with one, then by latency.
+    pub fn rank(&self) {
+        let mut data = self.data.write().unwrap();
+        let quotas = self.quotas.lock().unwrap();
+        let stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        data.0.sort_by_cached_key(|key| {
+            let stats = stats.get(key);
+            let remaining = quotas.get(key).map(|quota| {
+                if quota.reset_at <= now {
+                    quota.limit
+                } else {
+                    quota.remaining
+                }
+            });
+            (
+                stats.is_some_and(|stats| stats.errors > 0),
+                remaining == Some(0),
+                std::cmp::Reverse(remaining),
+                stats.map_or(Duration::MAX, |stats| stats.latency),
+            )
+        });
+        data.1 = 0;
+    }
+
+    /// The key to make a request with: the active one, or if it is out of
+    /// budget or quarantined the next one that is not, which becomes the
+    /// active one. When every key is out of budget or quarantined the active
+    /// one is used regardless, and the upstream has the last word. Keys with
+    /// `max_in_flight` requests in flight are passed over, without moving on
+    /// from them, and the request is counted in flight with the key taken
+    /// until [`KeyPool::release`].
+    pub fn take_key(&self) -> Option<ApiKey> {
+        let mut data = self.data.write().unwrap();
+        let len = data.0.len();
+        let mut usage = self.usage.lock().unwrap();
+        let quarantined = self.quarantined.lock().unwrap();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let now = Instant::now();
+        let mut unavailable = false;
+        for offset in 0..len {
+            let index = (data.1 + offset) % len;
+            let key = &data.0[index];
+            if quarantined.get(key).is_some_and(|until| *until > now) {
+                unavailable = true;
+                continue;
+            }
+            if self
+                .max_in_flight
+                .is_some_and(|max| in_flight.get(key).copied().unwrap_or(0) >= max)
+            {
+                continue;
+            }
+            if let Some(&budget) = self.budgets.get(key) {
+                let usage = usage.entry(key.clone()).or_insert(Usage {
+                    since: now,
+                    used: 0,
+                });
+                if now.duration_since(usage.since) >= BUDGET_PERIOD {
+                    *usage = Usage {
+                        since: now,
+                        used: 0,
+                    };
+                }
+                if usage.used >= budget {
+                    unavailable = true;
+                    continue;
+                }
+                usage.used += 1;
+            }
+            if unavailable {
+                tracing::warn!(key = %self.label(&data.0[data.1]), "key unavailable, rotated");
+                data.1 = index;
+            }
+            let key = data.0[index].clone();
+            if let Some(every) = self.rotate_every_requests {
+                if (self.taken.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every) {
+                    data.1 = (index + 1) % len;
+                }
+            }
+            self.count_in_flight(&mut in_flight, &key, 1);
+            return Some(key);
         }
+        let key = data.0.get(data.1).cloned()?;
+        self.count_in_flight(&mut in_flight, &key, 1);
+        Some(key)
     }
 
-    pub fn active_key(&self) -> Option<String> {
+    /// Take `key` rather than the one [`KeyPool::take_key`] would, counting
+    /// the request in flight with it all the same.
+    pub fn take_this_key(&self, key: ApiKey) -> ApiKey {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        self.count_in_flight(&mut in_flight, &key, 1);
+        key
+    }
+
+    fn count_in_flight(&self, in_flight: &mut HashMap<ApiKey, usize>, key: &ApiKey, delta: isize) {
+        if self.max_in_flight.is_none() {
+            return;
+        }
+        let count = in_flight.entry(key.clone()).or_insert(0);
+        *count = count.saturating_add_signed(delta);
+        KEY_IN_FLIGHT.set(&[("key", &self.label(key))], *count as f64);
+    }
+
+    /// Account a request taken with `key` by [`KeyPool::take_key`] as no
+    /// longer in flight, waking a request waiting for room.
+    pub fn release(&self, key: &ApiKey) {
+        if self.max_in_flight.is_none() {
+            return;
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        self.count_in_flight(&mut in_flight, key, -1);
+        if in_flight.get(key) == Some(&0) {
+            in_flight.remove(key);
+        }
+        self.in_flight_done.notify_one();
+    }
+
+    /// Whether every key has `max_in_flight` requests in flight.
+    pub fn is_saturated(&self) -> bool {
+        let Some(max) = self.max_in_flight else {
+            return false;
+        };
+        let keys = self.data.read().unwrap().0.clone();
+        let in_flight = self.in_flight.lock().unwrap();
+        !keys.is_empty()
+            && keys
+                .iter()
+                .all(|key| in_flight.get(key).copied().unwrap_or(0) >= max)
+    }
+
+    /// Wait until a key has room for another request, for up to
+    /// `in_flight_wait_ms`. Returns whether one has.
+    pub async fn wait_for_room(&self) -> bool {
+        let deadline = tokio::time::Instant::now() + self.in_flight_wait;
+        loop {
+            // registered before the check, so a release in between is not missed
+            let done = self.in_flight_done.notified();
+            if !self.is_saturated() {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, done).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// How long until the first key gets its quota back, when every key in
+    /// the pool is out of it.
+    pub fn exhausted_for(&self) -> Option<Duration> {
+        let keys = self.data.read().unwrap().0.clone();
+        let quotas = self.quotas.lock().unwrap();
+        let now = Instant::now();
+        let mut wait: Option<Duration> = None;
+        for key in &keys {
+            let quota = quotas.get(key)?;
+            if quota.remaining > 0 || quota.reset_at <= now {
+                return None;
+            }
+            let left = quota.reset_at - now;
+            wait = Some(wait.map_or(left, |wait| wait.min(left)));
+        }
+        wait
+    }
+
+    /// Spacing of requests with `key` that spends the quota it has left
+    /// evenly until it resets, if the upstream reported one that is not used
+    /// up or over. Keys outside the pool have none.
+    pub fn quota_interval(&self, key: &str) -> Option<Duration> {
+        let quota = *self.quotas.lock().unwrap().get(key)?;
+        let left = quota.reset_at.checked_duration_since(Instant::now())?;
+        (quota.remaining > 0).then(|| left / quota.remaining.min(u32::MAX as u64) as u32)
+    }
+
+    /// Requests `key` has left until its quota resets, if the upstream
+    /// reported one.
+    pub fn remaining(&self, key: &str) -> Option<u64> {
+        let quota = *self.quotas.lock().unwrap().get(key)?;
+        if quota.reset_at <= Instant::now() {
+            Some(quota.limit)
+        } else {
+            Some(quota.remaining)
+        }
+    }
+
+    /// The first key after the active one that is not quarantined and has
+    /// more than `low` requests left, or no reported quota.
+    pub fn next_key(&self, low: u64) -> Option<ApiKey> {
+        let data = self.data.read().unwrap();
+        let len = data.0.len();
+        (1..len)
+            .map(|offset| &data.0[(data.1 + offset) % len])
+            .find(|key| {
+                !self.is_quarantined(key) && self.remaining(key).is_none_or(|left| left > low)
+            })
+            .cloned()
+    }
+
+    pub fn active_key(&self) -> Option<ApiKey> {
         let data = self.data.read().unwrap();
         let cursor = data.1;
         data.0.get(cursor).cloned()
     }
 
-    pub fn remove_active_key(&self) -> Option<String> {
+    pub fn remove_active_key(&self) -> Option<ApiKey> {
         let mut data = self.data.write().unwrap();
         if data.0.is_empty() {
             None
         } else {
             let cursor = data.1;
             let key = data.0.remove(cursor);
-            tracing::log::warn!("active key removed: {}", key);
+            self.quotas.lock().unwrap().remove(&key);
+            self.stats.lock().unwrap().remove(&key);
+            tracing::warn!(key = %self.label(&key), "active key removed");
             if data.1 >= data.0.len() {
                 data.1 = 0;
             }
+            let _ = self.events.send(KeyEvent::Removed(key.clone()));
             Some(key)
         }
     }
@@ -54,35 +699,92 @@ impl KeyPool {
         if len > 1 {
             let current_cursor = data.1;
             data.1 = (current_cursor + 1) % len;
-            tracing::log::warn!("active key shifted");
+            tracing::warn!(key = %self.label(&data.0[current_cursor]), "active key shifted");
+            let _ = self
+                .events
+                .send(KeyEvent::Rotated(data.0[current_cursor].clone()));
         }
     }
 
-    pub fn shift_active_key_if_equal(&self, key: Option<String>) {
-        if self.active_key() == key {
-            self.shift_active_key();
+    /// Remove `key`, which a request was made with, wherever it is in the
+    /// pool: requests may spill over to keys other than the active one, or
+    /// be hedged with the next.
+    pub fn remove_used_key(&self, key: &str) {
+        if self.remove_key(key) {
+            tracing::warn!(key = %self.label(key), "key removed");
+            let _ = self.events.send(KeyEvent::Removed(ApiKey::from(key)));
         }
     }
 
-    pub fn remove_active_key_if_equal(&self, key: Option<String>) {
-        if self.active_key() == key {
-            self.remove_active_key();
+    /// Move on from `key`, which a request was made with, if it is the
+    /// active one. Either way, other replicas are told about it as long as
+    /// it is in the pool.
+    pub fn shift_used_key(&self, key: &str) {
+        let mut data = self.data.write().unwrap();
+        let Some(index) = data.0.iter().position(|k| k == key) else {
+            return;
+        };
+        let len = data.0.len();
+        if index == data.1 && len > 1 {
+            data.1 = (index + 1) % len;
+        }
+        tracing::warn!(key = %self.label(key), "key shifted");
+        let _ = self.events.send(KeyEvent::Rotated(data.0[index].clone()));
+    }
+}
+
+/// Rotate the active key of `keys` every `period`, so that no single key
+/// carries all the traffic for long.
+pub fn spawn_rotation(keys: KeyPool, period: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            keys.rotate();
         }
+    });
+}
+
+/// A request taken with a pooled key, in flight until dropped.
+struct InFlight {
+    keys: KeyPool,
+    key: ApiKey,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.keys.release(&self.key);
     }
 }
 
 pin_project! {
     pub struct ResponseFuture<F> {
         keys: KeyPool,
-        cur_key: Option<String>,
+        cur_key: Option<ApiKey>,
+        // the key came from the pool rather than the client
+        pooled: bool,
+        started: Instant,
+        // released once the response arrives, or the request is dropped
+        in_flight: Option<InFlight>,
         #[pin]
         fut: F,
     }
 }
 
 impl<F> ResponseFuture<F> {
-    fn new(fut: F, keys: KeyPool, cur_key: Option<String>) -> Self {
-        Self { fut, keys, cur_key }
+    fn new(fut: F, keys: KeyPool, cur_key: Option<ApiKey>, pooled: bool) -> Self {
+        let in_flight = cur_key.clone().filter(|_| pooled).map(|key| InFlight {
+            keys: keys.clone(),
+            key,
+        });
+        Self {
+            in_flight,
+            fut,
+            keys,
+            cur_key,
+            pooled,
+            started: Instant::now(),
+        }
     }
 }
 
@@ -94,18 +796,50 @@ where
 
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let result = ready!(this.fut.poll(cx));
+        let mut result = ready!(this.fut.poll(cx));
+        this.in_flight.take();
 
-        if let Ok(response) = &result {
+        if let (Some(key), true) = (this.cur_key.as_deref(), *this.pooled) {
+            let failed = match &result {
+                Ok(response) => is_key_error(response.status()),
+                Err(_) => true,
+            };
+            this.keys
+                .record_outcome(key, failed, this.started.elapsed());
+        }
+
+        if let Ok(response) = &mut result {
             let cur_key = this.cur_key.clone();
-            match response.status() {
-                StatusCode::UNAUTHORIZED => {
-                    this.keys.remove_active_key_if_equal(cur_key);
+            if let (Some(key), true) = (&cur_key, *this.pooled) {
+                this.keys
+                    .record_quota(key, response.status(), response.headers());
+            }
+            match this.keys.action(response.status()) {
+                Some(KeyAction::Remove) => {
+                    if let Some(key) = &cur_key {
+                        this.keys.remove_used_key(key);
+                    }
                 }
-                StatusCode::TOO_MANY_REQUESTS => {
-                    this.keys.shift_active_key_if_equal(cur_key);
+                Some(KeyAction::Rotate) => {
+                    if let Some(key) = &cur_key {
+                        this.keys.shift_used_key(key);
+                    }
+                }
+                Some(KeyAction::Quarantine) => {
+                    if let (Some(key), true) = (&cur_key, *this.pooled) {
+                        this.keys.quarantine_key(key, response.status());
+                    }
+                }
+                Some(KeyAction::Ignore) | None => (),
+            }
+            // tell clients about the pool as a whole rather than one key
+            if *this.pooled {
+                if let Some(quota) = this.keys.quota() {
+                    let headers = response.headers_mut();
+                    headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(quota.limit));
+                    headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(quota.remaining));
+                    headers.insert(X_RATELIMIT_RESET, HeaderValue::from(quota.reset_secs));
                 }
-                _ => (),
             }
         }
 
@@ -113,6 +847,11 @@ where
     }
 }
 
+/// Request extension making [`Authorize`] use this pooled key rather than
+/// take one.
+#[derive(Debug, Clone)]
+pub struct WithKey(pub ApiKey);
+
 #[derive(Clone)]
 pub struct Authorize<S> {
     keys: KeyPool,
@@ -124,14 +863,13 @@ impl<S> Authorize<S> {
         Self { inner, keys }
     }
 
-    fn extract_api_key<B>(&self, request: &Request<B>) -> Option<String> {
+    fn extract_api_key<B>(&self, request: &Request<B>) -> Option<ApiKey> {
         let auth_header = request.headers().get(AUTHORIZATION.to_string())?;
         let parts: Vec<&str> = auth_header.to_str().ok()?.splitn(2, ' ').collect();
         if parts.len() != 2 {
             return None;
         }
-        let api_key = parts[1].to_string();
-        Some(api_key)
+        Some(ApiKey::from(parts[1]))
     }
 }
 
@@ -152,16 +890,26 @@ where
     fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
         // add authorization Bearer if missing
         let mut api_key = self.extract_api_key(&req);
-        if api_key.is_none() {
-            api_key = self.keys.active_key();
-            if let Some(api_key) = api_key.clone() {
-                let header_value = HeaderValue::from_str(&format!("Bearer {}", api_key)).unwrap();
-                req.headers_mut().insert(AUTHORIZATION, header_value);
+        // the upstream picked the host of redirects elsewhere, not us
+        let pooled = api_key.is_none() && req.extensions().get::<ForeignHost>().is_none();
+        if pooled {
+            api_key = match req.extensions_mut().remove::<WithKey>() {
+                Some(WithKey(key)) => Some(self.keys.take_this_key(key)),
+                None => self.keys.take_key(),
+            };
+            if let Some(api_key) = &api_key {
+                if let Some(header_value) = api_key.bearer() {
+                    req.headers_mut().insert(AUTHORIZATION, header_value);
+                }
+                if let Some(context) = ProxyContext::of(&req) {
+                    let label = self.keys.label(api_key);
+                    context.update(|annotations| annotations.key = Some(label));
+                }
             }
         }
 
         let fut = self.inner.call(req);
-        ResponseFuture::new(fut, self.keys.clone(), api_key)
+        ResponseFuture::new(fut, self.keys.clone(), api_key, pooled)
     }
 }
 
@@ -184,3 +932,195 @@ impl<S> Layer<S> for AuthLayer {
         Authorize::new(service, self.keys.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(limit: u64, remaining: u64, reset: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(limit));
+        headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(remaining));
+        headers.insert(X_RATELIMIT_RESET, HeaderValue::from(reset));
+        headers
+    }
+
+    #[test]
+    fn test_pool_quota() {
+        let keys = KeyPool::from(vec!["a", "b"]);
+        assert_eq!(keys.quota(), None);
+
+        keys.record_quota("a", StatusCode::OK, &headers(100, 40, 30));
+        keys.record_quota("b", StatusCode::OK, &headers(100, 90, 50));
+        // client keys are not part of the pool
+        keys.record_quota("c", StatusCode::OK, &headers(100, 0, 10));
+        let quota = keys.quota().unwrap();
+        assert_eq!((quota.limit, quota.remaining), (200, 130));
+        assert!(quota.reset_secs <= 30 && quota.reset_secs >= 29);
+
+        // a 429 exhausts the key until its Retry-After
+        let mut throttled = HeaderMap::new();
+        throttled.insert(RETRY_AFTER, HeaderValue::from(5));
+        keys.record_quota("b", StatusCode::TOO_MANY_REQUESTS, &throttled);
+        let quota = keys.quota().unwrap();
+        assert_eq!((quota.limit, quota.remaining), (200, 40));
+
+        keys.remove_active_key();
+        assert_eq!(keys.quota().unwrap().limit, 100);
+    }
+
+    #[test]
+    fn test_key_budgets() {
+        let config = KeyPoolConfig {
+            requests_per_hour: Some(2),
+            budgets: HashMap::from([(key_id("c"), 1)]),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["a", "b", "c"]).with_config(&config);
+        let taken: Vec<_> = (0..6).filter_map(|_| keys.take_key()).collect();
+        // the last key keeps being used once every key is out of budget
+        assert_eq!(taken, ["a", "a", "b", "b", "c", "c"]);
+        assert_eq!(keys.active_key().as_deref(), Some("c"));
+
+        // keys without a budget are never rotated from
+        let keys = KeyPool::from(vec!["a", "b"]).with_config(&KeyPoolConfig::default());
+        assert!((0..5).all(|_| keys.take_key().as_deref() == Some("a")));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_rotation() {
+        let config = KeyPoolConfig {
+            rotate_every_requests: Some(2),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["a", "b", "c"]).with_config(&config);
+        let mut events = keys.events();
+        let taken: Vec<_> = (0..5).filter_map(|_| keys.take_key()).collect();
+        assert_eq!(taken, ["a", "a", "b", "b", "c"]);
+
+        spawn_rotation(keys.clone(), Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(keys.active_key().as_deref(), Some("a"));
+        // nothing to share with the other replicas
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_status_actions() {
+        let config = KeyPoolConfig {
+            statuses: HashMap::from([(419, KeyAction::Rotate), (401, KeyAction::Ignore)]),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["a", "b", "c"]).with_config(&config);
+        assert_eq!(
+            keys.action(StatusCode::UNAUTHORIZED),
+            Some(KeyAction::Ignore)
+        );
+        // a key missing a scope is only set aside when asked to
+        assert_eq!(keys.action(StatusCode::FORBIDDEN), None);
+        let quarantining = KeyPool::from(vec!["a"]).with_config(&KeyPoolConfig {
+            statuses: HashMap::from([(403, KeyAction::Quarantine)]),
+            ..Default::default()
+        });
+        assert_eq!(
+            quarantining.action(StatusCode::FORBIDDEN),
+            Some(KeyAction::Quarantine)
+        );
+        assert_eq!(
+            keys.action(StatusCode::from_u16(419).unwrap()),
+            Some(KeyAction::Rotate)
+        );
+        assert_eq!(keys.action(StatusCode::NOT_FOUND), None);
+
+        // quarantined keys are skipped until no other key is left
+        keys.quarantine_key("a", StatusCode::FORBIDDEN);
+        assert!(keys.is_quarantined("a"));
+        assert_eq!(keys.take_key().as_deref(), Some("b"));
+        keys.quarantine_key("b", StatusCode::FORBIDDEN);
+        keys.quarantine_key("c", StatusCode::FORBIDDEN);
+        assert!(keys.take_key().is_some());
+        assert_eq!(KEY_QUARANTINES.get(&[("key", &key_id("a"))]), 1.0);
+        assert_eq!(keys.keys(), ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_spilled_over_key_errors() {
+        use hyper::Body;
+        use tower::{service_fn, ServiceExt};
+
+        let config = KeyPoolConfig {
+            max_in_flight: Some(1),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["a", "b", "c"]).with_config(&config);
+        let mut events = keys.events();
+        let upstream = service_fn(|req: Request<Body>| async move {
+            let status = match req.headers()[AUTHORIZATION].to_str().unwrap() {
+                "Bearer b" => StatusCode::UNAUTHORIZED,
+                "Bearer c" => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::OK,
+            };
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = status;
+            Ok::<_, std::convert::Infallible>(res)
+        });
+        let send = || {
+            let service = AuthLayer::new(keys.clone()).layer(upstream);
+            service.oneshot(Request::new(Body::empty()))
+        };
+
+        // "a" is busy, so the request spills over to "b", which is revoked
+        let busy = keys.take_key().unwrap();
+        assert_eq!(send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(keys.keys(), ["a", "c"]);
+        assert_eq!(events.try_recv().unwrap(), KeyEvent::Removed("b".into()));
+
+        // and then to "c", which is throttled; "a" stays the active key
+        assert_eq!(
+            send().await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(keys.active_key().as_deref(), Some("a"));
+        assert_eq!(events.try_recv().unwrap(), KeyEvent::Rotated("c".into()));
+        keys.release(&busy);
+    }
+
+    #[test]
+    fn test_key_stats() {
+        let keys = KeyPool::from(vec!["stats-a", "stats-b"]);
+        keys.record_outcome("stats-a", false, Duration::from_millis(100));
+        keys.record_outcome("stats-a", true, Duration::from_millis(200));
+        // client keys are not part of the pool
+        keys.record_outcome("stats-c", true, Duration::from_millis(100));
+        assert_eq!(keys.stats("stats-b"), None);
+        assert_eq!(keys.stats("stats-c"), None);
+
+        let stats = keys.stats("stats-a").unwrap();
+        assert_eq!((stats.requests, stats.errors), (2, 1));
+        assert!((stats.success_ratio - 0.9).abs() < 1e-9);
+        assert!((stats.latency.as_secs_f64() - 0.11).abs() < 1e-6);
+
+        // exported by a hash of the key, never the key itself
+        let id = key_id("stats-a");
+        assert_eq!(KEY_ERRORS.get(&[("key", &id)]), 1.0);
+        assert!(!crate::metrics::render().contains("stats-a"));
+    }
+
+    #[test]
+    fn test_key_labels() {
+        let config = KeyPoolConfig {
+            labels: HashMap::from([(key_id("secret-a"), "fleet-a".to_owned())]),
+            budgets: HashMap::from([("fleet-a".to_owned(), 1)]),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["secret-a", "secret-b"]).with_config(&config);
+        assert_eq!(keys.label("secret-a"), "fleet-a");
+        assert_eq!(keys.label("secret-b"), key_id("secret-b"));
+
+        // budgets and metrics go by the label
+        let taken: Vec<_> = (0..2).filter_map(|_| keys.take_key()).collect();
+        assert_eq!(taken, ["secret-a", "secret-b"]);
+        keys.record_outcome("secret-a", false, Duration::from_millis(10));
+        assert_eq!(KEY_REQUESTS.get(&[("key", "fleet-a")]), 1.0);
+    }
+}
@@ -0,0 +1,228 @@
+//! Proxy facts for distributed tracing.
+//!
+//! Trace context (`traceparent`, `tracestate`, B3's `b3` and `x-b3-*`) and
+//! W3C `baggage` are forwarded upstream as clients sent them, retries and
+//! replayed writes included. [`BaggageLayer`] adds entries about how the
+//! proxy handled the request to `baggage`, e.g. `proxy.key_label`, so tracing
+//! systems downstream can slice requests by them. Entries clients sent under
+//! the same keys are replaced. It sits below the auth layer, so every attempt
+//! carries the label of the key it went out with.
+
+use std::{
+    collections::BTreeMap,
+    task::{Context, Poll},
+};
+
+use http::{HeaderValue, Request};
+use serde::{Deserialize, Serialize};
+use tower::{BoxError, Layer, Service};
+
+use crate::context::ProxyContext;
+
+const BAGGAGE: &str = "baggage";
+
+/// Longest `baggage` the W3C spec has propagators accept.
+const MAX_BAGGAGE_BYTES: usize = 8192;
+
+/// Facts about the request added as `proxy.<name>`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyBaggage {
+    /// Label of the pool key the attempt went out with.
+    KeyLabel,
+    /// API resource the request is about, e.g. `device`.
+    Route,
+    /// Attempt the request is on, 1 for the first.
+    Attempt,
+}
+
+impl ProxyBaggage {
+    fn key(self) -> &'static str {
+        match self {
+            ProxyBaggage::KeyLabel => "proxy.key_label",
+            ProxyBaggage::Route => "proxy.route",
+            ProxyBaggage::Attempt => "proxy.attempt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BaggageConfig {
+    /// Facts added when the request has them.
+    pub inject: Vec<ProxyBaggage>,
+    /// Fixed entries added to every request, e.g. `proxy.region: eu`.
+    pub entries: BTreeMap<String, String>,
+}
+
+/// Whether `key` is a token, as baggage keys must be.
+fn is_token(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&b))
+}
+
+/// Percent-encode what may not appear in a baggage value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'"' | b',' | b';' | b'\\' | b'%' => format!("%{:02X}", b),
+            b if b.is_ascii_graphic() => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct BaggageLayer {
+    config: BaggageConfig,
+}
+
+impl BaggageLayer {
+    /// Check the keys of the fixed entries.
+    pub fn new(config: BaggageConfig) -> Result<Self, BoxError> {
+        if let Some(key) = config.entries.keys().find(|key| !is_token(key)) {
+            return Err(format!("invalid baggage key {:?}", key).into());
+        }
+        Ok(Self { config })
+    }
+
+    /// The entries to add to a request with `context`.
+    fn entries(&self, context: Option<&ProxyContext>) -> Vec<(&str, String)> {
+        let annotations = context.map(ProxyContext::get).unwrap_or_default();
+        let injected = self.config.inject.iter().filter_map(|fact| {
+            let value = match fact {
+                ProxyBaggage::KeyLabel => annotations.key.clone()?,
+                ProxyBaggage::Route => annotations.route.clone()?,
+                ProxyBaggage::Attempt => context.map(|_| annotations.attempt.to_string())?,
+            };
+            Some((fact.key(), value))
+        });
+        let fixed = self
+            .config
+            .entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone()));
+        injected.chain(fixed).collect()
+    }
+}
+
+impl<S> Layer<S> for BaggageLayer {
+    type Service = Baggage<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Baggage {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Baggage<S> {
+    inner: S,
+    layer: BaggageLayer,
+}
+
+impl<S, B> Service<Request<B>> for Baggage<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let entries = self.layer.entries(ProxyContext::of(&req));
+        if entries.is_empty() {
+            return self.inner.call(req);
+        }
+
+        // the client's entries, but for those replaced
+        let mut members: Vec<String> = req
+            .headers()
+            .get_all(BAGGAGE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|member| {
+                let key = member.split(['=', ';']).next().unwrap_or_default().trim();
+                !member.is_empty() && entries.iter().all(|(k, _)| *k != key)
+            })
+            .map(str::to_owned)
+            .collect();
+        members.extend(
+            entries
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, encode(value))),
+        );
+        let baggage = members.join(",");
+        match HeaderValue::from_str(&baggage) {
+            Ok(value) if baggage.len() <= MAX_BAGGAGE_BYTES => {
+                req.headers_mut().insert(BAGGAGE, value);
+            }
+            _ => tracing::debug!(len = baggage.len(), "baggage too large, left as sent"),
+        }
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Annotations;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_baggage() -> Result<(), BoxError> {
+        let layer = BaggageLayer::new(serde_json::from_value(serde_json::json!({
+            "inject": ["key_label", "attempt"],
+            "entries": { "proxy.region": "eu west" },
+        }))?)?;
+        let service = layer.layer(service_fn(|req: Request<()>| async move {
+            Ok::<_, Infallible>(req.headers().clone())
+        }));
+
+        let req = Request::get("/v6/device")
+            .header(BAGGAGE, "proxy.key_label=spoofed,user=1;prop")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .header("x-b3-traceid", "80f198ee56343ba864fe8b2a57d3eff7")
+            .header("x-b3-sampled", "1")
+            .extension(ProxyContext::new(Annotations {
+                key: Some("fleet-a".to_owned()),
+                attempt: 2,
+                ..Default::default()
+            }))
+            .body(())?;
+        let headers = service.clone().oneshot(req).await?;
+        assert_eq!(
+            headers[BAGGAGE],
+            "user=1;prop,proxy.key_label=fleet-a,proxy.attempt=2,proxy.region=eu%20west"
+        );
+        // trace context goes through untouched
+        assert_eq!(headers["x-b3-traceid"], "80f198ee56343ba864fe8b2a57d3eff7");
+        assert_eq!(headers["x-b3-sampled"], "1");
+        assert!(headers.contains_key("traceparent"));
+
+        // facts the request lacks are left out
+        let headers = service.oneshot(Request::get("/").body(())?).await?;
+        assert_eq!(headers[BAGGAGE], "proxy.region=eu%20west");
+
+        let invalid = serde_json::json!({ "entries": { "proxy region": "eu" } });
+        assert!(BaggageLayer::new(serde_json::from_value(invalid)?).is_err());
+        Ok(())
+    }
+}
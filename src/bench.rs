@@ -0,0 +1,159 @@
+//! `proxy bench`: load the whole stack in-process and report its overhead.
+//!
+//! Requests go straight to the service the listener would call, upstream
+//! answered by the `mock_upstream` fixtures, so what is measured is the
+//! proxy itself: latency percentiles and heap allocations per request. The
+//! allocations are counted by [`CountingAlloc`], the global allocator of
+//! builds with the `bench` feature. `cargo bench` measures the binary over
+//! loopback instead.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use clap::Parser;
+use http::{header::AUTHORIZATION, Method, Request};
+use hyper::Body;
+use tower::{BoxError, ServiceExt};
+
+use crate::{mock_upstream::Fixture, replay::percentile, route::RouteMatcher, ProxyService};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations.
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct BenchArgs {
+    /// Requests measured, after as many as `concurrency` warm the stack up.
+    #[arg(long, default_value_t = 10_000)]
+    pub requests: usize,
+    /// Requests in flight at once.
+    #[arg(long, default_value_t = 32)]
+    pub concurrency: usize,
+    #[arg(long, default_value = "GET")]
+    pub method: String,
+    #[arg(long, default_value = "/v6/device")]
+    pub path: String,
+    /// Size of the JSON request body, none when 0.
+    #[arg(long, default_value_t = 0)]
+    pub body_bytes: usize,
+}
+
+/// Fixtures answering any request, for configurations without any.
+pub fn default_fixtures() -> Vec<Fixture> {
+    vec![Fixture {
+        route: RouteMatcher::default(),
+        status: 200,
+        headers: Default::default(),
+        body: Some(serde_json::json!({
+            "d": [{ "id": 1, "uuid": "0123456789abcdef", "is_online": true }]
+        })),
+    }]
+}
+
+fn request(args: &BenchArgs, body: &[u8]) -> Result<Request<Body>, BoxError> {
+    // a key of its own, the pool is empty against the mock upstream
+    Ok(Request::builder()
+        .method(Method::from_str(&args.method)?)
+        .uri(args.path.as_str())
+        .header(AUTHORIZATION, "Bearer bench")
+        .body(Body::from(body.to_vec()))?)
+}
+
+/// Send `count` requests, `concurrency` at a time, returning their
+/// latencies in microseconds.
+async fn load(
+    service: &ProxyService,
+    args: &Arc<BenchArgs>,
+    body: &Arc<Vec<u8>>,
+    count: usize,
+) -> Result<Vec<u64>, BoxError> {
+    let next = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|_| {
+            let (service, args, body, next) =
+                (service.clone(), args.clone(), body.clone(), next.clone());
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                while next.fetch_add(1, Ordering::Relaxed) < count {
+                    let req = request(&args, &body)?;
+                    let start = Instant::now();
+                    let res = service.clone().oneshot(req).await?;
+                    hyper::body::to_bytes(res.into_body()).await?;
+                    latencies.push(start.elapsed().as_micros() as u64);
+                }
+                Ok::<_, BoxError>(latencies)
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(count);
+    for worker in workers {
+        latencies.extend(worker.await??);
+    }
+    Ok(latencies)
+}
+
+/// Run the `bench` subcommand against `service`, printing a summary.
+pub async fn run(args: BenchArgs, service: ProxyService) -> Result<(), BoxError> {
+    let body = match args.body_bytes {
+        0 => Vec::new(),
+        n => serde_json::json!({ "data": "x".repeat(n.saturating_sub(11)) })
+            .to_string()
+            .into_bytes(),
+    };
+    let (args, body) = (Arc::new(args), Arc::new(body));
+    println!(
+        "sending {} {} {} requests, {} at a time",
+        args.requests, args.method, args.path, args.concurrency
+    );
+
+    load(&service, &args, &body, args.concurrency).await?;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut latencies = load(&service, &args, &body, args.requests).await?;
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    latencies.sort_unstable();
+    let count = latencies.len().max(1);
+    println!(
+        "{} requests in {:.2}s, {:.0} req/s",
+        latencies.len(),
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    for (name, p) in [("p50", 0.5), ("p99", 0.99)] {
+        println!("{} latency: {}µs", name, percentile(&latencies, p));
+    }
+    // the harness's own allocations included
+    println!("allocations per request: {}", allocations / count as u64);
+    Ok(())
+}
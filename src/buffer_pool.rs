@@ -0,0 +1,150 @@
+//! Reusable buffers for request bodies.
+//!
+//! Every request body is read whole before it is forwarded, and under load a
+//! fresh, repeatedly grown buffer per request adds up. [`BufferPool::take`]
+//! hands out a cleared buffer from the pool instead, with room for the body
+//! when its length is known, and [`PooledBuf`] puts it back once the body is
+//! dropped. Buffers grown past [`MAX_RETAINED`] are freed rather than kept,
+//! so one large upload does not pin its memory.
+
+use std::{ops::Deref, sync::Mutex};
+
+use crate::metrics::Metric;
+
+/// Largest buffer kept for reuse.
+pub const MAX_RETAINED: usize = 256 * 1024;
+/// Buffers kept for reuse at most.
+const MAX_POOLED: usize = 256;
+
+const REUSED: Metric = Metric::counter(
+    "proxy_body_buffers_reused_total",
+    "Request body buffers taken from the pool.",
+);
+const ALLOCATED: Metric = Metric::counter(
+    "proxy_body_buffers_allocated_total",
+    "Request body buffers allocated as the pool had none.",
+);
+const POOLED: Metric = Metric::gauge(
+    "proxy_body_buffers_pooled",
+    "Request body buffers waiting in the pool.",
+);
+
+/// The pool request bodies are read into.
+pub static POOL: BufferPool = BufferPool::new();
+
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// An empty buffer with room for `capacity` bytes, up to [`MAX_RETAINED`].
+    pub fn take(&'static self, capacity: usize) -> PooledBuf {
+        let capacity = capacity.min(MAX_RETAINED);
+        let buf = {
+            let mut buffers = self.buffers.lock().unwrap();
+            let buf = buffers.pop();
+            POOLED.set(&[], buffers.len() as f64);
+            buf
+        };
+        let buf = match buf {
+            Some(mut buf) => {
+                REUSED.increment(&[]);
+                buf.reserve(capacity);
+                buf
+            }
+            None => {
+                ALLOCATED.increment(&[]);
+                Vec::with_capacity(capacity)
+            }
+        };
+        PooledBuf { buf, pool: self }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > MAX_RETAINED {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+            POOLED.set(&[], buffers.len() as f64);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+/// A buffer of a [`BufferPool`], back in the pool when dropped.
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: &'static BufferPool,
+}
+
+impl PooledBuf {
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        static POOL: BufferPool = BufferPool::new();
+
+        let mut buf = POOL.take(10);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        drop(buf);
+        assert_eq!(POOL.len(), 1);
+        // the same buffer, cleared
+        let buf = POOL.take(10);
+        assert_eq!((buf.as_ptr(), buf.len()), (ptr, 0));
+        assert_eq!(POOL.len(), 0);
+        drop(buf);
+
+        // large buffers are not kept
+        let mut buf = POOL.take(10);
+        buf.extend_from_slice(&vec![0; MAX_RETAINED + 1]);
+        drop(buf);
+        assert_eq!(POOL.len(), 0);
+    }
+}
@@ -0,0 +1,31 @@
+use clap::{Parser, Subcommand};
+
+#[cfg(feature = "bench")]
+use crate::bench::BenchArgs;
+use crate::replay::ReplayArgs;
+
+/// Balena API proxy.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    /// Serve the `mock_upstream` fixtures from the config instead of
+    /// forwarding requests to Balena.
+    #[arg(long)]
+    pub mock_upstream: bool,
+    /// Run requests through the whole stack but answer with the request that
+    /// would have been sent upstream instead of sending it.
+    #[arg(long, conflicts_with = "mock_upstream")]
+    pub dry_run: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Replay recorded traffic against a target and report differences.
+    Replay(ReplayArgs),
+    /// Load the whole stack in-process against the mock upstream and report
+    /// latency and allocations per request.
+    #[cfg(feature = "bench")]
+    Bench(BenchArgs),
+}
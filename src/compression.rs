@@ -0,0 +1,290 @@
+//! Response compression and decompression per route.
+//!
+//! The upstream compresses responses for clients asking for it, and they
+//! pass through as they came. For routes with a [`CompressionRule`],
+//! [`CompressLayer`] compresses the responses it did not, for clients that
+//! accept an encoding, with tower-http's `CompressionLayer`. It sits at the
+//! top of the stack, so layers rewriting bodies see them as they were; bodies
+//! already encoded, partial ones, and those tower-http leaves alone, such as
+//! small bodies, images and event streams, are sent as they are, never
+//! encoded twice. With `decompress`, [`DecompressLayer`] asks the upstream
+//! for compressed responses, saving bytes between the two, and decodes them
+//! as they stream in with tower-http's `DecompressionLayer`, so layers above
+//! it see them plain and the body is not buffered encoded first. It sits
+//! above the retries, so responses retried away are never decoded, and
+//! requests are buffered by the read layer as before, neither layer touches
+//! them.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use futures_util::{stream, TryFutureExt};
+use http::{Request, Response, StatusCode};
+use http_body::Body as HttpBody;
+use hyper::Body;
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+use tower_http::{
+    compression::{
+        predicate::{And, DefaultPredicate, Predicate},
+        Compression, CompressionLayer,
+    },
+    decompression::Decompression,
+};
+
+use crate::route::RouteMatcher;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Compress responses for clients accepting an encoding.
+    #[serde(default = "default_compress")]
+    pub compress: bool,
+    /// Ask the upstream for compressed responses and decode them.
+    #[serde(default)]
+    pub decompress: bool,
+}
+
+fn default_compress() -> bool {
+    true
+}
+
+/// The rule of the first route matching `req`.
+fn rule<'a, B>(rules: &'a [CompressionRule], req: &Request<B>) -> Option<&'a CompressionRule> {
+    rules.iter().find(|rule| rule.route.matches(req))
+}
+
+/// Response extension marking the responses of routes compressed.
+#[derive(Debug, Clone, Copy)]
+struct Compressible;
+
+/// Compress marked responses, unless they are partial.
+#[derive(Debug, Clone, Copy)]
+pub struct Marked;
+
+impl Predicate for Marked {
+    fn should_compress<B: HttpBody>(&self, res: &Response<B>) -> bool {
+        res.status() != StatusCode::PARTIAL_CONTENT
+            && res.extensions().get::<Compressible>().is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressLayer {
+    rules: Arc<Vec<CompressionRule>>,
+}
+
+impl CompressLayer {
+    pub fn new(rules: Vec<CompressionRule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+}
+
+impl<S> Layer<S> for CompressLayer {
+    type Service = Compression<MarkCompressible<S>, And<DefaultPredicate, Marked>>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let mark = MarkCompressible {
+            inner: service,
+            rules: self.rules.clone(),
+        };
+        CompressionLayer::new()
+            .compress_when(DefaultPredicate::new().and(Marked))
+            .layer(mark)
+    }
+}
+
+/// Marks the responses of routes compressed, for the compression above.
+#[derive(Clone)]
+pub struct MarkCompressible<S> {
+    inner: S,
+    rules: Arc<Vec<CompressionRule>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MarkCompressible<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let compress = rule(&self.rules, &req).is_some_and(|rule| rule.compress);
+        let fut = self.inner.call(req);
+        if !compress {
+            return Box::pin(fut);
+        }
+        Box::pin(fut.map_ok(|mut res| {
+            res.extensions_mut().insert(Compressible);
+            res
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecompressLayer {
+    rules: Arc<Vec<CompressionRule>>,
+}
+
+impl DecompressLayer {
+    /// Decompress the responses of the routes of `rules` with `decompress`.
+    pub fn new(rules: Vec<CompressionRule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+}
+
+impl<S> Layer<S> for DecompressLayer {
+    type Service = Decompress<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Decompress {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Decompress<S> {
+    inner: S,
+    rules: Arc<Vec<CompressionRule>>,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for Decompress<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        if !rule(&self.rules, &req).is_some_and(|rule| rule.decompress) {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+        // the ready service, leaving a clone in its place
+        let inner = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, inner);
+        let fut = Decompression::new(inner).call(req);
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            // back to a plain body, decoded as it is read
+            Ok(res.map(|body| {
+                Body::wrap_stream(stream::unfold(Box::pin(body), |mut body| async move {
+                    let chunk = body.as_mut().data().await?;
+                    Some((chunk, body))
+                }))
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gzip;
+    use bytes::Bytes;
+    use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    fn rules() -> Vec<CompressionRule> {
+        serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/v6/device" }, "decompress": true },
+            { "route": { "path_prefix": "/v6/release" }, "compress": false },
+        ]))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compress() -> Result<(), BoxError> {
+        let json = serde_json::json!({ "d": vec!["device"; 200] }).to_string();
+        let upstream = service_fn(move |req: Request<()>| {
+            let mut res = Response::new(Body::from(json.clone()));
+            res.headers_mut()
+                .insert("content-type", "application/json".parse().unwrap());
+            // already encoded by the upstream
+            if req.uri().path() == "/v6/device/encoded" {
+                res.headers_mut()
+                    .insert(CONTENT_ENCODING, "br".parse().unwrap());
+            }
+            async { Ok::<_, Infallible>(res) }
+        });
+        let service = CompressLayer::new(rules()).layer(upstream);
+        let encoding = |path: &str| {
+            let req = Request::get(path)
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(())
+                .unwrap();
+            let service = service.clone();
+            async move {
+                let res = service.oneshot(req).await.unwrap();
+                res.headers().get(CONTENT_ENCODING).cloned()
+            }
+        };
+        assert_eq!(encoding("/v6/device").await.unwrap(), "gzip");
+        assert_eq!(encoding("/v6/device/encoded").await.unwrap(), "br");
+        assert_eq!(encoding("/v6/release").await, None);
+        assert_eq!(encoding("/v6/application").await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decompress() -> Result<(), BoxError> {
+        let upstream = service_fn(|req: Request<()>| async move {
+            // compressed when asked to
+            let accepts = req.headers().contains_key(ACCEPT_ENCODING);
+            let res = if accepts {
+                let mut res = Response::new(Body::from(gzip::encode(b"{\"id\":1}")));
+                res.headers_mut()
+                    .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+                res
+            } else {
+                Response::new(Body::from("{\"id\":1}"))
+            };
+            Ok::<_, Infallible>(res)
+        });
+        let service = DecompressLayer::new(rules()).layer(upstream);
+
+        let req = Request::get("/v6/device").body(())?;
+        let res = service.clone().oneshot(req).await?;
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body, Bytes::from_static(b"{\"id\":1}"));
+
+        // other routes pass through as they came
+        let req = Request::get("/v6/release")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+use tower::BoxError;
+
+#[cfg(feature = "tls")]
+use crate::pin::PinRule;
+#[cfg(feature = "cache")]
+use crate::response_cache::CacheConfig;
+#[cfg(feature = "retry")]
+use crate::retry::RetryConfig;
+#[cfg(feature = "scripting")]
+use crate::script::ScriptHook;
+use crate::{
+    access::AccessConfig, admin::AdminConfig, analytics::AnalyticsConfig, baggage::BaggageConfig,
+    compression::CompressionRule, content_type::ContentTypeRule, contract::ContractRule,
+    decrypt::DecryptorConfig, dns::DnsConfig, environment::EnvironmentRule, error_page::ErrorPage,
+    expand_limit::ExpandLimitConfig, fallback::FallbackRule, fan_out::FanOutConfig,
+    fault::FaultRule, forward_request::ForwardOverride, gateway::GatewayConfig,
+    graphql::GraphQlConfig, header_limit::HeaderLimitConfig, hmac::HmacConfig,
+    idempotency::IdempotencyConfig, last_known::LastKnownConfig, logging::LoggingConfig,
+    maintenance::MaintenanceConfig, method_override::MethodOverrideConfig, mock_upstream::Fixture,
+    outlier::OutlierConfig, preconnect::PreconnectConfig, priority::PriorityConfig,
+    record::RecordingConfig, redirect::RedirectRule, reload::ReloadConfig,
+    request_gzip::RequestGzipConfig, response_limit::ResponseLimitRule, route::RouteMatcher,
+    server::ServerConfig, shared_limit::SharedLimitConfig, sigv4::SigV4Rule,
+    status_map::StatusRule, store_forward::StoreForwardConfig, summarize::SummarizeRule,
+    supervisor::SupervisorConfig, throttle::ThrottleConfig, timeout::TimeoutConfig,
+    transform::TransformConfig, upstream_request_id::UpstreamRequestIdConfig,
+    validate::ValidationRule, webhook::WebhookConfig,
+};
+#[cfg(feature = "auth")]
+use crate::{
+    auth::KeyPoolConfig, hedge::HedgeConfig, hold::HoldConfig, key_probe::KeyProbeConfig,
+    key_sync::KeySyncConfig, pace::PaceConfig, token_exchange::TokenExchangeConfig,
+};
+
+/// Environment variable pointing to the JSON configuration file.
+pub const PROXY_CONFIG: &str = "PROXY_CONFIG";
+
+/// Upstream used when `upstream` is unset.
+pub const DEFAULT_UPSTREAM: &str = "https://api.balena-cloud.com/v6";
+
+/// Proxy configuration.
+///
+/// Everything is optional: a missing file or a missing section falls back to
+/// the defaults, so the proxy still runs with only `BALENA_API_KEY` set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Method and path allowlist, disabled when unset.
+    pub access: Option<AccessConfig>,
+    /// Admin API, disabled when unset.
+    pub admin: Option<AdminConfig>,
+    /// GET responses kept in memory and served while fresh, disabled when
+    /// unset.
+    #[cfg(feature = "cache")]
+    pub cache: Option<CacheConfig>,
+    /// HMAC signatures checked on inbound and added to upstream requests.
+    pub hmac: HmacConfig,
+    /// Allowlisted upstream hosts picked per request, instead of `upstream`.
+    pub gateway: Option<GatewayConfig>,
+    /// Reads sent with a second key while the active one runs out of quota,
+    /// disabled when unset.
+    #[cfg(feature = "auth")]
+    pub hedging: Option<HedgeConfig>,
+    /// Experimental GraphQL queries resolved with OData requests, disabled
+    /// when unset.
+    pub graphql: Option<GraphQlConfig>,
+    /// Response compression and decompression per route.
+    pub compression: Vec<CompressionRule>,
+    /// Resolution of upstream hosts and connects to their addresses.
+    pub dns: DnsConfig,
+    /// Checks of upstream responses against the API contract, per route.
+    pub contracts: Vec<ContractRule>,
+    /// Devices and fleets served by other upstream environments.
+    pub environments: Vec<EnvironmentRule>,
+    /// Bodies of gateway errors per route.
+    pub error_pages: Vec<ErrorPage>,
+    /// Static responses to reads while the upstream is unreachable, per
+    /// route.
+    pub fallbacks: Vec<FallbackRule>,
+    /// Batches of requests answered by the proxy, disabled when unset.
+    pub fan_out: Option<FanOutConfig>,
+    /// Decryption of `enc:` keys in `BALENA_API_KEY`.
+    pub key_decryption: Option<DecryptorConfig>,
+    /// Labels, budgets and scheduled rotation of the `BALENA_API_KEY` keys,
+    /// read at startup only.
+    #[cfg(feature = "auth")]
+    pub key_pool: KeyPoolConfig,
+    /// Startup probe ordering the keys by health, disabled when unset.
+    #[cfg(feature = "auth")]
+    pub key_probe: Option<KeyProbeConfig>,
+    /// Key pool changes shared with other replicas through Redis, disabled
+    /// when unset.
+    #[cfg(feature = "auth")]
+    pub key_sync: Option<KeySyncConfig>,
+    /// Latest GET responses kept on disk to serve offline, disabled when
+    /// unset.
+    pub last_known: Option<LastKnownConfig>,
+    pub logging: LoggingConfig,
+    /// 503s served instead of forwarding, switched on the admin API.
+    pub maintenance: MaintenanceConfig,
+    /// Proxy facts added to the `baggage` of upstream requests.
+    pub baggage: Option<BaggageConfig>,
+    /// Fault injection rules, for testing only.
+    pub faults: Vec<FaultRule>,
+    /// SNI and Host sent upstream per route instead of the upstream URI's.
+    pub forward_overrides: Vec<ForwardOverride>,
+    /// `X-HTTP-Method-Override` support, disabled when unset.
+    pub method_override: Option<MethodOverrideConfig>,
+    /// Fixtures served with `--mock-upstream`.
+    pub mock_upstream: Vec<Fixture>,
+    /// Limits on request header count and size.
+    pub header_limits: HeaderLimitConfig,
+    /// `Idempotency-Key` deduplication, disabled when unset.
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Limits on the depth and breadth of OData `$expand`.
+    pub expand_limits: ExpandLimitConfig,
+    /// Holding of requests while every key is rate limited, disabled when
+    /// unset.
+    #[cfg(feature = "auth")]
+    pub key_hold: Option<HoldConfig>,
+    /// Spacing of the requests of each pooled key, disabled when unset.
+    #[cfg(feature = "auth")]
+    pub pacing: Option<PaceConfig>,
+    /// Session tokens sent upstream instead of the pool keys, disabled when
+    /// unset.
+    #[cfg(feature = "auth")]
+    pub token_exchange: Option<TokenExchangeConfig>,
+    /// Ejection of slow or failing upstream endpoints, disabled when unset.
+    pub outlier_detection: Option<OutlierConfig>,
+    /// Upstream connections kept open, none when unset.
+    pub preconnect: Option<PreconnectConfig>,
+    /// Prioritization of requests under load, disabled when unset.
+    pub priority: Option<PriorityConfig>,
+    /// Traffic recording, disabled when unset.
+    pub recording: Option<RecordingConfig>,
+    /// Upstream redirects followed by the proxy, per route.
+    pub redirects: Vec<RedirectRule>,
+    /// Watching the file for changes to reload.
+    pub reload: ReloadConfig,
+    /// Largest upstream responses passed to clients, per route.
+    pub response_limits: Vec<ResponseLimitRule>,
+    /// Attempts and backoff of failed upstream requests.
+    #[cfg(feature = "retry")]
+    pub retry: RetryConfig,
+    /// Rhai scripts run on request and response heads.
+    #[cfg(feature = "scripting")]
+    pub scripts: Vec<ScriptHook>,
+    /// Sampled request metadata sent to a local collector.
+    pub analytics: Option<AnalyticsConfig>,
+    /// Listener address and inbound connection timeouts.
+    pub server: ServerConfig,
+    /// Per-key rate limits shared with other replicas through Redis,
+    /// disabled when unset.
+    pub shared_limit: Option<SharedLimitConfig>,
+    /// AWS SigV4 signing of requests to AWS upstreams.
+    pub sigv4: Vec<SigV4Rule>,
+    /// Upstream statuses rewritten for clients.
+    pub status_map: Vec<StatusRule>,
+    /// Queueing of failed writes, disabled when unset.
+    pub store_forward: Option<StoreForwardConfig>,
+    /// Summaries in place of huge collection responses, per route.
+    pub summarize: Vec<SummarizeRule>,
+    /// Gzip of large JSON request bodies, for upstreams that accept it.
+    pub request_gzip: Option<RequestGzipConfig>,
+    /// Local supervisor API routing, disabled when unset.
+    pub supervisor: Option<SupervisorConfig>,
+    /// Bandwidth limits.
+    pub throttle: ThrottleConfig,
+    /// Request timeouts, and how long callers may ask to wait.
+    pub timeout: TimeoutConfig,
+    /// JSON body rewrites per route.
+    pub transforms: TransformConfig,
+    /// Base URI of the Balena API, `https://api.balena-cloud.com/v6` when
+    /// unset.
+    pub upstream: Option<String>,
+    /// Public keys accepted from TLS upstreams, any when empty.
+    #[cfg(feature = "tls")]
+    pub upstream_pins: Vec<PinRule>,
+    /// Recording of the ids the upstream gives its responses.
+    pub upstream_request_id: Option<UpstreamRequestIdConfig>,
+    /// Request content types allowed per route.
+    pub content_types: Vec<ContentTypeRule>,
+    /// Request body schemas per route.
+    pub validation: Vec<ValidationRule>,
+    /// Webhook relay, disabled when unset.
+    pub webhooks: Option<WebhookConfig>,
+}
+
+impl Config {
+    /// Load configuration from the file named by `PROXY_CONFIG`, if any.
+    pub fn load() -> Result<Config, BoxError> {
+        match std::env::var(PROXY_CONFIG) {
+            Ok(path) => Config::from_file(path),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, BoxError> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let config: Config =
+            serde_json::from_slice(&data).map_err(|err| format!("{}: {}", path.display(), err))?;
+        config
+            .validate()
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    /// Check the sections that can be wrong beyond what deserializing
+    /// catches, the routes of every section included.
+    pub fn validate(&self) -> Result<(), BoxError> {
+        #[cfg(feature = "retry")]
+        self.retry.validate()?;
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &self.cache {
+            cache.validate()?;
+        }
+        for (section, route) in self.routes() {
+            route
+                .validate()
+                .map_err(|err| format!("{}: {}", section, err))?;
+        }
+        Ok(())
+    }
+
+    /// The routes the sections select requests by, after the name of their
+    /// section, except those of `cache`, which checks its own.
+    fn routes(&self) -> Vec<(String, &RouteMatcher)> {
+        fn each<'a, T>(
+            section: &str,
+            rules: &'a [T],
+            route: impl Fn(&'a T) -> Option<&'a RouteMatcher>,
+        ) -> Vec<(String, &'a RouteMatcher)> {
+            rules
+                .iter()
+                .enumerate()
+                .filter_map(|(i, rule)| Some((format!("{}[{}]", section, i), route(rule)?)))
+                .collect()
+        }
+
+        let mut routes = Vec::new();
+        if let Some(access) = &self.access {
+            routes.extend(each("access.rules", &access.rules, |r| Some(&r.route)));
+        }
+        if let Some(analytics) = &self.analytics {
+            routes.extend(each("analytics.routes", &analytics.routes, Some));
+        }
+        routes.extend(each("compression", &self.compression, |r| Some(&r.route)));
+        routes.extend(each("content_types", &self.content_types, |r| {
+            Some(&r.route)
+        }));
+        routes.extend(each("contracts", &self.contracts, |r| Some(&r.route)));
+        routes.extend(each("environments", &self.environments, |r| {
+            r.route.as_ref()
+        }));
+        routes.extend(each("error_pages", &self.error_pages, |r| Some(&r.route)));
+        routes.extend(each("fallbacks", &self.fallbacks, |r| Some(&r.route)));
+        routes.extend(each("faults", &self.faults, |r| Some(&r.route)));
+        routes.extend(each("forward_overrides", &self.forward_overrides, |r| {
+            Some(&r.route)
+        }));
+        routes.extend(each("hmac.verify", &self.hmac.verify, |r| Some(&r.route)));
+        routes.extend(each("hmac.sign", &self.hmac.sign, |r| Some(&r.route)));
+        if let Some(last_known) = &self.last_known {
+            routes.extend(each("last_known.routes", &last_known.routes, Some));
+        }
+        routes.extend(each(
+            "logging.sampling.routes",
+            &self.logging.sampling.routes,
+            |r| Some(&r.route),
+        ));
+        routes.extend(each("maintenance.routes", &self.maintenance.routes, Some));
+        routes.extend(each("mock_upstream", &self.mock_upstream, |r| {
+            Some(&r.route)
+        }));
+        if let Some(priority) = &self.priority {
+            for class in &priority.classes {
+                let section = format!("priority.classes[{}].routes", class.name);
+                routes.extend(each(&section, &class.routes, Some));
+            }
+        }
+        if let Some(recording) = &self.recording {
+            routes.extend(each("recording.routes", &recording.routes, Some));
+        }
+        routes.extend(each("redirects", &self.redirects, |r| Some(&r.route)));
+        if let Some(request_gzip) = &self.request_gzip {
+            routes.extend(each("request_gzip.routes", &request_gzip.routes, Some));
+        }
+        routes.extend(each("response_limits", &self.response_limits, |r| {
+            Some(&r.route)
+        }));
+        #[cfg(feature = "scripting")]
+        routes.extend(each("scripts", &self.scripts, |r| Some(&r.route)));
+        routes.extend(each("status_map", &self.status_map, |r| Some(&r.route)));
+        if let Some(store_forward) = &self.store_forward {
+            routes.extend(each("store_forward.routes", &store_forward.routes, Some));
+        }
+        routes.extend(each("summarize", &self.summarize, |r| Some(&r.route)));
+        routes.extend(each("throttle.rules", &self.throttle.rules, |r| {
+            Some(&r.route)
+        }));
+        routes.extend(each(
+            "transforms.requests",
+            &self.transforms.requests,
+            |r| Some(&r.route),
+        ));
+        routes.extend(each(
+            "transforms.responses",
+            &self.transforms.responses,
+            |r| Some(&r.route),
+        ));
+        routes.extend(each("validation", &self.validation, |r| Some(&r.route)));
+        if let Some(webhooks) = &self.webhooks {
+            routes.push(("webhooks.route".to_owned(), &webhooks.route));
+        }
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_validate_routes() {
+        assert!(Config::default().validate().is_ok());
+        let valid = config(serde_json::json!({
+            "throttle": { "rules": [{ "route": { "path_prefix": "/v6/device" } }] },
+            "access": { "rules": [{ "route": { "methods": ["GET"] } }] },
+            "environments": [{ "uuids": ["abc"], "upstream": "https://staging.example.com/v6" }],
+        }));
+        assert!(valid.validate().is_ok());
+
+        let invalid = [
+            (
+                serde_json::json!({ "throttle": { "rules": [{ "route": { "path_prefix": "v6" } }] } }),
+                "throttle.rules[0]",
+            ),
+            (
+                serde_json::json!({ "access": { "rules": [{}, { "route": { "methods": ["G ET"] } }] } }),
+                "access.rules[1]",
+            ),
+            (
+                serde_json::json!({ "transforms": { "responses": [
+                    { "route": { "headers": [{ "name": "x y" }] } },
+                ] } }),
+                "transforms.responses[0]",
+            ),
+            (
+                serde_json::json!({ "store_forward": {
+                    "directory": "/tmp/queue",
+                    "routes": [{ "path": "device" }],
+                } }),
+                "store_forward.routes[0]",
+            ),
+            (
+                serde_json::json!({ "last_known": {
+                    "directory": "/tmp/last-known",
+                    "routes": [{ "headers": [{ "name": "x-fleet", "value": "a\nb" }] }],
+                } }),
+                "last_known.routes[0]",
+            ),
+            (
+                serde_json::json!({ "environments": [{
+                    "route": { "path_prefix": "files/" },
+                    "upstream": "https://bucket.s3.amazonaws.com",
+                }] }),
+                "environments[0]",
+            ),
+        ];
+        for (value, section) in invalid {
+            let err = config(value.clone()).validate().unwrap_err().to_string();
+            assert!(err.starts_with(section), "{}: {}", value, err);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Facts about a request that layers share.
+//!
+//! Layers used to work out what others had done from the request itself; the
+//! retry policy dropped `Authorization` from retries for the auth layer to
+//! set again, which also dropped the keys clients brought themselves.
+//! [`ContextLayer`] puts a [`ProxyContext`] on every request where it is
+//! forwarded from. Layers below fill it in and read it, the clones of the
+//! request for retries share it, and it is logged once the request is done.
+//!
+//! Forwarded requests are counted, and their latency observed, by route and
+//! upstream host, with the client's trace id as the exemplar. Only the first
+//! `MAX_ROUTE_LABELS` routes seen get a label of their own, the path being
+//! the client's to choose; later ones are counted as `other`.
+
+use std::{
+    collections::BTreeSet,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use futures_core::Future;
+use http::{header::AUTHORIZATION, Request, Response};
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+
+use crate::{metrics::Metric, odata::ODataQuery};
+
+pub const FORWARDED: Metric = Metric::counter(
+    "proxy_requests_forwarded_total",
+    "Requests forwarded, by route, upstream and status class, or `error` when they got no response.",
+);
+const FORWARD_SECONDS: Metric = Metric::histogram(
+    "proxy_request_duration_seconds",
+    "Time to forward requests, retries included, by route, upstream and status class.",
+    &[
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+    ],
+);
+
+/// Id of the next request given a context.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Routes labelled in metrics, the others are labelled `other`.
+const MAX_ROUTE_LABELS: usize = 64;
+
+static ROUTE_LABELS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// The metrics label of `route`, from a bounded set.
+fn route_label(route: Option<&str>) -> String {
+    let Some(route) = route.filter(|route| {
+        !route.is_empty()
+            && route
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    }) else {
+        return "other".to_owned();
+    };
+    let mut labels = ROUTE_LABELS.lock().unwrap();
+    if labels.contains(route) || labels.len() < MAX_ROUTE_LABELS {
+        labels.insert(route.to_owned());
+        return route.to_owned();
+    }
+    "other".to_owned()
+}
+
+/// The trace id of the W3C `traceparent` of `req`, if any.
+fn trace_id<B>(req: &Request<B>) -> Option<String> {
+    let value = req.headers().get("traceparent")?.to_str().ok()?;
+    let trace_id = value.split('-').nth(1)?;
+    (trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0'))
+    .then(|| trace_id.to_ascii_lowercase())
+}
+
+/// Identifies `key` without exposing it.
+pub fn key_id(key: &str) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..4])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Annotations {
+    /// Id the proxy gave the request, unique within the process. Unlike
+    /// `X-Request-Id`, clients have no say in it.
+    pub id: u64,
+    /// Id of the caller's own API key, unset for requests using the pool.
+    pub caller: Option<String>,
+    /// API resource the request is about, e.g. `device`.
+    pub route: Option<String>,
+    /// Label of the pool key the latest attempt went out with.
+    pub key: Option<String>,
+    /// Attempt the request is on, 1 for the first.
+    pub attempt: u32,
+    /// When the caller stops waiting, unset without a timeout.
+    pub deadline: Option<Instant>,
+    /// Id the upstream gave its latest response.
+    pub upstream_request_id: Option<String>,
+    /// Host the request was forwarded to.
+    pub upstream: Option<String>,
+}
+
+/// The [`Annotations`] of a request, as a request extension.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyContext(Arc<Mutex<Annotations>>);
+
+impl ProxyContext {
+    pub fn new(annotations: Annotations) -> Self {
+        Self(Arc::new(Mutex::new(annotations)))
+    }
+
+    pub fn of<B>(req: &Request<B>) -> Option<&ProxyContext> {
+        req.extensions().get()
+    }
+
+    pub fn get(&self) -> Annotations {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut Annotations)) {
+        f(&mut self.0.lock().unwrap())
+    }
+}
+
+/// Clients' credentials, sent upstream as `Authorization`.
+pub(crate) const X_BALENA_AUTHORIZATION: &str = "x-balena-authorization";
+
+/// The key id of the client's own credentials, in `X-Balena-Authorization`
+/// or `Authorization`, if any. Only the key is hashed, not its scheme, so a
+/// client has the same id in both.
+pub fn caller<B>(req: &Request<B>) -> Option<String> {
+    let value = req
+        .headers()
+        .get(X_BALENA_AUTHORIZATION)
+        .or_else(|| req.headers().get(AUTHORIZATION))?
+        .to_str()
+        .ok()?;
+    let key = value.split_once(' ').map_or(value, |(_, key)| key);
+    Some(key_id(key.trim()))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ContextLayer;
+
+impl<S> Layer<S> for ContextLayer {
+    type Service = WithContext<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        WithContext { inner: service }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WithContext<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for WithContext<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let context = ProxyContext::new(Annotations {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            caller: caller(&req),
+            route: ODataQuery::parse(req.uri()).map(|query| query.resource),
+            attempt: 1,
+            ..Default::default()
+        });
+        req.extensions_mut().insert(context.clone());
+        let trace_id = trace_id(&req);
+        let started = Instant::now();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let result = fut.await;
+            let class = match &result {
+                Ok(res) => format!("{}xx", res.status().as_u16() / 100),
+                Err(_) => "error".to_owned(),
+            };
+            let annotations = context.get();
+            let route = route_label(annotations.route.as_deref());
+            let upstream = annotations.upstream.as_deref().unwrap_or("none");
+            let labels = [
+                ("route", route.as_str()),
+                ("upstream", upstream),
+                ("class", class.as_str()),
+            ];
+            FORWARDED.increment(&labels);
+            FORWARD_SECONDS.observe(
+                &labels,
+                started.elapsed().as_secs_f64(),
+                trace_id.as_deref(),
+            );
+            tracing::debug!(
+                route = annotations.route,
+                caller = annotations.caller,
+                key = annotations.key,
+                attempts = annotations.attempt,
+                upstream_request_id = annotations.upstream_request_id,
+                status = result.as_ref().ok().map(|res| res.status().as_u16()),
+                "request forwarded"
+            );
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_context() {
+        let service = ContextLayer.layer(service_fn(|req: Request<()>| async move {
+            let context = ProxyContext::of(&req).expect("context").clone();
+            context.update(|annotations| annotations.key = Some("fleet-a".to_owned()));
+            Ok::<_, Infallible>(Response::new(context))
+        }));
+
+        let req = Request::get("/v6/device(1)").body(()).unwrap();
+        let annotations = service
+            .clone()
+            .oneshot(req)
+            .await
+            .unwrap()
+            .into_body()
+            .get();
+        assert_eq!(annotations.route.as_deref(), Some("device"));
+        assert_eq!(annotations.key.as_deref(), Some("fleet-a"));
+        assert_eq!((annotations.caller, annotations.attempt), (None, 1));
+
+        let req = Request::get("/v6/release")
+            .header(AUTHORIZATION, "Bearer own")
+            .body(())
+            .unwrap();
+        let annotations = service.oneshot(req).await.unwrap().into_body().get();
+        assert_eq!(annotations.caller, Some(key_id("own")));
+    }
+
+    #[test]
+    fn test_caller() {
+        let req = |name: &str, value: &str| Request::get("/").header(name, value).body(()).unwrap();
+        let own = Some(key_id("own"));
+        assert_eq!(caller(&req("authorization", "Bearer own")), own);
+        assert_eq!(caller(&req(X_BALENA_AUTHORIZATION, "Bearer own")), own);
+        assert_eq!(caller(&req(X_BALENA_AUTHORIZATION, "own")), own);
+        let req = Request::get("/")
+            .header(X_BALENA_AUTHORIZATION, "Bearer own")
+            .header(AUTHORIZATION, "Bearer other")
+            .body(())
+            .unwrap();
+        assert_eq!(caller(&req), own);
+        assert_eq!(caller(&Request::get("/").body(()).unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_route_metrics() {
+        let service = ContextLayer.layer(service_fn(|req: Request<()>| async move {
+            let context = ProxyContext::of(&req).expect("context");
+            context.update(|annotations| annotations.upstream = Some("api.example".to_owned()));
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = Request::get("/v6/application(1)")
+            .header(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            )
+            .body(())
+            .unwrap();
+        service.oneshot(req).await.unwrap();
+
+        let labels = [("route", "application"), ("upstream", "api.example")];
+        assert!(FORWARDED.sum(&labels) >= 1.0);
+        assert!(crate::metrics::render_openmetrics()
+            .contains(&format!("# {{trace_id=\"{}\"}}", trace_id)));
+        assert_eq!(route_label(Some("device(uuid='x')")), "other");
+    }
+}
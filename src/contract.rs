@@ -0,0 +1,278 @@
+//! Checks of upstream responses against the API contract.
+//!
+//! The upstream may drop or retype a field without notice, and clients only
+//! notice once they break. [`ContractLayer`] checks successful JSON responses
+//! of the routes configured against a JSON schema, loaded the way
+//! [`crate::validate`] loads them, and for the properties listed in
+//! `required`. Each record of an OData `{"d": [...]}` collection is checked on
+//! its own, other bodies as a whole. Responses breaking the contract are
+//! counted and logged as errors to alert on, and passed on unchanged.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_ENCODING, Request, Response};
+use http_body::Body;
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    gzip,
+    metrics::Metric,
+    read_request_body::{buffer, FromBuffered},
+    route::RouteMatcher,
+    validate::{Schema, Violation},
+};
+
+const VIOLATIONS: Metric = Metric::counter(
+    "proxy_contract_violations_total",
+    "Upstream responses breaking the contract of their route, by contract.",
+);
+
+/// Violations logged per response, the rest are only counted.
+const LOGGED_VIOLATIONS: usize = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContractRule {
+    /// Name of the contract in metrics and logs.
+    pub name: String,
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// JSON file holding the schema of the records, none when unset.
+    pub schema: Option<PathBuf>,
+    /// JSON pointer to the schema inside the file, the whole file when unset.
+    pub pointer: Option<String>,
+    /// JSON pointers that must be present in every record.
+    #[serde(default)]
+    pub required: Vec<String>,
+}
+
+struct Contract {
+    name: String,
+    route: RouteMatcher,
+    schema: Option<Schema>,
+    required: Vec<String>,
+}
+
+impl Contract {
+    /// Every way `record` breaks the contract, with paths prefixed by
+    /// `path`.
+    fn check(&self, record: &Value, path: &str) -> Vec<Violation> {
+        let mut violations: Vec<Violation> = self
+            .required
+            .iter()
+            .filter(|pointer| record.pointer(pointer).is_none())
+            .map(|pointer| Violation {
+                path: format!("{}{}", path, pointer),
+                message: "missing required value".into(),
+            })
+            .collect();
+        if let Some(schema) = &self.schema {
+            violations.extend(
+                schema
+                    .validate(record)
+                    .into_iter()
+                    .map(|violation| Violation {
+                        path: format!("{}{}", path, violation.path),
+                        ..violation
+                    }),
+            );
+        }
+        violations
+    }
+
+    /// Every way `body` breaks the contract, `None` when it is not JSON.
+    fn verify(&self, body: &[u8]) -> Option<Vec<Violation>> {
+        let doc: Value = serde_json::from_slice(body).ok()?;
+        Some(match doc.get("d").and_then(Value::as_array) {
+            Some(records) => records
+                .iter()
+                .enumerate()
+                .flat_map(|(i, record)| self.check(record, &format!("/d/{}", i)))
+                .collect(),
+            None => self.check(&doc, ""),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ContractLayer {
+    contracts: Arc<Vec<Contract>>,
+}
+
+impl ContractLayer {
+    /// Load the schema of every rule. The first rule matching a request
+    /// applies.
+    pub fn new(rules: Vec<ContractRule>) -> Result<Self, BoxError> {
+        let contracts = rules
+            .into_iter()
+            .map(|rule| {
+                let schema = rule
+                    .schema
+                    .map(|path| Schema::load(&path, rule.pointer))
+                    .transpose()?;
+                Ok(Contract {
+                    name: rule.name,
+                    route: rule.route,
+                    schema,
+                    required: rule.required,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            contracts: Arc::new(contracts),
+        })
+    }
+}
+
+impl<S> Layer<S> for ContractLayer {
+    type Service = CheckContract<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CheckContract {
+            inner: service,
+            contracts: self.contracts.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CheckContract<S> {
+    inner: S,
+    contracts: Arc<Vec<Contract>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CheckContract<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let index = self
+            .contracts
+            .iter()
+            .position(|contract| contract.route.matches(&req));
+        let contracts = self.contracts.clone();
+        let path = req.uri().path().to_owned();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            let Some(contract) = index.map(|index| &contracts[index]) else {
+                return Ok(res);
+            };
+            // errors have a contract of their own, and other encodings are
+            // not ours to read
+            if !res.status().is_success()
+                || (res.headers().contains_key(CONTENT_ENCODING) && !gzip::is_gzip(res.headers()))
+            {
+                return Ok(res);
+            }
+            let (parts, body) = res.into_parts();
+            let (bytes, trailers) = buffer(body).await?;
+            let violations = contract
+                .verify(&gzip::inspect(&parts.headers, &bytes))
+                .unwrap_or_default();
+            if !violations.is_empty() {
+                VIOLATIONS.increment(&[("contract", &contract.name)]);
+                let logged: Vec<String> = violations
+                    .iter()
+                    .take(LOGGED_VIOLATIONS)
+                    .map(|violation| format!("{}: {}", violation.path, violation.message))
+                    .collect();
+                tracing::error!(
+                    contract = %contract.name,
+                    path,
+                    count = violations.len(),
+                    violations = ?logged,
+                    "upstream response breaks its contract"
+                );
+            }
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_contract() -> Result<(), BoxError> {
+        let path = std::env::temp_dir().join(format!("proxy-contract-{}.json", std::process::id()));
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "id": { "type": "integer" }, "device_name": { "type": "string" } },
+        });
+        std::fs::write(&path, schema.to_string())?;
+        let rules: Vec<ContractRule> = serde_json::from_value(serde_json::json!([{
+            "name": "device",
+            "route": { "path_prefix": "/v6/device" },
+            "schema": path,
+            "required": ["/id", "/belongs_to__application/__id"],
+        }]))?;
+        let layer = ContractLayer::new(rules)?;
+        std::fs::remove_file(&path)?;
+
+        let contract = &layer.contracts[0];
+        let valid = serde_json::json!({
+            "d": [{ "id": 1, "device_name": "pi", "belongs_to__application": { "__id": 2 } }],
+        });
+        assert!(contract
+            .verify(valid.to_string().as_bytes())
+            .unwrap()
+            .is_empty());
+        let changed = serde_json::json!({
+            "d": [
+                { "id": 1, "belongs_to__application": { "__id": 2 } },
+                { "id": "2", "device_name": "pi", "belongs_to__application": 2 },
+            ],
+        });
+        let paths: Vec<String> = contract
+            .verify(changed.to_string().as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|violation| violation.path)
+            .collect();
+        assert_eq!(paths, ["/d/1/belongs_to__application/__id", "/d/1/id"]);
+        assert!(contract.verify(b"not json").is_none());
+
+        // responses go through unchanged
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/v6/device" },
+            "body": changed,
+        }))?;
+        let res = layer
+            .layer(MockUpstream::new(vec![fixture]))
+            .oneshot(Request::get("/v6/device").body(hyper::Body::empty())?)
+            .await?;
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body, changed);
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Decryption of API keys at startup.
+//!
+//! Keys in `BALENA_API_KEY` written as `enc:<base64>` are decrypted with the
+//! configured [`Decryptor`] before the key pool is built, so plaintext keys
+//! never sit in the environment or on disk. Other keys are used as they are.
+//!
+//! - `local`: AES-256-GCM with a master key, the blob being the 12 byte nonce
+//!   followed by the ciphertext.
+//! - `aws_kms`: the blob is a KMS ciphertext, decrypted by calling KMS.
+//! - `envelope`: the blob is a 2 byte big-endian length, a data key encrypted
+//!   for the inner decryptor, then the key encrypted with the data key as for
+//!   `local`. Only the data key leaves for KMS, whatever the key count.
+
+use std::{future::Future, pin::Pin};
+
+use crate::https::HttpsConnector;
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header::CONTENT_TYPE, Request};
+use hyper::Client;
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::{read_request_body::ByteBody, sigv4::AwsSigner};
+
+/// Prefix of encrypted keys.
+pub const ENCRYPTED_PREFIX: &str = "enc:";
+
+const NONCE_LEN: usize = 12;
+
+pub type DecryptFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, BoxError>> + Send + 'a>>;
+
+pub trait Decryptor: Send + Sync {
+    fn decrypt<'a>(&'a self, blob: &'a [u8]) -> DecryptFuture<'a>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum DecryptorConfig {
+    Local {
+        /// Environment variable holding the base64 256-bit master key.
+        master_key_env: String,
+    },
+    AwsKms {
+        region: String,
+        /// KMS endpoint, e.g. a VPC endpoint, the regional one when unset.
+        endpoint: Option<String>,
+    },
+    Envelope {
+        /// Decryptor of the data keys.
+        key: Box<DecryptorConfig>,
+    },
+}
+
+impl DecryptorConfig {
+    pub fn build(&self) -> Result<Box<dyn Decryptor>, BoxError> {
+        Ok(match self {
+            Self::Local { master_key_env } => {
+                let key = std::env::var(master_key_env)
+                    .map_err(|err| format!("{}: {}", err, master_key_env))?;
+                let key = STANDARD
+                    .decode(key.trim())
+                    .map_err(|err| format!("{}: {}", master_key_env, err))?;
+                Box::new(LocalKey::new(&key)?)
+            }
+            Self::AwsKms { region, endpoint } => Box::new(AwsKms {
+                endpoint: endpoint
+                    .clone()
+                    .unwrap_or_else(|| format!("https://kms.{}.amazonaws.com/", region)),
+                signer: AwsSigner::new(region.clone(), "kms".to_owned()),
+            }),
+            Self::Envelope { key } => Box::new(Envelope { key: key.build()? }),
+        })
+    }
+}
+
+/// Decrypt the `enc:` values of `keys` with `decryptor`.
+pub async fn decrypt_keys(
+    keys: Vec<String>,
+    decryptor: Option<&dyn Decryptor>,
+) -> Result<Vec<String>, BoxError> {
+    let mut decrypted = Vec::with_capacity(keys.len());
+    for key in keys {
+        let Some(blob) = key.strip_prefix(ENCRYPTED_PREFIX) else {
+            decrypted.push(key);
+            continue;
+        };
+        let decryptor = decryptor.ok_or("encrypted API key without key_decryption")?;
+        let blob = STANDARD
+            .decode(blob)
+            .map_err(|err| format!("encrypted API key: {}", err))?;
+        let plaintext = decryptor
+            .decrypt(&blob)
+            .await
+            .map_err(|err| format!("encrypted API key: {}", err))?;
+        decrypted.push(String::from_utf8(plaintext)?);
+    }
+    Ok(decrypted)
+}
+
+pub struct LocalKey {
+    cipher: Aes256Gcm,
+}
+
+impl LocalKey {
+    pub fn new(key: &[u8]) -> Result<Self, BoxError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "master key must be 256 bits")?;
+        Ok(Self { cipher })
+    }
+
+    fn open(&self, blob: &[u8]) -> Result<Vec<u8>, BoxError> {
+        if blob.len() < NONCE_LEN {
+            return Err("ciphertext too short".into());
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "decryption failed".into())
+    }
+}
+
+impl Decryptor for LocalKey {
+    fn decrypt<'a>(&'a self, blob: &'a [u8]) -> DecryptFuture<'a> {
+        Box::pin(async move { self.open(blob) })
+    }
+}
+
+pub struct AwsKms {
+    endpoint: String,
+    signer: AwsSigner,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KmsPlaintext {
+    plaintext: String,
+}
+
+impl Decryptor for AwsKms {
+    fn decrypt<'a>(&'a self, blob: &'a [u8]) -> DecryptFuture<'a> {
+        Box::pin(async move {
+            let body = serde_json::json!({ "CiphertextBlob": STANDARD.encode(blob) });
+            let mut req = Request::post(self.endpoint.as_str())
+                .header(CONTENT_TYPE, "application/x-amz-json-1.1")
+                .header("x-amz-target", "TrentService.Decrypt")
+                .body(ByteBody::new(body.to_string().into_bytes()))?;
+            self.signer.sign(&mut req).await?;
+
+            let client = Client::builder().build::<_, ByteBody>(HttpsConnector::new());
+            let res = client.request(req).await?;
+            let status = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            if !status.is_success() {
+                return Err(format!("KMS: {} {}", status, String::from_utf8_lossy(&body)).into());
+            }
+            let plaintext: KmsPlaintext = serde_json::from_slice(&body)?;
+            Ok(STANDARD.decode(plaintext.plaintext)?)
+        })
+    }
+}
+
+pub struct Envelope {
+    key: Box<dyn Decryptor>,
+}
+
+impl Decryptor for Envelope {
+    fn decrypt<'a>(&'a self, blob: &'a [u8]) -> DecryptFuture<'a> {
+        Box::pin(async move {
+            let (len, rest) = blob.split_first_chunk::<2>().ok_or("envelope too short")?;
+            let len = u16::from_be_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err("envelope too short".into());
+            }
+            let (data_key, ciphertext) = rest.split_at(len);
+            let data_key = self.key.decrypt(data_key).await?;
+            LocalKey::new(&data_key)?.open(ciphertext)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(key: &[u8], nonce: [u8; NONCE_LEN], plaintext: &[u8]) -> Vec<u8> {
+        let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+        let mut blob = nonce.to_vec();
+        blob.extend(
+            cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .unwrap(),
+        );
+        blob
+    }
+
+    #[tokio::test]
+    async fn test_local_and_envelope() -> Result<(), BoxError> {
+        let master = [7u8; 32];
+        let data_key = [9u8; 32];
+
+        let local = seal(&master, [1; NONCE_LEN], b"key-one");
+        let wrapped = seal(&master, [2; NONCE_LEN], &data_key);
+        let mut envelope = (wrapped.len() as u16).to_be_bytes().to_vec();
+        envelope.extend(&wrapped);
+        envelope.extend(seal(&data_key, [3; NONCE_LEN], b"key-two"));
+
+        let keys = vec![
+            format!("enc:{}", STANDARD.encode(&local)),
+            "plain-key".to_owned(),
+        ];
+        let decryptor = LocalKey::new(&master)?;
+        assert_eq!(
+            decrypt_keys(keys.clone(), Some(&decryptor)).await?,
+            ["key-one", "plain-key"]
+        );
+        assert!(decrypt_keys(keys, None).await.is_err());
+
+        let decryptor = Envelope {
+            key: Box::new(LocalKey::new(&master)?),
+        };
+        let keys = vec![format!("enc:{}", STANDARD.encode(&envelope))];
+        assert_eq!(decrypt_keys(keys, Some(&decryptor)).await?, ["key-two"]);
+
+        // a tampered blob does not decrypt
+        let mut tampered = local.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let keys = vec![format!("enc:{}", STANDARD.encode(&tampered))];
+        assert!(decrypt_keys(keys, Some(&LocalKey::new(&master)?))
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+//! Upstream connects across the addresses of a host.
+//!
+//! hyper's connector tries the addresses of a host in the order the system
+//! resolver lists them, which is usually the same every time, so a dead
+//! address is waited on by every new connection. [`FailoverConnector`]
+//! resolves upstream hosts itself, keeping the addresses for `cache_secs`,
+//! and starts each connect at the next address in turn, A and AAAA records
+//! alike. Each address gets `connect_timeout_ms`; addresses whose last
+//! connect failed are tried last, and a failure drops the host's cached
+//! addresses, so the next connect resolves it again. A connect that ran out
+//! of cached addresses resolves the host once more and tries the addresses
+//! it had not. Failures are counted per address in
+//! `proxy_upstream_connect_failures_total`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+    vec,
+};
+
+use futures_core::Future;
+use http::Uri;
+use hyper::client::{connect::dns::Name, HttpConnector};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tower::{BoxError, Service, ServiceExt};
+
+use crate::metrics::Metric;
+
+pub const CONNECT_FAILURES: Metric = Metric::counter(
+    "proxy_upstream_connect_failures_total",
+    "Failed connects to upstream addresses.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// How long resolved addresses are used before resolving again.
+    pub cache_secs: u64,
+    /// How long a connect to one address may take before the next is tried.
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            cache_secs: 30,
+            connect_timeout_ms: 5000,
+        }
+    }
+}
+
+/// Failure to resolve an upstream host.
+#[derive(Debug)]
+pub struct ResolveError {
+    host: String,
+    source: io::Error,
+}
+
+impl ResolveError {
+    pub fn new(host: &str, source: io::Error) -> Self {
+        Self {
+            host: host.to_owned(),
+            source,
+        }
+    }
+
+    /// Whether the host does not exist, as far as the system resolver's
+    /// messages tell, rather than failed to resolve for now.
+    pub fn is_not_found(&self) -> bool {
+        let message = self.source.to_string().to_ascii_lowercase();
+        [
+            "name or service not known",
+            "no address associated with hostname",
+            "nodename nor servname provided",
+            "no such host is known",
+            "resolved to no addresses",
+        ]
+        .iter()
+        .any(|known| message.contains(known))
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "resolving {}: {}", self.host, self.source)
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+struct Resolved {
+    addrs: Vec<IpAddr>,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    hosts: HashMap<String, Resolved>,
+    /// Addresses whose last connect failed.
+    failing: HashSet<IpAddr>,
+    /// Where the next connect starts in the addresses of its host.
+    next: usize,
+}
+
+impl State {
+    /// `addrs` starting at the next address in turn, failing ones last.
+    fn order(&mut self, addrs: &[IpAddr]) -> Vec<IpAddr> {
+        let mut ordered = addrs.to_vec();
+        if !ordered.is_empty() {
+            let start = self.next % ordered.len();
+            ordered.rotate_left(start);
+        }
+        self.next = self.next.wrapping_add(1);
+        // stable, so the rotation holds within either group
+        ordered.sort_by_key(|addr| self.failing.contains(addr));
+        ordered
+    }
+}
+
+/// A connector trying the addresses of a host in turn, see the module docs.
+#[derive(Clone)]
+pub struct FailoverConnector<R> {
+    resolver: R,
+    http: HttpConnector,
+    cache: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl<R> FailoverConnector<R> {
+    pub fn new(resolver: R, config: DnsConfig) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(Duration::from_millis(config.connect_timeout_ms)));
+        Self {
+            resolver,
+            http,
+            cache: Duration::from_secs(config.cache_secs),
+            state: Arc::default(),
+        }
+    }
+}
+
+impl<R> FailoverConnector<R>
+where
+    R: Service<Name, Response = vec::IntoIter<SocketAddr>, Error = io::Error> + Clone,
+{
+    /// The addresses of `host`, cached unless `fresh`, and whether they were.
+    async fn resolve(&self, host: &str, fresh: bool) -> io::Result<(Vec<IpAddr>, bool)> {
+        if !fresh {
+            let state = self.state.lock().unwrap();
+            if let Some(resolved) = state.hosts.get(host) {
+                if resolved.at.elapsed() < self.cache {
+                    return Ok((resolved.addrs.clone(), true));
+                }
+            }
+        }
+        let name = Name::from_str(host).map_err(io::Error::other)?;
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        for addr in self.resolver.clone().oneshot(name).await? {
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+        self.state.lock().unwrap().hosts.insert(
+            host.to_owned(),
+            Resolved {
+                addrs: addrs.clone(),
+                at: Instant::now(),
+            },
+        );
+        Ok((addrs, false))
+    }
+
+    async fn connect(self, uri: Uri) -> Result<TcpStream, BoxError> {
+        let host = uri.host().unwrap_or_default();
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        // literal addresses have nothing to rotate
+        if IpAddr::from_str(&host).is_ok() {
+            return Ok(self.http.clone().oneshot(uri).await?);
+        }
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        let scheme = uri.scheme_str().unwrap_or("https");
+
+        let mut tried = HashSet::new();
+        let mut last_error: Option<BoxError> = None;
+        let resolve_error = |err| ResolveError::new(&host, err);
+        let (mut addrs, mut cached) = self.resolve(&host, false).await.map_err(resolve_error)?;
+        loop {
+            let ordered = self.state.lock().unwrap().order(&addrs);
+            for ip in ordered.into_iter().filter(|ip| tried.insert(*ip)) {
+                let addr = SocketAddr::new(ip, port);
+                let target = Uri::from_str(&format!("{}://{}", scheme, addr))?;
+                match self.http.clone().oneshot(target).await {
+                    Ok(stream) => {
+                        self.state.lock().unwrap().failing.remove(&ip);
+                        return Ok(stream);
+                    }
+                    Err(err) => {
+                        CONNECT_FAILURES.increment(&[("address", &ip.to_string())]);
+                        tracing::warn!(%host, %addr, %err, "upstream connect failed");
+                        let mut state = self.state.lock().unwrap();
+                        state.failing.insert(ip);
+                        state.hosts.remove(&host);
+                        last_error = Some(err.into());
+                    }
+                }
+            }
+            // the cached addresses may be out of date, look again once
+            if !cached {
+                break;
+            }
+            (addrs, cached) = self.resolve(&host, true).await.map_err(resolve_error)?;
+        }
+        Err(last_error.unwrap_or_else(|| {
+            let err = io::Error::other("resolved to no addresses");
+            ResolveError::new(&host, err).into()
+        }))
+    }
+}
+
+impl<R> Service<Uri> for FailoverConnector<R>
+where
+    R: Service<Name, Response = vec::IntoIter<SocketAddr>, Error = io::Error>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    R::Future: Send,
+{
+    type Response = TcpStream;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn test_failover() -> Result<(), BoxError> {
+        // only 127.0.0.1 listens, 127.0.0.2 refuses connections
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+        let resolutions = Arc::new(AtomicUsize::new(0));
+        let resolver = service_fn({
+            let resolutions = resolutions.clone();
+            move |_: Name| {
+                resolutions.fetch_add(1, Ordering::SeqCst);
+                let addrs: Vec<SocketAddr> =
+                    vec![([127, 0, 0, 2], 0).into(), ([127, 0, 0, 1], 0).into()];
+                async move { Ok::<_, io::Error>(addrs.into_iter()) }
+            }
+        });
+        let mut connector = FailoverConnector::new(resolver, DnsConfig::default());
+        let uri = Uri::from_str(&format!("http://upstream.example:{}", port))?;
+        let dead = IpAddr::from([127, 0, 0, 2]);
+        let failures = || CONNECT_FAILURES.get(&[("address", "127.0.0.2")]);
+
+        let stream = connector.call(uri.clone()).await?;
+        assert_eq!(stream.peer_addr()?.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(failures(), 1.0);
+        assert!(connector.state.lock().unwrap().failing.contains(&dead));
+
+        // resolved again after the failure, and the dead address is tried last
+        let stream = connector.call(uri.clone()).await?;
+        assert_eq!(stream.peer_addr()?.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(failures(), 1.0);
+        assert_eq!(resolutions.load(Ordering::SeqCst), 2);
+        // then cached
+        connector.call(uri).await?;
+        assert_eq!(resolutions.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
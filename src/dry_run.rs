@@ -0,0 +1,122 @@
+//! Dry-run upstream for validating configuration changes.
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    task::{Context, Poll},
+};
+
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Request, Response,
+};
+use hyper::Body;
+use serde_json::json;
+use tower::Service;
+
+use crate::read_request_body::ByteBody;
+
+/// Header added to dry-run responses.
+pub const X_PROXY_DRY_RUN: &str = "x-proxy-dry-run";
+
+/// Service answering every request with a 200 describing the request it
+/// would have sent upstream, after the whole middleware stack ran.
+#[derive(Clone, Default)]
+pub struct DryRun;
+
+impl DryRun {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// Hide all but the last four characters of the credential.
+fn mask_authorization(value: &str) -> String {
+    let (scheme, key) = value.split_once(' ').unwrap_or(("", value));
+    let visible = key
+        .char_indices()
+        .rev()
+        .nth(3)
+        .map_or("", |(i, _)| &key[i..]);
+    format!("{} ***{}", scheme, visible)
+        .trim_start()
+        .to_string()
+}
+
+fn headers_json(headers: &HeaderMap) -> serde_json::Value {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = if name == AUTHORIZATION {
+                mask_authorization(&value)
+            } else {
+                value.into_owned()
+            };
+            json!([name.as_str(), value])
+        })
+        .collect()
+}
+
+impl Service<Request<ByteBody>> for DryRun {
+    type Response = Response<Body>;
+
+    type Error = Infallible;
+
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let body = req.body().as_bytes();
+        let outbound = json!({
+            "method": req.method().as_str(),
+            "uri": req.uri().to_string(),
+            "headers": headers_json(req.headers()),
+            "body": (!body.is_empty()).then(|| String::from_utf8_lossy(body)),
+        });
+        tracing::info!(method = %req.method(), uri = %req.uri(), "dry run, not forwarding");
+
+        let mut res = Response::new(Body::from(outbound.to_string()));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res.headers_mut()
+            .insert(X_PROXY_DRY_RUN, HeaderValue::from_static("true"));
+        ready(Ok(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::{BoxError, ServiceExt};
+
+    #[tokio::test]
+    async fn test_dry_run() -> Result<(), BoxError> {
+        let req = Request::patch("https://api.balena-cloud.com/v6/device(1)")
+            .header(AUTHORIZATION, "Bearer abcdefgh")
+            .body(ByteBody::new(br#"{"status":"Idle"}"#.to_vec()))?;
+        let res = DryRun::new().oneshot(req).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()[X_PROXY_DRY_RUN], "true");
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let outbound: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(outbound["method"], "PATCH");
+        assert_eq!(outbound["uri"], "https://api.balena-cloud.com/v6/device(1)");
+        assert_eq!(
+            outbound["headers"],
+            json!([["authorization", "Bearer ***efgh"]])
+        );
+        assert_eq!(outbound["body"], r#"{"status":"Idle"}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mask_authorization() {
+        assert_eq!(mask_authorization("Bearer abcdefgh"), "Bearer ***efgh");
+        assert_eq!(mask_authorization("abc"), "***");
+    }
+}
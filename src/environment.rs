@@ -0,0 +1,303 @@
+//! Device-UUID-based routing to upstream environments.
+//!
+//! [`EnvironmentLayer`] sends requests about some devices or fleets to
+//! another environment (staging, on-prem) than the default upstream, for
+//! mixed-fleet setups. The device UUID is taken from `device(uuid='…')` in
+//! the path or `uuid eq '…'` in the OData query, the fleet from
+//! `application(<id>)` or `belongs_to__application eq <id>`. Rules may also
+//! select requests by route, e.g. to send a path to an S3 bucket. The first
+//! rule matching decides; `ForwardRequest` then builds the upstream URI on
+//! the rule's base.
+//!
+//! Developers steer single test requests with `x-proxy-upstream: <name>`,
+//! naming an environment rather than giving a URL. The header is honoured
+//! for the callers the environment lists by key id, and dropped before the
+//! request is forwarded either way.
+
+use std::{
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{header::AUTHORIZATION, HeaderName, HeaderValue, Request, Uri};
+use regex::Regex;
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    context::caller, forward_request::Upstream, route::RouteMatcher, sanitize::percent_decode,
+};
+
+/// Names the environment a request is sent to, for allowed callers.
+pub const X_PROXY_UPSTREAM: HeaderName = HeaderName::from_static("x-proxy-upstream");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentRule {
+    /// Name requests select the environment by with `x-proxy-upstream`.
+    pub name: Option<String>,
+    /// Key ids of the callers allowed to select it by name, see
+    /// [`crate::context::key_id`].
+    #[serde(default)]
+    pub callers: Vec<String>,
+    /// Device UUIDs routed to this environment, compared case-insensitively.
+    #[serde(default)]
+    pub uuids: Vec<String>,
+    /// Fleet IDs routed to this environment.
+    #[serde(default)]
+    pub fleets: Vec<u64>,
+    /// Requests routed to this environment whatever device they concern.
+    pub route: Option<RouteMatcher>,
+    /// Base URI of the environment API, e.g. `https://api.staging.example.com/v6`.
+    pub upstream: String,
+    /// Environment variable holding the API key of this environment. Requests
+    /// without one of their own use the default key pool when unset.
+    pub api_key_env: Option<String>,
+}
+
+struct Rule {
+    name: Option<String>,
+    callers: Vec<String>,
+    uuids: Vec<String>,
+    fleets: Vec<u64>,
+    route: Option<RouteMatcher>,
+    upstream: Uri,
+    authorization: Option<HeaderValue>,
+}
+
+struct Environments {
+    rules: Vec<Rule>,
+    uuid: Regex,
+    fleet: Regex,
+}
+
+impl Environments {
+    /// The device UUID and fleet a request is about, as far as its URI tells.
+    fn subject(&self, uri: &Uri) -> (Option<String>, Option<u64>) {
+        let query = uri.query().unwrap_or_default().replace('+', " ");
+        let target = format!(
+            "{}?{}",
+            String::from_utf8_lossy(&percent_decode(uri.path())),
+            String::from_utf8_lossy(&percent_decode(&query)),
+        );
+        let uuid = self
+            .uuid
+            .captures(&target)
+            .map(|c| c[1].to_ascii_lowercase());
+        let fleet = self.fleet.captures(&target).and_then(|c| c[1].parse().ok());
+        (uuid, fleet)
+    }
+
+    /// The environment named by `x-proxy-upstream`, if its caller may.
+    fn selected<B>(&self, req: &Request<B>) -> Option<&Rule> {
+        let name = req.headers().get(X_PROXY_UPSTREAM)?.to_str().ok()?;
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.name.as_deref() == Some(name))
+        else {
+            tracing::warn!(name, "unknown upstream requested");
+            return None;
+        };
+        let caller = caller(req);
+        if !caller.as_ref().is_some_and(|id| rule.callers.contains(id)) {
+            tracing::warn!(name, caller, "caller may not select upstream");
+            return None;
+        }
+        Some(rule)
+    }
+
+    fn route<B>(&self, req: &Request<B>) -> Option<&Rule> {
+        if req.headers().contains_key(X_PROXY_UPSTREAM) {
+            if let Some(rule) = self.selected(req) {
+                return Some(rule);
+            }
+        }
+        let (uuid, fleet) = self.subject(req.uri());
+        self.rules.iter().find(|rule| {
+            uuid.as_ref().is_some_and(|uuid| rule.uuids.contains(uuid))
+                || fleet.is_some_and(|fleet| rule.fleets.contains(&fleet))
+                || rule.route.as_ref().is_some_and(|route| route.matches(req))
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct EnvironmentLayer {
+    environments: Arc<Environments>,
+}
+
+impl EnvironmentLayer {
+    /// Check the upstreams and read the API keys of `rules`.
+    pub fn new(rules: Vec<EnvironmentRule>) -> Result<Self, BoxError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let upstream = Uri::from_str(&rule.upstream)
+                    .map_err(|err| format!("environment upstream {}: {}", rule.upstream, err))?;
+                let authorization = rule
+                    .api_key_env
+                    .map(|name| {
+                        let key =
+                            std::env::var(&name).map_err(|err| format!("{}: {}", err, name))?;
+                        HeaderValue::from_str(&format!("Bearer {}", key))
+                            .map_err(|_| format!("invalid {}", name))
+                    })
+                    .transpose()?;
+                Ok(Rule {
+                    name: rule.name,
+                    callers: rule.callers,
+                    uuids: rule
+                        .uuids
+                        .iter()
+                        .map(|uuid| uuid.to_ascii_lowercase())
+                        .collect(),
+                    fleets: rule.fleets,
+                    route: rule.route,
+                    upstream,
+                    authorization,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            environments: Arc::new(Environments {
+                rules,
+                uuid: Regex::new(r"(?i)\buuid(?:\s*=\s*|\s+eq\s+)'([0-9a-f]+)'").unwrap(),
+                fleet: Regex::new(
+                    r"(?i)(?:\bapplication\(|\bbelongs_to__application\s+eq\s+)(\d+)",
+                )
+                .unwrap(),
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for EnvironmentLayer {
+    type Service = Environment<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Environment {
+            inner: service,
+            environments: self.environments.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Environment<S> {
+    inner: S,
+    environments: Arc<Environments>,
+}
+
+impl<S, B> Service<Request<B>> for Environment<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let rule = self.environments.route(&req);
+        req.headers_mut().remove(X_PROXY_UPSTREAM);
+        if let Some(rule) = rule {
+            tracing::debug!(upstream = %rule.upstream, "routing to environment");
+            if let Some(authorization) = &rule.authorization {
+                if !req.headers().contains_key(AUTHORIZATION) {
+                    req.headers_mut()
+                        .insert(AUTHORIZATION, authorization.clone());
+                }
+            }
+            req.extensions_mut().insert(Upstream(rule.upstream.clone()));
+        }
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::key_id;
+
+    fn layer() -> EnvironmentLayer {
+        EnvironmentLayer::new(
+            serde_json::from_value(serde_json::json!([
+                { "uuids": ["ABC123"], "upstream": "https://staging.example.com/v6" },
+                { "fleets": [42], "upstream": "https://onprem.example.com/v6" },
+                { "route": { "path_prefix": "/files/" }, "upstream": "https://bucket.s3.amazonaws.com" },
+            ]))
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn upstream(path: &str) -> Option<String> {
+        let req = Request::get(path).body(()).unwrap();
+        layer()
+            .environments
+            .route(&req)
+            .map(|rule| rule.upstream.to_string())
+    }
+
+    #[test]
+    fn test_route() {
+        let staging = Some("https://staging.example.com/v6".to_owned());
+        let onprem = Some("https://onprem.example.com/v6".to_owned());
+        assert_eq!(upstream("/v6/device(uuid='abc123')"), staging);
+        assert_eq!(
+            upstream("/v6/device?$filter=uuid%20eq%20'abc123'&$select=id"),
+            staging
+        );
+        assert_eq!(
+            upstream("/v6/device_tag?$filter=device/uuid+eq+'ABC123'"),
+            staging
+        );
+        assert_eq!(upstream("/v6/application(42)"), onprem);
+        assert_eq!(
+            upstream("/v6/device?$filter=belongs_to__application%20eq%2042"),
+            onprem
+        );
+        assert_eq!(upstream("/v6/device(uuid='def456')"), None);
+        assert_eq!(upstream("/v6/application(420)"), None);
+        assert_eq!(upstream("/v6/device"), None);
+        assert_eq!(
+            upstream("/files/logs.txt"),
+            Some("https://bucket.s3.amazonaws.com/".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_selected_upstream() {
+        let layer = EnvironmentLayer::new(
+            serde_json::from_value(serde_json::json!([
+                { "name": "staging", "callers": [key_id("dev-key")],
+                  "upstream": "https://staging.example.com/v6" },
+            ]))
+            .unwrap(),
+        )
+        .unwrap();
+        let upstream = |name: &str, key: &str| {
+            let req = Request::get("/v6/device")
+                .header(X_PROXY_UPSTREAM, name)
+                .header(AUTHORIZATION, format!("Bearer {}", key))
+                .body(())
+                .unwrap();
+            let rule = layer.environments.route(&req);
+            rule.map(|rule| rule.upstream.to_string())
+        };
+        assert_eq!(
+            upstream("staging", "dev-key"),
+            Some("https://staging.example.com/v6".to_owned())
+        );
+        // other callers and names are forwarded as usual
+        assert_eq!(upstream("staging", "other-key"), None);
+        assert_eq!(upstream("https://evil.example.com", "dev-key"), None);
+    }
+}
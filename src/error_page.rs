@@ -0,0 +1,281 @@
+//! Custom bodies for gateway errors.
+//!
+//! When the proxy fronts a dashboard, a bare 502 or the upstream's own error
+//! body is not something to show users. [`ErrorPageLayer`] replaces the body
+//! of responses with one of the configured statuses, 502, 503 and 504 by
+//! default, with the page of the first rule matching the request. Requests
+//! that failed without a response from the upstream, e.g. as it could not be
+//! reached, get the page as a 502 rather than a dropped connection.
+//!
+//! Pages are given inline or read from a file when the stack is built, and
+//! may use `{{request_id}}`, `{{status}}` and `{{upstream_status}}`, the
+//! status before [`crate::status_map`] rewrote it, empty when the request
+//! failed without a response. Values are escaped for HTML, or for JSON
+//! strings when the content type is JSON.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{route::RouteMatcher, status_map::UpstreamStatus};
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPage {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Statuses whose body is replaced, 502, 503 and 504 when empty.
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+    /// The page.
+    pub body: Option<String>,
+    /// File the page is read from instead.
+    pub path: Option<PathBuf>,
+    /// `text/html; charset=utf-8` when unset.
+    pub content_type: Option<String>,
+}
+
+struct Page {
+    route: RouteMatcher,
+    statuses: Vec<StatusCode>,
+    template: String,
+    content_type: HeaderValue,
+    json: bool,
+}
+
+impl Page {
+    fn render(&self, request_id: &str, status: StatusCode, upstream: Option<StatusCode>) -> Bytes {
+        let escape = |value: &str| {
+            if self.json {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_owned()
+            } else {
+                value
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('"', "&quot;")
+                    .replace('\'', "&#39;")
+            }
+        };
+        let upstream = upstream.map_or(String::new(), |status| status.as_u16().to_string());
+        Bytes::from(
+            self.template
+                .replace("{{request_id}}", &escape(request_id))
+                .replace("{{status}}", status.as_str())
+                .replace("{{upstream_status}}", &upstream),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct ErrorPageLayer {
+    pages: Arc<Vec<Page>>,
+}
+
+impl ErrorPageLayer {
+    /// Check the statuses of `pages` and read those in files.
+    pub fn new(pages: Vec<ErrorPage>) -> Result<Self, BoxError> {
+        let pages = pages
+            .into_iter()
+            .map(|page| {
+                let template = match (page.body, &page.path) {
+                    (Some(body), None) => body,
+                    (None, Some(path)) => std::fs::read_to_string(path)
+                        .map_err(|err| format!("error page {}: {}", path.display(), err))?,
+                    _ => return Err("error page needs one of body and path".into()),
+                };
+                let statuses = if page.statuses.is_empty() {
+                    vec![
+                        StatusCode::BAD_GATEWAY,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        StatusCode::GATEWAY_TIMEOUT,
+                    ]
+                } else {
+                    page.statuses
+                        .iter()
+                        .map(|&code| {
+                            StatusCode::from_u16(code)
+                                .map_err(|_| format!("invalid status {}", code))
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+                let content_type = page
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("text/html; charset=utf-8");
+                Ok(Page {
+                    route: page.route,
+                    statuses,
+                    template,
+                    json: content_type.contains("json"),
+                    content_type: HeaderValue::from_str(content_type)?,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            pages: Arc::new(pages),
+        })
+    }
+}
+
+impl<S> Layer<S> for ErrorPageLayer {
+    type Service = ErrorPages<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ErrorPages {
+            inner: service,
+            pages: self.pages.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ErrorPages<S> {
+    inner: S,
+    pages: Arc<Vec<Page>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ErrorPages<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let pages = self.pages.clone();
+        let matching: Vec<usize> = (0..pages.len())
+            .filter(|&i| pages[i].route.matches(&req))
+            .collect();
+        let request_id = req
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let (status, upstream, res) = match fut.await.map_err(Into::into) {
+                Ok(res) => {
+                    let upstream = res
+                        .extensions()
+                        .get::<UpstreamStatus>()
+                        .map_or(res.status(), |status| status.0);
+                    (res.status(), Some(upstream), Ok(res))
+                }
+                Err(err) => (StatusCode::BAD_GATEWAY, None, Err(err)),
+            };
+            let page = matching
+                .into_iter()
+                .map(|i| &pages[i])
+                .find(|page| page.statuses.contains(&status));
+            let Some(page) = page else {
+                return res;
+            };
+            let mut res = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::warn!(%err, "upstream request failed, serving error page");
+                    let mut res = Response::new(ResBody::from(Bytes::new()));
+                    *res.status_mut() = status;
+                    res
+                }
+            };
+            let body = page.render(&request_id, status, upstream);
+            let headers = res.headers_mut();
+            headers.insert(CONTENT_TYPE, page.content_type.clone());
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            *res.body_mut() = ResBody::from(body);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status_map::StatusMapLayer;
+    use hyper::Body;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_error_pages() -> Result<(), BoxError> {
+        let layer = ErrorPageLayer::new(serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/v6/api" }, "content_type": "application/json",
+              "body": "{\"error\": \"{{status}}\", \"request\": \"{{request_id}}\"}" },
+            { "body": "<p>{{status}} from {{upstream_status}}, {{request_id}}</p>" },
+        ]))?)?;
+        let status_map = StatusMapLayer::new(serde_json::from_value(serde_json::json!([
+            { "from": 401, "to": 502 },
+        ]))?)?;
+        let service = layer.layer(
+            status_map.layer(service_fn(|req: Request<Body>| async move {
+                match req.uri().path() {
+                    "/v6/down" => Err::<Response<Body>, BoxError>("connection refused".into()),
+                    "/v6/auth" => Ok(Response::builder().status(401).body(Body::from("no"))?),
+                    _ => Ok(Response::builder().status(503).body(Body::from("busy"))?),
+                }
+            })),
+        );
+        let send = |path: &str, request_id: &str| {
+            let req = Request::get(path)
+                .header(X_REQUEST_ID, request_id)
+                .body(Body::empty())
+                .unwrap();
+            let service = service.clone();
+            async move {
+                let res = service.oneshot(req).await?;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>((status, body))
+            }
+        };
+
+        // failed requests get the page as a 502
+        let (status, body) = send("/v6/down", "<1>").await?;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body, "<p>502 from , &lt;1&gt;</p>");
+        // with the status the upstream answered
+        let (status, body) = send("/v6/auth", "2").await?;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body, "<p>502 from 401, 2</p>");
+        let (status, body) = send("/v6/api/busy", "\"3\"").await?;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, r#"{"error": "503", "request": "\"3\""}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_page() {
+        let page = |value| ErrorPageLayer::new(serde_json::from_value(value).unwrap());
+        assert!(page(serde_json::json!([{}])).is_err());
+        assert!(page(serde_json::json!([{ "body": "", "statuses": [1000] }])).is_err());
+        assert!(page(serde_json::json!([{ "body": "", "path": "/page.html" }])).is_err());
+    }
+}
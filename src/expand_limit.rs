@@ -0,0 +1,371 @@
+//! Limits on OData `$expand`.
+//!
+//! Every expanded navigation property multiplies what the upstream has to
+//! join and what comes back, so a query like
+//! `$expand=device($expand=device_tag,service_install($expand=service))` on
+//! an application costs far more quota and memory, when buffered, than its
+//! size suggests. [`ExpandLimitLayer`] measures the depth of `$expand`, how
+//! deeply expansions nest, and its breadth, the most properties expanded at
+//! one level, and answers queries over a limit with a 400 describing it. With
+//! `rewrite`, it drops the expansions past the limits and forwards the rest
+//! instead.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, uri::PathAndQuery, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{metrics::Metric, sanitize::percent_decode};
+
+const LIMITED: Metric = Metric::counter(
+    "proxy_expand_limited_total",
+    "Requests whose $expand was over a limit, by action taken.",
+);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpandLimitConfig {
+    /// Nesting of expansions allowed, no limit when unset.
+    pub max_depth: Option<usize>,
+    /// Properties expanded at one level, no limit when unset.
+    pub max_breadth: Option<usize>,
+    /// Drop the expansions past the limits instead of refusing the request.
+    pub rewrite: bool,
+}
+
+/// A navigation property of `$expand`, with its options.
+#[derive(Debug, Clone, PartialEq)]
+struct Expansion {
+    name: String,
+    /// Options other than `$expand`, e.g. `$select=id`.
+    options: Vec<String>,
+    expand: Vec<Expansion>,
+}
+
+/// Split `s` at the `sep` outside of parentheses and quotes.
+fn split_top(s: &str, sep: char) -> Vec<&str> {
+    let (mut parts, mut start, mut depth, mut quoted) = (Vec::new(), 0, 0usize, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            c if c == sep && !quoted && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn parse(expand: &str) -> Vec<Expansion> {
+    split_top(expand, ',')
+        .into_iter()
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let (name, inner) = match item.split_once('(') {
+                Some((name, inner)) => (name, inner.strip_suffix(')').unwrap_or(inner)),
+                None => (item, ""),
+            };
+            let mut expansion = Expansion {
+                name: name.trim().to_owned(),
+                options: Vec::new(),
+                expand: Vec::new(),
+            };
+            for option in split_top(inner, ';').into_iter().map(str::trim) {
+                match option.strip_prefix("$expand=") {
+                    Some(expand) => expansion.expand.extend(parse(expand)),
+                    None if !option.is_empty() => expansion.options.push(option.to_owned()),
+                    None => {}
+                }
+            }
+            expansion
+        })
+        .collect()
+}
+
+fn depth(expand: &[Expansion]) -> usize {
+    expand
+        .iter()
+        .map(|expansion| 1 + depth(&expansion.expand))
+        .max()
+        .unwrap_or(0)
+}
+
+fn breadth(expand: &[Expansion]) -> usize {
+    expand
+        .iter()
+        .map(|expansion| breadth(&expansion.expand))
+        .fold(expand.len(), usize::max)
+}
+
+/// Drop the expansions deeper than `depth` and past `breadth` at each level.
+fn prune(expand: &mut Vec<Expansion>, depth: usize, breadth: usize) {
+    if depth == 0 {
+        expand.clear();
+    }
+    expand.truncate(breadth);
+    for expansion in expand {
+        prune(&mut expansion.expand, depth.saturating_sub(1), breadth);
+    }
+}
+
+fn format(expand: &[Expansion]) -> String {
+    let items: Vec<String> = expand
+        .iter()
+        .map(|expansion| {
+            let mut options = expansion.options.clone();
+            if !expansion.expand.is_empty() {
+                options.push(format!("$expand={}", format(&expansion.expand)));
+            }
+            if options.is_empty() {
+                expansion.name.clone()
+            } else {
+                format!("{}({})", expansion.name, options.join(";"))
+            }
+        })
+        .collect();
+    items.join(",")
+}
+
+/// Percent-encode what may not appear as is in a query value.
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'-' | b'.' | b'_' | b'~' | b'$' | b'(' | b')' | b',' | b';' | b'=' | b'\'' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl ExpandLimitConfig {
+    pub fn is_empty(&self) -> bool {
+        self.max_depth.is_none() && self.max_breadth.is_none()
+    }
+
+    /// Describe the first limit `expand` exceeds.
+    fn check(&self, expand: &[Expansion]) -> Option<serde_json::Value> {
+        let depth = depth(expand);
+        if let Some(max) = self.max_depth.filter(|&max| depth > max) {
+            return Some(serde_json::json!({
+                "error": "$expand nested too deeply",
+                "depth": depth,
+                "max_depth": max,
+            }));
+        }
+        let breadth = breadth(expand);
+        if let Some(max) = self.max_breadth.filter(|&max| breadth > max) {
+            return Some(serde_json::json!({
+                "error": "$expand too broad",
+                "breadth": breadth,
+                "max_breadth": max,
+            }));
+        }
+        None
+    }
+
+    /// `query` with its `$expand` within the limits, dropped if nothing is left.
+    fn rewrite(&self, query: &str, mut expand: Vec<Expansion>) -> String {
+        prune(
+            &mut expand,
+            self.max_depth.unwrap_or(usize::MAX),
+            self.max_breadth.unwrap_or(usize::MAX),
+        );
+        query
+            .split('&')
+            .filter_map(|param| {
+                let name = param.split_once('=').map_or(param, |(name, _)| name);
+                if percent_decode(name) != b"$expand" {
+                    Some(param.to_owned())
+                } else if expand.is_empty() {
+                    None
+                } else {
+                    Some(format!("$expand={}", encode(&format(&expand))))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+/// The decoded `$expand` of `query`.
+fn expand_param(query: &str) -> Option<String> {
+    query.split('&').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        (percent_decode(name) == b"$expand").then(|| {
+            String::from_utf8_lossy(&percent_decode(&value.replace('+', " "))).into_owned()
+        })
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpandLimitLayer {
+    config: ExpandLimitConfig,
+}
+
+impl ExpandLimitLayer {
+    pub fn new(config: ExpandLimitConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for ExpandLimitLayer {
+    type Service = ExpandLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ExpandLimit {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ExpandLimit<S> {
+    inner: S,
+    config: ExpandLimitConfig,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ExpandLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let query = req.uri().query().unwrap_or_default();
+        let over_limit = expand_param(query)
+            .map(|expand| parse(&expand))
+            .and_then(|expand| Some((self.config.check(&expand)?, expand)));
+        let Some((error, expand)) = over_limit else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        if self.config.rewrite {
+            let query = self.config.rewrite(query, expand);
+            let path_and_query = if query.is_empty() {
+                req.uri().path().to_owned()
+            } else {
+                format!("{}?{}", req.uri().path(), query)
+            };
+            let mut parts = req.uri().clone().into_parts();
+            if let Ok(path_and_query) = PathAndQuery::try_from(path_and_query) {
+                parts.path_and_query = Some(path_and_query);
+                if let Ok(uri) = http::Uri::from_parts(parts) {
+                    tracing::debug!(%error, %uri, "$expand over limit, rewritten");
+                    LIMITED.increment(&[("action", "rewritten")]);
+                    *req.uri_mut() = uri;
+                    let fut = self.inner.call(req);
+                    return Box::pin(async move { fut.await.map_err(Into::into) });
+                }
+            }
+        }
+
+        tracing::warn!(%error, "$expand over limit");
+        LIMITED.increment(&[("action", "rejected")]);
+        let mut res = Response::new(ResBody::from(Bytes::from(error.to_string())));
+        *res.status_mut() = StatusCode::BAD_REQUEST;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[test]
+    fn test_parse_expand() {
+        let expand = parse("device($select=id;$expand=device_tag($filter=tag_key eq 'a,b')),owner");
+        assert_eq!(depth(&expand), 2);
+        assert_eq!(breadth(&expand), 2);
+        assert_eq!(expand[0].options, ["$select=id"]);
+        assert_eq!(expand[0].expand[0].options, ["$filter=tag_key eq 'a,b'"]);
+        assert_eq!(
+            format(&expand),
+            "device($select=id;$expand=device_tag($filter=tag_key eq 'a,b')),owner"
+        );
+        assert_eq!((depth(&parse("")), breadth(&parse(""))), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_expand_limits() -> Result<(), BoxError> {
+        let echo = service_fn(|req: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::new(Body::from(req.uri().to_string())))
+        });
+        let send = |config: ExpandLimitConfig, uri: &'static str| {
+            let service = ExpandLimitLayer::new(config).layer(echo);
+            async move {
+                let res = service
+                    .oneshot(Request::get(uri).body(Body::empty())?)
+                    .await?;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>((status, String::from_utf8(body.to_vec())?))
+            }
+        };
+        let limits = |rewrite| ExpandLimitConfig {
+            max_depth: Some(1),
+            max_breadth: Some(2),
+            rewrite,
+        };
+        let uri = "/v6/application?%24expand=device(%24expand=device_tag),owner,service&$top=1";
+
+        // within limits, forwarded as is
+        let within = "/v6/application?$expand=device($select=id)&$top=1";
+        assert_eq!(
+            send(limits(false), within).await?,
+            (StatusCode::OK, within.to_owned())
+        );
+
+        let (status, body) = send(limits(false), uri).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(
+            (body["depth"].clone(), body["max_depth"].clone()),
+            (2.into(), 1.into())
+        );
+
+        let (status, body) = send(limits(true), uri).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "/v6/application?$expand=device,owner&$top=1");
+
+        let depth_zero = ExpandLimitConfig {
+            max_depth: Some(0),
+            rewrite: true,
+            ..Default::default()
+        };
+        let (_, body) = send(depth_zero, "/v6/device?$expand=owner").await?;
+        assert_eq!(body, "/v6/device");
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! `Expect: 100-continue` handling.
+//!
+//! hyper answers `Expect: 100-continue` with a `100 Continue` once the body
+//! is first read, which the proxy does before forwarding. By then the body is
+//! in hand, and the hyper client neither waits for an upstream's `100
+//! Continue` nor passes it on, so [`ExpectLayer`] takes the expectation off
+//! requests before they go upstream, where it would only hold the body back.
+//! Other expectations are forwarded as they are, unless
+//! `server.refuse_expectations` has them answered with a 417 without the
+//! body being read.
+//!
+//! Other interim responses, such as 102 Processing or 103 Early Hints, are
+//! not forwarded and there is no switch for it: the hyper 0.14 client skips
+//! them while reading the response head, surfacing them only to its unstable
+//! C API, and its server has no way of sending them ahead of the response.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::EXPECT, Request, Response, StatusCode};
+use http_body::{combinators::UnsyncBoxBody, Body, Full};
+use tower::{BoxError, Layer, Service};
+
+#[derive(Debug, Clone, Default)]
+pub struct ExpectLayer {
+    refuse_others: bool,
+}
+
+impl ExpectLayer {
+    /// Strip `100-continue`, and with `refuse_others` answer any other
+    /// expectation with a 417.
+    pub fn new(refuse_others: bool) -> Self {
+        Self { refuse_others }
+    }
+}
+
+impl<S> Layer<S> for ExpectLayer {
+    type Service = Expect<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Expect {
+            inner: service,
+            refuse_others: self.refuse_others,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Expect<S> {
+    inner: S,
+    refuse_others: bool,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Expect<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<UnsyncBoxBody<Bytes, BoxError>>;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let other = req
+            .headers()
+            .get(EXPECT)
+            .is_some_and(|value| !value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+        if !other {
+            req.headers_mut().remove(EXPECT);
+        } else if self.refuse_others {
+            let body = Full::from(Bytes::from_static(
+                b"{\"error\":\"unsupported expectation\"}",
+            ));
+            let mut res = Response::new(body.map_err(|never| match never {}).boxed_unsync());
+            *res.status_mut() = StatusCode::EXPECTATION_FAILED;
+            return Box::pin(async move { Ok(res) });
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            Ok(res.map(|body| body.map_err(Into::into).boxed_unsync()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_expect() -> Result<(), BoxError> {
+        let upstream = service_fn(|req: Request<()>| async move {
+            let expect = req.headers().contains_key(EXPECT);
+            Ok::<_, Infallible>(Response::new(hyper::Body::from(expect.to_string())))
+        });
+        let send = |refuse_others: bool, expect: &'static str| {
+            let req = Request::put("/v6/image")
+                .header(EXPECT, expect)
+                .body(())
+                .expect("request");
+            ExpectLayer::new(refuse_others).layer(upstream).oneshot(req)
+        };
+
+        // the upstream never sees the expectation
+        let res = send(false, "100-Continue").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "false");
+
+        // others go on unless refused
+        let res = send(false, "something-else").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "true");
+        let res = send(true, "something-else").await?;
+        assert_eq!(res.status(), StatusCode::EXPECTATION_FAILED);
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Classes of failed upstream requests.
+//!
+//! A request that got no response failed somewhere between resolving the
+//! upstream host and reading its answer. Some of those failures pass, a DNS
+//! server timing out or a connect refused while an instance restarts, and
+//! some do not: a host that does not exist or a certificate that does not
+//! verify stays that way however long the retry policy backs off.
+//! [`FailureClass::of`] tells them apart from the error chain, and the retry
+//! policy only retries the passing ones. Failures are counted per class in
+//! `proxy_upstream_failures_total`.
+
+use std::{error::Error, io};
+
+use crate::{dns::ResolveError, metrics::Metric};
+
+const FAILURES: Metric = Metric::counter(
+    "proxy_upstream_failures_total",
+    "Upstream requests that got no response, by class of failure.",
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureClass {
+    /// The upstream host does not exist.
+    DnsNotFound,
+    /// Resolving the upstream host failed for now, e.g. timed out.
+    DnsTemporary,
+    /// No address of the upstream took the connection in time.
+    ConnectTimeout,
+    /// The connection was refused, reset or otherwise failed.
+    Connect,
+    /// The upstream certificate was not trusted or not pinned.
+    TlsCertificate,
+    /// Anything else, e.g. the connection closing mid-response.
+    Other,
+}
+
+impl FailureClass {
+    /// The class of `err`, from the first error in its chain telling.
+    pub fn of(err: &(dyn Error + 'static)) -> Self {
+        let mut next = Some(err);
+        while let Some(err) = next {
+            if let Some(err) = err.downcast_ref::<ResolveError>() {
+                return if err.is_not_found() {
+                    FailureClass::DnsNotFound
+                } else {
+                    FailureClass::DnsTemporary
+                };
+            }
+            // neither native-tls nor pinning failures have a type of their own
+            if err.to_string().to_ascii_lowercase().contains("certificate") {
+                return FailureClass::TlsCertificate;
+            }
+            if let Some(err) = err.downcast_ref::<io::Error>() {
+                return match err.kind() {
+                    io::ErrorKind::TimedOut => FailureClass::ConnectTimeout,
+                    _ => FailureClass::Connect,
+                };
+            }
+            next = err.source();
+        }
+        FailureClass::Other
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureClass::DnsNotFound => "dns_not_found",
+            FailureClass::DnsTemporary => "dns_temporary",
+            FailureClass::ConnectTimeout => "connect_timeout",
+            FailureClass::Connect => "connect",
+            FailureClass::TlsCertificate => "tls_certificate",
+            FailureClass::Other => "other",
+        }
+    }
+
+    /// Whether trying again may get a response.
+    pub fn is_retryable(self) -> bool {
+        !matches!(
+            self,
+            FailureClass::DnsNotFound | FailureClass::TlsCertificate
+        )
+    }
+
+    /// Count a failure of this class.
+    pub fn record(self) {
+        FAILURES.increment(&[("class", self.as_str())]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::BoxError;
+
+    #[test]
+    fn test_failure_class() {
+        let class = |err: BoxError| FailureClass::of(err.as_ref());
+        let lookup =
+            |message: &str| ResolveError::new("api.example", io::Error::other(message.to_owned()));
+
+        let not_found = lookup("failed to lookup address information: Name or service not known");
+        assert_eq!(class(not_found.into()), FailureClass::DnsNotFound);
+        let again =
+            lookup("failed to lookup address information: Temporary failure in name resolution");
+        assert_eq!(class(again.into()), FailureClass::DnsTemporary);
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "connect timed out");
+        assert_eq!(class(timeout.into()), FailureClass::ConnectTimeout);
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(class(refused.into()), FailureClass::Connect);
+        let pinned =
+            "certificate pinning failed for api.example: public key sha256 x is not pinned";
+        assert_eq!(class(pinned.into()), FailureClass::TlsCertificate);
+        assert_eq!(class("connection closed".into()), FailureClass::Other);
+
+        assert!(!FailureClass::TlsCertificate.is_retryable());
+        assert!(FailureClass::ConnectTimeout.is_retryable());
+    }
+}
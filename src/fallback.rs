@@ -0,0 +1,231 @@
+//! Static fallback responses while the upstream is unreachable.
+//!
+//! A device that loses its connection keeps asking for its target state, and
+//! an error sends it into a retry loop instead of carrying on with what it
+//! last knew. [`FallbackLayer`] answers GETs of the routes configured with a
+//! static body, e.g. the last-known target state, when the request failed
+//! without a response or got one of `statuses`, 502 and 504 by default, the
+//! proxy's timeout among them. Fallbacks carry `X-Proxy-Fallback: static`
+//! for clients to tell them from the upstream's answers. Bodies are given
+//! inline or read from a file when the stack is built.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue, Method, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{metrics::Metric, route::RouteMatcher};
+
+pub const X_PROXY_FALLBACK: &str = "x-proxy-fallback";
+
+const SERVED: Metric = Metric::counter(
+    "proxy_fallbacks_served_total",
+    "Static fallbacks served in place of upstream responses, by whether the request failed or got an error status.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Upstream statuses answered with the fallback, 502 and 504 when
+    /// empty. Requests failing without a response always are.
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+    /// The body, as JSON.
+    pub body: Option<serde_json::Value>,
+    /// File the body is read from instead.
+    pub path: Option<PathBuf>,
+    /// `application/json` when unset.
+    pub content_type: Option<String>,
+}
+
+struct Fallback {
+    route: RouteMatcher,
+    statuses: Vec<StatusCode>,
+    body: Bytes,
+    content_type: HeaderValue,
+}
+
+impl Fallback {
+    fn response<B: From<Bytes>>(&self) -> Response<B> {
+        let mut res = Response::new(B::from(self.body.clone()));
+        let headers = res.headers_mut();
+        headers.insert(CONTENT_TYPE, self.content_type.clone());
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        headers.insert(X_PROXY_FALLBACK, HeaderValue::from_static("static"));
+        res
+    }
+}
+
+#[derive(Clone)]
+pub struct FallbackLayer {
+    fallbacks: Arc<Vec<Fallback>>,
+}
+
+impl FallbackLayer {
+    /// Check the statuses of `rules` and read the bodies in files.
+    pub fn new(rules: Vec<FallbackRule>) -> Result<Self, BoxError> {
+        let fallbacks = rules
+            .into_iter()
+            .map(|rule| {
+                let body = match (rule.body, &rule.path) {
+                    (Some(body), None) => Bytes::from(body.to_string()),
+                    (None, Some(path)) => Bytes::from(
+                        std::fs::read(path)
+                            .map_err(|err| format!("fallback {}: {}", path.display(), err))?,
+                    ),
+                    _ => return Err("fallback needs one of body and path".into()),
+                };
+                let statuses = if rule.statuses.is_empty() {
+                    vec![StatusCode::BAD_GATEWAY, StatusCode::GATEWAY_TIMEOUT]
+                } else {
+                    rule.statuses
+                        .iter()
+                        .map(|&code| {
+                            StatusCode::from_u16(code)
+                                .map_err(|_| format!("invalid status {}", code))
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+                let content_type = rule.content_type.as_deref().unwrap_or("application/json");
+                Ok(Fallback {
+                    route: rule.route,
+                    statuses,
+                    body,
+                    content_type: HeaderValue::from_str(content_type)?,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            fallbacks: Arc::new(fallbacks),
+        })
+    }
+}
+
+impl<S> Layer<S> for FallbackLayer {
+    type Service = Fallbacks<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Fallbacks {
+            inner: service,
+            fallbacks: self.fallbacks.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Fallbacks<S> {
+    inner: S,
+    fallbacks: Arc<Vec<Fallback>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Fallbacks<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // only reads have a last-known answer
+        let matching = matches!(*req.method(), Method::GET | Method::HEAD)
+            .then(|| {
+                self.fallbacks
+                    .iter()
+                    .position(|fallback| fallback.route.matches(&req))
+            })
+            .flatten();
+        let fut = self.inner.call(req);
+        let Some(i) = matching else {
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+        let fallbacks = self.fallbacks.clone();
+
+        Box::pin(async move {
+            let fallback = &fallbacks[i];
+            match fut.await.map_err(Into::into) {
+                Ok(res) if fallback.statuses.contains(&res.status()) => {
+                    tracing::warn!(status = %res.status(), "upstream unavailable, serving fallback");
+                    SERVED.increment(&[("cause", "status")]);
+                    Ok(fallback.response())
+                }
+                Ok(res) => Ok(res),
+                Err(err) => {
+                    tracing::warn!(%err, "upstream request failed, serving fallback");
+                    SERVED.increment(&[("cause", "failed")]);
+                    Ok(fallback.response())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_fallback() -> Result<(), BoxError> {
+        let layer = FallbackLayer::new(serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/device/v3" }, "body": { "local": { "apps": {} } } },
+        ]))?)?;
+        let service = layer.layer(service_fn(|req: Request<Body>| async move {
+            match req.uri().path() {
+                "/device/v3/down/state" => {
+                    Err::<Response<Body>, BoxError>("connection refused".into())
+                }
+                "/device/v3/slow/state" => {
+                    Ok(Response::builder().status(504).body(Body::empty())?)
+                }
+                _ => Ok(Response::builder().status(503).body(Body::from("busy"))?),
+            }
+        }));
+        let send = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            service.clone().oneshot(req)
+        };
+
+        for path in ["/device/v3/down/state", "/device/v3/slow/state"] {
+            let res = send(Method::GET, path).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[X_PROXY_FALLBACK], "static");
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            assert_eq!(body, r#"{"local":{"apps":{}}}"#);
+        }
+
+        // other statuses, writes and other routes are left alone
+        let res = send(Method::GET, "/device/v3/busy/state").await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(send(Method::PATCH, "/device/v3/down/state").await.is_err());
+        assert!(send(Method::GET, "/v6/down").await.is_ok());
+        Ok(())
+    }
+}
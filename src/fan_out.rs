@@ -0,0 +1,411 @@
+//! Batches of requests in one round-trip.
+//!
+//! Device code tends to make many small calls in a row, each paying the
+//! latency of a cellular link. [`FanOutLayer`] answers POSTs to `path` on the
+//! proxy itself: the body is a JSON array of sub-requests, each with a
+//! `method`, a `path` and optionally `headers` and a JSON `body`, which are
+//! sent concurrently through the rest of the stack, with the credentials and
+//! tracing headers of the batch under their own. The answer is a 207
+//! with the status, headers and body of each sub-request in order, bodies
+//! as JSON where they parse. Sub-requests that fail without a response get
+//! a 502 and the error.
+//!
+//! Steps of a workflow depending on each other are posted as
+//! `{"sequential": true, "requests": [...]}` instead: sub-requests are sent
+//! one after the other, stopping at the first that fails or gets a status of
+//! 400 or more, and the answer tells how many were `executed` and whether
+//! the batch `completed`. Nothing is undone, the sub-requests before the
+//! failure stay done.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use futures_util::{stream, StreamExt};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+};
+use http_body::Body;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::{
+    metrics::Metric,
+    read_request_body::{buffer, ByteBody},
+};
+
+const SUB_REQUESTS: Metric = Metric::counter(
+    "proxy_fan_out_requests_total",
+    "Sub-requests of batches sent through the proxy.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanOutConfig {
+    /// Path batches are posted to, answered by the proxy.
+    pub path: String,
+    /// Sub-requests allowed in a batch.
+    pub max_requests: usize,
+    /// Sub-requests of a batch in flight at once.
+    pub concurrency: usize,
+}
+
+impl Default for FanOutConfig {
+    fn default() -> Self {
+        Self {
+            path: "/proxy/v1/batch".to_owned(),
+            max_requests: 20,
+            concurrency: 8,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<Value>,
+}
+
+/// A batch, its sub-requests alone or with how to send them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Batch {
+    Concurrent(Vec<SubRequest>),
+    Options(BatchOptions),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchOptions {
+    /// Send the sub-requests in order, stopping at the first failure.
+    #[serde(default)]
+    sequential: bool,
+    requests: Vec<SubRequest>,
+}
+
+#[derive(Debug, Serialize)]
+struct SubResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: Value,
+}
+
+pub fn json_response<B: From<Bytes>>(status: StatusCode, body: Value) -> Response<B> {
+    let mut res = Response::new(B::from(Bytes::from(body.to_string())));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+/// Headers a request made on behalf of another takes from that one: its
+/// credentials and what ties it to the same trace. Others, such as
+/// `Idempotency-Key` or `Range`, are about that request alone.
+const INHERITED: [&str; 6] = [
+    "authorization",
+    "x-balena-authorization",
+    "traceparent",
+    "tracestate",
+    "baggage",
+    "x-request-id",
+];
+
+/// Give a request made on behalf of another the [`INHERITED`] headers of
+/// that one.
+pub fn inherit_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    for name in INHERITED {
+        for value in from.get_all(name) {
+            to.append(name, value.clone());
+        }
+    }
+}
+
+/// The request `sub` of the batch `batch` stands for.
+fn sub_request(
+    batch: &Request<ByteBody>,
+    sub: SubRequest,
+    path: &str,
+) -> Result<Request<ByteBody>, BoxError> {
+    if !sub.path.starts_with('/') || sub.path.split('?').next() == Some(path) {
+        return Err(format!("invalid sub-request path {}", sub.path).into());
+    }
+    let body = sub.body.map(|body| body.to_string()).unwrap_or_default();
+    let mut req = Request::builder()
+        .method(Method::from_str(&sub.method.to_ascii_uppercase())?)
+        .uri(sub.path.as_str())
+        .body(ByteBody::new(body.into_bytes()))?;
+    inherit_headers(batch.headers(), req.headers_mut());
+    let headers = req.headers_mut();
+    for (name, value) in sub.headers {
+        headers.insert(HeaderName::from_str(&name)?, HeaderValue::from_str(&value)?);
+    }
+    if !req.body().as_bytes().is_empty() {
+        let length = req.body().as_bytes().len();
+        let headers = req.headers_mut();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        headers
+            .entry(CONTENT_TYPE)
+            .or_insert(HeaderValue::from_static("application/json"));
+    }
+    Ok(req)
+}
+
+/// Send `req` through `inner`, answering what came back.
+async fn send<S, ResBody>(inner: S, req: Request<ByteBody>) -> SubResponse
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    ResBody: Body<Data = Bytes>,
+    ResBody::Error: Into<BoxError>,
+{
+    SUB_REQUESTS.increment(&[]);
+    let res = match inner.oneshot(req).await.map_err(Into::into) {
+        Ok(res) => res,
+        Err(err) => {
+            return SubResponse {
+                status: StatusCode::BAD_GATEWAY.as_u16(),
+                headers: BTreeMap::new(),
+                body: serde_json::json!({ "error": err.to_string() }),
+            }
+        }
+    };
+    let (parts, body) = res.into_parts();
+    let headers = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+        .collect();
+    let body = match buffer(body).await {
+        Ok((bytes, _)) if bytes.is_empty() => Value::Null,
+        Ok((bytes, _)) => serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::from(String::from_utf8_lossy(&bytes).into_owned())),
+        Err(err) => serde_json::json!({ "error": err.to_string() }),
+    };
+    SubResponse {
+        status: parts.status.as_u16(),
+        headers,
+        body,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FanOutLayer {
+    config: FanOutConfig,
+}
+
+impl FanOutLayer {
+    pub fn new(config: FanOutConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for FanOutLayer {
+    type Service = FanOut<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FanOut {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FanOut<S> {
+    inner: S,
+    config: FanOutConfig,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for FanOut<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + From<Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        if req.method() != Method::POST || req.uri().path() != self.config.path {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+        let bad_request = |error: String| {
+            let res = json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "error": error }),
+            );
+            Box::pin(async move { Ok(res) }) as Self::Future
+        };
+        let (subs, sequential) = match serde_json::from_slice(req.body().as_bytes()) {
+            Ok(Batch::Concurrent(subs)) => (subs, false),
+            Ok(Batch::Options(options)) => (options.requests, options.sequential),
+            Err(err) => return bad_request(format!("invalid batch: {}", err)),
+        };
+        if subs.len() > self.config.max_requests {
+            return bad_request(format!(
+                "batch of {} requests, at most {} allowed",
+                subs.len(),
+                self.config.max_requests
+            ));
+        }
+        let subs = match subs
+            .into_iter()
+            .map(|sub| sub_request(&req, sub, &self.config.path))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(subs) => subs,
+            Err(err) => return bad_request(err.to_string()),
+        };
+        let inner = self.inner.clone();
+        let concurrency = self.config.concurrency.max(1);
+
+        if sequential {
+            return Box::pin(async move {
+                let count = subs.len();
+                let mut responses = Vec::with_capacity(count);
+                for sub in subs {
+                    let res = send(inner.clone(), sub).await;
+                    let failed = res.status >= 400;
+                    responses.push(res);
+                    if failed {
+                        break;
+                    }
+                }
+                let completed =
+                    responses.len() == count && responses.last().is_none_or(|res| res.status < 400);
+                Ok(json_response(
+                    StatusCode::MULTI_STATUS,
+                    serde_json::json!({
+                        "responses": responses,
+                        "executed": responses.len(),
+                        "completed": completed,
+                    }),
+                ))
+            });
+        }
+
+        Box::pin(async move {
+            let responses: Vec<SubResponse> = stream::iter(subs)
+                .map(move |sub| send(inner.clone(), sub))
+                .buffered(concurrency)
+                .collect()
+                .await;
+            Ok(json_response(
+                StatusCode::MULTI_STATUS,
+                serde_json::json!({ "responses": responses }),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::AUTHORIZATION;
+    use hyper::Body as HyperBody;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn test_fan_out() -> Result<(), BoxError> {
+        let service = FanOutLayer::new(FanOutConfig::default()).layer(service_fn(
+            |req: Request<ByteBody>| async move {
+                let auth = req.headers().get(AUTHORIZATION).cloned();
+                match req.uri().path() {
+                    "/v6/down" => Err::<Response<HyperBody>, BoxError>("connection refused".into()),
+                    path => {
+                        Ok(Response::builder()
+                            .header("x-path", path)
+                            .body(HyperBody::from(
+                                serde_json::json!({
+                                    "method": req.method().as_str(),
+                                    "auth": auth.map(|auth| auth.to_str().unwrap().to_owned()),
+                                    "idempotency": req.headers().contains_key("idempotency-key"),
+                                    "body": String::from_utf8_lossy(req.body().as_bytes()),
+                                })
+                                .to_string(),
+                            ))?)
+                    }
+                }
+            },
+        ));
+        let batch = |body: Value| {
+            Request::post("/proxy/v1/batch")
+                .header(AUTHORIZATION, "Bearer key")
+                .header("idempotency-key", "batch-1")
+                .body(ByteBody::new(body.to_string().into_bytes()))
+                .unwrap()
+        };
+
+        let res = service
+            .clone()
+            .oneshot(batch(serde_json::json!([
+                { "method": "get", "path": "/v6/device" },
+                { "method": "PATCH", "path": "/v6/device(1)", "body": { "note": "n" } },
+                { "method": "GET", "path": "/v6/down" },
+            ])))
+            .await?;
+        assert_eq!(res.status(), StatusCode::MULTI_STATUS);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        let responses = body["responses"].as_array().unwrap();
+        assert_eq!(responses[0]["status"], 200);
+        assert_eq!(responses[0]["headers"]["x-path"], "/v6/device");
+        assert_eq!(responses[0]["body"]["auth"], "Bearer key");
+        assert_eq!(responses[0]["body"]["idempotency"], false);
+        assert_eq!(responses[1]["body"]["method"], "PATCH");
+        assert_eq!(responses[1]["body"]["body"], r#"{"note":"n"}"#);
+        assert_eq!(responses[2]["status"], 502);
+
+        // in order, up to the first failure
+        let res = service
+            .clone()
+            .oneshot(batch(serde_json::json!({
+                "sequential": true,
+                "requests": [
+                    { "method": "POST", "path": "/v6/device" },
+                    { "method": "GET", "path": "/v6/down" },
+                    { "method": "GET", "path": "/v6/device" },
+                ],
+            })))
+            .await?;
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["executed"], 2);
+        assert_eq!(body["completed"], false);
+        assert_eq!(body["responses"][0]["body"]["method"], "POST");
+        assert_eq!(body["responses"][1]["status"], 502);
+
+        // batches of batches are not
+        let res = service
+            .clone()
+            .oneshot(batch(serde_json::json!([
+                { "method": "POST", "path": "/proxy/v1/batch" },
+            ])))
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // other requests pass
+        let req = Request::get("/proxy/v1/batch").body(ByteBody::new(Vec::new()))?;
+        assert_eq!(service.oneshot(req).await?.status(), StatusCode::OK);
+        Ok(())
+    }
+}
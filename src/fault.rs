@@ -0,0 +1,252 @@
+//! Fault injection for testing.
+//!
+//! [`FaultLayer`] delays, aborts or answers with a synthetic status a
+//! configurable percentage of the requests matching a rule, without
+//! contacting the upstream. It sits right in front of the HTTP client so
+//! injected 429s and 5xxs go through the proxy's own retry and key rotation.
+
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Future;
+use http::{HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    rng::{HasherRng, Rng},
+    route::RouteMatcher,
+};
+
+/// Header added to synthetic responses.
+pub const X_PROXY_FAULT: &str = "x-proxy-fault";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Percentage of the matching requests the fault is applied to.
+    pub percentage: f64,
+    /// Delay before the request is forwarded, aborted or answered.
+    pub delay_ms: Option<u64>,
+    /// Fail the request as if the connection to the upstream was lost.
+    #[serde(default)]
+    pub abort: bool,
+    /// Answer with this status instead of forwarding the request.
+    pub status: Option<u16>,
+}
+
+#[derive(Debug)]
+pub struct FaultAborted;
+
+impl fmt::Display for FaultAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request aborted by fault injection")
+    }
+}
+
+impl std::error::Error for FaultAborted {}
+
+enum Fault {
+    Abort,
+    Status(StatusCode),
+    Forward,
+}
+
+struct CompiledRule {
+    rule: FaultRule,
+    status: Option<StatusCode>,
+}
+
+#[derive(Clone)]
+pub struct FaultLayer {
+    rules: Arc<Vec<CompiledRule>>,
+    rng: Arc<Mutex<HasherRng>>,
+}
+
+impl FaultLayer {
+    pub fn new(rules: Vec<FaultRule>) -> Result<Self, BoxError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let status = rule
+                    .status
+                    .map(StatusCode::from_u16)
+                    .transpose()
+                    .map_err(|err| format!("fault status {:?}: {}", rule.status, err))?;
+                Ok(CompiledRule { rule, status })
+            })
+            .collect::<Result<Vec<_>, BoxError>>()?;
+        Ok(Self {
+            rules: Arc::new(rules),
+            rng: Arc::new(Mutex::new(HasherRng::new())),
+        })
+    }
+}
+
+impl<S> Layer<S> for FaultLayer {
+    type Service = InjectFault<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        InjectFault {
+            inner: service,
+            rules: self.rules.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct InjectFault<S> {
+    inner: S,
+    rules: Arc<Vec<CompiledRule>>,
+    rng: Arc<Mutex<HasherRng>>,
+}
+
+impl<S> InjectFault<S> {
+    /// Pick the first matching rule and roll the dice for it.
+    fn pick<B>(&self, req: &Request<B>) -> Option<&CompiledRule> {
+        let compiled = self.rules.iter().find(|c| c.rule.route.matches(req))?;
+        let roll = self.rng.lock().unwrap().next_f64() * 100.0;
+        (roll < compiled.rule.percentage).then_some(compiled)
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for InjectFault<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (delay, fault) = match self.pick(&req) {
+            Some(compiled) => {
+                let fault = match (compiled.rule.abort, compiled.status) {
+                    (true, _) => Fault::Abort,
+                    (false, Some(status)) => Fault::Status(status),
+                    (false, None) => Fault::Forward,
+                };
+                (compiled.rule.delay_ms.map(Duration::from_millis), fault)
+            }
+            None => (None, Fault::Forward),
+        };
+
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            if let Some(delay) = delay {
+                tracing::debug!(?delay, "injecting delay");
+                tokio::time::sleep(delay).await;
+            }
+            match fault {
+                Fault::Abort => {
+                    tracing::debug!("injecting abort");
+                    Err(FaultAborted.into())
+                }
+                Fault::Status(status) => {
+                    tracing::debug!(%status, "injecting response");
+                    let mut res = Response::new(ResBody::default());
+                    *res.status_mut() = status;
+                    res.headers_mut()
+                        .insert(X_PROXY_FAULT, HeaderValue::from_static("injected"));
+                    Ok(res)
+                }
+                Fault::Forward => inner.call(req).await.map_err(Into::into),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use hyper::{Body, Client};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    fn rule(json: serde_json::Value) -> FaultRule {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_inject_status() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/device");
+            then.status(200);
+        });
+
+        let mut client = ServiceBuilder::new()
+            .layer(FaultLayer::new(vec![rule(serde_json::json!({
+                "route": { "path_prefix": "/device" },
+                "percentage": 100.0,
+                "status": 429,
+            }))])?)
+            .service(Client::new());
+
+        let request = Request::builder()
+            .uri(&format!("http://{}/device", server.address()))
+            .body(Body::empty())?;
+        let response = client.ready().await?.call(request).await?;
+
+        m.assert_hits(0);
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()[X_PROXY_FAULT], "injected");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_unmatched() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.path("/application");
+            then.status(200);
+        });
+
+        let mut client = ServiceBuilder::new()
+            .layer(FaultLayer::new(vec![rule(serde_json::json!({
+                "route": { "path_prefix": "/device" },
+                "percentage": 100.0,
+                "abort": true,
+            }))])?)
+            .service(Client::new());
+
+        let request = Request::builder()
+            .uri(&format!("http://{}/application", server.address()))
+            .body(Body::empty())?;
+        let response = client.ready().await?.call(request).await?;
+
+        m.assert();
+        assert_eq!(response.status(), 200);
+
+        Ok(())
+    }
+    #[test]
+    fn test_invalid_status() {
+        let rules = vec![rule(
+            serde_json::json!({ "percentage": 1.0, "status": 1000 }),
+        )];
+        assert!(FaultLayer::new(rules).is_err());
+    }
+}
@@ -1,41 +1,178 @@
-use http::{Request, Uri};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    vec,
+};
+
+use http::{header::HOST, uri::Authority, HeaderValue, Request, Uri};
 use http_body::Body;
-use std::str::FromStr;
-use tower::{Layer, Service};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{context::ProxyContext, route::RouteMatcher};
+
+/// Upstream base overriding the one of [`ForwardRequestLayer`] for a single
+/// request, set as a request extension by earlier layers.
+#[derive(Debug, Clone)]
+pub struct Upstream(pub Uri);
+
+/// SNI and Host sent upstream instead of those of the upstream URI, e.g. to
+/// reach an IP-addressed openBalena behind a certificate for its name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardOverride {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Server name sent in the TLS handshake and checked against the
+    /// certificate; the connection still goes to the upstream URI host.
+    pub sni: Option<String>,
+    /// `Host` header, the SNI name, or else the upstream URI host, when unset.
+    pub host: Option<String>,
+}
+
+/// Resolver of the upstream client, sending server names set by SNI
+/// overrides to the hosts they replaced in the URI. A name is pinned to one
+/// host at a time, so routes sharing an SNI name must share the upstream.
+#[derive(Clone)]
+pub struct OverrideResolver {
+    pinned: Arc<RwLock<HashMap<String, String>>>,
+    gai: GaiResolver,
+}
+
+impl Default for OverrideResolver {
+    fn default() -> Self {
+        Self {
+            pinned: Arc::default(),
+            gai: GaiResolver::new(),
+        }
+    }
+}
+
+impl Service<Name> for OverrideResolver {
+    type Response = vec::IntoIter<SocketAddr>;
+
+    type Error = io::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = self.pinned.read().unwrap().get(name.as_str()).cloned();
+        let mut gai = self.gai.clone();
+        Box::pin(async move {
+            let Some(host) = host else {
+                return Ok(gai.call(name).await?.collect::<Vec<_>>().into_iter());
+            };
+            // the connector sets the port of the URI
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if let Ok(ip) = IpAddr::from_str(host) {
+                return Ok(vec![SocketAddr::new(ip, 0)].into_iter());
+            }
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addrs.collect::<Vec<_>>().into_iter())
+        })
+    }
+}
 
 /// Enforces a rate limit on the number of requests the underlying
 /// service can handle over a period of time.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ForwardRequestLayer {
     uri: Uri,
+    overrides: Arc<Vec<ForwardOverride>>,
+    resolver: OverrideResolver,
 }
 
 impl ForwardRequestLayer {
     /// Create new rate limit layer.
     pub fn new(uri: Uri) -> Self {
-        ForwardRequestLayer { uri }
+        ForwardRequestLayer {
+            uri,
+            overrides: Arc::default(),
+            resolver: OverrideResolver::default(),
+        }
+    }
+
+    /// Send another SNI or Host upstream for the requests matching
+    /// `overrides`. SNI overrides need the upstream client to use
+    /// [`ForwardRequestLayer::resolver`].
+    pub fn with_overrides(mut self, overrides: Vec<ForwardOverride>) -> Result<Self, BoxError> {
+        for rule in &overrides {
+            if let Some(sni) = &rule.sni {
+                Authority::from_str(sni).map_err(|err| format!("sni {}: {}", sni, err))?;
+            }
+            if let Some(host) = &rule.host {
+                HeaderValue::from_str(host).map_err(|err| format!("host {}: {}", host, err))?;
+            }
+        }
+        self.overrides = Arc::new(overrides);
+        Ok(self)
+    }
+
+    pub fn resolver(&self) -> OverrideResolver {
+        self.resolver.clone()
+    }
+
+    /// Point `uri` at `sni` instead of its host, pinning the name to the host.
+    fn apply_sni(&self, uri: Uri, sni: &str) -> Uri {
+        let Some(authority) = uri.authority() else {
+            return uri;
+        };
+        let host = authority.host().to_owned();
+        let target = match authority.port() {
+            Some(port) => format!("{}:{}", sni, port),
+            None => sni.to_owned(),
+        };
+        let mut parts = uri.into_parts();
+        parts.authority = Some(target.parse().expect("SNI names are checked"));
+        let uri = Uri::from_parts(parts).expect("SNI names are checked");
+        self.resolver
+            .pinned
+            .write()
+            .unwrap()
+            .insert(sni.to_owned(), host);
+        uri
     }
 }
 
+/// Where a request for `uri` goes on the upstream at `base`.
+pub fn forward_uri(base: &Uri, uri: &Uri) -> Uri {
+    // a base without a path displays as `scheme://host/`
+    let base = base.to_string();
+    let base = base.trim_end_matches('/');
+    let forward_uri = match uri.query() {
+        Some(query) => format!("{}{}?{}", base, uri.path(), query),
+        None => format!("{}{}", base, uri.path()),
+    };
+    Uri::from_str(forward_uri.as_str()).expect("valid url")
+}
+
 impl<S> Layer<S> for ForwardRequestLayer {
     type Service = ForwardRequest<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        ForwardRequest::new(service, self.uri.clone())
+        ForwardRequest {
+            inner: service,
+            layer: self.clone(),
+        }
     }
 }
 
 pub struct ForwardRequest<S> {
-    uri: Uri,
+    layer: ForwardRequestLayer,
     inner: S,
 }
 
-impl<S> ForwardRequest<S> {
-    fn new(inner: S, uri: Uri) -> Self {
-        Self { inner, uri }
-    }
-}
-
 impl<S, B> Service<Request<B>> for ForwardRequest<S>
 where
     S: Service<Request<B>>,
@@ -55,11 +192,32 @@ where
     }
 
     fn call(&mut self, mut req: Request<B>) -> Self::Future {
-        let forward_uri = match req.uri().query() {
-            Some(query) => format!("{}{}?{}", self.uri, req.uri().path(), query),
-            None => format!("{}{}", self.uri, req.uri().path()),
+        let rule = self
+            .layer
+            .overrides
+            .iter()
+            .find(|rule| rule.route.matches(&req))
+            .cloned();
+        let base = match req.extensions().get::<Upstream>() {
+            Some(Upstream(uri)) => uri,
+            None => &self.layer.uri,
         };
-        let uri = Uri::from_str(forward_uri.as_str()).expect("valid url");
+        if let (Some(context), Some(host)) = (ProxyContext::of(&req), base.host()) {
+            context.update(|annotations| annotations.upstream = Some(host.to_owned()));
+        }
+        let mut uri = forward_uri(base, req.uri());
+        if let Some(rule) = rule {
+            if let Some(sni) = &rule.sni {
+                uri = self.layer.apply_sni(uri, sni);
+            }
+            if let Some(host) = rule
+                .host
+                .as_deref()
+                .and_then(|h| HeaderValue::from_str(h).ok())
+            {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
         *req.uri_mut() = uri;
         self.inner.call(req)
     }
@@ -69,7 +227,54 @@ impl<S: Clone> Clone for ForwardRequest<S> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
-            uri: self.uri.clone(),
+            layer: self.layer.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_sni_and_host_override() -> Result<(), BoxError> {
+        let layer = ForwardRequestLayer::new(Uri::from_static("https://10.0.0.5:8443/v6"))
+            .with_overrides(serde_json::from_value(serde_json::json!([
+                { "route": { "path_prefix": "/device" }, "sni": "api.balena.example" },
+                { "route": { "path_prefix": "/release" }, "host": "api.other.example" },
+            ]))?)?;
+        let echo = service_fn(|req: Request<hyper::Body>| async move {
+            let host = req.headers().get(HOST).cloned();
+            Ok::<_, Infallible>((req.uri().to_string(), host))
+        });
+        let service = layer.layer(echo);
+
+        let req = Request::get("/device?x=1").body(hyper::Body::empty())?;
+        let (uri, host) = service.clone().oneshot(req).await?;
+        assert_eq!(uri, "https://api.balena.example:8443/v6/device?x=1");
+        assert_eq!(host, None);
+        // the name still connects to the upstream host
+        let mut resolver = layer.resolver();
+        let addrs: Vec<_> = resolver
+            .call(Name::from_str("api.balena.example")?)
+            .await?
+            .collect();
+        assert_eq!(addrs, [SocketAddr::from(([10, 0, 0, 5], 0))]);
+
+        let req = Request::get("/release").body(hyper::Body::empty())?;
+        let (uri, host) = service.oneshot(req).await?;
+        assert_eq!(uri, "https://10.0.0.5:8443/v6/release");
+        assert_eq!(host, Some(HeaderValue::from_static("api.other.example")));
+
+        assert!(ForwardRequestLayer::new(Uri::from_static("https://a"))
+            .with_overrides(vec![ForwardOverride {
+                route: RouteMatcher::default(),
+                sni: Some("bad name".into()),
+                host: None,
+            }])
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Upstream host per request.
+//!
+//! In gateway mode the proxy fronts several APIs instead of one upstream:
+//! [`GatewayLayer`] takes the upstream host from the first path segment, as
+//! in `/api.balena-cloud.com/v6/device`, or from a header when one is
+//! configured, and forwards the rest of the path there. Only allowlisted
+//! hosts are reached; other requests get a 403, and those naming no host a
+//! 400. Layers below see the path without the host, so routes are written as
+//! for a single upstream. Pool keys go to every allowlisted host, so list
+//! only hosts trusted with them, or leave the key pool unset.
+
+use std::{
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::CONTENT_TYPE, uri::PathAndQuery, HeaderName, HeaderValue, Request, Response,
+    StatusCode, Uri,
+};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::forward_request::Upstream;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// Hosts requests may go to, with a port if not the scheme's default.
+    pub hosts: Vec<String>,
+    /// Scheme of the upstream URIs.
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    /// Header naming the host instead of the first path segment.
+    pub header: Option<String>,
+}
+
+fn default_scheme() -> String {
+    "https".to_owned()
+}
+
+struct Gateway {
+    hosts: Vec<String>,
+    scheme: String,
+    header: Option<HeaderName>,
+}
+
+impl Gateway {
+    /// The upstream of `req` and its path and query there, or the status to
+    /// answer with.
+    fn route<B>(&self, req: &Request<B>) -> Result<(Uri, Option<PathAndQuery>), StatusCode> {
+        let (host, path) = match &self.header {
+            Some(header) => {
+                let host = req.headers().get(header).and_then(|v| v.to_str().ok());
+                (host.unwrap_or_default(), None)
+            }
+            None => {
+                let path = req.uri().path().trim_start_matches('/');
+                let (host, rest) = path.split_once('/').unwrap_or((path, ""));
+                let path = match req.uri().query() {
+                    Some(query) => format!("/{}?{}", rest, query),
+                    None => format!("/{}", rest),
+                };
+                (host, Some(path))
+            }
+        };
+        if host.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if !self.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let upstream = Uri::from_str(&format!("{}://{}", self.scheme, host))
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let path = path
+            .map(|path| PathAndQuery::from_str(&path))
+            .transpose()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        Ok((upstream, path))
+    }
+}
+
+#[derive(Clone)]
+pub struct GatewayLayer {
+    gateway: Arc<Gateway>,
+}
+
+impl GatewayLayer {
+    /// Check the hosts, scheme and header of `config`.
+    pub fn new(config: GatewayConfig) -> Result<Self, BoxError> {
+        for host in &config.hosts {
+            Uri::from_str(&format!("{}://{}", config.scheme, host))
+                .map_err(|err| format!("gateway host {}: {}", host, err))?;
+        }
+        let header = config
+            .header
+            .map(|header| HeaderName::from_str(&header))
+            .transpose()?;
+        Ok(Self {
+            gateway: Arc::new(Gateway {
+                hosts: config.hosts,
+                scheme: config.scheme,
+                header,
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for GatewayLayer {
+    type Service = GatewayService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        GatewayService {
+            inner: service,
+            gateway: self.gateway.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GatewayService<S> {
+    inner: S,
+    gateway: Arc<Gateway>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GatewayService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let status = match self.gateway.route(&req) {
+            Ok((upstream, path)) => {
+                if let Some(header) = &self.gateway.header {
+                    req.headers_mut().remove(header);
+                }
+                if let Some(path) = path {
+                    let mut parts = req.uri().clone().into_parts();
+                    parts.path_and_query = Some(path);
+                    *req.uri_mut() = Uri::from_parts(parts).expect("path of a valid URI");
+                }
+                req.extensions_mut().insert(Upstream(upstream));
+                let fut = self.inner.call(req);
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+            Err(status) => status,
+        };
+
+        let error = match status {
+            StatusCode::FORBIDDEN => "upstream host not allowed",
+            _ => "no upstream host",
+        };
+        tracing::debug!(uri = %req.uri(), error, "gateway request refused");
+        let body = serde_json::json!({ "error": error }).to_string();
+        let mut res = Response::new(ResBody::from(Bytes::from(body)));
+        *res.status_mut() = status;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_gateway() -> Result<(), BoxError> {
+        let echo = service_fn(|req: Request<Body>| async move {
+            let upstream = req.extensions().get::<Upstream>().map(|u| u.0.to_string());
+            let body = format!("{} {}", upstream.unwrap_or_default(), req.uri());
+            Ok::<_, Infallible>(Response::new(Body::from(body)))
+        });
+        let send = |layer: GatewayLayer, req: Request<Body>| {
+            let service = layer.layer(echo);
+            async move {
+                let res = service.oneshot(req).await?;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>((status, String::from_utf8(body.to_vec())?))
+            }
+        };
+        let config = serde_json::json!({ "hosts": ["api.balena-cloud.com", "localhost:8080"] });
+        let layer = GatewayLayer::new(serde_json::from_value(config)?)?;
+
+        let req = Request::get("/api.balena-cloud.com/v6/device?$top=1").body(Body::empty())?;
+        assert_eq!(
+            send(layer.clone(), req).await?,
+            (
+                StatusCode::OK,
+                "https://api.balena-cloud.com/ /v6/device?$top=1".to_owned()
+            )
+        );
+        let req = Request::get("/evil.example.com/v6/device").body(Body::empty())?;
+        assert_eq!(send(layer.clone(), req).await?.0, StatusCode::FORBIDDEN);
+        let req = Request::get("/").body(Body::empty())?;
+        assert_eq!(send(layer, req).await?.0, StatusCode::BAD_REQUEST);
+
+        // with a header, the path is left as is
+        let config = serde_json::json!({
+            "hosts": ["localhost:8080"], "scheme": "http", "header": "x-upstream-host",
+        });
+        let layer = GatewayLayer::new(serde_json::from_value(config)?)?;
+        let req = Request::get("/v6/device")
+            .header("x-upstream-host", "localhost:8080")
+            .body(Body::empty())?;
+        assert_eq!(
+            send(layer, req).await?.1,
+            "http://localhost:8080/ /v6/device"
+        );
+        Ok(())
+    }
+}
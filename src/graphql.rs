@@ -0,0 +1,690 @@
+//! An experimental GraphQL front for the OData API.
+//!
+//! Some frontends would rather speak GraphQL than build OData queries.
+//! [`GraphQlLayer`] answers POSTs of `{"query": ..., "variables": ...}` to
+//! `path` on the proxy itself, for a narrow schema: the top-level fields
+//! `devices`, `applications` and `releases`, each resolved by a GET of its
+//! OData resource sent through the rest of the stack with the headers of
+//! the query, its `Authorization` among them. Scalar fields become
+//! `$select`, fields with a selection of their own `$expand`, and the
+//! arguments `id`, `first`, `skip`, `orderBy` and `where`, an object of
+//! fields equal to values, the key, `$top`, `$skip`, `$orderby` and
+//! `$filter`, at any depth but `id`. A top-level field with an `id` is one
+//! object or null, others lists. Aliases and variables are understood;
+//! fragments, directives and mutations are not, and are answered with a
+//! 400 listing the error.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use futures_util::future::join_all;
+use http::{Method, Request, Response, StatusCode};
+use http_body::Body;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::{
+    fan_out::{inherit_headers, json_response},
+    metrics::Metric,
+    read_request_body::{buffer, ByteBody},
+};
+
+const QUERIES: Metric = Metric::counter(
+    "proxy_graphql_queries_total",
+    "GraphQL queries answered by the proxy, by whether they resolved without errors.",
+);
+
+/// Top-level fields and the resources they resolve to.
+const RESOURCES: [(&str, &str); 3] = [
+    ("devices", "device"),
+    ("applications", "application"),
+    ("releases", "release"),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphQlConfig {
+    /// Path queries are posted to, answered by the proxy.
+    pub path: String,
+    /// Prefix of the resource paths, e.g. `/v6` for an upstream without the
+    /// API version in its base.
+    pub prefix: String,
+}
+
+impl Default for GraphQlConfig {
+    fn default() -> Self {
+        Self {
+            path: "/proxy/v1/graphql".to_owned(),
+            prefix: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphQlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Variable(String),
+    Str(String),
+    Number(String),
+    Punct(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            // commas are insignificant in GraphQL
+            c if c.is_whitespace() || c == ',' => {
+                chars.next();
+            }
+            '#' => while chars.next().is_some_and(|c| c != '\n') {},
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '!' | '=' => {
+                tokens.push(Token::Punct(c));
+                chars.next();
+            }
+            '.' => return Err("fragments are not supported".to_owned()),
+            '@' => return Err("directives are not supported".to_owned()),
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                            _ => return Err("unsupported escape in string".to_owned()),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '$' => {
+                chars.next();
+                tokens.push(Token::Variable(name(&mut chars)));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Number(number));
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => tokens.push(Token::Name(name(&mut chars))),
+            c => return Err(format!("unexpected character {:?}", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn name(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c == '_' || c.is_ascii_alphanumeric()) {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    name
+}
+
+/// A selected field, with its arguments resolved.
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    args: Vec<(String, Value)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    /// The key of the field in the answer.
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    fn arg(&self, name: &str) -> Option<&Value> {
+        self.args.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    variables: &'a Map<String, Value>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.peek().cloned().ok_or("unexpected end of query")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(p) if p == c => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}", c, token)),
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(c));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// The selection set of the one query of the document.
+    fn document(&mut self) -> Result<Vec<Field>, String> {
+        if let Some(Token::Name(keyword)) = self.peek().cloned() {
+            if keyword != "query" {
+                return Err(format!("{} operations are not supported", keyword));
+            }
+            self.pos += 1;
+            if let Some(Token::Name(_)) = self.peek() {
+                self.pos += 1;
+            }
+            // variable definitions, the values come with the request
+            if self.eat('(') {
+                let mut depth = 1;
+                while depth > 0 {
+                    match self.next()? {
+                        Token::Punct('(') => depth += 1,
+                        Token::Punct(')') => depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        let selection = self.selection()?;
+        if self.pos < self.tokens.len() {
+            return Err("only one operation is supported".to_owned());
+        }
+        Ok(selection)
+    }
+
+    fn selection(&mut self) -> Result<Vec<Field>, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            fields.push(self.field()?);
+        }
+        if fields.is_empty() {
+            return Err("empty selection".to_owned());
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field, String> {
+        let Token::Name(mut name) = self.next()? else {
+            return Err("expected a field".to_owned());
+        };
+        let mut alias = None;
+        if self.eat(':') {
+            let Token::Name(aliased) = self.next()? else {
+                return Err("expected a field after its alias".to_owned());
+            };
+            alias = Some(std::mem::replace(&mut name, aliased));
+        }
+        let mut args = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let Token::Name(arg) = self.next()? else {
+                    return Err(format!("expected an argument of {}", name));
+                };
+                self.expect(':')?;
+                args.push((arg, self.value()?));
+            }
+        }
+        let selection = match self.peek() {
+            Some(Token::Punct('{')) => self.selection()?,
+            _ => Vec::new(),
+        };
+        Ok(Field {
+            alias,
+            name,
+            args,
+            selection,
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        Ok(match self.next()? {
+            Token::Variable(name) => self.variables.get(&name).cloned().unwrap_or(Value::Null),
+            Token::Str(s) => Value::String(s),
+            Token::Number(n) => {
+                serde_json::from_str(&n).map_err(|_| format!("bad number {}", n))?
+            }
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // enum values
+                _ => Value::String(name),
+            },
+            Token::Punct('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value()?);
+                }
+                Value::Array(items)
+            }
+            Token::Punct('{') => {
+                let mut object = Map::new();
+                while !self.eat('}') {
+                    let Token::Name(key) = self.next()? else {
+                        return Err("expected an object field".to_owned());
+                    };
+                    self.expect(':')?;
+                    object.insert(key, self.value()?);
+                }
+                Value::Object(object)
+            }
+            token => return Err(format!("unexpected {:?}", token)),
+        })
+    }
+}
+
+fn parse(query: &str, variables: &Map<String, Value>) -> Result<Vec<Field>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        pos: 0,
+        variables,
+    };
+    parser.document()
+}
+
+/// `value` as an OData literal.
+fn literal(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(format!("'{}'", s.replace('\'', "''"))),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Null => Ok("null".to_owned()),
+        _ => Err("only scalar values are supported in where".to_owned()),
+    }
+}
+
+/// The OData query options of `field`, at the top level or in an expansion.
+fn query_options(field: &Field) -> Result<Vec<String>, String> {
+    let mut options = Vec::new();
+    let (scalars, expanded): (Vec<&Field>, Vec<&Field>) = field
+        .selection
+        .iter()
+        .filter(|f| !f.name.starts_with("__"))
+        .partition(|f| f.selection.is_empty());
+    if !scalars.is_empty() {
+        let names: Vec<&str> = scalars.iter().map(|f| f.name.as_str()).collect();
+        options.push(format!("$select={}", names.join(",")));
+    }
+    if !expanded.is_empty() {
+        let expansions = expanded
+            .iter()
+            .map(|f| {
+                let nested = query_options(f)?;
+                Ok(if nested.is_empty() {
+                    f.name.clone()
+                } else {
+                    format!("{}({})", f.name, nested.join(";"))
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        options.push(format!("$expand={}", expansions.join(",")));
+    }
+    for (arg, value) in &field.args {
+        match arg.as_str() {
+            // the key is part of the path
+            "id" => {}
+            "first" => options.push(format!(
+                "$top={}",
+                value.as_u64().ok_or("first takes a count")?
+            )),
+            "skip" => options.push(format!(
+                "$skip={}",
+                value.as_u64().ok_or("skip takes a count")?
+            )),
+            "orderBy" => options.push(format!(
+                "$orderby={}",
+                value.as_str().ok_or("orderBy takes a string")?
+            )),
+            "where" => {
+                let object = value.as_object().ok_or("where takes an object")?;
+                let filter = object
+                    .iter()
+                    .map(|(name, value)| Ok(format!("{} eq {}", name, literal(value)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                if !filter.is_empty() {
+                    options.push(format!("$filter={}", filter.join(" and ")));
+                }
+            }
+            arg => return Err(format!("unknown argument {} of {}", arg, field.name)),
+        }
+    }
+    Ok(options)
+}
+
+/// Percent-encode what may not stand in a query string as it is.
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'$' | b'(' | b')' | b',' | b';' | b'=' | b'\'' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// The path and query of the OData request resolving the top-level `field`.
+fn resolve(field: &Field, prefix: &str) -> Result<String, String> {
+    let resource = RESOURCES
+        .iter()
+        .find(|(name, _)| *name == field.name)
+        .map(|(_, resource)| resource)
+        .ok_or_else(|| format!("unknown field {}", field.name))?;
+    if field.selection.is_empty() {
+        return Err(format!("{} needs a selection", field.name));
+    }
+    let key = match field.arg("id") {
+        Some(Value::Number(id)) => format!("({})", id),
+        Some(Value::String(id)) => format!("({})", encode(&literal(&Value::from(id.as_str()))?)),
+        Some(_) => return Err("id takes a number or a string".to_owned()),
+        None => String::new(),
+    };
+    let options: Vec<String> = query_options(field)?
+        .iter()
+        .map(|option| {
+            let (name, value) = option.split_once('=').expect("options are name=value");
+            format!("{}={}", name, encode(value))
+        })
+        .collect();
+    Ok(format!(
+        "{}/{}{}?{}",
+        prefix,
+        resource,
+        key,
+        options.join("&")
+    ))
+}
+
+/// `value` cut down to the fields `selection` asks for.
+fn project(value: &Value, selection: &[Field]) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.iter().map(|v| project(v, selection)).collect()),
+        Value::Object(object) => {
+            let mut projected = Map::new();
+            for field in selection {
+                let value = match object.get(&field.name) {
+                    Some(value) if !field.selection.is_empty() => project(value, &field.selection),
+                    Some(value) => value.clone(),
+                    None => Value::Null,
+                };
+                projected.insert(field.key().to_owned(), value);
+            }
+            Value::Object(projected)
+        }
+        value => value.clone(),
+    }
+}
+
+fn error(message: impl Into<String>, path: Option<&str>) -> Value {
+    match path {
+        Some(path) => serde_json::json!({ "message": message.into(), "path": [path] }),
+        None => serde_json::json!({ "message": message.into() }),
+    }
+}
+
+/// Resolve the top-level `field` with `inner`, answering its value.
+async fn execute<S, ResBody>(
+    inner: S,
+    req: Request<ByteBody>,
+    field: &Field,
+) -> Result<Value, Value>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    ResBody: Body<Data = Bytes>,
+    ResBody::Error: Into<BoxError>,
+{
+    let key = Some(field.key());
+    let res = inner
+        .oneshot(req)
+        .await
+        .map_err(|err| error(err.into().to_string(), key))?;
+    let status = res.status();
+    let (body, _) = buffer(res.into_body())
+        .await
+        .map_err(|err| error(err.to_string(), key))?;
+    if !status.is_success() {
+        return Err(error(format!("upstream answered {}", status), key));
+    }
+    let doc: Value = serde_json::from_slice(&body)
+        .map_err(|err| error(format!("upstream answered no JSON: {}", err), key))?;
+    let records = doc.get("d").cloned().unwrap_or(doc);
+    let records = project(&records, &field.selection);
+    Ok(match field.arg("id") {
+        Some(_) => records.get(0).cloned().unwrap_or(Value::Null),
+        None => records,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphQlLayer {
+    config: GraphQlConfig,
+}
+
+impl GraphQlLayer {
+    pub fn new(config: GraphQlConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for GraphQlLayer {
+    type Service = GraphQl<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        GraphQl {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphQl<S> {
+    inner: S,
+    config: GraphQlConfig,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for GraphQl<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + From<Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        if req.method() != Method::POST || req.uri().path() != self.config.path {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+        let requests = serde_json::from_slice::<GraphQlRequest>(req.body().as_bytes())
+            .map_err(|err| format!("invalid request: {}", err))
+            .and_then(|query| parse(&query.query, &query.variables))
+            .and_then(|fields| {
+                fields
+                    .into_iter()
+                    .map(|field| {
+                        let uri = resolve(&field, &self.config.prefix)?;
+                        let mut sub = Request::get(uri)
+                            .body(ByteBody::new(Vec::new()))
+                            .map_err(|err| err.to_string())?;
+                        inherit_headers(req.headers(), sub.headers_mut());
+                        Ok((sub, field))
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            });
+        let requests = match requests {
+            Ok(requests) => requests,
+            Err(message) => {
+                QUERIES.increment(&[("result", "invalid")]);
+                let body = serde_json::json!({ "errors": [error(message, None)] });
+                let res = json_response(StatusCode::BAD_REQUEST, body);
+                return Box::pin(async move { Ok(res) });
+            }
+        };
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            let results = join_all(requests.into_iter().map(|(sub, field)| {
+                let inner = inner.clone();
+                async move {
+                    let result = execute(inner, sub, &field).await;
+                    (field, result)
+                }
+            }))
+            .await;
+            let mut data = Map::new();
+            let mut errors = Vec::new();
+            for (field, result) in results {
+                let value = result.unwrap_or_else(|err| {
+                    errors.push(err);
+                    Value::Null
+                });
+                data.insert(field.key().to_owned(), value);
+            }
+            QUERIES.increment(&[("result", if errors.is_empty() { "ok" } else { "errors" })]);
+            let body = if errors.is_empty() {
+                serde_json::json!({ "data": data })
+            } else {
+                serde_json::json!({ "data": data, "errors": errors })
+            };
+            Ok(json_response(StatusCode::OK, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::AUTHORIZATION;
+    use hyper::Body as HyperBody;
+    use tower::service_fn;
+
+    fn uris(query: &str, variables: Value) -> Result<Vec<String>, String> {
+        let variables = variables.as_object().cloned().unwrap_or_default();
+        parse(query, &variables)?
+            .iter()
+            .map(|field| resolve(field, "/v6"))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            uris(
+                r#"query Fleet($app: Int) {
+                    devices(where: { belongs_to__application: $app, status: "Idle" }, first: 2) {
+                        id device_name
+                        is_running__release { commit }
+                    }
+                    app: applications(id: $app) { app_name }
+                }"#,
+                serde_json::json!({ "app": 7 }),
+            ),
+            Ok(vec![
+                "/v6/device?$select=id,device_name&$expand=is_running__release($select=commit)\
+                 &$filter=belongs_to__application%20eq%207%20and%20status%20eq%20'Idle'&$top=2"
+                    .to_owned(),
+                "/v6/application(7)?$select=app_name".to_owned(),
+            ])
+        );
+        assert!(uris("{ users { id } }", Value::Null).is_err());
+        assert!(uris("mutation { devices { id } }", Value::Null).is_err());
+        assert!(uris("{ devices { ...on device { id } } }", Value::Null).is_err());
+        assert!(uris("{ devices }", Value::Null).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_graphql() -> Result<(), BoxError> {
+        let service = GraphQlLayer::new(GraphQlConfig::default()).layer(service_fn(
+            |req: Request<ByteBody>| async move {
+                assert_eq!(req.headers()[AUTHORIZATION], "Bearer key");
+                let body = match req.uri().path() {
+                    "/device" => serde_json::json!({ "d": [
+                        { "id": 1, "device_name": "pi", "__metadata": { "uri": "/x" },
+                          "belongs_to__application": [{ "id": 7, "app_name": "fleet" }] },
+                    ] }),
+                    "/release(3)" => serde_json::json!({ "d": [] }),
+                    _ => return Ok(Response::builder().status(401).body(HyperBody::empty())?),
+                };
+                Ok::<_, BoxError>(Response::new(HyperBody::from(body.to_string())))
+            },
+        ));
+        let query = serde_json::json!({ "query": r#"{
+            devices { name: device_name belongs_to__application { app_name } }
+            releases(id: 3) { commit }
+            applications { id }
+        }"# });
+        let req = Request::post("/proxy/v1/graphql")
+            .header(AUTHORIZATION, "Bearer key")
+            .body(ByteBody::new(query.to_string().into_bytes()))?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(
+            body["data"],
+            serde_json::json!({
+                "devices": [{ "name": "pi", "belongs_to__application": [{ "app_name": "fleet" }] }],
+                "releases": null,
+                "applications": null,
+            })
+        );
+        assert_eq!(
+            body["errors"][0]["path"],
+            serde_json::json!(["applications"])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! Gzip-encoded bodies.
+//!
+//! Layers inspecting or rewriting response bodies would otherwise only see
+//! opaque bytes when the upstream compresses them. These helpers let them
+//! look through `Content-Encoding: gzip`; other encodings stay opaque.
+
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use http::{header::CONTENT_ENCODING, HeaderMap};
+
+/// Whether `headers` say the body is gzip encoded, and nothing else.
+pub fn is_gzip(headers: &HeaderMap) -> bool {
+    let mut codings = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("?").split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"));
+    matches!(
+        (codings.next(), codings.next()),
+        (Some(coding), None) if coding.eq_ignore_ascii_case("gzip")
+            || coding.eq_ignore_ascii_case("x-gzip")
+    )
+}
+
+pub fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).expect("writing to a Vec");
+    encoder.finish().expect("writing to a Vec")
+}
+
+/// The body of a message with `headers` as it is to be inspected: decoded
+/// when gzip encoded, as it is otherwise or when it does not decode.
+pub fn inspect<'a>(headers: &HeaderMap, data: &'a [u8]) -> Cow<'a, [u8]> {
+    if !is_gzip(headers) {
+        return Cow::Borrowed(data);
+    }
+    match decode(data) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(_) => Cow::Borrowed(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_gzip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(inspect(&headers, b"plain"), &b"plain"[..]);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let encoded = encode(b"{\"id\":1}");
+        assert_eq!(inspect(&headers, &encoded), &b"{\"id\":1}"[..]);
+        // not actually gzip
+        assert_eq!(inspect(&headers, b"plain"), &b"plain"[..]);
+
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip, br"));
+        assert!(!is_gzip(&headers));
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(!is_gzip(&headers));
+    }
+}
@@ -0,0 +1,162 @@
+//! Request header limits.
+//!
+//! hyper already refuses headers that overflow its read buffer, see
+//! `ServerConfig::max_buf_bytes`. [`HeaderLimitLayer`] adds finer limits on
+//! the number of headers and their total size, answering with a 431.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderLimitConfig {
+    /// Headers allowed per request, no limit when unset.
+    pub max_headers: Option<usize>,
+    /// Total bytes of header names and values, no limit when unset.
+    pub max_header_bytes: Option<usize>,
+}
+
+impl HeaderLimitConfig {
+    pub fn is_empty(&self) -> bool {
+        self.max_headers.is_none() && self.max_header_bytes.is_none()
+    }
+
+    /// Describe the first limit `headers` exceed.
+    fn check(&self, headers: &HeaderMap) -> Option<serde_json::Value> {
+        let count = headers.len();
+        if let Some(max) = self.max_headers.filter(|&max| count > max) {
+            return Some(serde_json::json!({
+                "error": "too many request headers",
+                "headers": count,
+                "max_headers": max,
+            }));
+        }
+        let bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if let Some(max) = self.max_header_bytes.filter(|&max| bytes > max) {
+            return Some(serde_json::json!({
+                "error": "request headers too large",
+                "header_bytes": bytes,
+                "max_header_bytes": max,
+            }));
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderLimitLayer {
+    config: HeaderLimitConfig,
+}
+
+impl HeaderLimitLayer {
+    pub fn new(config: HeaderLimitConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for HeaderLimitLayer {
+    type Service = HeaderLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HeaderLimit {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HeaderLimit<S> {
+    inner: S,
+    config: HeaderLimitConfig,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for HeaderLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let error = match self.config.check(req.headers()) {
+            Some(error) => error,
+            None => {
+                let fut = self.inner.call(req);
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+        };
+
+        tracing::warn!(%error, "request headers over limit");
+        let mut res = Response::new(ResBody::from(Bytes::from(error.to_string())));
+        *res.status_mut() = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_upstream::MockUpstream, read_request_body::ByteBody};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_header_limits() -> Result<(), BoxError> {
+        let layer = HeaderLimitLayer::new(HeaderLimitConfig {
+            max_headers: Some(2),
+            max_header_bytes: Some(64),
+        });
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+
+        // within limits, the upstream answers
+        let req = Request::get("/v6/device")
+            .header("a", "1")
+            .body(ByteBody::new(Vec::new()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = Request::get("/v6/device")
+            .header("a", "1")
+            .header("b", "2")
+            .header("c", "3")
+            .body(ByteBody::new(Vec::new()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let body: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["headers"], 3);
+
+        let req = Request::get("/v6/device")
+            .header("a", "x".repeat(100))
+            .body(ByteBody::new(Vec::new()))?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let body: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["max_header_bytes"], 64);
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Hedging reads across keys.
+//!
+//! A key close to the end of its quota is likely to get a 429 for the next
+//! request, which then waits for a retry. [`HedgeLayer`] sends GETs made with
+//! the active pooled key, while it has `remaining_below` requests left or
+//! fewer, with the next key as well, `delay_ms` later, and answers with
+//! whichever succeeds first. The other request is dropped.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Future;
+use futures_util::future::{select, Either};
+use http::{header::AUTHORIZATION, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::{
+    auth::{KeyPool, WithKey},
+    context::ProxyContext,
+    metrics::Metric,
+    read_request_body::StreamedBody,
+    redirect::ForeignHost,
+    secret::ApiKey,
+};
+
+const HEDGED: Metric = Metric::counter(
+    "proxy_hedged_requests_total",
+    "Reads sent with a second key, by the request answered with.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HedgeConfig {
+    /// Requests left to the active key at which reads are hedged.
+    pub remaining_below: u64,
+    /// Wait before sending the hedged request.
+    pub delay_ms: u64,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            remaining_below: 10,
+            delay_ms: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HedgeLayer {
+    keys: KeyPool,
+    remaining_below: u64,
+    delay: Duration,
+}
+
+impl HedgeLayer {
+    pub fn new(config: HedgeConfig, keys: KeyPool) -> Self {
+        Self {
+            keys,
+            remaining_below: config.remaining_below,
+            delay: Duration::from_millis(config.delay_ms),
+        }
+    }
+}
+
+impl<S> Layer<S> for HedgeLayer {
+    type Service = Hedge<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Hedge {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hedge<S> {
+    inner: S,
+    layer: HedgeLayer,
+}
+
+impl<S> Hedge<S> {
+    /// The next key to send `req` with as well, if it is a read with a pooled
+    /// key and the active one is running out.
+    fn hedge_key<B>(&self, req: &Request<B>) -> Option<ApiKey> {
+        if req.method() != Method::GET
+            || req.extensions().get::<StreamedBody>().is_some()
+            || req.extensions().get::<ForeignHost>().is_some()
+        {
+            return None;
+        }
+        // requests with their own key are none of the pool's business
+        let pooled = match ProxyContext::of(req) {
+            Some(context) => context.get().caller.is_none(),
+            None => !req.headers().contains_key(AUTHORIZATION),
+        };
+        if !pooled {
+            return None;
+        }
+        let keys = &self.layer.keys;
+        let remaining = keys.remaining(&keys.active_key()?)?;
+        if remaining > self.layer.remaining_below {
+            return None;
+        }
+        keys.next_key(self.layer.remaining_below)
+    }
+}
+
+/// Copy of `req` sent with `key`, with a context of its own so that the key
+/// it is sent with does not show on the original's until it wins.
+fn clone_request<B: Clone>(req: &Request<B>, key: ApiKey) -> Request<B> {
+    let mut clone = Request::new(req.body().clone());
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.version_mut() = req.version();
+    *clone.headers_mut() = req.headers().clone();
+    clone.headers_mut().remove(AUTHORIZATION);
+    if let Some(context) = ProxyContext::of(req) {
+        clone
+            .extensions_mut()
+            .insert(ProxyContext::new(context.get()));
+    }
+    clone.extensions_mut().insert(WithKey(key));
+    clone
+}
+
+/// Whether `result` is one to answer with rather than wait for the other.
+fn succeeded<ResBody>(result: &Result<Response<ResBody>, BoxError>) -> bool {
+    result.as_ref().is_ok_and(|response| {
+        let status = response.status();
+        !status.is_server_error()
+            && status != StatusCode::TOO_MANY_REQUESTS
+            && status != StatusCode::UNAUTHORIZED
+    })
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Hedge<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ReqBody: Clone + Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let Some(key) = self.hedge_key(&req) else {
+            let fut = inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+        let hedged = clone_request(&req, key);
+        let context = ProxyContext::of(&hedged).cloned();
+        let original = ProxyContext::of(&req).cloned();
+        let primary = inner.call(req);
+        let mut second = self.inner.clone();
+        let delay = self.layer.delay;
+        Box::pin(async move {
+            let primary = Box::pin(async move { primary.await.map_err(Into::into) });
+            let secondary = Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                second.ready().await.map_err(Into::into)?;
+                second.call(hedged).await.map_err(Into::into)
+            });
+            let response = match select(primary, secondary).await {
+                Either::Left((result, _)) if succeeded(&result) => {
+                    HEDGED.increment(&[("winner", "primary")]);
+                    return result;
+                }
+                Either::Left((primary, secondary)) => {
+                    let result = secondary.await;
+                    if !succeeded(&result) {
+                        HEDGED.increment(&[("winner", "none")]);
+                        return primary;
+                    }
+                    result
+                }
+                Either::Right((result, _)) if succeeded(&result) => result,
+                Either::Right((_, primary)) => {
+                    let result = primary.await;
+                    let winner = if succeeded(&result) {
+                        "primary"
+                    } else {
+                        "none"
+                    };
+                    HEDGED.increment(&[("winner", winner)]);
+                    return result;
+                }
+            };
+            HEDGED.increment(&[("winner", "hedged")]);
+            tracing::debug!("hedged request answered first");
+            if let (Some(hedged), Some(original)) = (context, original) {
+                let key = hedged.get().key;
+                original.update(|annotations| annotations.key = key);
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthLayer, X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING, X_RATELIMIT_RESET};
+    use http::{HeaderMap, HeaderValue};
+    use std::convert::Infallible;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn test_hedge() {
+        let keys = KeyPool::from(vec!["a", "b"]);
+        let quota = |remaining: u64| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(100));
+            headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(remaining));
+            headers.insert(X_RATELIMIT_RESET, HeaderValue::from(60));
+            headers
+        };
+        let service = HedgeLayer::new(HedgeConfig::default(), keys.clone()).layer(
+            AuthLayer::new(keys.clone()).layer(service_fn(|req: Request<()>| async move {
+                let key = req.headers()[AUTHORIZATION].to_str().unwrap().to_string();
+                // the active key is the slow one
+                if key.ends_with('a') {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Ok::<_, Infallible>(Response::new(key))
+            })),
+        );
+        let answer = |method: Method| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/v6/device")
+                .body(())
+                .unwrap();
+            let service = service.clone();
+            async move { service.oneshot(req).await.unwrap().into_body() }
+        };
+
+        keys.record_quota("a", StatusCode::OK, &quota(50));
+        assert_eq!(answer(Method::GET).await, "Bearer a");
+        // running low, the next key answers first
+        keys.record_quota("a", StatusCode::OK, &quota(5));
+        assert_eq!(answer(Method::GET).await, "Bearer b");
+        // only reads are hedged
+        assert_eq!(answer(Method::POST).await, "Bearer a");
+        // not with a key running low as well
+        keys.record_quota("b", StatusCode::OK, &quota(3));
+        assert_eq!(answer(Method::GET).await, "Bearer a");
+    }
+}
@@ -0,0 +1,395 @@
+//! HMAC request signing and verification.
+//!
+//! A signature is the hex HMAC-SHA256, with a shared secret, of the method,
+//! path and query, and body of a request, each followed by a newline, sent
+//! as `sha256=<hex>` in `X-Signature`. [`VerifyLayer`] rejects inbound
+//! requests whose signature is missing or wrong with a 401, before anything
+//! rewrites them but the path normalization of [`crate::sanitize`], so
+//! clients sign normalized paths; [`SignLayer`] signs outbound requests as they are sent
+//! upstream, for webhook-style integrations expecting signed calls.
+//!
+//! Bodies over the buffering cap are streamed and cannot be signed whole:
+//! such requests are refused with a 413 on verified routes, and sent
+//! unsigned to signed ones.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use ::hmac::{Hmac, Mac};
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderName, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use sha2::Sha256;
+use tower::{BoxError, Layer, Service};
+
+use crate::{read_request_body::ByteBody, route::RouteMatcher};
+
+pub const X_SIGNATURE: &str = "x-signature";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HmacConfig {
+    /// Inbound requests that must be signed.
+    pub verify: Vec<HmacRule>,
+    /// Upstream requests to sign, matched against the upstream path.
+    pub sign: Vec<HmacRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HmacRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Environment variable holding the shared secret.
+    pub secret_env: String,
+    /// Header carrying the signature.
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+fn default_header() -> String {
+    X_SIGNATURE.to_owned()
+}
+
+struct Rule {
+    route: RouteMatcher,
+    secret: Vec<u8>,
+    header: HeaderName,
+}
+
+fn rules(rules: Vec<HmacRule>) -> Result<Arc<Vec<Rule>>, BoxError> {
+    let rules = rules
+        .into_iter()
+        .map(|rule| {
+            let secret = std::env::var(&rule.secret_env)
+                .map_err(|err| format!("{}: {}", err, rule.secret_env))?;
+            let header = HeaderName::from_bytes(rule.header.as_bytes())
+                .map_err(|err| format!("signature header {}: {}", rule.header, err))?;
+            Ok(Rule {
+                route: rule.route,
+                secret: secret.into_bytes(),
+                header,
+            })
+        })
+        .collect::<Result<_, BoxError>>()?;
+    Ok(Arc::new(rules))
+}
+
+fn mac(secret: &[u8], req: &Request<ByteBody>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    mac.update(req.method().as_str().as_bytes());
+    mac.update(b"\n");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(req.body().as_bytes());
+    mac.update(b"\n");
+    mac
+}
+
+fn payload_mac(secret: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+    mac.update(payload);
+    mac
+}
+
+fn encode(mac: Hmac<Sha256>) -> String {
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Check `signature` against `mac`, in constant time.
+fn check(mac: Hmac<Sha256>, signature: &[u8]) -> bool {
+    let Some(signature) = signature
+        .strip_prefix(b"sha256=")
+        .and_then(|hex| hex::decode(hex).ok())
+    else {
+        return false;
+    };
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// The signature header value of `req`.
+pub fn sign(secret: &[u8], req: &Request<ByteBody>) -> String {
+    encode(mac(secret, req))
+}
+
+/// Check the signature header value of `req`, in constant time.
+pub fn verify(secret: &[u8], req: &Request<ByteBody>, signature: &[u8]) -> bool {
+    check(mac(secret, req), signature)
+}
+
+/// The signature of `payload`, in the format of request signatures, for
+/// messages exchanged outside HTTP.
+pub fn sign_payload(secret: &[u8], payload: &[u8]) -> String {
+    encode(payload_mac(secret, payload))
+}
+
+/// Check the signature of `payload`, in constant time.
+pub fn verify_payload(secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+    check(payload_mac(secret, payload), signature)
+}
+
+#[derive(Clone)]
+pub struct VerifyLayer {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl VerifyLayer {
+    /// Read the secrets of `rules`.
+    pub fn new(rules: Vec<HmacRule>) -> Result<Self, BoxError> {
+        Ok(Self {
+            rules: self::rules(rules)?,
+        })
+    }
+}
+
+impl<S> Layer<S> for VerifyLayer {
+    type Service = Verify<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Verify {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Verify<S> {
+    inner: S,
+    rules: Arc<Vec<Rule>>,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Verify<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let mut rules = self.rules.iter().filter(|rule| rule.route.matches(&req));
+        // only the start of a streamed body is at hand to check
+        if req.body().is_streamed() && rules.next().is_some() {
+            tracing::warn!(method = %req.method(), path = req.uri().path(), "signed body too large to verify");
+            let body = serde_json::json!({ "error": "request body too large to verify" });
+            let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+            *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            return Box::pin(async move { Ok(res) });
+        }
+        let valid = self
+            .rules
+            .iter()
+            .filter(|rule| rule.route.matches(&req))
+            .all(|rule| {
+                req.headers()
+                    .get(&rule.header)
+                    .is_some_and(|signature| verify(&rule.secret, &req, signature.as_bytes()))
+            });
+        if valid {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        tracing::warn!(method = %req.method(), path = req.uri().path(), "invalid request signature");
+        let body = serde_json::json!({ "error": "invalid signature" });
+        let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+        *res.status_mut() = StatusCode::UNAUTHORIZED;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[derive(Clone)]
+pub struct SignLayer {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl SignLayer {
+    /// Read the secrets of `rules`.
+    pub fn new(rules: Vec<HmacRule>) -> Result<Self, BoxError> {
+        Ok(Self {
+            rules: self::rules(rules)?,
+        })
+    }
+}
+
+impl<S> Layer<S> for SignLayer {
+    type Service = Sign<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Sign {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Sign<S> {
+    inner: S,
+    rules: Arc<Vec<Rule>>,
+}
+
+impl<S> Service<Request<ByteBody>> for Sign<S>
+where
+    S: Service<Request<ByteBody>>,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.route.matches(&req)) {
+            if req.body().is_streamed() {
+                tracing::warn!(
+                    path = req.uri().path(),
+                    "body too large to sign, sent unsigned"
+                );
+                return self.inner.call(req);
+            }
+            let signature = sign(&rule.secret, &req);
+            req.headers_mut().insert(
+                rule.header.clone(),
+                HeaderValue::from_str(&signature).expect("hex is a valid header"),
+            );
+        }
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    fn rule(route: serde_json::Value) -> Rule {
+        Rule {
+            route: serde_json::from_value(route).unwrap(),
+            secret: b"shared".to_vec(),
+            header: HeaderName::from_static(X_SIGNATURE),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_and_verify() -> Result<(), BoxError> {
+        let fixtures: Vec<Fixture> = serde_json::from_value(serde_json::json!([
+            { "route": { "path": "/hooks/build", "headers": [{ "name": X_SIGNATURE }] },
+              "status": 204 },
+        ]))?;
+        let sign = SignLayer {
+            rules: Arc::new(vec![rule(serde_json::json!({ "path_prefix": "/hooks" }))]),
+        };
+        let verify = VerifyLayer {
+            rules: Arc::new(vec![rule(serde_json::json!({ "path_prefix": "/hooks" }))]),
+        };
+        // what one proxy signs, another verifies
+        let service = sign.layer(verify.layer(MockUpstream::new(fixtures)));
+
+        let req = Request::post("/hooks/build").body(ByteBody::new(b"{}".to_vec()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let unsigned = verify.layer(MockUpstream::new(Vec::new()));
+        let mut req = Request::post("/hooks/build").body(ByteBody::new(b"{}".to_vec()))?;
+        let signature = super::sign(b"shared", &req);
+        req.headers_mut()
+            .insert(X_SIGNATURE, HeaderValue::from_str(&signature)?);
+        // tampering with the body invalidates the signature
+        *req.body_mut() = ByteBody::new(b"{\"x\":1}".to_vec());
+        let res = unsigned.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::post("/hooks/build").body(ByteBody::new(b"{}".to_vec()))?;
+        let res = unsigned.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // other routes need no signature
+        let req = Request::get("/v6/device").body(ByteBody::new(Vec::new()))?;
+        let res = unsigned.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_batched() -> Result<(), BoxError> {
+        use crate::fan_out::{FanOutConfig, FanOutLayer};
+
+        let verify = VerifyLayer {
+            rules: Arc::new(vec![rule(serde_json::json!({ "path_prefix": "/hooks" }))]),
+        };
+        // batches are answered above verification, as in the proxy
+        let service = FanOutLayer::new(FanOutConfig::default())
+            .layer(verify.layer(MockUpstream::new(Vec::new())));
+        let batch = serde_json::json!([{ "method": "POST", "path": "/hooks/build", "body": {} }]);
+        let req =
+            Request::post("/proxy/v1/batch").body(ByteBody::new(batch.to_string().into_bytes()))?;
+        let res = service.oneshot(req).await?;
+        let body: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["responses"][0]["status"], 401);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_streamed() -> Result<(), BoxError> {
+        use crate::read_request_body::ReadRequestLayer;
+
+        let verify = VerifyLayer {
+            rules: Arc::new(vec![rule(serde_json::json!({ "path_prefix": "/hooks" }))]),
+        };
+        let service = ReadRequestLayer::new()
+            .max_buffered(Some(4))
+            .layer(verify.layer(MockUpstream::new(Vec::new())));
+        let body = b"{\"long\":\"body\"}".to_vec();
+        let signature = super::sign(
+            b"shared",
+            &Request::post("/hooks/build").body(ByteBody::new(body.clone()))?,
+        );
+        let req = Request::post("/hooks/build")
+            .header(X_SIGNATURE, signature)
+            .body(hyper::Body::from(body))?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature() {
+        let req = Request::post("/hooks?a=1")
+            .body(ByteBody::new(b"body".to_vec()))
+            .unwrap();
+        let signature = sign(b"key", &req);
+        assert!(verify(b"key", &req, signature.as_bytes()));
+        assert!(!verify(b"other", &req, signature.as_bytes()));
+        assert!(!verify(b"key", &req, b"sha256=zz"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Holding requests while the key pool is rate limited.
+//!
+//! Once every key of the pool got a 429, forwarding more requests only
+//! collects more of them. [`HoldLayer`] makes requests that would use a pooled
+//! key wait until the first key gets its quota back, as its `Retry-After` or
+//! rate limit headers said, when that is within `max_wait_ms`. At most
+//! `max_held` requests wait at once; the others, and those that would wait
+//! longer, are forwarded right away.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Future;
+use http::{header::AUTHORIZATION, Request};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tower::{Layer, Service};
+
+use crate::{auth::KeyPool, context::ProxyContext, metrics::Metric};
+
+const HELD: Metric = Metric::counter(
+    "proxy_key_held_total",
+    "Requests held while every pooled key was rate limited.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoldConfig {
+    /// Longest a request waits for a key to get its quota back.
+    pub max_wait_ms: u64,
+    /// Requests waiting at once.
+    pub max_held: usize,
+}
+
+#[derive(Clone)]
+pub struct HoldLayer {
+    keys: KeyPool,
+    max_wait: Duration,
+    slots: Arc<Semaphore>,
+}
+
+impl HoldLayer {
+    pub fn new(config: HoldConfig, keys: KeyPool) -> Self {
+        Self {
+            keys,
+            max_wait: Duration::from_millis(config.max_wait_ms),
+            slots: Arc::new(Semaphore::new(config.max_held)),
+        }
+    }
+}
+
+impl<S> Layer<S> for HoldLayer {
+    type Service = Hold<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Hold {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hold<S> {
+    inner: S,
+    layer: HoldLayer,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for Hold<S>
+where
+    S: Service<Request<ReqBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        // requests with their own key are none of the pool's business
+        let pooled = match ProxyContext::of(&req) {
+            Some(context) => context.get().caller.is_none(),
+            None => !req.headers().contains_key(AUTHORIZATION),
+        };
+        let wait = pooled
+            .then(|| self.layer.keys.exhausted_for())
+            .flatten()
+            .filter(|wait| *wait <= self.layer.max_wait);
+        let permit = wait.and_then(|_| self.layer.slots.clone().try_acquire_owned().ok());
+        Box::pin(async move {
+            if let (Some(wait), Some(_permit)) = (wait, permit) {
+                HELD.increment(&[]);
+                tracing::debug!(
+                    wait_ms = wait.as_millis() as u64,
+                    "every key rate limited, holding"
+                );
+                tokio::time::sleep(wait).await;
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Response, StatusCode};
+    use std::{convert::Infallible, time::Instant};
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_hold() {
+        let keys = KeyPool::from(vec!["a", "b"]);
+        let mut throttled = HeaderMap::new();
+        throttled.insert(RETRY_AFTER, HeaderValue::from(1));
+        keys.record_quota("a", StatusCode::TOO_MANY_REQUESTS, &throttled);
+        assert_eq!(keys.exhausted_for(), None);
+        keys.record_quota("b", StatusCode::TOO_MANY_REQUESTS, &throttled);
+        assert!(keys.exhausted_for().is_some());
+
+        let service = |max_wait_ms| {
+            let config = HoldConfig {
+                max_wait_ms,
+                max_held: 1,
+            };
+            HoldLayer::new(config, keys.clone()).layer(service_fn(|_: Request<()>| async {
+                Ok::<_, Infallible>(Response::new(()))
+            }))
+        };
+        let elapsed = |max_wait_ms, key: Option<&str>| {
+            let mut req = Request::get("/v6/device");
+            if let Some(key) = key {
+                req = req.header(AUTHORIZATION, format!("Bearer {}", key));
+            }
+            let req = req.body(()).unwrap();
+            let service = service(max_wait_ms);
+            async move {
+                let started = Instant::now();
+                service.oneshot(req).await.unwrap();
+                started.elapsed()
+            }
+        };
+
+        // not held when the quota is too far off, nor with a key of their own
+        assert!(elapsed(100, None).await < Duration::from_millis(100));
+        assert!(elapsed(2_000, Some("c")).await < Duration::from_millis(100));
+        // held until the first key gets its quota back
+        assert!(elapsed(2_000, None).await >= Duration::from_millis(500));
+        assert_eq!(keys.exhausted_for(), None);
+    }
+}
@@ -0,0 +1,75 @@
+//! Connector for upstream and outbound requests.
+//!
+//! With the `tls` feature, this is [`hyper_tls::HttpsConnector`] and reaches
+//! `https://` URLs through OpenSSL. Builds without it don't link OpenSSL: the
+//! stand-in below connects to `http://` URLs only, and refuses the others
+//! rather than send their requests in the clear.
+
+#[cfg(feature = "tls")]
+pub use hyper_tls::HttpsConnector;
+
+#[cfg(not(feature = "tls"))]
+pub use plain::HttpsConnector;
+
+#[cfg(not(feature = "tls"))]
+mod plain {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures_core::Future;
+    use http::{uri::Scheme, Uri};
+    use hyper::client::HttpConnector;
+    use tower::{BoxError, Service};
+
+    #[derive(Debug, Clone)]
+    pub struct HttpsConnector<T> {
+        http: T,
+    }
+
+    impl HttpsConnector<HttpConnector> {
+        pub fn new() -> Self {
+            Self::new_with_connector(HttpConnector::new())
+        }
+    }
+
+    impl Default for HttpsConnector<HttpConnector> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> HttpsConnector<T> {
+        pub fn new_with_connector(http: T) -> Self {
+            Self { http }
+        }
+    }
+
+    impl<T> Service<Uri> for HttpsConnector<T>
+    where
+        T: Service<Uri>,
+        T::Error: Into<BoxError>,
+        T::Future: Send + 'static,
+    {
+        type Response = T::Response;
+
+        type Error = BoxError;
+
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.http.poll_ready(cx).map_err(Into::into)
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            if uri.scheme() == Some(&Scheme::HTTPS) {
+                return Box::pin(async move {
+                    Err(format!("{}: built without TLS, https is not supported", uri).into())
+                });
+            }
+            let fut = self.http.call(uri);
+            Box::pin(async move { fut.await.map_err(Into::into) })
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! `Idempotency-Key` based deduplication of client retries.
+//!
+//! [`IdempotencyLayer`] remembers the response to every write carrying an
+//! `Idempotency-Key` header and answers a repeated request with the same key
+//! from memory, so a client retrying a POST whose response got lost does not
+//! create the resource twice. A repeat arriving while the first request is
+//! still in flight gets a 409. Keys are scoped to the caller's credentials,
+//! `X-Balena-Authorization` or else `Authorization` as the upstream gets
+//! them, so one caller never gets another's response, and a key
+//! reused with a different body gets a 422. Keys are kept in a bounded LRU
+//! with a TTL.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    context::caller,
+    read_request_body::{buffer, ByteBody, FromBuffered},
+};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Header added to responses answered from memory.
+pub const X_PROXY_IDEMPOTENT_REPLAY: &str = "x-proxy-idempotent-replay";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    /// Maximum number of remembered keys.
+    pub capacity: usize,
+    /// How long a response is remembered.
+    pub ttl_secs: u64,
+    /// Methods the header is honored for.
+    pub methods: Vec<String>,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl_secs: 3600,
+            methods: vec!["POST".into(), "PATCH".into()],
+        }
+    }
+}
+
+/// Requests are deduplicated on their caller, key, method and path.
+type CacheKey = (String, String, String, String);
+
+/// SHA-256 of a request body.
+type BodyHash = [u8; 32];
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    trailers: Option<Box<HeaderMap>>,
+}
+
+enum Slot {
+    InFlight,
+    Done(StoredResponse),
+}
+
+struct Entry {
+    slot: Slot,
+    hash: BodyHash,
+    expires: Instant,
+    last_used: u64,
+}
+
+struct Store {
+    entries: HashMap<CacheKey, Entry>,
+    capacity: usize,
+    ttl: Duration,
+    clock: u64,
+}
+
+enum Lookup {
+    /// First time the key is seen, the request must be forwarded.
+    Forward,
+    InFlight,
+    Replay(StoredResponse),
+    /// The key was used with another body.
+    Mismatch,
+}
+
+impl Store {
+    fn lookup(&mut self, key: &CacheKey, hash: BodyHash) -> Lookup {
+        let now = Instant::now();
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.expires > now && entry.hash != hash => Lookup::Mismatch,
+            Some(entry) if entry.expires > now => {
+                entry.last_used = self.clock;
+                match &entry.slot {
+                    Slot::InFlight => Lookup::InFlight,
+                    Slot::Done(res) => Lookup::Replay(res.clone()),
+                }
+            }
+            _ => {
+                self.insert(key.clone(), Slot::InFlight, hash, now);
+                Lookup::Forward
+            }
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, slot: Slot, hash: BodyHash, now: Instant) {
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.entries.retain(|_, e| e.expires > now);
+            if self.entries.len() >= self.capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.last_used)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        let entry = Entry {
+            slot,
+            hash,
+            expires: now + self.ttl,
+            last_used: self.clock,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn complete(&mut self, key: CacheKey, hash: BodyHash, res: Option<StoredResponse>) {
+        match res {
+            Some(res) => self.insert(key, Slot::Done(res), hash, Instant::now()),
+            // let the client try again
+            None => {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IdempotencyLayer {
+    store: Arc<Mutex<Store>>,
+    methods: Arc<Vec<String>>,
+}
+
+impl IdempotencyLayer {
+    pub fn new(config: IdempotencyConfig) -> Self {
+        let store = Store {
+            entries: HashMap::new(),
+            capacity: config.capacity.max(1),
+            ttl: Duration::from_secs(config.ttl_secs),
+            clock: 0,
+        };
+        Self {
+            store: Arc::new(Mutex::new(store)),
+            methods: Arc::new(config.methods),
+        }
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = Idempotency<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Idempotency {
+            inner: service,
+            store: self.store.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Idempotency<S> {
+    inner: S,
+    store: Arc<Mutex<Store>>,
+    methods: Arc<Vec<String>>,
+}
+
+impl<S> Idempotency<S> {
+    fn cache_key<B>(&self, req: &Request<B>) -> Option<CacheKey> {
+        let method = req.method().as_str();
+        if !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return None;
+        }
+        let key = req.headers().get(IDEMPOTENCY_KEY)?.to_str().ok()?;
+        let caller = caller(req).unwrap_or_default();
+        Some((
+            caller,
+            key.to_string(),
+            method.to_string(),
+            req.uri().path().to_string(),
+        ))
+    }
+}
+
+/// Hash of the body of `req`. Of streamed bodies only the buffered part is
+/// read, and their length stands in for the rest.
+fn body_hash(req: &Request<ByteBody>) -> BodyHash {
+    let mut hasher = Sha256::new();
+    hasher.update(req.body().as_bytes());
+    if req.body().is_streamed() {
+        if let Some(length) = req.headers().get(http::header::CONTENT_LENGTH) {
+            hasher.update(length.as_bytes());
+        }
+    }
+    hasher.finalize().into()
+}
+
+fn response<B: FromBuffered>(stored: StoredResponse) -> Response<B> {
+    let mut res = Response::new(B::from_buffered(stored.body, stored.trailers.map(|t| *t)));
+    *res.status_mut() = stored.status;
+    *res.headers_mut() = stored.headers;
+    res.headers_mut()
+        .insert(X_PROXY_IDEMPOTENT_REPLAY, HeaderValue::from_static("true"));
+    res
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Idempotency<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ResBody: http_body::Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let key = match self.cache_key(&req) {
+            Some(key) => key,
+            None => return Box::pin(async move { inner.call(req).await.map_err(Into::into) }),
+        };
+
+        let hash = body_hash(&req);
+        match self.store.lock().unwrap().lookup(&key, hash) {
+            Lookup::Forward => (),
+            Lookup::Mismatch => {
+                tracing::debug!(key = %key.1, "idempotency key reused with another body");
+                let mut res = Response::new(ResBody::from(Bytes::from_static(
+                    br#"{"error":"this idempotency key was used with another request body"}"#,
+                )));
+                *res.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                return Box::pin(async move { Ok(res) });
+            }
+            Lookup::InFlight => {
+                tracing::debug!(key = %key.1, "idempotency key in flight");
+                let mut res = Response::new(ResBody::from(Bytes::from_static(
+                    br#"{"error":"a request with this idempotency key is in progress"}"#,
+                )));
+                *res.status_mut() = StatusCode::CONFLICT;
+                return Box::pin(async move { Ok(res) });
+            }
+            Lookup::Replay(stored) => {
+                tracing::debug!(key = %key.1, "replaying stored response");
+                return Box::pin(async move { Ok(response(stored)) });
+            }
+        }
+
+        let guard = InFlightGuard {
+            store: self.store.clone(),
+            key: Some(key),
+            hash,
+        };
+        Box::pin(async move {
+            let res = inner.call(req).await.map_err(Into::into)?;
+            // server errors, throttling and timeouts are not final, a retry
+            // may well succeed
+            if res.status().is_server_error()
+                || matches!(
+                    res.status(),
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT
+                )
+            {
+                return Ok(res);
+            }
+
+            let (parts, body) = res.into_parts();
+            let (body, trailers) = buffer(body).await?;
+            guard.complete(StoredResponse {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                trailers: trailers.clone().map(Box::new),
+            });
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(body, trailers),
+            ))
+        })
+    }
+}
+
+/// Forgets the in-flight key unless a response was stored, including when
+/// the request fails or the client goes away.
+struct InFlightGuard {
+    store: Arc<Mutex<Store>>,
+    key: Option<CacheKey>,
+    hash: BodyHash,
+}
+
+impl InFlightGuard {
+    fn complete(mut self, res: StoredResponse) {
+        if let Some(key) = self.key.take() {
+            self.store
+                .lock()
+                .unwrap()
+                .complete(key, self.hash, Some(res));
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.lock().unwrap().complete(key, self.hash, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::X_BALENA_AUTHORIZATION;
+    use http::header::AUTHORIZATION;
+    use httpmock::prelude::*;
+    use hyper::Client;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn test_deduplicate() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST).path("/v6/device");
+            then.status(201).body(r#"{"id":1}"#);
+        });
+
+        let client = ServiceBuilder::new()
+            .layer(IdempotencyLayer::new(IdempotencyConfig::default()))
+            .service(Client::builder().build_http::<ByteBody>());
+        let request_as = |key: &str, caller: &str, body: &str| {
+            Request::post(format!("http://{}/v6/device", server.address()))
+                .header(IDEMPOTENCY_KEY, key)
+                .header(AUTHORIZATION, format!("Bearer {}", caller))
+                .body(ByteBody::new(body.as_bytes().to_vec()))
+        };
+        let request = |key: &str| request_as(key, "c1", "{}");
+
+        let res = client.clone().oneshot(request("a")?).await?;
+        assert_eq!(res.status(), 201);
+        assert!(res.headers().get(X_PROXY_IDEMPOTENT_REPLAY).is_none());
+
+        let res = client.clone().oneshot(request("a")?).await?;
+        assert_eq!(res.status(), 201);
+        assert_eq!(res.headers()[X_PROXY_IDEMPOTENT_REPLAY], "true");
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, r#"{"id":1}"#);
+        m.assert_hits(1);
+
+        // another key is forwarded
+        client.clone().oneshot(request("b")?).await?;
+        m.assert_hits(2);
+
+        // so is the same key from another caller
+        let res = client.clone().oneshot(request_as("a", "c2", "{}")?).await?;
+        assert!(res.headers().get(X_PROXY_IDEMPOTENT_REPLAY).is_none());
+        m.assert_hits(3);
+
+        // or with credentials in X-Balena-Authorization
+        let balena = |caller: &str| {
+            Request::post(format!("http://{}/v6/device", server.address()))
+                .header(IDEMPOTENCY_KEY, "shared")
+                .header(X_BALENA_AUTHORIZATION, format!("Bearer {}", caller))
+                .body(ByteBody::new(b"{}".to_vec()))
+        };
+        client.clone().oneshot(balena("t1")?).await?;
+        let res = client.clone().oneshot(balena("t2")?).await?;
+        assert!(res.headers().get(X_PROXY_IDEMPOTENT_REPLAY).is_none());
+        m.assert_hits(5);
+        let res = client.clone().oneshot(balena("t1")?).await?;
+        assert_eq!(res.headers()[X_PROXY_IDEMPOTENT_REPLAY], "true");
+        m.assert_hits(5);
+
+        // the same key with another body is refused
+        let res = client.oneshot(request_as("a", "c1", "{\"x\":1}")?).await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        m.assert_hits(5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_final_not_stored() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let client = ServiceBuilder::new()
+            .layer(IdempotencyLayer::new(IdempotencyConfig::default()))
+            .service(Client::builder().build_http::<ByteBody>());
+        for (status, path) in [(429, "/v6/throttled"), (408, "/v6/slow"), (503, "/v6/down")] {
+            let m = server.mock(|when, then| {
+                when.method(POST).path(path);
+                then.status(status);
+            });
+            for _ in 0..2 {
+                let req = Request::post(format!("http://{}{}", server.address(), path))
+                    .header(IDEMPOTENCY_KEY, "a")
+                    .body(ByteBody::new(b"{}".to_vec()))?;
+                let res = client.clone().oneshot(req).await?;
+                assert_eq!(res.status(), status);
+                assert!(res.headers().get(X_PROXY_IDEMPOTENT_REPLAY).is_none());
+            }
+            m.assert_hits(2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_eviction() {
+        let mut store = Store {
+            entries: HashMap::new(),
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+            clock: 0,
+        };
+        let key = |k: &str| {
+            (
+                String::new(),
+                k.to_string(),
+                "POST".to_string(),
+                "/".to_string(),
+            )
+        };
+        let hash = [0; 32];
+        let done = || {
+            Some(StoredResponse {
+                status: StatusCode::CREATED,
+                headers: HeaderMap::new(),
+                body: Bytes::new(),
+                trailers: None,
+            })
+        };
+
+        assert!(matches!(store.lookup(&key("a"), hash), Lookup::Forward));
+        assert!(matches!(store.lookup(&key("a"), hash), Lookup::InFlight));
+        store.complete(key("a"), hash, done());
+        assert!(matches!(store.lookup(&key("b"), hash), Lookup::Forward));
+        store.complete(key("b"), hash, done());
+        // touch "a" so "b" is the least recently used
+        assert!(matches!(store.lookup(&key("a"), hash), Lookup::Replay(_)));
+        assert!(matches!(store.lookup(&key("c"), hash), Lookup::Forward));
+        assert!(matches!(store.lookup(&key("a"), hash), Lookup::Replay(_)));
+        assert!(matches!(store.lookup(&key("b"), hash), Lookup::Forward));
+
+        assert!(matches!(store.lookup(&key("a"), [1; 32]), Lookup::Mismatch));
+
+        // failed requests are forgotten
+        store.complete(key("b"), hash, None);
+        assert!(matches!(store.lookup(&key("b"), hash), Lookup::Forward));
+    }
+}
@@ -0,0 +1,148 @@
+//! Waiting for a key with room.
+//!
+//! The upstream limits the requests each key has in flight on top of its
+//! rate limits. With `key_pool.max_in_flight`, the pool passes over keys
+//! with that many requests in flight, and [`InFlightLayer`] makes requests
+//! that would use a pooled key wait while every key has, until a request
+//! completes or `in_flight_wait_ms` passes. Requests that waited that long
+//! go with the active key regardless, for the upstream to have the last
+//! word.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use http::{header::AUTHORIZATION, Request};
+use tower::{Layer, Service};
+
+use crate::{auth::KeyPool, context::ProxyContext, metrics::Metric};
+
+const WAITED: Metric = Metric::counter(
+    "proxy_key_in_flight_waits_total",
+    "Requests that waited for a pooled key with room, by whether one freed up in time.",
+);
+
+#[derive(Clone)]
+pub struct InFlightLayer {
+    keys: KeyPool,
+}
+
+impl InFlightLayer {
+    pub fn new(keys: KeyPool) -> Self {
+        Self { keys }
+    }
+}
+
+impl<S> Layer<S> for InFlightLayer {
+    type Service = WaitForRoom<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        WaitForRoom {
+            inner: service,
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WaitForRoom<S> {
+    inner: S,
+    keys: KeyPool,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for WaitForRoom<S>
+where
+    S: Service<Request<ReqBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        // requests with their own key are none of the pool's business
+        let pooled = match ProxyContext::of(&req) {
+            Some(context) => context.get().caller.is_none(),
+            None => !req.headers().contains_key(AUTHORIZATION),
+        };
+        if !pooled || !self.keys.is_saturated() {
+            return Box::pin(inner.call(req));
+        }
+        let keys = self.keys.clone();
+        Box::pin(async move {
+            let room = keys.wait_for_room().await;
+            WAITED.increment(&[("freed", if room { "true" } else { "false" })]);
+            if !room {
+                tracing::warn!("every key saturated, sending with the active key");
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthLayer, KeyPoolConfig};
+    use http::Response;
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let config = KeyPoolConfig {
+            max_in_flight: Some(1),
+            in_flight_wait_ms: Some(2_000),
+            ..Default::default()
+        };
+        let keys = KeyPool::from(vec!["a", "b"]).with_config(&config);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let service = ServiceBuilder::new()
+            .layer(InFlightLayer::new(keys.clone()))
+            .layer(AuthLayer::new(keys.clone()))
+            .service(service_fn({
+                let sent = sent.clone();
+                move |req: Request<()>| {
+                    let key = req.headers()[AUTHORIZATION].to_str().unwrap().to_owned();
+                    sent.lock().unwrap().push(key);
+                    async {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        Ok::<_, Infallible>(Response::new(()))
+                    }
+                }
+            }));
+        let send = || {
+            let req = Request::get("/v6/device").body(()).unwrap();
+            service.clone().oneshot(req)
+        };
+
+        let started = Instant::now();
+        let (a, b, c) = tokio::join!(send(), send(), send());
+        a.unwrap();
+        b.unwrap();
+        c.unwrap();
+        // the second spilled over to `b`, the third waited for room
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[..2], ["Bearer a", "Bearer b"]);
+        assert!(!keys.is_saturated());
+        // spilling over is not rotating
+        assert_eq!(keys.active_key().as_deref(), Some("a"));
+    }
+}
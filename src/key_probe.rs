@@ -0,0 +1,174 @@
+//! Startup probe of the pool keys.
+//!
+//! The pool starts out with the first key of `BALENA_API_KEY`, whatever
+//! state it is in. With `key_probe`, every key makes a request to `path` at
+//! startup, `concurrency` at a time, before the proxy listens. Outcomes and
+//! quotas are recorded as for any request, keys the upstream rejects are
+//! removed or quarantined as their status says, and [`KeyPool::rank`] orders
+//! the pool so the first requests use the healthiest key. Keys without an
+//! answer within `timeout_ms` count as failed.
+
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use http::{header::AUTHORIZATION, Method, Request, Uri};
+use http_body::Body as HttpBody;
+use hyper::{client::connect::Connect, Client};
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::{
+    auth::{KeyAction, KeyPool},
+    forward_request::forward_uri,
+    secret::ApiKey,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyProbeConfig {
+    /// Request made with each key, relative to the upstream.
+    pub path: String,
+    /// Keys probed at once.
+    pub concurrency: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for KeyProbeConfig {
+    fn default() -> Self {
+        Self {
+            path: "/device?$select=id&$top=1".to_owned(),
+            concurrency: 4,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// Make the probe request with `key` and record how it went.
+async fn probe_key<C, B>(
+    config: &KeyProbeConfig,
+    keys: &KeyPool,
+    client: &Client<C, B>,
+    uri: &Uri,
+    key: ApiKey,
+) where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: HttpBody + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let mut req = Request::builder()
+        .method(Method::GET)
+        .uri(uri.clone())
+        .body(B::from(Bytes::new()))
+        .expect("valid probe request");
+    if let Some(bearer) = key.bearer() {
+        req.headers_mut().insert(AUTHORIZATION, bearer);
+    }
+    let label = keys.label(&key);
+    let started = Instant::now();
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let res = match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(err)) => {
+            tracing::warn!(key = %label, %err, "key probe failed");
+            keys.record_outcome(&key, true, started.elapsed());
+            return;
+        }
+        Err(_) => {
+            tracing::warn!(key = %label, "key probe timed out");
+            keys.record_outcome(&key, true, timeout);
+            return;
+        }
+    };
+    let status = res.status();
+    let failed = !status.is_success();
+    keys.record_outcome(&key, failed, started.elapsed());
+    keys.record_quota(&key, status, res.headers());
+    match keys.action(status) {
+        Some(KeyAction::Remove) => {
+            if keys.remove_key(&key) {
+                tracing::error!(key = %label, status = status.as_u16(), "key rejected by the upstream, removed");
+            }
+        }
+        Some(KeyAction::Quarantine) => keys.quarantine_key(&key, status),
+        Some(KeyAction::Rotate) | Some(KeyAction::Ignore) | None => {
+            tracing::info!(key = %label, status = status.as_u16(), latency = ?started.elapsed(), "key probed")
+        }
+    }
+}
+
+/// Probe every key of `keys` against the upstream at `upstream`, then rank
+/// the pool by the results.
+pub async fn probe<C, B>(
+    config: &KeyProbeConfig,
+    keys: &KeyPool,
+    client: Client<C, B>,
+    upstream: &Uri,
+) -> Result<(), BoxError>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: HttpBody + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let uri = forward_uri(upstream, &Uri::from_str(&config.path)?);
+    stream::iter(keys.keys())
+        .map(|key| probe_key(config, keys, &client, &uri, key))
+        .buffer_unordered(config.concurrency.max(1))
+        .collect::<()>()
+        .await;
+    keys.rank();
+    if let Some(key) = keys.active_key() {
+        tracing::info!(key = %keys.label(&key), "starting with the healthiest key");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING};
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn test_probe() -> Result<(), BoxError> {
+        // `revoked` is rejected, `low` and `high` have that much quota left
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                assert_eq!(req.uri().path(), "/v6/device");
+                let key = req.headers()[AUTHORIZATION].to_str().unwrap().to_owned();
+                let mut res = Response::new(Body::empty());
+                let remaining = match key.as_str() {
+                    "Bearer revoked" => {
+                        *res.status_mut() = StatusCode::UNAUTHORIZED;
+                        return Ok::<_, Infallible>(res);
+                    }
+                    "Bearer low" => "3",
+                    _ => "900",
+                };
+                let headers = res.headers_mut();
+                headers.insert(X_RATELIMIT_LIMIT, "1000".parse().unwrap());
+                headers.insert(X_RATELIMIT_REMAINING, remaining.parse().unwrap());
+                Ok(res)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let upstream: Uri = format!("http://{}/v6", server.local_addr()).parse()?;
+        tokio::spawn(server);
+
+        let keys = KeyPool::from(vec!["revoked", "low", "high"]);
+        let config = KeyProbeConfig::default();
+        probe(&config, &keys, Client::<_, Body>::new(), &upstream).await?;
+        assert_eq!(keys.keys(), vec![ApiKey::from("high"), ApiKey::from("low")]);
+        assert_eq!(keys.active_key(), Some(ApiKey::from("high")));
+        Ok(())
+    }
+}
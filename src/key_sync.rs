@@ -0,0 +1,271 @@
+//! Key pool state shared by proxy replicas.
+//!
+//! When several proxies use the same `BALENA_API_KEY` keys, a key revoked or
+//! rate limited upstream would cost each of them a request to find out.
+//! [`spawn`] publishes the removals and rotations of the local [`KeyPool`] on
+//! a Redis channel and applies those of the other replicas. Keys go out as
+//! their [`key_id`], the hash logs and metrics know them by, and replicas only
+//! act on the keys they hold. Redis being down only means replicas find out
+//! for themselves, as without sync.
+//!
+//! Anyone able to publish on the channel can bench the keys of every
+//! replica. With `secret_env` set, messages are signed with the shared
+//! secret and those without a valid signature are ignored; without it, the
+//! channel must only be writable by the replicas.
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tower::BoxError;
+
+use crate::{
+    auth::{KeyEvent, KeyPool},
+    context::key_id,
+    hmac::{sign_payload, verify_payload},
+    rng::{HasherRng, Rng},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySyncConfig {
+    /// Redis relaying the changes, e.g. `redis://keys.internal:6379/0`.
+    pub redis_url: String,
+    /// Channel shared by the replicas.
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    /// Environment variable holding a secret shared by the replicas to sign
+    /// their messages, unsigned when unset.
+    pub secret_env: Option<String>,
+}
+
+fn default_channel() -> String {
+    "proxy:keys".to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    Removed,
+    Rotated,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    /// The replica publishing, which ignores its own messages.
+    instance: u64,
+    change: Change,
+    key: String,
+    /// Signature of the fields above, with the shared secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl Message {
+    fn new(instance: u64, change: Change, key: &str, secret: Option<&[u8]>) -> Self {
+        let mut message = Self {
+            instance,
+            change,
+            key: key_id(key),
+            signature: None,
+        };
+        message.signature = secret.map(|secret| sign_payload(secret, &message.signed()));
+        message
+    }
+
+    /// What the signature covers.
+    fn signed(&self) -> Vec<u8> {
+        let change = match self.change {
+            Change::Removed => "removed",
+            Change::Rotated => "rotated",
+        };
+        format!("{}\n{}\n{}\n", self.instance, change, self.key).into_bytes()
+    }
+
+    fn is_signed_with(&self, secret: &[u8]) -> bool {
+        self.signature
+            .as_ref()
+            .is_some_and(|signature| verify_payload(secret, &self.signed(), signature.as_bytes()))
+    }
+}
+
+/// Apply the change of another replica to `keys`, if signed with `secret`
+/// when there is one.
+fn apply(keys: &KeyPool, instance: u64, secret: Option<&[u8]>, message: Message) {
+    if message.instance == instance {
+        return;
+    }
+    if secret.is_some_and(|secret| !message.is_signed_with(secret)) {
+        tracing::warn!("key sync: ignoring message without a valid signature");
+        return;
+    }
+    let Some(key) = keys
+        .keys()
+        .into_iter()
+        .find(|key| key_id(key) == message.key)
+    else {
+        return;
+    };
+    match message.change {
+        Change::Removed => {
+            if keys.remove_key(&key) {
+                tracing::warn!(key = %keys.label(&key), "key removed by another replica");
+            }
+        }
+        Change::Rotated => keys.rotate_key(&key),
+    }
+}
+
+/// Check the Redis URL of `config` and keep `keys` in sync in the background.
+pub fn spawn(config: KeySyncConfig, keys: KeyPool) -> Result<(), BoxError> {
+    let client = redis::Client::open(config.redis_url.as_str())
+        .map_err(|err| format!("key sync redis_url: {}", err))?;
+    let secret = config
+        .secret_env
+        .as_ref()
+        .map(|name| {
+            std::env::var(name)
+                .map(String::into_bytes)
+                .map_err(|err| format!("key sync secret_env: {}: {}", err, name))
+        })
+        .transpose()?;
+    let instance = HasherRng::default().next_u64();
+    tokio::spawn(publish(
+        client.clone(),
+        config.channel.clone(),
+        instance,
+        secret.clone(),
+        keys.clone(),
+    ));
+    tokio::spawn(subscribe(client, config.channel, instance, secret, keys));
+    Ok(())
+}
+
+async fn publish(
+    client: redis::Client,
+    channel: String,
+    instance: u64,
+    secret: Option<Vec<u8>>,
+    keys: KeyPool,
+) {
+    let mut events = keys.events();
+    let mut connection: Option<ConnectionManager> = None;
+    loop {
+        let (change, key) = match events.recv().await {
+            Ok(KeyEvent::Removed(key)) => (Change::Removed, key),
+            Ok(KeyEvent::Rotated(key)) => (Change::Rotated, key),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "key sync fell behind");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let message = Message::new(instance, change, &key, secret.as_deref());
+        if connection.is_none() {
+            match ConnectionManager::new(client.clone()).await {
+                Ok(conn) => connection = Some(conn),
+                Err(err) => tracing::warn!(%err, "key sync: cannot connect to redis"),
+            }
+        }
+        if let Some(connection) = &mut connection {
+            let payload = serde_json::to_string(&message).expect("message serializes");
+            if let Err(err) = connection.publish::<_, _, ()>(&channel, payload).await {
+                tracing::warn!(%err, "key sync: cannot publish");
+            }
+        }
+    }
+}
+
+async fn subscribe(
+    client: redis::Client,
+    channel: String,
+    instance: u64,
+    secret: Option<Vec<u8>>,
+    keys: KeyPool,
+) {
+    loop {
+        if let Err(err) = listen(&client, &channel, instance, secret.as_deref(), &keys).await {
+            tracing::warn!(%err, "key sync: subscription lost");
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn listen(
+    client: &redis::Client,
+    channel: &str,
+    instance: u64,
+    secret: Option<&[u8]>,
+    keys: &KeyPool,
+) -> Result<(), BoxError> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match serde_json::from_str(&payload) {
+            Ok(message) => apply(keys, instance, secret, message),
+            Err(err) => tracing::warn!(%err, "key sync: invalid message"),
+        }
+    }
+    Err("subscription closed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(instance: u64, change: Change, key: &str) -> Message {
+        Message::new(instance, change, key, None)
+    }
+
+    #[test]
+    fn test_apply() {
+        let keys = KeyPool::from(vec!["a", "b", "c"]);
+        let mut events = keys.events();
+
+        apply(&keys, 1, None, message(2, Change::Rotated, "a"));
+        assert_eq!(keys.active_key().as_deref(), Some("b"));
+        // rotations of keys no longer active are stale
+        apply(&keys, 1, None, message(2, Change::Rotated, "a"));
+        assert_eq!(keys.active_key().as_deref(), Some("b"));
+
+        apply(&keys, 1, None, message(2, Change::Removed, "a"));
+        assert_eq!(keys.keys(), ["b", "c"]);
+        assert_eq!(keys.active_key().as_deref(), Some("b"));
+        // own messages and unknown keys are ignored
+        apply(&keys, 1, None, message(1, Change::Removed, "b"));
+        apply(&keys, 1, None, message(2, Change::Removed, "z"));
+        assert_eq!(keys.keys(), ["b", "c"]);
+        // changes from other replicas are not published again
+        assert!(events.try_recv().is_err());
+
+        keys.remove_active_key();
+        assert_eq!(events.try_recv().unwrap(), KeyEvent::Removed("b".into()));
+    }
+
+    #[test]
+    fn test_signed_messages() {
+        let keys = KeyPool::from(vec!["a", "b"]);
+        let secret = Some(&b"shared"[..]);
+
+        // unsigned and forged messages are ignored
+        apply(&keys, 1, secret, message(2, Change::Removed, "a"));
+        let forged = Message::new(2, Change::Removed, "a", Some(b"guess"));
+        apply(&keys, 1, secret, forged);
+        let mut tampered = Message::new(2, Change::Removed, "b", secret);
+        tampered.key = key_id("a");
+        apply(&keys, 1, secret, tampered);
+        assert_eq!(keys.keys(), ["a", "b"]);
+
+        apply(
+            &keys,
+            1,
+            secret,
+            Message::new(2, Change::Removed, "a", secret),
+        );
+        assert_eq!(keys.keys(), ["b"]);
+    }
+}
@@ -0,0 +1,463 @@
+//! Last-known responses, kept on disk for offline mode.
+//!
+//! A device rebooting while offline loses whatever it knew of the API, and
+//! its local consumers get errors until the connection is back.
+//! [`LastKnownLayer`] writes the latest successful GET response of the routes
+//! configured to a [`LastKnownStore`] directory, one JSON file per request,
+//! by path and query, by the upstream a gateway sent the request to, and by
+//! the caller's own key, if any. The store is
+//! loaded again on startup. When a GET fails without a response or gets a
+//! 502 or 504, the last-known response is served in its place with
+//! `X-Proxy-Fallback: stored`, before any static fallback. Only the status,
+//! body and content headers are kept, and bodies over `max_body_bytes` are
+//! not kept at all.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LAST_MODIFIED},
+    HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    context::caller,
+    fallback::X_PROXY_FALLBACK,
+    forward_request::Upstream,
+    metrics::Metric,
+    read_request_body::{buffer, FromBuffered},
+    route::RouteMatcher,
+};
+
+const SERVED: Metric = Metric::counter(
+    "proxy_last_known_served_total",
+    "Stored responses served while the upstream was unreachable.",
+);
+
+/// Response headers kept with a stored body.
+const KEPT_HEADERS: [HeaderName; 4] = [CONTENT_TYPE, CONTENT_ENCODING, ETAG, LAST_MODIFIED];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LastKnownConfig {
+    pub directory: PathBuf,
+    /// Routes whose GET responses are kept.
+    pub routes: Vec<RouteMatcher>,
+    /// Responses kept, the least recently stored are dropped beyond this.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Larger bodies are passed on without being kept.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_max_entries() -> usize {
+    1_000
+}
+
+fn default_max_body_bytes() -> usize {
+    1 << 20
+}
+
+/// A response as kept on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredResponse {
+    key: String,
+    stored_at: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Base64 of the body, as it came.
+    body: String,
+}
+
+impl StoredResponse {
+    fn to_response<B: From<Bytes>>(&self) -> Result<Response<B>, BoxError> {
+        let body = Bytes::from(STANDARD.decode(&self.body)?);
+        let mut res = Response::new(B::from(body.clone()));
+        *res.status_mut() = StatusCode::from_u16(self.status)?;
+        let headers = res.headers_mut();
+        for (name, value) in &self.headers {
+            headers.insert(HeaderName::try_from(name)?, HeaderValue::from_str(value)?);
+        }
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        headers.insert(X_PROXY_FALLBACK, HeaderValue::from_static("stored"));
+        Ok(res)
+    }
+}
+
+/// Latest responses persisted as one JSON file each in a directory, and
+/// held in memory.
+#[derive(Clone)]
+pub struct LastKnownStore {
+    dir: Arc<PathBuf>,
+    max_entries: usize,
+    entries: Arc<Mutex<HashMap<String, Arc<StoredResponse>>>>,
+}
+
+impl LastKnownStore {
+    /// Open the store, loading the responses kept by a previous run.
+    pub fn open(dir: &Path, max_entries: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut entries = HashMap::new();
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<StoredResponse>(&data)) {
+                Ok(Ok(entry)) => {
+                    entries.insert(entry.key.clone(), Arc::new(entry));
+                }
+                _ => tracing::warn!(path = %path.display(), "skipping unreadable stored response"),
+            }
+        }
+        if !entries.is_empty() {
+            tracing::info!(entries = entries.len(), dir = %dir.display(), "loaded last-known responses");
+        }
+        Ok(Self {
+            dir: Arc::new(dir.to_path_buf()),
+            max_entries,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let name = hex::encode(&Sha256::digest(key.as_bytes())[..16]);
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn get(&self, key: &str) -> Option<Arc<StoredResponse>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    /// Keep `entry`, in memory at once and then on disk. Blocks on file
+    /// I/O, so run it off the runtime.
+    fn put(&self, entry: StoredResponse) -> io::Result<()> {
+        static WRITES: AtomicU64 = AtomicU64::new(0);
+
+        let data = serde_json::to_vec(&entry)?;
+        let path = self.path(&entry.key);
+        let oldest = {
+            let mut entries = self.entries.lock().unwrap();
+            let oldest = if !entries.contains_key(&entry.key) && entries.len() >= self.max_entries {
+                entries
+                    .values()
+                    .min_by_key(|entry| OffsetDateTime::parse(&entry.stored_at, &Rfc3339).ok())
+                    .map(|entry| entry.key.clone())
+            } else {
+                None
+            };
+            if let Some(oldest) = &oldest {
+                entries.remove(oldest);
+            }
+            entries.insert(entry.key.clone(), Arc::new(entry));
+            oldest
+        };
+        // the files are written outside the lock, lookups don't wait on disk
+        if let Some(oldest) = oldest {
+            if let Err(err) = fs::remove_file(self.path(&oldest)) {
+                tracing::warn!(%err, "failed to remove stored response");
+            }
+        }
+        // write then rename, a crash never leaves a truncated entry behind;
+        // each write has its own temporary file, concurrent ones don't mix
+        let tmp = path.with_extension(format!("{}.tmp", WRITES.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What a response to `req` is kept by: the caller's key, if it brought
+/// its own, the upstream a gateway picked, if any, and the path and query.
+fn store_key<B>(req: &Request<B>) -> String {
+    let caller = caller(req).unwrap_or_default();
+    let upstream = req
+        .extensions()
+        .get::<Upstream>()
+        .map(|Upstream(uri)| uri.to_string())
+        .unwrap_or_default();
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    format!("{} {} {}", caller, upstream, path)
+}
+
+#[derive(Clone)]
+pub struct LastKnownLayer {
+    store: LastKnownStore,
+    config: Arc<LastKnownConfig>,
+}
+
+impl LastKnownLayer {
+    pub fn new(config: LastKnownConfig) -> Result<Self, BoxError> {
+        let store = LastKnownStore::open(&config.directory, config.max_entries)
+            .map_err(|err| format!("last-known store {}: {}", config.directory.display(), err))?;
+        Ok(Self {
+            store,
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn store(&self) -> &LastKnownStore {
+        &self.store
+    }
+}
+
+impl<S> Layer<S> for LastKnownLayer {
+    type Service = LastKnown<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LastKnown {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LastKnown<S> {
+    inner: S,
+    layer: LastKnownLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LastKnown<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: http_body::Body<Data = Bytes> + From<Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let kept = req.method() == Method::GET
+            && self
+                .layer
+                .config
+                .routes
+                .iter()
+                .any(|route| route.matches(&req));
+        let key = kept.then(|| store_key(&req));
+        let fut = self.inner.call(req);
+        let Some(key) = key else {
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let res = match fut.await.map_err(Into::into) {
+                Ok(res) if res.status() == StatusCode::OK => res,
+                Ok(res)
+                    if !matches!(
+                        res.status(),
+                        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT
+                    ) =>
+                {
+                    return Ok(res)
+                }
+                // unreachable, answer with what was known
+                res => {
+                    let Some(stored) = layer.store.get(&key) else {
+                        return res;
+                    };
+                    match stored.to_response() {
+                        Ok(stored) => {
+                            SERVED.increment(&[]);
+                            tracing::warn!("upstream unavailable, serving last-known response");
+                            return Ok(stored);
+                        }
+                        Err(err) => {
+                            tracing::warn!(%err, "unusable stored response");
+                            return res;
+                        }
+                    }
+                }
+            };
+            let (parts, body) = res.into_parts();
+            let (bytes, trailers) = buffer(body).await?;
+            if bytes.len() <= layer.config.max_body_bytes {
+                let headers = KEPT_HEADERS
+                    .iter()
+                    .filter_map(|name| {
+                        let value = parts.headers.get(name)?.to_str().ok()?;
+                        Some((name.to_string(), value.to_owned()))
+                    })
+                    .collect();
+                let entry = StoredResponse {
+                    key,
+                    stored_at: OffsetDateTime::now_utc()
+                        .format(&Rfc3339)
+                        .unwrap_or_default(),
+                    status: parts.status.as_u16(),
+                    headers,
+                    body: STANDARD.encode(&bytes),
+                };
+                // off the runtime, every successful GET of the routes is kept
+                let store = layer.store.clone();
+                let kept = tokio::task::spawn_blocking(move || store.put(entry))
+                    .await
+                    .unwrap_or_else(|err| Err(io::Error::other(err)));
+                if let Err(err) = kept {
+                    tracing::warn!(%err, "failed to keep last-known response");
+                }
+            }
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::X_BALENA_AUTHORIZATION;
+    use hyper::Body;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tower::{service_fn, ServiceExt};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proxy-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_last_known() -> Result<(), BoxError> {
+        let dir = temp_dir("last-known");
+        let config: LastKnownConfig = serde_json::from_value(serde_json::json!({
+            "directory": dir,
+            "routes": [{ "path_prefix": "/device/v3" }],
+            "max_entries": 2,
+        }))?;
+        let online = Arc::new(AtomicBool::new(true));
+        let upstream = service_fn({
+            let online = online.clone();
+            move |req: Request<Body>| {
+                let online = online.load(Ordering::SeqCst);
+                async move {
+                    if !online {
+                        return Err::<Response<Body>, BoxError>("connection refused".into());
+                    }
+                    Ok(Response::builder()
+                        .header(CONTENT_TYPE, "application/json")
+                        .header("set-cookie", "session=1")
+                        .body(Body::from(format!("{{\"path\":\"{}\"}}", req.uri().path())))?)
+                }
+            }
+        });
+        let get = |layer: &LastKnownLayer, path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            layer.layer(upstream.clone()).oneshot(req)
+        };
+
+        let layer = LastKnownLayer::new(config.clone())?;
+        for path in [
+            "/device/v3/a/state",
+            "/device/v3/b/state",
+            "/device/v3/c/state",
+        ] {
+            get(&layer, path).await?;
+        }
+        assert_eq!(layer.store().len(), 2);
+
+        // a reboot while offline
+        online.store(false, Ordering::SeqCst);
+        let layer = LastKnownLayer::new(config)?;
+        assert_eq!(layer.store().len(), 2);
+        let res = get(&layer, "/device/v3/c/state").await?;
+        assert_eq!(res.headers()[X_PROXY_FALLBACK], "stored");
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        assert!(!res.headers().contains_key("set-cookie"));
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body, r#"{"path":"/device/v3/c/state"}"#);
+
+        // dropped as the oldest, and never kept
+        assert!(get(&layer, "/device/v3/a/state").await.is_err());
+        assert!(get(&layer, "/v6/device").await.is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scoped_by_caller_and_upstream() -> Result<(), BoxError> {
+        let dir = temp_dir("last-known-scoped");
+        let config: LastKnownConfig = serde_json::from_value(serde_json::json!({
+            "directory": dir,
+            "routes": [{ "path_prefix": "/v6/device" }],
+        }))?;
+        let online = Arc::new(AtomicBool::new(true));
+        let upstream = service_fn({
+            let online = online.clone();
+            move |req: Request<Body>| {
+                let online = online.load(Ordering::SeqCst);
+                async move {
+                    if !online {
+                        return Err::<Response<Body>, BoxError>("connection refused".into());
+                    }
+                    let caller = req.headers()[X_BALENA_AUTHORIZATION].clone();
+                    Ok(Response::new(Body::from(caller.to_str()?.to_owned())))
+                }
+            }
+        });
+        let layer = LastKnownLayer::new(config)?;
+        let get = |caller: &str, host: Option<&str>| {
+            let mut req = Request::get("/v6/device")
+                .header(X_BALENA_AUTHORIZATION, format!("Bearer {}", caller))
+                .body(Body::empty())
+                .unwrap();
+            if let Some(host) = host {
+                req.extensions_mut().insert(Upstream(host.parse().unwrap()));
+            }
+            let service = layer.layer(upstream.clone());
+            async move {
+                let res = service.oneshot(req).await?;
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>(String::from_utf8(body.to_vec())?)
+            }
+        };
+
+        get("c1", None).await?;
+        get("c2", None).await?;
+        get("c1", Some("https://api.b.example")).await?;
+        assert_eq!(layer.store().len(), 3);
+
+        online.store(false, Ordering::SeqCst);
+        assert_eq!(get("c1", None).await?, "Bearer c1");
+        assert_eq!(get("c2", None).await?, "Bearer c2");
+        assert!(get("c3", None).await.is_err());
+        assert!(get("c2", Some("https://api.b.example")).await.is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,708 @@
+//! Balena API proxy, as a library.
+//!
+//! The `proxy` binary builds its stack with [`build_service`] from a
+//! [`Config`] read from JSON. Embedders can build the same [`Config`] in code
+//! instead, from the typed sections of each module, e.g.
+//! [`retry::RetryConfig`] or [`idempotency::IdempotencyConfig`] with routes
+//! built as a [`route::RouteConfig`], check it with [`Config::validate`],
+//! which checks the routes of every section, and stack the layers they need
+//! on their own services.
+
+use std::{str::FromStr, sync::Arc, time::Instant};
+
+use access::AccessLayer;
+use admin::Admin;
+use analytics::AnalyticsSink;
+#[cfg(feature = "auth")]
+use auth::{AuthLayer, KeyPool};
+use baggage::BaggageLayer;
+use bytes::Bytes;
+use cli::Args;
+use compression::{CompressLayer, DecompressLayer};
+use config::{Config, DEFAULT_UPSTREAM};
+use content_type::ContentTypeLayer;
+use context::{ContextLayer, X_BALENA_AUTHORIZATION};
+use contract::ContractLayer;
+use dns::FailoverConnector;
+use dry_run::DryRun;
+use environment::EnvironmentLayer;
+use error_page::ErrorPageLayer;
+use expand_limit::ExpandLimitLayer;
+use expect::ExpectLayer;
+use fallback::FallbackLayer;
+use fan_out::FanOutLayer;
+use fault::FaultLayer;
+use forward_request::ForwardRequestLayer;
+use gateway::GatewayLayer;
+use graphql::GraphQlLayer;
+use header_limit::HeaderLimitLayer;
+#[cfg(feature = "auth")]
+use hedge::HedgeLayer;
+use hmac::{SignLayer, VerifyLayer};
+#[cfg(feature = "auth")]
+use hold::HoldLayer;
+use http::{
+    header::{HeaderName, AUTHORIZATION, HOST},
+    Uri,
+};
+use http_body::{combinators::UnsyncBoxBody, Body as _};
+use https::HttpsConnector;
+use hyper::{Body, Client, Request, Response};
+use idempotency::IdempotencyLayer;
+#[cfg(feature = "auth")]
+use in_flight::InFlightLayer;
+use last_known::LastKnownLayer;
+use log_sampling::SampledMakeSpan;
+use maintenance::{Maintenance, MaintenanceLayer};
+use method_override::MethodOverrideLayer;
+use mock_upstream::MockUpstream;
+use outlier::{OutlierDetector, OutlierLayer, OutlierResolver};
+#[cfg(feature = "auth")]
+use pace::PaceLayer;
+use plugin::{PluginLayer, ProxyPlugin};
+use preconnect::Preconnector;
+use priority::PriorityLayer;
+use read_request_body::{ByteBody, ReadRequestLayer};
+use record::RecordLayer;
+use redirect::FollowRedirectLayer;
+use rename_header::RenameHeaderLayer;
+use request_gzip::RequestGzipLayer;
+use request_id::MakeIntRequestId;
+#[cfg(feature = "cache")]
+use response_cache::CacheLayer;
+use response_limit::ResponseLimitLayer;
+use retry::{with_idempotency_key, without_attempt};
+use sanitize::SanitizeLayer;
+#[cfg(feature = "scripting")]
+use script::ScriptPlugin;
+use shared_limit::SharedLimitLayer;
+use shutdown::Shutdown;
+#[cfg(feature = "retry")]
+use shutdown::ShutdownLayer;
+use sigv4::SigV4Layer;
+use status_map::StatusMapLayer;
+use store_forward::StoreForwardLayer;
+use summarize::SummarizeLayer;
+use supervisor::{SupervisorLayer, BALENA_SUPERVISOR_API_KEY};
+use throttle::{ThrottleLayer, ThrottleUpload};
+use timeout::TimeoutLayer;
+#[cfg(feature = "auth")]
+use token_exchange::TokenExchangeLayer;
+#[cfg(not(all(
+    feature = "auth",
+    feature = "retry",
+    feature = "cache",
+    feature = "websocket"
+)))]
+use tower::layer::util::Identity;
+#[cfg(feature = "retry")]
+use tower::retry::RetryLayer;
+use tower::{
+    util::{BoxCloneService, Either, MapErrLayer, MapRequestLayer},
+    BoxError, ServiceBuilder,
+};
+use tower_http::{
+    map_response_body::MapResponseBodyLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::{DefaultOnRequest, TraceLayer},
+    ServiceBuilderExt,
+};
+use tracing::Level;
+use transform::TransformLayer;
+use upstream_request_id::UpstreamRequestIdLayer;
+use validate::ValidateLayer;
+use webhook::WebhookLayer;
+
+pub mod access;
+#[cfg(feature = "tls")]
+pub mod acme;
+pub mod admin;
+pub mod analytics;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod baggage;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod buffer_pool;
+pub mod cli;
+pub mod compression;
+pub mod config;
+pub mod content_type;
+pub mod context;
+pub mod contract;
+pub mod decrypt;
+pub mod dns;
+pub mod dry_run;
+pub mod environment;
+pub mod error_page;
+pub mod expand_limit;
+pub mod expect;
+pub mod failure;
+pub mod fallback;
+pub mod fan_out;
+pub mod fault;
+pub mod forward_request;
+pub mod gateway;
+pub mod graphql;
+pub mod gzip;
+pub mod header_limit;
+#[cfg(feature = "auth")]
+pub mod hedge;
+pub mod hmac;
+#[cfg(feature = "auth")]
+pub mod hold;
+pub mod https;
+pub mod idempotency;
+#[cfg(feature = "auth")]
+pub mod in_flight;
+#[cfg(feature = "auth")]
+pub mod key_probe;
+#[cfg(feature = "auth")]
+pub mod key_sync;
+pub mod last_known;
+pub mod log_sampling;
+pub mod logging;
+pub mod maintenance;
+pub mod method_override;
+pub mod metrics;
+pub mod mock_upstream;
+pub mod odata;
+pub mod outlier;
+#[cfg(feature = "auth")]
+pub mod pace;
+#[cfg(feature = "tls")]
+pub mod pin;
+pub mod plugin;
+pub mod preconnect;
+pub mod priority;
+pub mod privacy;
+pub mod range;
+pub mod read_request_body;
+pub mod record;
+pub mod redirect;
+pub mod reload;
+pub mod rename_header;
+pub mod replay;
+pub mod request_gzip;
+pub mod request_id;
+#[cfg(feature = "cache")]
+pub mod response_cache;
+pub mod response_limit;
+pub mod retry;
+pub mod rng;
+pub mod route;
+pub mod route_docs;
+pub mod sanitize;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod secret;
+pub mod server;
+pub mod shared_limit;
+pub mod shutdown;
+pub mod sigv4;
+pub mod status_map;
+pub mod store_forward;
+pub mod summarize;
+pub mod supervisor;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod throttle;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "auth")]
+pub mod token_exchange;
+pub mod transform;
+pub mod upstream_request_id;
+pub mod validate;
+pub mod version;
+pub mod webhook;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub const BALENA_API_KEY: &str = "BALENA_API_KEY";
+
+// Balena does not like host header
+fn without_host_header<B>(mut req: Request<B>) -> Request<B> {
+    req.headers_mut().remove(HOST);
+    req
+}
+
+// Register custom plugins here, e.g. `.register(CompanyAuth::new())`.
+#[cfg_attr(not(feature = "scripting"), allow(unused_variables))]
+fn plugins(config: &Config, durable: &Durable) -> Result<PluginLayer, BoxError> {
+    let mut plugins = PluginLayer::new();
+    #[cfg(feature = "scripting")]
+    if !config.scripts.is_empty() {
+        plugins = plugins.register(ScriptPlugin::new(config.scripts.clone())?);
+    }
+    if let Some(analytics) = durable.analytics.clone() {
+        plugins = plugins.register_shared(analytics);
+    }
+    Ok(plugins)
+}
+
+// fn debug_request<B: std::fmt::Debug>(req: Request<B>) -> Request<B> {
+//     tracing::log::trace!("{:?}", req);
+//     req
+// }
+
+/// The proxy stack, boxed so it can be rebuilt on reload.
+pub type ProxyService =
+    BoxCloneService<Request<Body>, Response<UnsyncBoxBody<Bytes, BoxError>>, BoxError>;
+
+/// Layers built once at startup and shared by every rebuilt stack, as they own
+/// durable queues and the workers draining them, the key pool, or state that
+/// must outlive a reload: the recording file, stored idempotent responses,
+/// cached responses, priority queues, throttle buckets, outlier ejections and the analytics
+/// socket. Their config sections keep their startup values.
+pub struct Durable {
+    maintenance: Maintenance,
+    preconnector: Preconnector,
+    shutdown: Shutdown,
+    store_forward_layer: Option<StoreForwardLayer>,
+    last_known_layer: Option<LastKnownLayer>,
+    webhook_layer: Option<WebhookLayer>,
+    record_layer: Option<RecordLayer>,
+    idempotency_layer: Option<IdempotencyLayer>,
+    #[cfg(feature = "cache")]
+    cache_layer: Option<CacheLayer>,
+    priority_layer: Option<PriorityLayer>,
+    throttle_layer: ThrottleLayer,
+    outlier_detector: Option<OutlierDetector>,
+    analytics: Option<Arc<dyn ProxyPlugin>>,
+    #[cfg(feature = "auth")]
+    keys: KeyPool,
+}
+
+impl Durable {
+    pub fn new(config: &Config, #[cfg(feature = "auth")] keys: KeyPool) -> Result<Self, BoxError> {
+        Ok(Durable {
+            maintenance: Maintenance::new(config.maintenance.enabled),
+            preconnector: Preconnector::default(),
+            shutdown: Shutdown::default(),
+            store_forward_layer: config
+                .store_forward
+                .clone()
+                .map(StoreForwardLayer::new)
+                .transpose()?,
+            last_known_layer: config
+                .last_known
+                .clone()
+                .map(LastKnownLayer::new)
+                .transpose()?,
+            webhook_layer: config.webhooks.clone().map(WebhookLayer::new).transpose()?,
+            record_layer: config.recording.clone().map(RecordLayer::new).transpose()?,
+            idempotency_layer: config.idempotency.clone().map(IdempotencyLayer::new),
+            #[cfg(feature = "cache")]
+            cache_layer: config.cache.clone().map(CacheLayer::new),
+            priority_layer: config.priority.clone().map(PriorityLayer::new),
+            throttle_layer: ThrottleLayer::new(config.throttle.clone()),
+            outlier_detector: config.outlier_detection.clone().map(OutlierDetector::new),
+            analytics: match config.analytics.clone() {
+                Some(analytics) => Some(Arc::new(AnalyticsSink::new(analytics)?)),
+                None => None,
+            },
+            #[cfg(feature = "auth")]
+            keys,
+        })
+    }
+
+    /// What stops the listener on SIGTERM, and cuts retry backoffs short.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// The admin API over these layers, its reloader and routes unset.
+    pub fn admin(&self, started: Instant) -> Admin {
+        Admin {
+            queue: self
+                .store_forward_layer
+                .as_ref()
+                .map(StoreForwardLayer::queue),
+            webhooks: self.webhook_layer.as_ref().map(WebhookLayer::queue),
+            #[cfg(feature = "cache")]
+            cache: self.cache_layer.as_ref().map(CacheLayer::cache),
+            reloader: None,
+            routes: None,
+            maintenance: Some(self.maintenance.clone()),
+            #[cfg(feature = "auth")]
+            keys: Some(self.keys.clone()),
+            started: Some(started),
+        }
+    }
+}
+
+/// The lower half of the proxy stack, from where requests are forwarded.
+type ForwardService = BoxCloneService<Request<ByteBody>, Response<Body>, BoxError>;
+
+fn box_error<E: Into<BoxError>>(err: E) -> BoxError {
+    err.into()
+}
+
+fn box_body<B>(body: B) -> UnsyncBoxBody<Bytes, BoxError>
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// Build the proxy stack from `config`.
+pub fn build_service(
+    args: &Args,
+    config: &Config,
+    durable: &Durable,
+) -> Result<ProxyService, BoxError> {
+    let trace_layer = TraceLayer::new_for_http()
+        .make_span_with(SampledMakeSpan::new(config.logging.sampling.clone()))
+        .on_request(DefaultOnRequest::new().level(Level::INFO));
+
+    // let trace_layer = init_tracing();
+
+    // the mock upstream does not need real keys
+    #[cfg(feature = "auth")]
+    let auth_layer = Some(AuthLayer::new(durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let auth_layer: Option<Identity> = None;
+    #[cfg(feature = "auth")]
+    let hold_layer = config
+        .key_hold
+        .clone()
+        .map(|config| HoldLayer::new(config, durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let hold_layer: Option<Identity> = None;
+    #[cfg(feature = "auth")]
+    let hedge_layer = config
+        .hedging
+        .clone()
+        .map(|config| HedgeLayer::new(config, durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let hedge_layer: Option<Identity> = None;
+    #[cfg(feature = "auth")]
+    let in_flight_layer = config
+        .key_pool
+        .max_in_flight
+        .map(|_| InFlightLayer::new(durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let in_flight_layer: Option<Identity> = None;
+    #[cfg(feature = "auth")]
+    let pace_layer = config
+        .pacing
+        .clone()
+        .map(|config| PaceLayer::new(config, durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let pace_layer: Option<Identity> = None;
+    // mock and dry runs have no login endpoint to exchange keys at
+    #[cfg(feature = "auth")]
+    let token_exchange_layer = config
+        .token_exchange
+        .clone()
+        .filter(|_| !args.mock_upstream && !args.dry_run)
+        .map(|config| TokenExchangeLayer::new(config, durable.keys.clone()));
+    #[cfg(not(feature = "auth"))]
+    let token_exchange_layer: Option<Identity> = None;
+    #[cfg(feature = "websocket")]
+    let websocket_layer = Some(websocket::WebSocketLayer);
+    #[cfg(not(feature = "websocket"))]
+    let websocket_layer: Option<Identity> = None;
+    #[cfg(feature = "cache")]
+    let cache_layer = durable.cache_layer.clone();
+    #[cfg(not(feature = "cache"))]
+    let cache_layer: Option<Identity> = None;
+    let request_gzip_layer = config.request_gzip.clone().map(RequestGzipLayer::new);
+    #[cfg(feature = "retry")]
+    let retry_layer = Some(RetryLayer::new(
+        config.retry.policy(durable.shutdown.clone()),
+    ));
+    #[cfg(not(feature = "retry"))]
+    let retry_layer: Option<Identity> = None;
+    #[cfg(feature = "retry")]
+    let shutdown_layer = Some(ShutdownLayer::new(
+        durable.shutdown.clone(),
+        config.retry.shutdown_retry_after_secs,
+    ));
+    #[cfg(not(feature = "retry"))]
+    let shutdown_layer: Option<Identity> = None;
+    let forward_uri = Uri::from_str(config.upstream.as_deref().unwrap_or(DEFAULT_UPSTREAM))?;
+    let redirect_layer = (!config.redirects.is_empty())
+        .then(|| FollowRedirectLayer::new(config.redirects.clone(), forward_uri.clone()));
+    let forward_layer = ForwardRequestLayer::new(forward_uri.clone())
+        .with_overrides(config.forward_overrides.clone())?;
+    let baggage_layer = config.baggage.clone().map(BaggageLayer::new).transpose()?;
+    let upstream_request_id_layer = config
+        .upstream_request_id
+        .clone()
+        .map(UpstreamRequestIdLayer::new)
+        .transpose()?;
+    let fault_layer = (!config.faults.is_empty())
+        .then(|| FaultLayer::new(config.faults.clone()))
+        .transpose()?;
+    let environment_layer = (!config.environments.is_empty())
+        .then(|| EnvironmentLayer::new(config.environments.clone()))
+        .transpose()?;
+    let verify_layer = (!config.hmac.verify.is_empty())
+        .then(|| VerifyLayer::new(config.hmac.verify.clone()))
+        .transpose()?;
+    let sign_layer = (!config.hmac.sign.is_empty())
+        .then(|| SignLayer::new(config.hmac.sign.clone()))
+        .transpose()?;
+    let sigv4_layer = (!config.sigv4.is_empty()).then(|| SigV4Layer::new(config.sigv4.clone()));
+    let shared_limit_layer = config
+        .shared_limit
+        .clone()
+        .map(SharedLimitLayer::new)
+        .transpose()?;
+    let header_limit_layer = (!config.header_limits.is_empty())
+        .then(|| HeaderLimitLayer::new(config.header_limits.clone()));
+    let gateway_layer = config.gateway.clone().map(GatewayLayer::new).transpose()?;
+    let maintenance_layer =
+        MaintenanceLayer::new(config.maintenance.clone(), durable.maintenance.clone())?;
+    let method_override_layer = config.method_override.clone().map(MethodOverrideLayer::new);
+    let access_layer = config.access.clone().map(AccessLayer::new).transpose()?;
+    let expand_limit_layer = (!config.expand_limits.is_empty())
+        .then(|| ExpandLimitLayer::new(config.expand_limits.clone()));
+    let decompress_layer = config
+        .compression
+        .iter()
+        .any(|rule| rule.decompress)
+        .then(|| DecompressLayer::new(config.compression.clone()));
+    let content_type_layer = (!config.content_types.is_empty())
+        .then(|| ContentTypeLayer::new(config.content_types.clone()));
+    let contract_layer = (!config.contracts.is_empty())
+        .then(|| ContractLayer::new(config.contracts.clone()))
+        .transpose()?;
+    let validate_layer = (!config.validation.is_empty())
+        .then(|| ValidateLayer::new(config.validation.clone()))
+        .transpose()?;
+    let transform_layer =
+        (!config.transforms.is_empty()).then(|| TransformLayer::new(config.transforms.clone()));
+    let fan_out_layer = config.fan_out.clone().map(FanOutLayer::new);
+    let graphql_layer = config.graphql.clone().map(GraphQlLayer::new);
+    let summarize_layer =
+        (!config.summarize.is_empty()).then(|| SummarizeLayer::new(config.summarize.clone()));
+    let error_page_layer = (!config.error_pages.is_empty())
+        .then(|| ErrorPageLayer::new(config.error_pages.clone()))
+        .transpose()?;
+    let fallback_layer = (!config.fallbacks.is_empty())
+        .then(|| FallbackLayer::new(config.fallbacks.clone()))
+        .transpose()?;
+    let status_map_layer = (!config.status_map.is_empty())
+        .then(|| StatusMapLayer::new(config.status_map.clone()))
+        .transpose()?;
+    // mock and dry runs stay off the network, the supervisor included
+    let supervisor_layer = config
+        .supervisor
+        .clone()
+        .filter(|_| !args.mock_upstream && !args.dry_run)
+        .map(|config| SupervisorLayer::new(config, std::env::var(BALENA_SUPERVISOR_API_KEY).ok()))
+        .transpose()?;
+    let response_limit_layer = (!config.response_limits.is_empty())
+        .then(|| ResponseLimitLayer::new(config.response_limits.clone()));
+    let outlier_detector = durable
+        .outlier_detector
+        .clone()
+        .filter(|_| !args.mock_upstream && !args.dry_run);
+    let outlier_layer = outlier_detector.clone().map(OutlierLayer::new);
+    let version_layer = config.server.version_header.then(|| {
+        SetResponseHeaderLayer::overriding(
+            HeaderName::from_static(version::X_PROXY_VERSION),
+            version::header_value(),
+        )
+    });
+    let plugin_layer = Some(plugins(config, durable)?).filter(|plugins| !plugins.is_empty());
+    let upstream = if args.dry_run {
+        tracing::warn!("dry run, requests are not sent upstream");
+        Either::B(Either::B(DryRun::new()))
+    } else if args.mock_upstream {
+        tracing::warn!("serving mock upstream fixtures");
+        Either::B(Either::A(MockUpstream::new(config.mock_upstream.clone())))
+    } else {
+        // pin SNI override names to the hosts they stand for
+        // and leave ejected endpoints out
+        let resolver = OutlierResolver::new(forward_layer.resolver(), outlier_detector.clone());
+        // and rotate through the addresses, resolving again when one fails
+        let http = FailoverConnector::new(resolver, config.dns.clone());
+        let https = HttpsConnector::new_with_connector(http);
+        // drop connections to upstreams whose certificate is not pinned
+        #[cfg(feature = "tls")]
+        let https = pin::PinnedConnector::new(https, config.upstream_pins.clone())?;
+        let client = Client::builder().build(https);
+        // keep connections open for the first request after a quiet spell
+        durable
+            .preconnector
+            .restart(config.preconnect.clone(), client.clone(), forward_uri);
+        // and pace uploads by their throttle bucket
+        Either::A(ThrottleUpload::new(client))
+    };
+
+    // The stack is boxed below the retries, from where each attempt is sent,
+    // above where requests are forwarded, and below the layers acting on the
+    // client's view of requests, which keeps the type of each part, and
+    // compile times, in check.
+    let attempt_service: ForwardService = BoxCloneService::new(
+        ServiceBuilder::new()
+            .layer(MapErrLayer::new(box_error))
+            // answer retries with a 503 rather than send them when shutting down
+            .option_layer(shutdown_layer)
+            // sign requests to AWS upstreams instead of using a Balena key
+            .option_layer(sigv4_layer)
+            // sign upstream requests with a shared secret
+            .option_layer(sign_layer)
+            // wait for a key to get its quota back rather than collect 429s
+            .option_layer(hold_layer)
+            // send reads with the next key as well when the active one runs low
+            .option_layer(hedge_layer)
+            // wait for a key with room when every key is saturated
+            .option_layer(in_flight_layer)
+            // assign balena api key if missing, rotate key on 429, remove key on 401
+            .option_layer(auth_layer)
+            // spread the requests of each key out rather than burst into 429s
+            .option_layer(pace_layer)
+            // stay within the per-key limits along with the other replicas
+            .option_layer(shared_limit_layer)
+            // tell downstream tracing which key and attempt this was
+            .option_layer(baggage_layer)
+            // send short-lived session tokens rather than the pool keys
+            .option_layer(token_exchange_layer)
+            // .layer(MapRequestLayer::new(debug_request)) // print request
+            .propagate_x_request_id()
+            // log the upstream's id for the request next to ours
+            .option_layer(upstream_request_id_layer)
+            // inject configured faults instead of calling the upstream
+            .option_layer(fault_layer)
+            // note which upstream endpoints are slow or failing
+            .option_layer(outlier_layer)
+            .service(upstream),
+    );
+
+    let forward_service: ForwardService = BoxCloneService::new(
+        ServiceBuilder::new()
+            .layer(MapErrLayer::new(box_error))
+            // share what layers learn about the request, and log it
+            .layer(ContextLayer)
+            // cut off upstream responses too large for the client
+            .option_layer(response_limit_layer)
+            .layer(forward_layer)
+            // .layer(MapRequestBodyLayer::new(BufBody::new))
+            // let the upstream deduplicate retried writes
+            .layer(MapRequestLayer::new(with_idempotency_key))
+            // persist writes that still fail after retrying, replay them later
+            .option_layer(durable.store_forward_layer.clone())
+            // answer 504 when the caller's deadline passes, retries included;
+            // below the queue, which takes the writes it cuts short
+            .layer(TimeoutLayer::new(config.timeout.clone()))
+            // compress large JSON bodies for upstreams that accept it
+            .option_layer(request_gzip_layer)
+            .option_layer(retry_layer) // retry request if failed
+            .service(attempt_service),
+    );
+
+    let route_service: ForwardService = BoxCloneService::new(
+        ServiceBuilder::new()
+            // report upstream responses breaking their contract
+            .option_layer(contract_layer)
+            // ask the upstream for compressed responses and decode them
+            .option_layer(decompress_layer)
+            // surface upstream statuses the way clients should act on them
+            .option_layer(status_map_layer)
+            // accept webhooks and deliver them in the background
+            .option_layer(durable.webhook_layer.clone())
+            // record sampled request/response pairs as the client sees them
+            .option_layer(durable.record_layer.clone())
+            // answer client retries of writes from memory
+            .option_layer(durable.idempotency_layer.clone())
+            // run registered plugins on everything the upstream gets to see
+            .option_layer(plugin_layer)
+            // send supervisor API requests to the device instead of the cloud
+            .option_layer(supervisor_layer)
+            .layer(RenameHeaderLayer::new(
+                X_BALENA_AUTHORIZATION,
+                AUTHORIZATION,
+            ))
+            .layer(MapRequestLayer::new(without_host_header)) // Balena does not like host header
+            // bound the requests in flight, favouring the important ones
+            .option_layer(durable.priority_layer.clone())
+            // send requests about some devices and fleets to other environments
+            .option_layer(environment_layer)
+            // answer repeated reads from memory, until a write makes them
+            // stale; below the routing to other upstreams, which it keys by
+            .option_layer(cache_layer)
+            // follow upstream redirects for clients that cannot
+            .option_layer(redirect_layer)
+            .service(forward_service),
+    );
+
+    // the stack below batches, which their requests go through as well
+    let request_service: ForwardService = BoxCloneService::new(
+        ServiceBuilder::new()
+            // normalize the paths of batched requests as well
+            .layer(SanitizeLayer::new())
+            // reject requests with too many or too large headers
+            .option_layer(header_limit_layer)
+            // reject tampered requests before anything but sanitization
+            // rewrites them
+            .option_layer(verify_layer)
+            // take the upstream host from the path when serving as a gateway
+            .option_layer(gateway_layer)
+            // answer with a 503 while the upstream is under maintenance
+            .layer(maintenance_layer)
+            // turn POSTs into the method clients behind restrictive proxies meant
+            .option_layer(method_override_layer)
+            // refuse methods and paths outside the allowlist
+            .option_layer(access_layer)
+            // refuse or trim OData expansions over the limits
+            .option_layer(expand_limit_layer)
+            // adapt request and response bodies between clients and the API
+            .option_layer(transform_layer)
+            // cut huge collections down to their first records
+            .option_layer(summarize_layer)
+            // refuse request bodies of types their route does not take
+            .option_layer(content_type_layer)
+            // reject request bodies not matching their schema
+            .option_layer(validate_layer)
+            // show the configured pages for gateway errors
+            .option_layer(error_page_layer)
+            // answer reads with their static fallback while the upstream is away
+            .option_layer(fallback_layer)
+            // keep the latest answers to reads on disk, and serve them offline
+            .option_layer(durable.last_known_layer.clone())
+            .service(route_service),
+    );
+
+    // Use tower's `ServiceBuilder` API to build a stack of tower middleware
+    // wrapping our request handler.
+    let service = ServiceBuilder::new()
+        .layer(MapResponseBodyLayer::new(box_body))
+        // compress responses of the routes configured, unless already encoded;
+        // always there, as it wraps response bodies
+        .layer(CompressLayer::new(config.compression.clone()))
+        // tell which build answered
+        .option_layer(version_layer)
+        .set_x_request_id(MakeIntRequestId::default())
+        // only the retry policy says which attempt a request is
+        .layer(MapRequestLayer::new(without_attempt))
+        .layer(trace_layer)
+        // tunnel WebSockets to the upstream once it switches protocols
+        .option_layer(websocket_layer)
+        // strip 100-continue, met as the body is read, and refuse other
+        // expectations if asked to
+        .layer(ExpectLayer::new(config.server.refuse_expectations))
+        // next layer reads streaming request body before we proceed,
+        // we need it to get retry layer work as it clones request.
+        .layer(
+            ReadRequestLayer::new()
+                .idle_timeout(config.server.body_read_timeout())
+                .max_buffered(config.server.max_buffered_body_bytes),
+        )
+        // normalize the path before any route is matched
+        .layer(SanitizeLayer::new())
+        .layer(durable.throttle_layer.clone())
+        // answer batches by sending their requests down the rest of the stack
+        .option_layer(fan_out_layer)
+        // answer GraphQL queries with OData requests down the rest of the stack
+        .option_layer(graphql_layer)
+        .service(request_service);
+
+    Ok(BoxCloneService::new(service))
+}
@@ -0,0 +1,263 @@
+//! Per-route sampling of request logs.
+//!
+//! [`SampledMakeSpan`] decides for every request whether its routine events
+//! are logged and marks the request span accordingly. [`SamplingFilter`] then
+//! drops the debug/info events inside unsampled spans, but always lets
+//! warnings, errors and responses with an error status through, so failures
+//! and retries stay fully visible.
+
+use std::sync::{Arc, Mutex};
+
+use http::Request;
+use serde::Deserialize;
+use tower_http::trace::MakeSpan;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    subscriber::Interest,
+    Event, Level, Metadata, Span, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Filter},
+    registry::LookupSpan,
+};
+
+use crate::{
+    rng::{HasherRng, Rng},
+    route::RouteMatcher,
+};
+
+/// Name of the request span field carrying the sampling decision.
+const SAMPLED_FIELD: &str = "sampled";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamplingConfig {
+    /// Fraction of successful requests logged when no route matches.
+    pub rate: f64,
+    /// Per-route rates, the first matching route wins.
+    pub routes: Vec<SamplingRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    pub rate: f64,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl SamplingConfig {
+    fn rate<B>(&self, req: &Request<B>) -> f64 {
+        self.routes
+            .iter()
+            .find(|rule| rule.route.matches(req))
+            .map_or(self.rate, |rule| rule.rate)
+    }
+}
+
+/// Creates the request span, recording whether the request is sampled.
+#[derive(Clone)]
+pub struct SampledMakeSpan {
+    config: Arc<SamplingConfig>,
+    // shared so that every clone of the service draws from the same sequence
+    rng: Arc<Mutex<HasherRng>>,
+}
+
+impl SampledMakeSpan {
+    pub fn new(config: SamplingConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            rng: Arc::new(Mutex::new(HasherRng::new())),
+        }
+    }
+
+    fn sample<B>(&self, req: &Request<B>) -> bool {
+        let rate = self.config.rate(req);
+        rate >= 1.0 || self.rng.lock().unwrap().next_f64() < rate
+    }
+}
+
+impl<B> MakeSpan<B> for SampledMakeSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let sampled = self.sample(request);
+        tracing::debug_span!(
+            target: "tower_http::trace::make_span",
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+            headers = ?request.headers(),
+            sampled,
+            upstream_request_id = tracing::field::Empty,
+        )
+    }
+}
+
+/// Span extension marking a request that was not picked for logging.
+struct Unsampled;
+
+/// Per-layer filter dropping routine events of unsampled requests.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingFilter;
+
+impl<S> Filter<S> for SamplingFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, _meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        true
+    }
+
+    fn callsite_enabled(&self, _meta: &'static Metadata<'static>) -> Interest {
+        // decided per event, `always` would skip `enabled` and leave the
+        // per-layer filter state of dropped events behind
+        Interest::sometimes()
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &Context<'_, S>) -> bool {
+        if *event.metadata().level() <= Level::WARN {
+            return true;
+        }
+
+        let unsampled = cx.event_scope(event).is_some_and(|mut scope| {
+            scope.any(|span| span.extensions().get::<Unsampled>().is_some())
+        });
+        if !unsampled {
+            return true;
+        }
+
+        let mut visitor = StatusVisitor(None);
+        event.record(&mut visitor);
+        visitor.0.is_some_and(|status| status >= 400)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, cx: Context<'_, S>) {
+        let mut visitor = SampledVisitor(true);
+        attrs.record(&mut visitor);
+        if !visitor.0 {
+            if let Some(span) = cx.span(id) {
+                // every filtered layer gets here, so the marker may be present already
+                span.extensions_mut().replace(Unsampled);
+            }
+        }
+    }
+}
+
+struct SampledVisitor(bool);
+
+impl Visit for SampledVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == SAMPLED_FIELD {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+struct StatusVisitor(Option<i64>);
+
+impl Visit for StatusVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "status" {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "status" {
+            self.0 = Some(value as i64);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::{layer::SubscriberExt, Layer};
+
+    /// Collects the messages of the events it sees.
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Events {
+        fn on_event(&self, event: &Event<'_>, _cx: Context<'_, S>) {
+            let mut visitor = MessageVisitor(String::new());
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.0);
+        }
+    }
+
+    struct MessageVisitor(String);
+
+    impl Visit for MessageVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rates() {
+        let config: SamplingConfig = serde_json::from_value(serde_json::json!({
+            "rate": 0.25,
+            "routes": [
+                { "route": { "path_prefix": "/v6/device" }, "rate": 0.0 },
+                { "route": { "path_prefix": "/v6/release" }, "rate": 1.0 },
+            ],
+        }))
+        .expect("valid config");
+        let make_span = SampledMakeSpan::new(config);
+        let sampled = |path: &str| {
+            let req = Request::get(path).body(()).expect("request");
+            (0..1000).filter(|_| make_span.sample(&req)).count()
+        };
+
+        assert_eq!(sampled("/v6/device"), 0);
+        assert_eq!(sampled("/v6/release"), 1000);
+        let other = sampled("/v6/application");
+        assert!((150..350).contains(&other), "{}", other);
+    }
+
+    #[test]
+    fn test_filter() {
+        let events = Events::default();
+        let subscriber =
+            tracing_subscriber::registry().with(events.clone().with_filter(SamplingFilter));
+        tracing::subscriber::with_default(subscriber, || {
+            let req = Request::new(());
+            let unsampled = SampledMakeSpan::new(SamplingConfig {
+                rate: 0.0,
+                routes: Vec::new(),
+            })
+            .make_span(&req);
+            unsampled.in_scope(|| {
+                tracing::info!("routine");
+                tracing::info!(status = 200, "finished");
+                tracing::info!(status = 503, "failed");
+                tracing::warn!("retrying");
+            });
+            let sampled = SampledMakeSpan::new(SamplingConfig::default()).make_span(&req);
+            sampled.in_scope(|| tracing::info!("sampled"));
+        });
+
+        // errors and warnings of unsampled requests are kept
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            vec!["failed", "retrying", "sampled"]
+        );
+    }
+}
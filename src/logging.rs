@@ -0,0 +1,319 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use tower::BoxError;
+use tracing::Metadata;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, RollingFileAppender},
+};
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{self, MakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    log_sampling::{SamplingConfig, SamplingFilter},
+    privacy::{PrivacyConfig, Scrubbed, Scrubber},
+};
+
+/// Events emitted by tower-http's `TraceLayer` make up the access log.
+const ACCESS_LOG_TARGET: &str = "tower_http::trace";
+
+/// Overrides the configured log format, e.g. `PROXY_LOG_FORMAT=pretty`.
+pub const PROXY_LOG_FORMAT: &str = "PROXY_LOG_FORMAT";
+
+type BoxLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Application log file, everything except the access log.
+    pub file: Option<FileSink>,
+    /// Access log file, request and response events of the trace layer.
+    pub access_file: Option<FileSink>,
+    pub sampling: SamplingConfig,
+    /// Hash or truncate device UUIDs, addresses and other identifiers in
+    /// every log, see [`crate::privacy`].
+    pub privacy: Option<PrivacyConfig>,
+}
+
+/// A log file with optional rotation and retention.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSink {
+    pub directory: PathBuf,
+    /// File name, used as prefix of the rotated files for time-based rotation.
+    pub file_name: String,
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// How many rotated files to keep, all of them when unset.
+    pub max_files: Option<usize>,
+    /// Format of this file, the global one when unset.
+    pub format: Option<LogFormat>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line human-readable output for local development.
+    Pretty,
+    /// Newline-delimited JSON for log collectors.
+    #[default]
+    Json,
+    /// Terse single-line output, smallest on disk.
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            "compact" => Ok(LogFormat::Compact),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+    /// Rotate once the file grows past the given number of bytes.
+    Size(u64),
+}
+
+impl FileSink {
+    fn writer(&self) -> Result<Box<dyn Write + Send>, BoxError> {
+        let rotation = match self.rotation {
+            Rotation::Never => rolling::Rotation::NEVER,
+            Rotation::Minutely => rolling::Rotation::MINUTELY,
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Size(max_size) => {
+                let path = self.directory.join(&self.file_name);
+                let writer = SizeRollingWriter::new(path, max_size, self.max_files)?;
+                return Ok(Box::new(writer));
+            }
+        };
+
+        let mut builder = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(&self.file_name);
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+        Ok(Box::new(builder.build(&self.directory)?))
+    }
+}
+
+/// Writer that moves `file` to `file.1` (and `file.1` to `file.2`, ...) once
+/// it grows past `max_size` bytes.
+struct SizeRollingWriter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: Option<usize>,
+    file: File,
+    size: u64,
+}
+
+impl SizeRollingWriter {
+    fn new(path: PathBuf, max_size: u64, max_files: Option<usize>) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files != Some(0) {
+            // find the first free slot, or the oldest one we are allowed to keep
+            let mut last = 1;
+            while self.rotated(last).exists() && self.max_files.is_none_or(|m| last < m) {
+                last += 1;
+            }
+            for n in (1..last).rev() {
+                fs::rename(self.rotated(n), self.rotated(n + 1))?;
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRollingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn is_access_log(metadata: &Metadata<'_>) -> bool {
+    metadata.target().starts_with(ACCESS_LOG_TARGET)
+}
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+    }
+}
+
+/// Install the global tracing subscriber.
+///
+/// Logs always go to stdout, and in addition to the configured files. The
+/// returned guards flush the file writers and must be held until exit.
+pub fn init(config: &LoggingConfig) -> Result<Vec<WorkerGuard>, BoxError> {
+    let format = match std::env::var(PROXY_LOG_FORMAT) {
+        Ok(value) => value.parse()?,
+        Err(_) => config.format,
+    };
+
+    let scrubber = config
+        .privacy
+        .clone()
+        .map(Scrubber::new)
+        .transpose()?
+        .map(Arc::new);
+
+    let mut guards = Vec::new();
+    // colors would split fields from their values
+    let ansi = scrubber.is_none();
+    let mut layers = vec![fmt_layer(
+        format,
+        Scrubbed::new(io::stdout, scrubber.clone()),
+        ansi,
+    )];
+
+    if let Some(sink) = &config.file {
+        let (writer, guard) = tracing_appender::non_blocking(sink.writer()?);
+        guards.push(guard);
+        let layer = fmt_layer(
+            sink.format.unwrap_or(format),
+            Scrubbed::new(writer, scrubber.clone()),
+            false,
+        )
+        .with_filter(filter_fn(|m| m.is_span() || !is_access_log(m)));
+        layers.push(layer.boxed());
+    }
+
+    if let Some(sink) = &config.access_file {
+        let (writer, guard) = tracing_appender::non_blocking(sink.writer()?);
+        guards.push(guard);
+        let layer = fmt_layer(
+            sink.format.unwrap_or(format),
+            Scrubbed::new(writer, scrubber.clone()),
+            false,
+        )
+        .with_filter(filter_fn(is_access_log));
+        layers.push(layer.boxed());
+    }
+
+    let layers: Vec<BoxLayer> = layers
+        .into_iter()
+        .map(|layer| layer.with_filter(SamplingFilter).boxed())
+        .collect();
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "proxy=trace,tower_http=debug".into()),
+        )
+        .init();
+
+    Ok(guards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_rolling() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("proxy-logging-{}", std::process::id()));
+        let path = dir.join("proxy.log");
+        let mut writer = SizeRollingWriter::new(path.clone(), 10, Some(2))?;
+
+        writer.write_all(b"first\n")?;
+        writer.write_all(b"one\n")?;
+        // would grow past 10 bytes
+        writer.write_all(b"second\n")?;
+        writer.write_all(b"third\n")?;
+        writer.write_all(b"fourth\n")?;
+        // a line longer than the limit still gets a file of its own
+        writer.write_all(b"longer than the limit\n")?;
+        writer.flush()?;
+
+        let read = |path: PathBuf| fs::read_to_string(path);
+        assert_eq!(read(path.clone())?, "longer than the limit\n");
+        assert_eq!(read(writer.rotated(1))?, "fourth\n");
+        assert_eq!(read(writer.rotated(2))?, "third\n");
+        // the oldest file is dropped past `max_files`
+        assert!(!writer.rotated(3).exists());
+
+        // the size of an existing file counts towards the limit
+        drop(writer);
+        let mut writer = SizeRollingWriter::new(path.clone(), 30, Some(2))?;
+        writer.write_all(b"appended\n")?;
+        writer.flush()?;
+        assert_eq!(read(path.clone())?, "appended\n");
+        assert_eq!(read(writer.rotated(1))?, "longer than the limit\n");
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
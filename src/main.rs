@@ -1,99 +1,161 @@
-#![allow(dead_code)]
-
-use std::{net::SocketAddr, str::FromStr};
+#[cfg(feature = "auth")]
+use std::str::FromStr;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use auth::{AuthLayer, KeyPool};
-use forward_request::ForwardRequestLayer;
-use http::{
-    header::{AUTHORIZATION, HOST},
-    Uri,
+use clap::Parser;
+#[cfg(feature = "auth")]
+use http::Uri;
+#[cfg(feature = "auth")]
+use hyper::{Body, Client};
+#[cfg(feature = "bench")]
+use proxy::bench;
+#[cfg(all(feature = "auth", feature = "tls"))]
+use proxy::pin;
+#[cfg(feature = "auth")]
+use proxy::{
+    auth::{self, KeyPool},
+    config::DEFAULT_UPSTREAM,
+    decrypt::{self, DecryptorConfig},
+    https::HttpsConnector,
+    key_probe, key_sync,
+    secret::ApiKey,
+    BALENA_API_KEY,
 };
-use hyper::{Client, Request, Server};
-use hyper_tls::HttpsConnector;
-use read_request_body::ReadRequestLayer;
-use rename_header::RenameHeaderLayer;
-use request_id::MakeIntRequestId;
-use retry::{ExponentialBackoff, WithBackoff};
-use tower::{make::Shared, retry::RetryLayer, util::MapRequestLayer, BoxError, ServiceBuilder};
-use tower_http::{
-    trace::{DefaultMakeSpan, DefaultOnRequest, TraceLayer},
-    ServiceBuilderExt,
+use proxy::{
+    build_service,
+    cli::{Args, Command},
+    config::{Config, PROXY_CONFIG},
+    logging, reload, replay,
+    route_docs::RouteDocs,
+    server, Durable,
 };
-use tracing::Level;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tower::BoxError;
+#[cfg(feature = "auth")]
+use zeroize::Zeroizing;
 
-mod auth;
-mod forward_request;
-mod read_request_body;
-mod rename_header;
-mod request_id;
-mod retry;
-mod rng;
-
-const X_BALENA_AUTHORIZATION: &str = "x-balena-authorization";
-const BALENA_API_KEY: &str = "BALENA_API_KEY";
-
-// Balena does not like host header
-fn without_host_header<B>(mut req: Request<B>) -> Request<B> {
-    req.headers_mut().remove(HOST);
-    req
-}
-
-// fn debug_request<B: std::fmt::Debug>(req: Request<B>) -> Request<B> {
-//     tracing::log::trace!("{:?}", req);
-//     req
-// }
+// count allocations for `proxy bench`
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOC: bench::CountingAlloc = bench::CountingAlloc;
 
 #[tokio::main]
 async fn main() -> Result<(), BoxError> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "proxy=trace,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
+    let started = Instant::now();
+    let mut args = Args::parse();
+    #[allow(unused_mut)]
+    let mut config = Config::load()?;
+    let _log_guards = logging::init(&config.logging)?;
+
+    #[cfg(feature = "bench")]
+    let mut bench = None;
+    match args.command.take() {
+        Some(Command::Replay(args)) => return replay::run(args).await,
+        // benchmarks run against the mock upstream, with or without fixtures
+        #[cfg(feature = "bench")]
+        Some(Command::Bench(bench_args)) => {
+            args.mock_upstream = true;
+            if config.mock_upstream.is_empty() {
+                config.mock_upstream = bench::default_fixtures();
+            }
+            bench = Some(bench_args);
+        }
+        None => {}
+    }
 
-    let trace_layer = TraceLayer::new_for_http()
-        .make_span_with(DefaultMakeSpan::new().include_headers(true))
-        .on_request(DefaultOnRequest::new().level(Level::INFO));
+    // the mock upstream does not need real keys
+    #[cfg(feature = "auth")]
+    let keys = {
+        let balena_api_key = match std::env::var(BALENA_API_KEY) {
+            Ok(value) => Zeroizing::new(value),
+            Err(_) if args.mock_upstream || args.dry_run => Zeroizing::default(),
+            Err(err) => panic!("{}: {}", err, BALENA_API_KEY),
+        };
+        let keys = balena_api_key
+            .split(',')
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let decryptor = config
+            .key_decryption
+            .as_ref()
+            .map(DecryptorConfig::build)
+            .transpose()?;
+        let keys = decrypt::decrypt_keys(keys, decryptor.as_deref()).await?;
+        KeyPool::new(keys.into_iter().map(ApiKey::from).collect()).with_config(&config.key_pool)
+    };
+    // start with the healthiest key rather than the first one listed
+    #[cfg(feature = "auth")]
+    if let Some(probe) = config
+        .key_probe
+        .as_ref()
+        .filter(|_| !args.mock_upstream && !args.dry_run)
+    {
+        let upstream = Uri::from_str(config.upstream.as_deref().unwrap_or(DEFAULT_UPSTREAM))?;
+        let https = HttpsConnector::new();
+        #[cfg(feature = "tls")]
+        let https = pin::PinnedConnector::new(https, config.upstream_pins.clone())?;
+        let client = Client::builder().build::<_, Body>(https);
+        key_probe::probe(probe, &keys, client, &upstream).await?;
+    }
+    // share key removals and rotations with the other replicas
+    #[cfg(feature = "auth")]
+    if let Some(key_sync) = config.key_sync.clone() {
+        key_sync::spawn(key_sync, keys.clone())?;
+    }
+    // rotate keys on schedule, not only on errors
+    #[cfg(feature = "auth")]
+    if let Some(secs) = config.key_pool.rotate_every_secs.filter(|&secs| secs > 0) {
+        auth::spawn_rotation(keys.clone(), Duration::from_secs(secs));
+    }
 
-    // let trace_layer = init_tracing();
+    let durable = Durable::new(
+        &config,
+        #[cfg(feature = "auth")]
+        keys,
+    )?;
+    // stop accepting on SIGTERM, and cut retry backoffs short
+    let shutdown = durable.shutdown();
+    shutdown.watch_signals();
+    let mut admin = durable.admin(started);
 
-    let balena_api_key =
-        std::env::var(BALENA_API_KEY).unwrap_or_else(|err| panic!("{}: {}", err, BALENA_API_KEY));
-    let keys = KeyPool::from(balena_api_key.split(',').collect::<Vec<&str>>());
-    let retry_policy = WithBackoff::new(3, ExponentialBackoff::default());
-    let forward_uri = Uri::from_str("https://api.balena-cloud.com/v6").unwrap();
+    // the listener, logging and the durable layers keep their startup config
+    let service = build_service(&args, &config, &durable)?;
+    #[cfg(feature = "bench")]
+    if let Some(bench) = bench {
+        return bench::run(bench, service).await;
+    }
+    let route_docs = RouteDocs::default();
+    route_docs.set(&config);
+    admin.routes = Some(route_docs.clone());
+    #[cfg(feature = "auth")]
+    let key_pool = config.key_pool.clone();
+    let (service, reloader) = reload::reloadable(service, move || {
+        let config = Config::load()?;
+        // the pool and its keys live as long as the process
+        #[cfg(feature = "auth")]
+        if config.key_pool != key_pool {
+            return Err("key_pool changed, it is read at startup only".into());
+        }
+        let service = build_service(&args, &config, &durable)?;
+        route_docs.set(&config);
+        Ok(service)
+    });
+    admin.reloader = Some(reloader.clone());
+    tokio::spawn(reload::watch(
+        reloader,
+        std::env::var_os(PROXY_CONFIG).map(PathBuf::from),
+        config.reload.watch_interval_secs.map(Duration::from_secs),
+    ));
 
-    // Use tower's `ServiceBuilder` API to build a stack of tower middleware
-    // wrapping our request handler.
-    let service = ServiceBuilder::new()
-        .set_x_request_id(MakeIntRequestId::default())
-        // next layer reads streaming request body before we proceed,
-        // we need it to get retry layer work as it clones request.
-        .layer(ReadRequestLayer::new())
-        .layer(trace_layer)
-        .layer(RenameHeaderLayer::new(
-            X_BALENA_AUTHORIZATION,
-            AUTHORIZATION,
-        ))
-        .layer(MapRequestLayer::new(without_host_header)) // Balena does not like host header
-        .layer(ForwardRequestLayer::new(forward_uri))
-        // .layer(MapRequestBodyLayer::new(BufBody::new))
-        .layer(RetryLayer::new(retry_policy)) // retry request if failed
-        // assign balena api key if missing, rotate key on 429, remove key on 401
-        .layer(AuthLayer::new(keys))
-        // .layer(MapRequestLayer::new(debug_request)) // print request
-        .propagate_x_request_id()
-        .service(Client::builder().build(HttpsConnector::new()));
+    if let Some(admin_config) = &config.admin {
+        tokio::spawn(admin.serve(admin_config.listen));
+    }
 
     // And run our service using `hyper`
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    Server::bind(&addr)
-        .serve(Shared::new(service))
-        .await
-        .expect("server error");
+    server::serve(&config.server, service, shutdown).await?;
 
     Ok(())
 }
@@ -0,0 +1,212 @@
+//! Maintenance mode.
+//!
+//! During planned upstream maintenance, forwarding requests only collects
+//! errors, retries and alerts. While [`Maintenance`] is on, [`MaintenanceLayer`]
+//! answers requests to the configured routes, all of them when none is
+//! configured, with a 503, a `Retry-After` and the configured body, without
+//! forwarding them. It is switched with `POST` and `DELETE /maintenance` on
+//! the admin API, and starts as `enabled` says; the switch outlives reloads.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    HeaderValue, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{metrics::Metric, route::RouteMatcher};
+
+const REJECTED: Metric = Metric::counter(
+    "proxy_maintenance_rejected_total",
+    "Requests answered with a 503 during maintenance.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Whether maintenance is on at startup.
+    pub enabled: bool,
+    /// Routes under maintenance, all when empty.
+    pub routes: Vec<RouteMatcher>,
+    /// `Retry-After` of the 503s.
+    pub retry_after_secs: u64,
+    /// Body of the 503s, a JSON error when unset.
+    pub body: Option<String>,
+    /// Content type of `body`.
+    pub content_type: Option<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            routes: Vec::new(),
+            retry_after_secs: 300,
+            body: None,
+            content_type: None,
+        }
+    }
+}
+
+/// The maintenance switch.
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance(Arc<AtomicBool>);
+
+impl Maintenance {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        if self.0.swap(enabled, Ordering::Relaxed) != enabled {
+            tracing::warn!(enabled, "maintenance mode switched");
+        }
+    }
+}
+
+struct Page {
+    routes: Vec<RouteMatcher>,
+    retry_after: HeaderValue,
+    body: Bytes,
+    content_type: HeaderValue,
+}
+
+#[derive(Clone)]
+pub struct MaintenanceLayer {
+    switch: Maintenance,
+    page: Arc<Page>,
+}
+
+impl MaintenanceLayer {
+    /// Answer requests as `config` says while `switch` is on.
+    pub fn new(config: MaintenanceConfig, switch: Maintenance) -> Result<Self, BoxError> {
+        let (body, content_type) = match config.body {
+            Some(body) => (
+                Bytes::from(body),
+                config
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("text/plain; charset=utf-8"),
+            ),
+            None => (
+                Bytes::from(serde_json::json!({ "error": "under maintenance" }).to_string()),
+                "application/json",
+            ),
+        };
+        Ok(Self {
+            switch,
+            page: Arc::new(Page {
+                routes: config.routes,
+                retry_after: HeaderValue::from(config.retry_after_secs),
+                body,
+                content_type: HeaderValue::from_str(content_type)?,
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for MaintenanceLayer {
+    type Service = UnderMaintenance<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        UnderMaintenance {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UnderMaintenance<S> {
+    inner: S,
+    layer: MaintenanceLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for UnderMaintenance<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let page = &self.layer.page;
+        let under_maintenance = self.layer.switch.is_enabled()
+            && (page.routes.is_empty() || page.routes.iter().any(|route| route.matches(&req)));
+        if !under_maintenance {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        REJECTED.increment(&[]);
+        let mut res = Response::new(ResBody::from(page.body.clone()));
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, page.content_type.clone());
+        res.headers_mut()
+            .insert(RETRY_AFTER, page.retry_after.clone());
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::MockUpstream;
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_maintenance() -> Result<(), BoxError> {
+        let switch = Maintenance::default();
+        let layer = MaintenanceLayer::new(
+            serde_json::from_value(serde_json::json!({
+                "routes": [{ "path_prefix": "/v6/device" }],
+                "retry_after_secs": 60,
+            }))?,
+            switch.clone(),
+        )?;
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+        let status = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let service = service.clone();
+            async move { service.oneshot(req).await }
+        };
+
+        assert_eq!(status("/v6/device").await?.status(), StatusCode::NOT_FOUND);
+        switch.set(true);
+        let res = status("/v6/device(1)").await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "60");
+        // other routes are still forwarded
+        assert_eq!(status("/v6/release").await?.status(), StatusCode::NOT_FOUND);
+        switch.set(false);
+        assert_eq!(status("/v6/device").await?.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
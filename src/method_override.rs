@@ -0,0 +1,159 @@
+//! `X-HTTP-Method-Override` support.
+//!
+//! Some clients sit behind intermediaries that only let GET and POST
+//! through. [`MethodOverrideLayer`] turns a POST carrying the override
+//! header into the method it names, provided the method is allowed, and
+//! refuses the request with a 400 otherwise.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderValue, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+pub const X_HTTP_METHOD_OVERRIDE: &str = "x-http-method-override";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MethodOverrideConfig {
+    /// Methods a POST may be turned into.
+    pub allow: Vec<String>,
+}
+
+impl Default for MethodOverrideConfig {
+    fn default() -> Self {
+        Self {
+            allow: vec!["PATCH".into(), "PUT".into(), "DELETE".into()],
+        }
+    }
+}
+
+impl MethodOverrideConfig {
+    /// The method `req` asks for, `Err` when the override is not allowed.
+    fn method<B>(&self, req: &Request<B>) -> Result<Option<Method>, String> {
+        let value = match req.headers().get(X_HTTP_METHOD_OVERRIDE) {
+            Some(value) if req.method() == Method::POST => value,
+            _ => return Ok(None),
+        };
+        let name = value
+            .to_str()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_uppercase();
+        if !self.allow.iter().any(|m| m.eq_ignore_ascii_case(&name)) {
+            return Err(name);
+        }
+        Method::from_bytes(name.as_bytes())
+            .map(Some)
+            .map_err(|_| name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MethodOverrideLayer {
+    config: Arc<MethodOverrideConfig>,
+}
+
+impl MethodOverrideLayer {
+    pub fn new(config: MethodOverrideConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for MethodOverrideLayer {
+    type Service = MethodOverride<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MethodOverride {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MethodOverride<S> {
+    inner: S,
+    config: Arc<MethodOverrideConfig>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MethodOverride<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let method = match self.config.method(&req) {
+            Ok(method) => method,
+            Err(name) => {
+                tracing::warn!(method = name, "method override refused");
+                let body = serde_json::json!({
+                    "error": "method override not allowed",
+                    "method": name,
+                });
+                let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+                *res.status_mut() = StatusCode::BAD_REQUEST;
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                return Box::pin(async move { Ok(res) });
+            }
+        };
+
+        if let Some(method) = method {
+            tracing::debug!(%method, "overriding POST");
+            req.headers_mut().remove(X_HTTP_METHOD_OVERRIDE);
+            *req.method_mut() = method;
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, value: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri("/v6/device(1)")
+            .header(X_HTTP_METHOD_OVERRIDE, value)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_method() {
+        let config = MethodOverrideConfig::default();
+        assert_eq!(
+            config.method(&request(Method::POST, "patch")),
+            Ok(Some(Method::PATCH))
+        );
+        assert_eq!(
+            config.method(&request(Method::POST, "CONNECT")),
+            Err("CONNECT".into())
+        );
+        // only POST can be overridden
+        assert_eq!(config.method(&request(Method::GET, "DELETE")), Ok(None));
+    }
+}
@@ -0,0 +1,335 @@
+//! Process-wide counters, gauges and histograms.
+//!
+//! Modules declare their metrics as [`Metric`] constants and update them with
+//! label values; the admin API renders them all in the Prometheus text format
+//! on `/metrics`, or in the OpenMetrics one for scrapers asking for it, which
+//! adds the exemplars of histogram buckets: the trace id of the latest
+//! observation in each, linking a latency spike to a trace. Series are kept
+//! in one map behind a lock, which is plenty for the handful of updates a
+//! request makes.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// Observations counted in buckets by upper bound.
+    Histogram(&'static [f64]),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// The trace id an observation was made for, and its value.
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations up to each bound, not cumulated.
+    counts: Vec<u64>,
+    /// Latest exemplar of each bucket, `+Inf`'s last.
+    exemplars: Vec<Option<Exemplar>>,
+    count: u64,
+    sum: f64,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Labels, f64>,
+    histograms: BTreeMap<Labels, Histogram>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+
+impl Metric {
+    pub const fn counter(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: Kind::Counter,
+        }
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: Kind::Gauge,
+        }
+    }
+
+    /// A histogram counting observations up to each of `buckets`, which
+    /// are ascending.
+    pub const fn histogram(
+        name: &'static str,
+        help: &'static str,
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            kind: Kind::Histogram(buckets),
+        }
+    }
+
+    fn family<T>(&self, update: impl FnOnce(&mut Family) -> T) -> T {
+        let mut registry = REGISTRY.lock().unwrap();
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            help: self.help,
+            kind: self.kind,
+            series: BTreeMap::new(),
+            histograms: BTreeMap::new(),
+        });
+        update(family)
+    }
+
+    fn update(&self, labels: &[(&'static str, &str)], update: impl FnOnce(&mut f64)) {
+        let labels = owned(labels);
+        self.family(|family| update(family.series.entry(labels).or_insert(0.0)));
+    }
+
+    /// Count `value` in its bucket, with the trace it was made for as the
+    /// bucket's exemplar.
+    pub fn observe(&self, labels: &[(&'static str, &str)], value: f64, trace_id: Option<&str>) {
+        let Kind::Histogram(buckets) = self.kind else {
+            debug_assert!(false, "{} is not a histogram", self.name);
+            return;
+        };
+        let labels = owned(labels);
+        self.family(|family| {
+            let histogram = family.histograms.entry(labels).or_default();
+            if histogram.counts.is_empty() {
+                histogram.counts = vec![0; buckets.len() + 1];
+                histogram.exemplars = vec![None; buckets.len() + 1];
+            }
+            let bucket = buckets
+                .iter()
+                .position(|&bound| value <= bound)
+                .unwrap_or(buckets.len());
+            histogram.counts[bucket] += 1;
+            histogram.count += 1;
+            histogram.sum += value;
+            if let Some(trace_id) = trace_id {
+                histogram.exemplars[bucket] = Some(Exemplar {
+                    trace_id: trace_id.to_owned(),
+                    value,
+                });
+            }
+        });
+    }
+
+    pub fn increment(&self, labels: &[(&'static str, &str)]) {
+        self.add(labels, 1.0);
+    }
+
+    /// Add `value` to the series; gauges may go down.
+    pub fn add(&self, labels: &[(&'static str, &str)], value: f64) {
+        debug_assert!(self.kind == Kind::Gauge || value >= 0.0);
+        self.update(labels, |series| *series += value);
+    }
+
+    pub fn set(&self, labels: &[(&'static str, &str)], value: f64) {
+        debug_assert!(self.kind == Kind::Gauge);
+        self.update(labels, |series| *series = value);
+    }
+
+    /// The current value of a series, zero when never updated.
+    pub fn get(&self, labels: &[(&'static str, &str)]) -> f64 {
+        let registry = REGISTRY.lock().unwrap();
+        let Some(family) = registry.get(self.name) else {
+            return 0.0;
+        };
+        family
+            .series
+            .iter()
+            .find(|(series, _)| {
+                series.len() == labels.len()
+                    && series
+                        .iter()
+                        .zip(labels)
+                        .all(|((n, v), (name, value))| n == name && v == value)
+            })
+            .map_or(0.0, |(_, value)| *value)
+    }
+
+    /// The sum of the series with `labels` among theirs.
+    pub fn sum(&self, labels: &[(&'static str, &str)]) -> f64 {
+        let registry = REGISTRY.lock().unwrap();
+        let Some(family) = registry.get(self.name) else {
+            return 0.0;
+        };
+        family
+            .series
+            .iter()
+            .filter(|(series, _)| {
+                labels
+                    .iter()
+                    .all(|(name, value)| series.iter().any(|(n, v)| n == name && v == value))
+            })
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// The sum of all series.
+    pub fn total(&self) -> f64 {
+        let registry = REGISTRY.lock().unwrap();
+        registry
+            .get(self.name)
+            .map_or(0.0, |family| family.series.values().sum())
+    }
+}
+
+fn owned(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// `{name="value",...}`, with `extra` last, or nothing without labels.
+fn label_set(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// All series, in the Prometheus text exposition format.
+pub fn render() -> String {
+    render_format(false)
+}
+
+/// All series, in the OpenMetrics text format, with exemplars.
+pub fn render_openmetrics() -> String {
+    render_format(true)
+}
+
+fn render_format(openmetrics: bool) -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+        // OpenMetrics names counter families without their `_total`
+        let family_name = match family.kind {
+            Kind::Counter if openmetrics => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let _ = writeln!(out, "# HELP {} {}", family_name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family_name, kind);
+        for (labels, value) in &family.series {
+            let _ = writeln!(out, "{}{} {}", name, label_set(labels, None), value);
+        }
+        let Kind::Histogram(buckets) = family.kind else {
+            continue;
+        };
+        for (labels, histogram) in &family.histograms {
+            let mut cumulated = 0;
+            let bounds = buckets.iter().map(|bound| bound.to_string());
+            for (bucket, le) in bounds.chain(["+Inf".to_owned()]).enumerate() {
+                cumulated += histogram.counts[bucket];
+                let _ = write!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    label_set(labels, Some(("le", &le))),
+                    cumulated
+                );
+                if let (true, Some(exemplar)) = (openmetrics, &histogram.exemplars[bucket]) {
+                    let _ = write!(
+                        out,
+                        " # {{trace_id=\"{}\"}} {}",
+                        escape(&exemplar.trace_id),
+                        exemplar.value
+                    );
+                }
+                out.push('\n');
+            }
+            let labels = label_set(labels, None);
+            let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+        }
+    }
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTS: Metric = Metric::counter("test_requests_total", "Requests seen.");
+    const QUEUED: Metric = Metric::gauge("test_queued", "Requests queued.");
+
+    #[test]
+    fn test_render() {
+        REQUESTS.increment(&[("route", "devices")]);
+        REQUESTS.add(&[("route", "devices")], 2.0);
+        REQUESTS.increment(&[("route", "say \"hi\"")]);
+        QUEUED.set(&[], 4.0);
+        QUEUED.add(&[], -1.0);
+        assert_eq!(REQUESTS.get(&[("route", "devices")]), 3.0);
+
+        let out = render();
+        assert!(out.contains(
+            "# HELP test_requests_total Requests seen.\n# TYPE test_requests_total counter\n"
+        ));
+        assert!(out.contains("test_requests_total{route=\"devices\"} 3\n"));
+        assert!(out.contains("test_requests_total{route=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(out.contains("# TYPE test_queued gauge\ntest_queued 3\n"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        const LATENCY: Metric = Metric::histogram("test_latency_seconds", "Latency.", &[0.1, 1.0]);
+        LATENCY.observe(&[("route", "device")], 0.05, None);
+        LATENCY.observe(&[("route", "device")], 0.5, Some("4bf92f3577b34da6"));
+        LATENCY.observe(&[("route", "device")], 3.0, None);
+
+        let out = render();
+        assert!(out.contains("# TYPE test_latency_seconds histogram\n"));
+        assert!(out.contains("test_latency_seconds_bucket{route=\"device\",le=\"0.1\"} 1\n"));
+        assert!(out.contains("test_latency_seconds_bucket{route=\"device\",le=\"1\"} 2\n"));
+        assert!(out.contains("test_latency_seconds_bucket{route=\"device\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_latency_seconds_sum{route=\"device\"} 3.55\n"));
+        assert!(out.contains("test_latency_seconds_count{route=\"device\"} 3\n"));
+        assert!(!out.contains("4bf92f3577b34da6"));
+
+        let out = render_openmetrics();
+        assert!(out.contains(
+            "test_latency_seconds_bucket{route=\"device\",le=\"1\"} 2 # {trace_id=\"4bf92f3577b34da6\"} 0.5\n"
+        ));
+        assert!(out.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Canned upstream responses for local development and integration tests.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{header::CONTENT_TYPE, HeaderName, HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use tower::Service;
+
+use crate::{range, route::RouteMatcher};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// Matched against the upstream request, e.g. `{"path_prefix": "/v6/device"}`.
+    #[serde(default)]
+    pub route: RouteMatcher,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Sent as is when a string, serialized as JSON otherwise.
+    pub body: Option<serde_json::Value>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Fixture {
+    fn response(&self) -> Response<Bytes> {
+        let mut res = Response::new(Bytes::new());
+        *res.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+
+        match &self.body {
+            Some(serde_json::Value::String(text)) => {
+                *res.body_mut() = Bytes::from(text.clone());
+            }
+            Some(value) => {
+                *res.body_mut() = Bytes::from(value.to_string());
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            }
+            None => (),
+        }
+
+        for (name, value) in &self.headers {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    res.headers_mut().insert(name, value);
+                }
+                _ => tracing::warn!(%name, "invalid fixture header"),
+            }
+        }
+
+        res
+    }
+}
+
+/// Service answering requests from fixtures instead of calling the upstream.
+///
+/// The first matching fixture wins, unmatched requests get a 404.
+#[derive(Clone)]
+pub struct MockUpstream {
+    fixtures: Arc<Vec<Fixture>>,
+}
+
+impl MockUpstream {
+    pub fn new(fixtures: Vec<Fixture>) -> Self {
+        Self {
+            fixtures: Arc::new(fixtures),
+        }
+    }
+}
+
+impl<B> Service<Request<B>> for MockUpstream {
+    type Response = Response<Body>;
+
+    type Error = Infallible;
+
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let res = match self.fixtures.iter().find(|f| f.route.matches(&req)) {
+            // fixtures are full bodies, ranges of them are served here
+            Some(fixture) => range::serve_range(req.headers(), fixture.response()).map(Body::from),
+            None => {
+                tracing::warn!(method = %req.method(), path = req.uri().path(), "no mock fixture");
+                let mut res = Response::new(Body::from(r#"{"error":"no mock fixture"}"#));
+                *res.status_mut() = StatusCode::NOT_FOUND;
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                res
+            }
+        };
+        ready(Ok(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use http::header::RANGE;
+    use tower::{BoxError, ServiceExt};
+
+    #[tokio::test]
+    async fn test_fixture_file() -> Result<(), BoxError> {
+        let path = std::env::temp_dir().join(format!("proxy-mock-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{ "mock_upstream": [
+                { "route": { "path_prefix": "/v6/device" }, "body": { "id": 1 } },
+                { "route": { "path": "/v6/status" }, "status": 503, "body": "down",
+                  "headers": { "retry-after": "5" } }
+            ] }"#,
+        )?;
+        let config = Config::from_file(&path);
+        std::fs::remove_file(&path)?;
+        let mock = MockUpstream::new(config?.mock_upstream);
+
+        let req = Request::get("/v6/device/42").body(Body::empty())?;
+        let res = mock.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, r#"{"id":1}"#);
+
+        let req = Request::get("/v6/status").body(Body::empty())?;
+        let res = mock.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["retry-after"], "5");
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "down");
+
+        let req = Request::get("/v6/device/42")
+            .header(RANGE, "bytes=1-4")
+            .body(Body::empty())?;
+        let res = mock.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, r#""id""#);
+
+        let req = Request::get("/v6/status/extra").body(Body::empty())?;
+        let res = mock.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! OData query shapes of Balena API requests.
+//!
+//! A GET of every device of an account is far heavier than the lookup of one
+//! device, yet both go to `/v6/device`. [`ODataQuery`] tells them apart by
+//! their resource, key, `$filter` and `$select`, and [`ODataMatcher`] lets
+//! route rules, priority classes included, select GETs by that shape.
+
+use std::sync::OnceLock;
+
+use http::{Method, Uri};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::sanitize::percent_decode;
+
+/// The shape of an OData request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ODataQuery {
+    /// Resource, e.g. `device` of `/v6/device`.
+    pub resource: String,
+    /// Key in parentheses, e.g. `123` of `/v6/device(123)`.
+    pub key: Option<String>,
+    pub filter: Option<String>,
+    pub select: Vec<String>,
+}
+
+fn decode(s: &str) -> String {
+    String::from_utf8_lossy(&percent_decode(&s.replace('+', " "))).into_owned()
+}
+
+impl ODataQuery {
+    /// The shape of a request to `uri`, skipping an API version segment.
+    pub fn parse(uri: &Uri) -> Option<ODataQuery> {
+        let mut segments = uri.path().split('/').filter(|s| !s.is_empty());
+        let mut segment = segments.next()?;
+        if segment.strip_prefix('v').is_some_and(|version| {
+            !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit())
+        }) {
+            segment = segments.next()?;
+        }
+        let (resource, key) = match segment.split_once('(') {
+            Some((resource, key)) => (resource, Some(key.trim_end_matches(')'))),
+            None => (segment, None),
+        };
+        let mut query = ODataQuery {
+            resource: decode(resource),
+            key: key.map(decode),
+            ..Default::default()
+        };
+        for param in uri.query().unwrap_or_default().split('&') {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match decode(name).as_str() {
+                "$filter" => query.filter = Some(decode(value)),
+                "$select" => {
+                    query.select = decode(value)
+                        .split(',')
+                        .map(|field| field.trim().to_owned())
+                        .filter(|field| !field.is_empty())
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        Some(query)
+    }
+
+    /// Whether the request is about one entity, by key or by a filter on the
+    /// equality of its `id` or `uuid`.
+    pub fn is_single(&self) -> bool {
+        static SINGLE: OnceLock<Regex> = OnceLock::new();
+        let single = SINGLE.get_or_init(|| {
+            Regex::new(r"^\s*\(?\s*(id|uuid)\s+eq\s+('[^']*'|\d+)\s*\)?\s*$").unwrap()
+        });
+        self.key.is_some() || self.filter.as_deref().is_some_and(|f| single.is_match(f))
+    }
+
+    /// The `uuid` the request is about, from a `(uuid='...')` key or a filter
+    /// on its equality.
+    pub fn uuid(&self) -> Option<&str> {
+        static UUID: OnceLock<Regex> = OnceLock::new();
+        let uuid = UUID.get_or_init(|| {
+            Regex::new(r"^\s*\(?\s*uuid\s*(=|\s+eq\s+)\s*'([^']*)'\s*\)?\s*$").unwrap()
+        });
+        [self.key.as_deref(), self.filter.as_deref()]
+            .into_iter()
+            .flatten()
+            .find_map(|s| uuid.captures(s)?.get(2))
+            .map(|m| m.as_str())
+    }
+}
+
+/// Selects GETs by their OData query shape.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ODataMatcher {
+    /// Resources, e.g. `device`, `application` or `release`, any when empty.
+    pub resources: Vec<String>,
+    /// Lookups of one entity only when true, other queries only when false.
+    pub single: Option<bool>,
+    /// Queries with a `$filter` only when true, without only when false.
+    pub filtered: Option<bool>,
+    /// Queries with a `$select` only when true, without only when false.
+    pub selected: Option<bool>,
+}
+
+impl ODataMatcher {
+    pub fn matches(&self, method: &Method, uri: &Uri) -> bool {
+        if method != Method::GET {
+            return false;
+        }
+        let Some(query) = ODataQuery::parse(uri) else {
+            return false;
+        };
+        (self.resources.is_empty() || self.resources.contains(&query.resource))
+            && self.single.is_none_or(|single| single == query.is_single())
+            && self
+                .filtered
+                .is_none_or(|filtered| filtered == query.filter.is_some())
+            && self
+                .selected
+                .is_none_or(|selected| selected != query.select.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_odata_query() {
+        let query = ODataQuery::parse(&Uri::from_static(
+            "/v6/device?%24filter=uuid%20eq%20'abc'&$select=id,device_name",
+        ))
+        .unwrap();
+        assert_eq!(query.resource, "device");
+        assert_eq!(query.filter.as_deref(), Some("uuid eq 'abc'"));
+        assert_eq!(query.select, ["id", "device_name"]);
+        assert!(query.is_single());
+        assert_eq!(query.uuid(), Some("abc"));
+
+        let query = ODataQuery::parse(&Uri::from_static("/v6/device(uuid='def')")).unwrap();
+        assert_eq!(query.uuid(), Some("def"));
+
+        let query = ODataQuery::parse(&Uri::from_static("/v6/release(42)")).unwrap();
+        assert_eq!(
+            (query.resource.as_str(), query.key.as_deref()),
+            ("release", Some("42"))
+        );
+        assert!(query.is_single());
+
+        let query = ODataQuery::parse(&Uri::from_static(
+            "/v6/device?$filter=belongs_to__application%20eq%201%20or%20id%20eq%202",
+        ))
+        .unwrap();
+        assert!(!query.is_single());
+        assert_eq!(query.uuid(), None);
+        assert_eq!(ODataQuery::parse(&Uri::from_static("/v6")), None);
+    }
+
+    #[test]
+    fn test_odata_matcher() {
+        // every device of the account, the heaviest of queries
+        let all_devices = ODataMatcher {
+            resources: vec!["device".to_owned()],
+            single: Some(false),
+            filtered: Some(false),
+            ..Default::default()
+        };
+        let get = |uri| all_devices.matches(&Method::GET, &Uri::from_static(uri));
+        assert!(get("/v6/device"));
+        assert!(get("/v6/device?$select=uuid"));
+        assert!(!get("/v6/device(1)"));
+        assert!(!get("/v6/device?$filter=is_online%20eq%20true"));
+        assert!(!get("/v6/application"));
+        assert!(!all_devices.matches(&Method::POST, &Uri::from_static("/v6/device")));
+    }
+}
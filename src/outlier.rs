@@ -0,0 +1,404 @@
+//! Ejection of misbehaving upstream endpoints.
+//!
+//! The upstream host may resolve to several addresses, and one slow or
+//! failing instance behind it drags every request down with it. Much like
+//! Envoy's outlier detection, [`OutlierLayer`] records the latency and
+//! outcome of each upstream response against the address it came from.
+//! Every `interval_secs`, endpoints with enough requests whose p99 latency is
+//! over `p99_factor` times the median p99 of the others are ejected, as are
+//! endpoints right after `consecutive_errors` failures in a row.
+//!
+//! Ejected endpoints are left out of what [`OutlierResolver`] resolves the
+//! upstream host to, so new connections go to the others, for
+//! `base_ejection_secs` times the number of times they were ejected, after
+//! which they are reinstated. At most `max_ejection_percent` of the known
+//! endpoints are ejected at once, and the host is never resolved to nothing.
+//! Connections already open to an ejected endpoint are not closed early.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+    vec,
+};
+
+use futures_core::Future;
+use http::{Request, Response};
+use hyper::client::connect::{dns::Name, HttpInfo};
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::{metrics::Metric, replay::percentile};
+
+const EJECTIONS: Metric = Metric::counter(
+    "proxy_upstream_ejections_total",
+    "Upstream endpoints ejected for their latency or errors.",
+);
+pub const EJECTED: Metric = Metric::gauge(
+    "proxy_upstream_ejected",
+    "Upstream endpoints currently ejected.",
+);
+
+/// Latencies kept per endpoint between evaluations.
+const MAX_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierConfig {
+    /// Seconds between evaluations of the endpoints' latency.
+    pub interval_secs: u64,
+    /// Requests an endpoint needs in an interval for its latency to count.
+    pub min_requests: usize,
+    /// How many times the median p99 of the other endpoints an endpoint's
+    /// p99 may be.
+    pub p99_factor: f64,
+    /// Failures in a row that eject an endpoint right away.
+    pub consecutive_errors: u32,
+    /// Seconds an endpoint sits out the first time it is ejected.
+    pub base_ejection_secs: u64,
+    /// Share of the known endpoints that may be ejected at once.
+    pub max_ejection_percent: u8,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 10,
+            min_requests: 20,
+            p99_factor: 3.0,
+            consecutive_errors: 5,
+            base_ejection_secs: 30,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Endpoint {
+    /// Latencies in microseconds since the last evaluation.
+    latencies: Vec<u64>,
+    consecutive_errors: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+struct State {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    evaluated_at: Instant,
+}
+
+/// What the upstream endpoints have been up to, shared by the layer that
+/// records it and the resolver that acts on it.
+#[derive(Clone)]
+pub struct OutlierDetector {
+    config: Arc<OutlierConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(State {
+                endpoints: HashMap::new(),
+                evaluated_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Account a response from `addr` that took `latency`, and `failed` if
+    /// it was a server error.
+    pub fn record(&self, addr: SocketAddr, latency: Duration, failed: bool) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let endpoint = state.endpoints.entry(addr).or_default();
+        if endpoint.latencies.len() < MAX_SAMPLES {
+            endpoint.latencies.push(latency.as_micros() as u64);
+        }
+        endpoint.consecutive_errors = if failed {
+            endpoint.consecutive_errors + 1
+        } else {
+            0
+        };
+        if endpoint.consecutive_errors >= self.config.consecutive_errors {
+            self.eject(&mut state, addr, now, "consecutive errors");
+        }
+        if now.duration_since(state.evaluated_at) >= Duration::from_secs(self.config.interval_secs)
+        {
+            self.evaluate(&mut state, now);
+        }
+    }
+
+    /// Eject the endpoints whose p99 is far over that of the others.
+    fn evaluate(&self, state: &mut State, now: Instant) {
+        state.evaluated_at = now;
+        let p99s: Vec<(SocketAddr, u64)> = state
+            .endpoints
+            .iter_mut()
+            .filter_map(|(addr, endpoint)| {
+                let mut latencies = std::mem::take(&mut endpoint.latencies);
+                if latencies.len() < self.config.min_requests || endpoint.is_ejected(now) {
+                    return None;
+                }
+                latencies.sort_unstable();
+                Some((*addr, percentile(&latencies, 0.99)))
+            })
+            .collect();
+        for (addr, p99) in &p99s {
+            let mut others: Vec<u64> = p99s
+                .iter()
+                .filter(|(other, _)| other != addr)
+                .map(|(_, p99)| *p99)
+                .collect();
+            if others.is_empty() {
+                continue;
+            }
+            others.sort_unstable();
+            let median = others[others.len() / 2];
+            if *p99 as f64 > median as f64 * self.config.p99_factor {
+                self.eject(state, *addr, now, "latency");
+            }
+        }
+    }
+
+    fn eject(&self, state: &mut State, addr: SocketAddr, now: Instant, reason: &str) {
+        let known = state.endpoints.len();
+        let ejected = state
+            .endpoints
+            .values()
+            .filter(|endpoint| endpoint.is_ejected(now))
+            .count();
+        // rounded down, so a lone endpoint is never ejected
+        let max = known * self.config.max_ejection_percent.min(100) as usize / 100;
+        let Some(endpoint) = state.endpoints.get_mut(&addr) else {
+            return;
+        };
+        if endpoint.is_ejected(now) || ejected >= max {
+            return;
+        }
+        endpoint.ejections += 1;
+        endpoint.consecutive_errors = 0;
+        let secs = self.config.base_ejection_secs * endpoint.ejections as u64;
+        endpoint.ejected_until = Some(now + Duration::from_secs(secs));
+        EJECTIONS.increment(&[]);
+        EJECTED.set(&[], (ejected + 1) as f64);
+        tracing::warn!(%addr, reason, secs, "upstream endpoint ejected");
+    }
+
+    #[cfg(test)]
+    fn is_ejected(&self, addr: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .endpoints
+            .get(&addr)
+            .is_some_and(|endpoint| endpoint.is_ejected(Instant::now()))
+    }
+
+    /// `addrs` without the ejected endpoints, or all of them if every one is.
+    fn filter(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        // the resolver sets the port of the URI, responses carry the real one
+        let ejected = |addr: &SocketAddr| {
+            state
+                .endpoints
+                .iter()
+                .any(|(endpoint, state)| endpoint.ip() == addr.ip() && state.is_ejected(now))
+        };
+        let healthy: Vec<_> = addrs.iter().copied().filter(|a| !ejected(a)).collect();
+        let ejected = state
+            .endpoints
+            .values()
+            .filter(|e| e.is_ejected(now))
+            .count();
+        EJECTED.set(&[], ejected as f64);
+        if healthy.is_empty() {
+            addrs
+        } else {
+            healthy
+        }
+    }
+}
+
+/// A resolver leaving the ejected endpoints out, if detection is on.
+#[derive(Clone)]
+pub struct OutlierResolver<R> {
+    inner: R,
+    detector: Option<OutlierDetector>,
+}
+
+impl<R> OutlierResolver<R> {
+    pub fn new(resolver: R, detector: Option<OutlierDetector>) -> Self {
+        Self {
+            inner: resolver,
+            detector,
+        }
+    }
+}
+
+impl<R> Service<Name> for OutlierResolver<R>
+where
+    R: Service<Name, Response = vec::IntoIter<SocketAddr>, Error = io::Error>,
+    R::Future: Send + 'static,
+{
+    type Response = vec::IntoIter<SocketAddr>;
+
+    type Error = io::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let fut = self.inner.call(name);
+        let detector = self.detector.clone();
+        Box::pin(async move {
+            let addrs = fut.await?;
+            match detector {
+                Some(detector) => Ok(detector.filter(addrs.collect()).into_iter()),
+                None => Ok(addrs),
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct OutlierLayer {
+    detector: OutlierDetector,
+}
+
+impl OutlierLayer {
+    pub fn new(detector: OutlierDetector) -> Self {
+        Self { detector }
+    }
+}
+
+impl<S> Layer<S> for OutlierLayer {
+    type Service = Outlier<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Outlier {
+            inner: service,
+            detector: self.detector.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Outlier<S> {
+    inner: S,
+    detector: OutlierDetector,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Outlier<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let started = Instant::now();
+        let fut = self.inner.call(req);
+        let detector = self.detector.clone();
+        Box::pin(async move {
+            let res = fut.await?;
+            // only responses of the upstream client say where they came from
+            if let Some(info) = res.extensions().get::<HttpInfo>() {
+                let failed = res.status().is_server_error();
+                detector.record(info.remote_addr(), started.elapsed(), failed);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 443))
+    }
+
+    #[test]
+    fn test_latency_outlier() {
+        let detector = OutlierDetector::new(OutlierConfig {
+            min_requests: 3,
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            detector.record(addr(1), Duration::from_millis(10), false);
+            detector.record(addr(2), Duration::from_millis(12), false);
+            detector.record(addr(3), Duration::from_millis(100), false);
+        }
+        detector.record(addr(4), Duration::from_millis(100), false);
+        {
+            let mut state = detector.state.lock().unwrap();
+            detector.evaluate(&mut state, Instant::now());
+        }
+        assert!(!detector.is_ejected(addr(1)) && !detector.is_ejected(addr(2)));
+        assert!(detector.is_ejected(addr(3)));
+        // too few requests to tell
+        assert!(!detector.is_ejected(addr(4)));
+        // resolved without it, whatever the port
+        let resolved = detector.filter(vec![
+            SocketAddr::from(([10, 0, 0, 1], 0)),
+            SocketAddr::from(([10, 0, 0, 3], 0)),
+        ]);
+        assert_eq!(resolved, vec![SocketAddr::from(([10, 0, 0, 1], 0))]);
+        // but never to nothing
+        let resolved = detector.filter(vec![addr(3)]);
+        assert_eq!(resolved, vec![addr(3)]);
+    }
+
+    #[test]
+    fn test_consecutive_errors() {
+        let detector = OutlierDetector::new(OutlierConfig {
+            consecutive_errors: 2,
+            base_ejection_secs: 0,
+            ..Default::default()
+        });
+        detector.record(addr(1), Duration::from_millis(10), false);
+        detector.record(addr(2), Duration::from_millis(10), true);
+        detector.record(addr(2), Duration::from_millis(10), false);
+        detector.record(addr(2), Duration::from_millis(10), true);
+        assert!(!detector.is_ejected(addr(2)));
+        detector.record(addr(2), Duration::from_millis(10), true);
+        // ejected for no time, so already reinstated
+        let state = detector.state.lock().unwrap();
+        assert_eq!(state.endpoints[&addr(2)].ejections, 1);
+        drop(state);
+        assert!(!detector.is_ejected(addr(2)));
+
+        // at most half of the endpoints are out at once
+        let detector = OutlierDetector::new(OutlierConfig {
+            consecutive_errors: 1,
+            ..Default::default()
+        });
+        detector.record(addr(2), Duration::from_millis(10), false);
+        detector.record(addr(1), Duration::from_millis(10), true);
+        detector.record(addr(2), Duration::from_millis(10), true);
+        assert!(detector.is_ejected(addr(1)) && !detector.is_ejected(addr(2)));
+    }
+}
@@ -0,0 +1,217 @@
+//! Pacing of upstream requests per pooled key.
+//!
+//! When a fleet wakes up at once, its requests used to go out in one burst,
+//! run the active key into a 429, rotate, and do the same to the next key.
+//! [`PaceLayer`] spaces the requests of each key out instead, leaky bucket
+//! style: at the rate that spends the quota the upstream last reported for
+//! the key evenly until it resets, or at `requests_per_minute` until it
+//! reported one. Up to `burst` requests go at once. Requests that would wait
+//! longer than `max_wait_ms` for their turn are forwarded right away, for the
+//! upstream to have the last word.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_core::Future;
+use http::{header::AUTHORIZATION, Request};
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::{auth::KeyPool, context::key_id, metrics::Metric};
+
+const PACED: Metric = Metric::counter(
+    "proxy_paced_total",
+    "Upstream requests delayed to pace the requests of each pooled key.",
+);
+const PACED_SECONDS: Metric = Metric::counter(
+    "proxy_paced_seconds_total",
+    "Time upstream requests waited for their turn, per pooled key.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PaceConfig {
+    /// Requests per minute per key until the upstream reports its quota,
+    /// unpaced until then when unset.
+    pub requests_per_minute: Option<u64>,
+    /// Requests per key that may go at once.
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Longest a request waits for its turn.
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+fn default_max_wait_ms() -> u64 {
+    10_000
+}
+
+#[derive(Clone)]
+pub struct PaceLayer {
+    keys: KeyPool,
+    interval: Option<Duration>,
+    burst: u32,
+    max_wait: Duration,
+    /// When the bucket of each key, by key id, is empty again.
+    drained_at: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl PaceLayer {
+    pub fn new(config: PaceConfig, keys: KeyPool) -> Self {
+        Self {
+            keys,
+            interval: config
+                .requests_per_minute
+                .filter(|&rpm| rpm > 0)
+                .map(|rpm| Duration::from_secs(60) / rpm.min(u32::MAX as u64) as u32),
+            burst: config.burst.max(1),
+            max_wait: Duration::from_millis(config.max_wait_ms),
+            drained_at: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How long a request with `key` waits for its turn, taking it, or
+    /// `None` when it is not paced or would wait too long.
+    fn turn(&self, key: &str) -> Option<Duration> {
+        if !self.keys.keys().iter().any(|k| k == key) {
+            return None;
+        }
+        let interval = self.keys.quota_interval(key).or(self.interval)?;
+        let now = Instant::now();
+        let mut drained_at = self.drained_at.lock().unwrap();
+        let drained_at = drained_at.entry(key_id(key)).or_insert(now);
+        let start = (*drained_at).max(now);
+        // the bucket holds `burst` requests, each draining in `interval`
+        let wait = (start + interval)
+            .saturating_duration_since(now)
+            .saturating_sub(interval * self.burst);
+        if wait > self.max_wait {
+            return None;
+        }
+        *drained_at = start + interval;
+        Some(wait)
+    }
+}
+
+impl<S> Layer<S> for PaceLayer {
+    type Service = Pace<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Pace {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pace<S> {
+    inner: S,
+    layer: PaceLayer,
+}
+
+fn api_key<B>(req: &Request<B>) -> Option<&str> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (_, key) = value.split_once(' ')?;
+    Some(key.trim())
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for Pace<S>
+where
+    S: Service<Request<ReqBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let paced = api_key(&req).and_then(|key| {
+            let wait = self.layer.turn(key).filter(|wait| !wait.is_zero())?;
+            Some((wait, self.layer.keys.label(key)))
+        });
+        Box::pin(async move {
+            if let Some((wait, label)) = paced {
+                let labels = [("key", label.as_str())];
+                PACED.increment(&labels);
+                PACED_SECONDS.add(&labels, wait.as_secs_f64());
+                tracing::debug!(key = %label, wait_ms = wait.as_millis() as u64, "pacing");
+                tokio::time::sleep(wait).await;
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{HeaderMap, HeaderValue, StatusCode};
+
+    use crate::auth::{X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING, X_RATELIMIT_RESET};
+
+    fn pace_layer(requests_per_minute: Option<u64>, burst: u32, max_wait_ms: u64) -> PaceLayer {
+        let config = PaceConfig {
+            requests_per_minute,
+            burst,
+            max_wait_ms,
+        };
+        PaceLayer::new(config, KeyPool::from(vec!["a", "b"]))
+    }
+
+    #[test]
+    fn test_pace() {
+        // a request per second, two at once
+        let layer = pace_layer(Some(60), 2, 10_000);
+        assert_eq!(layer.turn("a"), Some(Duration::ZERO));
+        assert_eq!(layer.turn("a"), Some(Duration::ZERO));
+        let wait = layer.turn("a").unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        let wait = layer.turn("a").unwrap();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        // keys have buckets of their own, clients' keys are not paced
+        assert_eq!(layer.turn("b"), Some(Duration::ZERO));
+        assert_eq!(layer.turn("c"), None);
+
+        // too long a wait is not taken
+        let layer = pace_layer(Some(60), 1, 1);
+        assert_eq!(layer.turn("a"), Some(Duration::ZERO));
+        assert_eq!(layer.turn("a"), None);
+        assert_eq!(layer.turn("a"), None);
+    }
+
+    #[test]
+    fn test_pace_to_quota() {
+        // unpaced until the upstream reports the quota
+        let layer = pace_layer(None, 1, 10_000);
+        assert_eq!(layer.turn("a"), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(100));
+        headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(10));
+        headers.insert(X_RATELIMIT_RESET, HeaderValue::from(5));
+        layer.keys.record_quota("a", StatusCode::OK, &headers);
+        // ten requests left over five seconds
+        assert_eq!(layer.turn("a"), Some(Duration::ZERO));
+        let wait = layer.turn("a").unwrap();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,244 @@
+//! Certificate pinning for upstream connections.
+//!
+//! [`PinnedConnector`] checks, after the TLS handshake, that the public key
+//! of the upstream's leaf certificate is one of the pinned ones, given as
+//! base64 SHA-256 hashes of its SubjectPublicKeyInfo (the `pin-sha256` of
+//! HPKP, as `openssl x509 -pubkey | openssl pkey -pubin -outform der |
+//! openssl dgst -sha256 -binary | base64` prints). Connections failing the
+//! check are dropped before any request, and so any API key, is sent on
+//! them, and counted in `proxy_upstream_pin_failures_total`. Pinned hosts
+//! reached without TLS fail the same way.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_core::Future;
+use http::Uri;
+use hyper_tls::MaybeHttpsStream;
+use openssl::x509::X509;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tower::{BoxError, Service};
+
+use crate::metrics::Metric;
+
+const PIN_FAILURES: Metric = Metric::counter(
+    "proxy_upstream_pin_failures_total",
+    "Upstream connections dropped as their certificate was not pinned.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinRule {
+    /// Upstream hosts the pins apply to, all of them when empty.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Accepted public keys, as base64 SHA-256 hashes of their SPKI. Pin a
+    /// backup key too, so the upstream can rotate its certificate.
+    pub spki_sha256: Vec<String>,
+}
+
+struct Pins {
+    hosts: Vec<String>,
+    hashes: Vec<Vec<u8>>,
+}
+
+/// The SHA-256 hash of the SPKI of a DER certificate.
+fn spki_sha256(der: &[u8]) -> Result<Vec<u8>, BoxError> {
+    let spki = X509::from_der(der)?.public_key()?.public_key_to_der()?;
+    Ok(Sha256::digest(spki).to_vec())
+}
+
+#[derive(Clone)]
+pub struct PinnedConnector<C> {
+    inner: C,
+    pins: Arc<Vec<Pins>>,
+}
+
+impl<C> PinnedConnector<C> {
+    /// Check the hashes of `rules`; the first rule matching a host applies.
+    pub fn new(inner: C, rules: Vec<PinRule>) -> Result<Self, BoxError> {
+        let pins = rules
+            .into_iter()
+            .map(|rule| {
+                let hashes = rule
+                    .spki_sha256
+                    .iter()
+                    .map(|hash| match STANDARD.decode(hash) {
+                        Ok(hash) if hash.len() == 32 => Ok(hash),
+                        _ => Err(format!("invalid spki_sha256 {}", hash)),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Pins {
+                    hosts: rule
+                        .hosts
+                        .iter()
+                        .map(|host| host.to_ascii_lowercase())
+                        .collect(),
+                    hashes,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            inner,
+            pins: Arc::new(pins),
+        })
+    }
+}
+
+impl<C, T> Service<Uri> for PinnedConnector<C>
+where
+    C: Service<Uri, Response = MaybeHttpsStream<T>>,
+    C::Error: Into<BoxError>,
+    C::Future: Send + 'static,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    type Response = C::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let pins = self.pins.clone();
+        let fut = self.inner.call(uri);
+        Box::pin(async move {
+            let stream = fut.await.map_err(Into::into)?;
+            let Some(pins) = pins
+                .iter()
+                .find(|pins| pins.hosts.is_empty() || pins.hosts.contains(&host))
+            else {
+                return Ok(stream);
+            };
+            let hash = match &stream {
+                MaybeHttpsStream::Https(tls) => match tls.get_ref().peer_certificate()? {
+                    Some(cert) => Some(spki_sha256(&cert.to_der()?)?),
+                    None => None,
+                },
+                MaybeHttpsStream::Http(_) => None,
+            };
+            if hash.as_ref().is_some_and(|hash| pins.hashes.contains(hash)) {
+                return Ok(stream);
+            }
+            PIN_FAILURES.increment(&[("host", &host)]);
+            let hash = hash.map_or_else(|| "none".to_owned(), |hash| STANDARD.encode(hash));
+            tracing::error!(%host, spki_sha256 = %hash, "upstream certificate not pinned");
+            Err(format!(
+                "certificate pinning failed for {}: public key sha256 {} is not pinned",
+                host, hash
+            )
+            .into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{Response, StatusCode};
+    use hyper::{client::HttpConnector, server::conn::Http, service::service_fn, Body, Client};
+    use hyper_tls::HttpsConnector;
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::X509Builder,
+    };
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tokio_native_tls::native_tls;
+
+    /// A TLS server answering every connection with a 204, and the hash of
+    /// its key.
+    async fn server() -> Result<(u16, String), BoxError> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(1)?);
+        let mut cert = X509Builder::new()?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&not_before)?;
+        cert.set_not_after(&not_after)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+        let cert = cert.build();
+        let identity =
+            native_tls::Identity::from_pkcs8(&cert.to_pem()?, &key.private_key_to_pem_pkcs8()?)?;
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(tls) = acceptor.accept(stream).await {
+                    let service = service_fn(|_| async {
+                        let mut res = Response::new(Body::empty());
+                        *res.status_mut() = StatusCode::NO_CONTENT;
+                        Ok::<_, Infallible>(res)
+                    });
+                    let _ = Http::new().serve_connection(tls, service).await;
+                }
+            }
+        });
+        Ok((port, STANDARD.encode(spki_sha256(&cert.to_der()?)?)))
+    }
+
+    fn client(pins: serde_json::Value) -> Client<PinnedConnector<HttpsConnector<HttpConnector>>> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        // the test certificate is self-signed, only the pin vouches for it
+        let tls = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let https = HttpsConnector::from((http, tls.into()));
+        let connector = PinnedConnector::new(https, serde_json::from_value(pins).unwrap()).unwrap();
+        Client::builder().build::<_, Body>(connector)
+    }
+
+    #[tokio::test]
+    async fn test_pinning() -> Result<(), BoxError> {
+        let (port, hash) = server().await?;
+        let uri: Uri = format!("https://127.0.0.1:{}/", port).parse()?;
+
+        let res = client(serde_json::json!([{ "spki_sha256": [hash] }]))
+            .get(uri.clone())
+            .await?;
+        assert_eq!(res.status(), 204);
+
+        let other = STANDARD.encode([0u8; 32]);
+        let failures = PIN_FAILURES.get(&[("host", "127.0.0.1")]);
+        let res = client(serde_json::json!([{ "spki_sha256": [other] }]))
+            .get(uri.clone())
+            .await;
+        assert!(res.is_err());
+        assert_eq!(PIN_FAILURES.get(&[("host", "127.0.0.1")]), failures + 1.0);
+
+        // pins of other hosts do not apply
+        let res = client(
+            serde_json::json!([{ "hosts": ["api.balena-cloud.com"], "spki_sha256": [other] }]),
+        )
+        .get(uri)
+        .await?;
+        assert_eq!(res.status(), 204);
+
+        assert!(PinnedConnector::new(
+            (),
+            vec![PinRule {
+                hosts: Vec::new(),
+                spki_sha256: vec!["short".into()],
+            }]
+        )
+        .is_err());
+        Ok(())
+    }
+}
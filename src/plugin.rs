@@ -0,0 +1,256 @@
+//! Custom behavior without forking the layer stack.
+//!
+//! A [`ProxyPlugin`] sees every request head before it is forwarded, every
+//! response head on its way back and every error the stack below produces.
+//! Plugins are registered with [`PluginLayer::register`]; request hooks run in
+//! registration order, response and error hooks in reverse, so the first
+//! plugin registered wraps all the others. Once the request is done, every
+//! plugin that saw it is told how it ended and how long it took.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{request, response, Method, Request, Response, StatusCode, Uri};
+use tower::{BoxError, Layer, Service};
+
+pub trait ProxyPlugin: Send + Sync + 'static {
+    /// Name used when logging what the plugin did.
+    fn name(&self) -> &str;
+
+    /// Inspect or rewrite the request head. Returning a response answers the
+    /// request without forwarding it, plugins registered later are skipped.
+    fn on_request(&self, _req: &mut request::Parts) -> Option<Response<Bytes>> {
+        None
+    }
+
+    /// Inspect or rewrite the response head of a request to `method` `uri`.
+    fn on_response(&self, _method: &Method, _uri: &Uri, _res: &mut response::Parts) {}
+
+    /// Observe an error returned instead of a response.
+    fn on_error(&self, _method: &Method, _uri: &Uri, _err: &BoxError) {}
+
+    /// Observe the outcome of a request to `method` `uri`, `elapsed` after it
+    /// reached the plugins, with the final status or none if it failed.
+    fn on_complete(
+        &self,
+        _method: &Method,
+        _uri: &Uri,
+        _status: Option<StatusCode>,
+        _elapsed: Duration,
+    ) {
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PluginLayer {
+    plugins: Vec<Arc<dyn ProxyPlugin>>,
+}
+
+impl PluginLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `plugin` after the plugins already registered.
+    pub fn register<P: ProxyPlugin>(mut self, plugin: P) -> Self {
+        self.plugins.push(Arc::new(plugin));
+        self
+    }
+
+    /// Add `plugin`, shared with other layers, after the plugins already
+    /// registered.
+    pub fn register_shared(mut self, plugin: Arc<dyn ProxyPlugin>) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+}
+
+impl<S> Layer<S> for PluginLayer {
+    type Service = Plugins<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Plugins {
+            inner: service,
+            plugins: self.plugins.clone().into(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Plugins<S> {
+    inner: S,
+    plugins: Arc<[Arc<dyn ProxyPlugin>]>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Plugins<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let plugins = self.plugins.clone();
+        let started = Instant::now();
+        let (mut parts, body) = req.into_parts();
+        let (method, uri) = (parts.method.clone(), parts.uri.clone());
+
+        // only the plugins that saw the request see its response
+        let mut ran = plugins.len();
+        let mut answer = None;
+        for (i, plugin) in plugins.iter().enumerate() {
+            if let Some(res) = plugin.on_request(&mut parts) {
+                tracing::debug!(plugin = plugin.name(), "plugin answered request");
+                ran = i;
+                answer = Some(res.map(ResBody::from));
+                break;
+            }
+        }
+        let fut = match answer {
+            Some(res) => Err(res),
+            None => Ok(self.inner.call(Request::from_parts(parts, body))),
+        };
+
+        Box::pin(async move {
+            let result = match fut {
+                Ok(fut) => fut.await.map_err(Into::into),
+                Err(res) => Ok(res),
+            };
+            let seen = &plugins[..ran];
+            let result = match result {
+                Ok(res) => {
+                    let (mut parts, body) = res.into_parts();
+                    for plugin in seen.iter().rev() {
+                        plugin.on_response(&method, &uri, &mut parts);
+                    }
+                    Ok(Response::from_parts(parts, body))
+                }
+                Err(err) => {
+                    for plugin in seen.iter().rev() {
+                        plugin.on_error(&method, &uri, &err);
+                    }
+                    Err(err)
+                }
+            };
+            let status = result.as_ref().ok().map(Response::status);
+            for plugin in seen.iter().rev() {
+                plugin.on_complete(&method, &uri, status, started.elapsed());
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use http::{HeaderValue, StatusCode};
+    use hyper::Body;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    struct Tag {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        deny: bool,
+    }
+
+    impl ProxyPlugin for Tag {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn on_request(&self, req: &mut request::Parts) -> Option<Response<Bytes>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("request {}", self.name));
+            if self.deny && req.headers.get("x-company-token").is_none() {
+                let mut res = Response::new(Bytes::from_static(b"missing token"));
+                *res.status_mut() = StatusCode::UNAUTHORIZED;
+                return Some(res);
+            }
+            None
+        }
+
+        fn on_response(&self, _method: &Method, uri: &Uri, res: &mut response::Parts) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("response {} {}", self.name, uri.path()));
+            res.headers
+                .append("x-plugin", HeaderValue::from_static(self.name));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugins() -> Result<(), BoxError> {
+        let fixtures: Vec<Fixture> = serde_json::from_value(serde_json::json!([
+            { "route": { "path": "/v6/device" }, "status": 200 },
+        ]))?;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let tag = |name, deny| Tag {
+            name,
+            calls: calls.clone(),
+            deny,
+        };
+        let service = PluginLayer::new()
+            .register(tag("outer", false))
+            .register(tag("auth", true))
+            .register(tag("inner", false))
+            .layer(MockUpstream::new(fixtures));
+
+        let req = Request::get("/v6/device")
+            .header("x-company-token", "secret")
+            .body(Body::empty())?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let tags: Vec<_> = res.headers().get_all("x-plugin").iter().collect();
+        assert_eq!(tags, ["inner", "auth", "outer"]);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "request outer",
+                "request auth",
+                "request inner",
+                "response inner /v6/device",
+                "response auth /v6/device",
+                "response outer /v6/device",
+            ]
+        );
+
+        // the answering plugin and those after it are skipped on the way back
+        calls.lock().unwrap().clear();
+        let res = service
+            .oneshot(Request::get("/v6/device").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["x-plugin"], "outer");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["request outer", "request auth", "response outer /v6/device"]
+        );
+        Ok(())
+    }
+}
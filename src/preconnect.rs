@@ -0,0 +1,137 @@
+//! Warm upstream connections.
+//!
+//! The first request after startup, or after a quiet spell long enough for
+//! the client to close its idle connections, waits for DNS, TCP and TLS
+//! before it is sent. [`Preconnector`] opens `connections` connections to the
+//! upstream at startup with unauthenticated `HEAD` requests, and repeats them
+//! every `interval_secs`, so that many stay in the client's pool. Keep the
+//! interval below the pool's 90 second idle timeout. Only the configured
+//! upstream is warmed, not gateway hosts or other environments.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_util::future::join_all;
+use http::{Method, Request, Uri};
+use http_body::Body as HttpBody;
+use hyper::{client::connect::Connect, Client};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreconnectConfig {
+    /// Connections kept open.
+    pub connections: usize,
+    /// How often the connections are used to keep them open.
+    pub interval_secs: u64,
+}
+
+impl Default for PreconnectConfig {
+    fn default() -> Self {
+        Self {
+            connections: 2,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// Send `connections` concurrent probes to `uri`, each needing a connection
+/// of its own unless the pool has enough idle ones.
+async fn warm<C, B>(client: &Client<C, B>, uri: &Uri, connections: usize)
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    B: HttpBody + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let probes = (0..connections).map(|_| async {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(uri.clone())
+            .body(B::from(Bytes::new()))
+            .expect("valid probe request");
+        // any answer leaves the connection in the pool, only failures matter
+        if let Err(err) = client.request(req).await {
+            tracing::debug!(%uri, %err, "upstream preconnect failed");
+        }
+    });
+    join_all(probes).await;
+}
+
+/// The task keeping upstream connections warm, replaced when the stack is
+/// rebuilt so a single one runs for the current client.
+#[derive(Clone, Default)]
+pub struct Preconnector(Arc<Mutex<Option<JoinHandle<()>>>>);
+
+impl Preconnector {
+    /// Keep connections of `client` to `uri` warm, stopping the task started
+    /// for a previous client. `None` only stops it.
+    pub fn restart<C, B>(&self, config: Option<PreconnectConfig>, client: Client<C, B>, uri: Uri)
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+        B: HttpBody + From<Bytes> + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let task = config
+            .filter(|config| config.connections > 0)
+            .map(|config| {
+                let period = Duration::from_secs(config.interval_secs.max(1));
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        warm(&client, &uri, config.connections).await;
+                    }
+                })
+            });
+        if let Some(previous) = std::mem::replace(&mut *self.0.lock().unwrap(), task) {
+            previous.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    #[tokio::test]
+    async fn test_warm() -> Result<(), BoxError> {
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let make_service = make_service_fn({
+            let accepted = accepted.clone();
+            move |_: &AddrStream| {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                async {
+                    Ok::<_, Infallible>(service_fn(|_| async {
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let uri: Uri = format!("http://{}/v6", server.local_addr()).parse()?;
+        tokio::spawn(server);
+
+        let client = Client::<_, Body>::new();
+        warm(&client, &uri, 3).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        // the pooled connections are reused rather than opened again
+        warm(&client, &uri, 3).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
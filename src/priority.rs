@@ -0,0 +1,332 @@
+//! Request prioritization.
+//!
+//! [`PriorityLayer`] bounds the number of requests in flight. Requests over
+//! the limit wait in a queue per priority class, and a freed slot goes to the
+//! next class picked by smooth weighted round robin, so a busy low class
+//! cannot starve a high one. When the queues are full, the newest waiter of
+//! the lowest class below the arriving request is shed with a 503, and the
+//! arriving request itself when no lower class is waiting.
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::RETRY_AFTER, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tower::{BoxError, Layer, Service};
+
+use crate::route::RouteMatcher;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityConfig {
+    /// Requests forwarded concurrently, the rest wait in their class queue.
+    pub max_concurrency: usize,
+    /// Requests waiting across all classes before shedding starts.
+    pub max_queue: usize,
+    /// Classes from the highest to the lowest priority. Requests matching
+    /// none of them go to the last one.
+    pub classes: Vec<PriorityClass>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityClass {
+    pub name: String,
+    /// Share of the freed slots this class gets while others are waiting.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Requests matching any of these routes belong to the class.
+    #[serde(default)]
+    pub routes: Vec<RouteMatcher>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+struct State {
+    in_flight: usize,
+    queued: usize,
+    queues: Vec<VecDeque<oneshot::Sender<Permit>>>,
+    /// Smooth weighted round robin counters.
+    current: Vec<i64>,
+}
+
+struct Scheduler {
+    max_concurrency: usize,
+    max_queue: usize,
+    weights: Vec<i64>,
+    state: Mutex<State>,
+}
+
+/// A slot for one request in flight, handed to the next waiter on drop.
+pub struct Permit {
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            Scheduler::release(scheduler);
+        }
+    }
+}
+
+/// The request was dropped to make room for more important ones.
+#[derive(Debug)]
+struct Shed;
+
+impl State {
+    /// Pick the class the next free slot goes to.
+    fn next_class(&mut self, weights: &[i64]) -> Option<usize> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, queue) in self.queues.iter().enumerate() {
+            if queue.is_empty() {
+                continue;
+            }
+            self.current[i] += weights[i];
+            total += weights[i];
+            if best.is_none_or(|b| self.current[i] > self.current[b]) {
+                best = Some(i);
+            }
+        }
+        let best = best?;
+        self.current[best] -= total;
+        Some(best)
+    }
+}
+
+impl Scheduler {
+    fn new(config: &PriorityConfig) -> Self {
+        let classes = config.classes.len().max(1);
+        Self {
+            max_concurrency: config.max_concurrency.max(1),
+            max_queue: config.max_queue,
+            weights: (0..classes)
+                .map(|i| config.classes.get(i).map_or(1, |c| c.weight.max(1) as i64))
+                .collect(),
+            state: Mutex::new(State {
+                in_flight: 0,
+                queued: 0,
+                queues: (0..classes).map(|_| VecDeque::new()).collect(),
+                current: vec![0; classes],
+            }),
+        }
+    }
+
+    fn permit(self: &Arc<Self>) -> Permit {
+        Permit {
+            scheduler: Some(self.clone()),
+        }
+    }
+
+    async fn acquire(self: Arc<Self>, class: usize) -> Result<Permit, Shed> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < self.max_concurrency {
+                state.in_flight += 1;
+                return Ok(self.permit());
+            }
+            if state.queued >= self.max_queue {
+                // dropping the sender wakes the shed waiter with an error
+                let lower = (class + 1..state.queues.len())
+                    .rev()
+                    .find(|&i| !state.queues[i].is_empty());
+                match lower {
+                    Some(lower) => {
+                        state.queues[lower].pop_back();
+                        state.queued -= 1;
+                    }
+                    None => return Err(Shed),
+                }
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.queues[class].push_back(sender);
+            state.queued += 1;
+            receiver
+        };
+        receiver.await.map_err(|_| Shed)
+    }
+
+    fn release(scheduler: Arc<Self>) {
+        let mut state = scheduler.state.lock().unwrap();
+        while let Some(class) = state.next_class(&scheduler.weights) {
+            let sender = state.queues[class].pop_front().expect("class has waiters");
+            state.queued -= 1;
+            // the slot stays taken and moves over to the waiter
+            match sender.send(scheduler.permit()) {
+                Ok(()) => return,
+                Err(mut permit) => {
+                    // the waiter went away, don't let the permit release again
+                    permit.scheduler = None;
+                }
+            }
+        }
+        state.in_flight -= 1;
+    }
+}
+
+#[derive(Clone)]
+pub struct PriorityLayer {
+    scheduler: Arc<Scheduler>,
+    classes: Arc<Vec<PriorityClass>>,
+}
+
+impl PriorityLayer {
+    pub fn new(config: PriorityConfig) -> Self {
+        Self {
+            scheduler: Arc::new(Scheduler::new(&config)),
+            classes: Arc::new(config.classes),
+        }
+    }
+}
+
+impl<S> Layer<S> for PriorityLayer {
+    type Service = Prioritize<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Prioritize {
+            inner: service,
+            scheduler: self.scheduler.clone(),
+            classes: self.classes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Prioritize<S> {
+    inner: S,
+    scheduler: Arc<Scheduler>,
+    classes: Arc<Vec<PriorityClass>>,
+}
+
+impl<S> Prioritize<S> {
+    fn classify<B>(&self, req: &Request<B>) -> usize {
+        self.classes
+            .iter()
+            .position(|class| class.routes.iter().any(|route| route.matches(req)))
+            .unwrap_or(self.classes.len().saturating_sub(1))
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Prioritize<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: From<Bytes>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let class = self.classify(&req);
+        let scheduler = self.scheduler.clone();
+        let name = self.classes.get(class).map(|c| c.name.clone());
+        Box::pin(async move {
+            let _permit = match scheduler.acquire(class).await {
+                Ok(permit) => permit,
+                Err(Shed) => {
+                    tracing::warn!(class = name, "request shed under load");
+                    let mut res = Response::new(ResBody::from(Bytes::from_static(
+                        br#"{"error":"proxy overloaded"}"#,
+                    )));
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                    return Ok(res);
+                }
+            };
+            inner.call(req).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_queue: usize) -> PriorityConfig {
+        serde_json::from_value(serde_json::json!({
+            "max_concurrency": 1,
+            "max_queue": max_queue,
+            "classes": [
+                { "name": "device", "weight": 3,
+                  "routes": [{ "path_prefix": "/v6/device", "methods": ["PATCH"] }] },
+                { "name": "other" },
+            ],
+        }))
+        .expect("valid config")
+    }
+
+    #[test]
+    fn test_classify() {
+        let layer = PriorityLayer::new(config(10));
+        let service = layer.layer(());
+        let patch = Request::patch("/v6/device(1)").body(()).unwrap();
+        let get = Request::get("/v6/device(1)").body(()).unwrap();
+        assert_eq!(service.classify(&patch), 0);
+        assert_eq!(service.classify(&get), 1);
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let scheduler = Scheduler::new(&config(10));
+        let mut state = scheduler.state.lock().unwrap();
+        for queue in state.queues.iter_mut() {
+            for _ in 0..8 {
+                queue.push_back(oneshot::channel().0);
+            }
+        }
+        let picks: Vec<usize> = (0..8)
+            .map(|_| {
+                let class = state.next_class(&scheduler.weights).unwrap();
+                state.queues[class].pop_front();
+                class
+            })
+            .collect();
+        assert_eq!(picks.iter().filter(|&&c| c == 0).count(), 6);
+        assert_eq!(picks.iter().filter(|&&c| c == 1).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shed_lowest_class() {
+        let scheduler = Arc::new(Scheduler::new(&config(1)));
+        let busy = scheduler.clone().acquire(1).await.expect("free slot");
+
+        let low = tokio::spawn(scheduler.clone().acquire(1));
+        tokio::task::yield_now().await;
+        let high = tokio::spawn(scheduler.clone().acquire(0));
+        tokio::task::yield_now().await;
+
+        // the queue is full and only the low class can make room
+        assert!(scheduler.clone().acquire(1).await.is_err());
+        assert!(low.await.unwrap().is_err());
+
+        drop(busy);
+        let permit = high.await.unwrap().expect("slot handed over");
+        assert_eq!(scheduler.state.lock().unwrap().in_flight, 1);
+        drop(permit);
+        assert_eq!(scheduler.state.lock().unwrap().in_flight, 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! De-identified logs.
+//!
+//! The access log and traces carry device UUIDs in request paths, client
+//! addresses, and whatever headers clients send, which some deployments may
+//! not keep. With `logging.privacy`, every log line is scrubbed before it is
+//! written, to stdout and files alike: the `identifiers` found anywhere in it
+//! and the values of the listed `fields`, log fields or headers, are hashed
+//! or truncated. Hashes are keyed with `salt`, a random one per process when
+//! unset, so the same device hashes the same within the logs of a process,
+//! and addresses cannot be recovered by hashing all of them. Truncated
+//! addresses keep their network, a /24 or a /48, and other values their
+//! first characters. Colored output is turned off, as it splits fields up.
+
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tower::BoxError;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::rng::{HasherRng, Rng};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    pub mode: PrivacyMode,
+    /// Identifiers de-identified wherever they appear in a line.
+    pub identifiers: Vec<Identifier>,
+    /// Log fields and headers whose values are de-identified whole, e.g.
+    /// `x-device-name`.
+    pub fields: Vec<String>,
+    /// Key of the hashes, random per process when unset.
+    pub salt: Option<String>,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            mode: PrivacyMode::Hash,
+            identifiers: vec![Identifier::Uuid, Identifier::Ip, Identifier::Email],
+            fields: Vec::new(),
+            salt: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyMode {
+    /// Replace values with a keyed hash, e.g. `hash:1f0c2a9e`.
+    #[default]
+    Hash,
+    /// Keep the network of addresses and the first characters of values.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Identifier {
+    /// Device UUIDs, 32 or 62 hex digits, and RFC 4122 UUIDs.
+    Uuid,
+    /// IPv4 and IPv6 addresses.
+    Ip,
+    /// Email addresses, which users are known by.
+    Email,
+}
+
+impl Identifier {
+    fn pattern(self) -> &'static str {
+        match self {
+            Identifier::Uuid => {
+                r"\b(?:[0-9a-fA-F]{62}|[0-9a-fA-F]{32}|[0-9a-fA-F]{8}(?:-[0-9a-fA-F]{4}){3}-[0-9a-fA-F]{12})\b"
+            }
+            // candidates, only those parsing as addresses are replaced
+            Identifier::Ip => {
+                r"\b\d{1,3}(?:\.\d{1,3}){3}\b|[0-9a-fA-F]{0,4}(?::[0-9a-fA-F]{0,4}){2,7}"
+            }
+            Identifier::Email => r"[\w.+-]+@[\w-]+(?:\.[\w-]+)+",
+        }
+    }
+}
+
+/// Characters of values kept when truncating.
+const KEPT_CHARS: usize = 7;
+
+/// Rewrites log lines as a [`PrivacyConfig`] says.
+#[derive(Debug)]
+pub struct Scrubber {
+    mode: PrivacyMode,
+    fields: Option<Regex>,
+    identifiers: Vec<(Identifier, Regex)>,
+    salt: Vec<u8>,
+}
+
+impl Scrubber {
+    pub fn new(config: PrivacyConfig) -> Result<Self, BoxError> {
+        // `name=value`, `"name":"value"` and `"name": "value"`, quotes
+        // escaped or not, as the log formats and header maps write them
+        let fields = (!config.fields.is_empty())
+            .then(|| {
+                let names = config
+                    .fields
+                    .iter()
+                    .map(|field| regex::escape(field))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                Regex::new(&format!(
+                    r#"(?i)(\b(?:{})(?:\\?")?\s*[:=]\s*(?:\\?")?)([^"\\\s,}}]+)"#,
+                    names
+                ))
+            })
+            .transpose()?;
+        let identifiers = config
+            .identifiers
+            .iter()
+            .map(|&identifier| Ok((identifier, Regex::new(identifier.pattern())?)))
+            .collect::<Result<_, BoxError>>()?;
+        let salt = match config.salt {
+            Some(salt) => salt.into_bytes(),
+            None => HasherRng::new().next_u64().to_be_bytes().to_vec(),
+        };
+        Ok(Self {
+            mode: config.mode,
+            fields,
+            identifiers,
+            salt,
+        })
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(value.as_bytes());
+        format!("hash:{}", hex::encode(&hasher.finalize()[..4]))
+    }
+
+    fn truncate(&self, value: &str) -> String {
+        match IpAddr::from_str(value) {
+            Ok(IpAddr::V4(ip)) => {
+                let [a, b, c, _] = ip.octets();
+                Ipv4Addr::new(a, b, c, 0).to_string()
+            }
+            Ok(IpAddr::V6(ip)) => {
+                let [a, b, c, ..] = ip.segments();
+                Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+            }
+            Err(_) => match value.char_indices().nth(KEPT_CHARS) {
+                Some((end, _)) => format!("{}...", &value[..end]),
+                None => value.to_owned(),
+            },
+        }
+    }
+
+    fn replace(&self, value: &str) -> String {
+        match self.mode {
+            PrivacyMode::Hash => self.hash(value),
+            PrivacyMode::Truncate => self.truncate(value),
+        }
+    }
+
+    /// `line` with its identifiers de-identified.
+    pub fn scrub<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(line);
+        if let Some(fields) = &self.fields {
+            let scrubbed = fields.replace_all(&line, |caps: &Captures| {
+                format!("{}{}", &caps[1], self.replace(&caps[2]))
+            });
+            if let Cow::Owned(scrubbed) = scrubbed {
+                line = Cow::Owned(scrubbed);
+            }
+        }
+        for (identifier, regex) in &self.identifiers {
+            let scrubbed = regex.replace_all(&line, |caps: &Captures| {
+                let found = caps.get(0).expect("whole match");
+                let value = found.as_str();
+                let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+                let is_address = match identifier {
+                    // the pattern also takes times, and paths such as `proxy::dns`
+                    Identifier::Ip => {
+                        !is_word(line[..found.start()].chars().next_back())
+                            && !is_word(line[found.end()..].chars().next())
+                            && value.contains(|c: char| c.is_ascii_hexdigit())
+                            && IpAddr::from_str(value).is_ok()
+                    }
+                    _ => true,
+                };
+                if is_address {
+                    self.replace(value)
+                } else {
+                    value.to_owned()
+                }
+            });
+            if let Cow::Owned(scrubbed) = scrubbed {
+                line = Cow::Owned(scrubbed);
+            }
+        }
+        line
+    }
+}
+
+/// A log writer scrubbing what is written through it, see the module docs.
+#[derive(Clone)]
+pub struct Scrubbed<W> {
+    inner: W,
+    scrubber: Option<Arc<Scrubber>>,
+}
+
+impl<W> Scrubbed<W> {
+    pub fn new(inner: W, scrubber: Option<Arc<Scrubber>>) -> Self {
+        Self { inner, scrubber }
+    }
+}
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for Scrubbed<W> {
+    type Writer = Scrubbed<W::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Scrubbed::new(self.inner.make_writer(), self.scrubber.clone())
+    }
+}
+
+impl<W: Write> Write for Scrubbed<W> {
+    // events are formatted whole and written at once
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.scrubber {
+            Some(scrubber) => {
+                let line = String::from_utf8_lossy(buf);
+                self.inner.write_all(scrubber.scrub(&line).as_bytes())?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub() -> Result<(), BoxError> {
+        let config = PrivacyConfig {
+            fields: vec!["x-device-name".to_owned(), "peer".to_owned()],
+            salt: Some("pepper".to_owned()),
+            ..Default::default()
+        };
+        let uuid = "7f1b9e0c2d4a4e6f8a1b3c5d7e9f0a12";
+        let line = format!(
+            r#"{{"timestamp":"2026-10-17T12:34:56.789Z","uri":"/v6/device(uuid='{}')","headers":"{{\"x-device-name\": \"kiosk-7\", \"x-forwarded-for\": \"203.0.113.9\"}}","user":"ann@example.com","peer":"[2001:db8::7]:4431"}}"#,
+            uuid
+        );
+        let hashed = Scrubber::new(config.clone())?;
+        let scrubbed = hashed.scrub(&line);
+        for identifier in [
+            uuid,
+            "kiosk-7",
+            "203.0.113.9",
+            "ann@example.com",
+            "2001:db8::7",
+        ] {
+            assert!(!scrubbed.contains(identifier), "{}", scrubbed);
+        }
+        assert!(scrubbed.contains("2026-10-17T12:34:56.789Z"));
+        assert!(scrubbed.contains(&format!("device(uuid='{}')", hashed.hash(uuid))));
+        // the same value hashes the same, for a salt
+        assert_eq!(hashed.scrub(uuid), hashed.scrub(uuid));
+
+        let truncated = Scrubber::new(PrivacyConfig {
+            mode: PrivacyMode::Truncate,
+            fields: Vec::new(),
+            ..config
+        })?;
+        let scrubbed = truncated.scrub(&line);
+        assert!(
+            scrubbed.contains("device(uuid='7f1b9e0...')"),
+            "{}",
+            scrubbed
+        );
+        assert!(scrubbed.contains("203.0.113.0"));
+        assert!(scrubbed.contains("[2001:db8::]:4431"));
+        assert!(scrubbed.contains("kiosk-7"));
+        // lines without identifiers are left alone
+        let plain = "upstream endpoint ejected addr=proxy::dns version=1.2.3";
+        assert_eq!(truncated.scrub(plain), plain);
+        Ok(())
+    }
+}
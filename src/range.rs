@@ -0,0 +1,132 @@
+//! Range requests.
+//!
+//! `Range` and `If-Range` requests go upstream as they are, and layers
+//! buffering response bodies leave 206 responses alone, so image and delta
+//! downloads can resume through the proxy. [`serve_range`] answers a range
+//! request out of a full body the proxy holds itself.
+
+use bytes::Bytes;
+use http::{
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+
+/// Whether `res` carries only part of its resource.
+pub fn is_partial<B>(res: &Response<B>) -> bool {
+    res.status() == StatusCode::PARTIAL_CONTENT
+}
+
+/// The first and last byte a single `bytes=` range selects of `len` bytes,
+/// `Err` when it selects none. Ranges that don't parse, and multiple
+/// ranges, are ignored.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        // the last `last` bytes
+        let suffix: u64 = last.parse().ok()?;
+        (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+    } else {
+        let first: u64 = first.parse().ok()?;
+        let last = match last {
+            "" => len.saturating_sub(1),
+            last => last.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+        };
+        (first <= last && first < len).then_some((first, last))
+    };
+    Some(range.ok_or(()))
+}
+
+/// Whether the validator of an `If-Range` request matches the resource.
+fn if_range_matches(request: &HeaderMap, response: &HeaderMap) -> bool {
+    let Some(validator) = request.get(IF_RANGE) else {
+        return true;
+    };
+    // weak entity tags never match
+    if validator.as_bytes().starts_with(b"W/") {
+        return false;
+    }
+    response.get(ETAG) == Some(validator) || response.get(LAST_MODIFIED) == Some(validator)
+}
+
+/// Answer the range, if any, that a request with `request` headers asks of
+/// `res`, a full 200 response: a 206 with the range, a 416 when the range
+/// is out of the body, or `res` itself otherwise.
+pub fn serve_range(request: &HeaderMap, mut res: Response<Bytes>) -> Response<Bytes> {
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+    res.headers_mut()
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let len = res.body().len() as u64;
+    let range = match request.get(RANGE).and_then(|value| value.to_str().ok()) {
+        Some(value) if if_range_matches(request, res.headers()) => parse_range(value, len),
+        _ => None,
+    };
+    match range {
+        None => res,
+        Some(Ok((first, last))) => {
+            let body = res.body().slice(first as usize..=last as usize);
+            let headers = res.headers_mut();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, len))
+                    .expect("valid header value"),
+            );
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            *res.body_mut() = body;
+            res
+        }
+        Some(Err(())) => {
+            let mut res = Response::new(Bytes::new());
+            *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            res.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len)).expect("valid header value"),
+            );
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serve(range: &str, if_range: Option<&'static str>) -> Response<Bytes> {
+        let mut request = HeaderMap::new();
+        request.insert(RANGE, HeaderValue::from_str(range).unwrap());
+        if let Some(if_range) = if_range {
+            request.insert(IF_RANGE, HeaderValue::from_static(if_range));
+        }
+        let mut res = Response::new(Bytes::from_static(b"0123456789"));
+        res.headers_mut()
+            .insert(ETAG, HeaderValue::from_static("\"v1\""));
+        serve_range(&request, res)
+    }
+
+    #[test]
+    fn test_serve_range() {
+        let res = serve("bytes=2-4", None);
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(res.body(), "234");
+        assert_eq!(serve("bytes=7-", None).body(), "789");
+        assert_eq!(serve("bytes=-3", None).body(), "789");
+        assert_eq!(serve("bytes=8-100", None).body(), "89");
+
+        let res = serve("bytes=10-", None);
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes */10");
+
+        // the whole body when the resource changed, or for multiple ranges
+        assert_eq!(serve("bytes=2-4", Some("\"v1\"")).body(), "234");
+        assert_eq!(serve("bytes=2-4", Some("\"v0\"")).status(), StatusCode::OK);
+        assert_eq!(serve("bytes=0-1,4-5", None).body(), "0123456789");
+    }
+}
@@ -1,19 +1,46 @@
 use std::{
+    fmt,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::Duration,
 };
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_core::Future;
-use http::Request;
-use tower::{Layer, Service};
-
+use futures_util::FutureExt;
+use http::{
+    header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+    HeaderMap, HeaderValue, Method, Request,
+};
+use http_body::Body;
+use tower::{BoxError, Layer, Service};
+
+use crate::buffer_pool::POOL;
+
+/// A buffered body, along with the trailers that followed it. Note that
+/// hyper only reads and writes trailers on HTTP/2 connections.
+///
+/// Clones share the data, which is handed out without copying it.
+///
+/// A body over the buffering cap is streamed instead: the data holds what was
+/// read before the cap was reached, and the rest follows from the client
+/// connection. Its clones share that rest, which only one of them can send.
 #[derive(Clone)]
 pub struct ByteBody {
-    data: Arc<Vec<u8>>,
+    data: Bytes,
+    trailers: Option<HeaderMap>,
+    // the data was handed out already
+    done: bool,
+    rest: Option<Arc<Mutex<hyper::Body>>>,
 }
 
+/// Marks a request whose body is streamed rather than buffered, see
+/// [`ByteBody`]. It can only be sent once, so it is neither retried nor
+/// queued for later delivery.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamedBody;
+
 impl std::fmt::Debug for ByteBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut data = Vec::new();
@@ -25,18 +52,105 @@ impl std::fmt::Debug for ByteBody {
 
 impl ByteBody {
     pub fn new(data: Vec<u8>) -> Self {
+        Self::from(Bytes::from(data))
+    }
+
+    pub fn with_trailers(mut self, trailers: Option<HeaderMap>) -> Self {
+        self.trailers = trailers;
+        self
+    }
+
+    /// The body before `rest`, sent once `data` is.
+    pub(crate) fn streaming(data: Bytes, rest: hyper::Body) -> Self {
         Self {
-            data: Arc::new(data),
+            rest: Some(Arc::new(Mutex::new(rest))),
+            ..Self::from(data)
         }
     }
+
+    /// The buffered data, only the start of the body if it is streamed.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.rest.is_some()
+    }
+
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
 }
 
 impl From<Bytes> for ByteBody {
-    fn from(value: Bytes) -> Self {
-        Self::new(Vec::from(value))
+    fn from(data: Bytes) -> Self {
+        Self {
+            data,
+            trailers: None,
+            done: false,
+            rest: None,
+        }
+    }
+}
+
+/// Make the framing headers of a request match its buffered body, so the
+/// upstream never sees a stale `Content-Length` or `Transfer-Encoding`.
+pub fn fix_length(headers: &mut HeaderMap, body: &ByteBody) {
+    if body.is_streamed() {
+        // the client's framing still describes it
+        return;
+    }
+    headers.remove(TRANSFER_ENCODING);
+    if body.trailers().is_some() {
+        // sent chunked so the trailers can follow
+        headers.remove(CONTENT_LENGTH);
+    } else if !body.as_bytes().is_empty() || headers.contains_key(CONTENT_LENGTH) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.as_bytes().len()));
+    }
+}
+
+/// Bodies that can be rebuilt from buffered data and trailers, so layers
+/// buffering responses don't drop the trailers.
+pub trait FromBuffered: From<Bytes> {
+    fn from_buffered(data: Bytes, trailers: Option<HeaderMap>) -> Self;
+}
+
+impl FromBuffered for ByteBody {
+    fn from_buffered(data: Bytes, trailers: Option<HeaderMap>) -> Self {
+        ByteBody::from(data).with_trailers(trailers)
+    }
+}
+
+impl FromBuffered for hyper::Body {
+    fn from_buffered(data: Bytes, trailers: Option<HeaderMap>) -> Self {
+        let trailers = match trailers {
+            Some(trailers) => trailers,
+            None => return hyper::Body::from(data),
+        };
+        let (mut sender, body) = hyper::Body::channel();
+        // a fresh channel has room for one chunk, and sending trailers
+        // completes right away
+        let _ = sender.try_send_data(data);
+        let _ = sender.send_trailers(trailers).now_or_never();
+        body
     }
 }
 
+/// Read `body` to the end, keeping its trailers.
+pub async fn buffer<B>(body: B) -> Result<(Bytes, Option<HeaderMap>), BoxError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<BoxError>,
+{
+    let mut body = Box::pin(body);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk.map_err(Into::into)?);
+    }
+    let trailers = body.trailers().await.map_err(Into::into)?;
+    Ok((buf.freeze(), trailers))
+}
+
 impl http_body::Body for ByteBody {
     type Data = Bytes;
 
@@ -44,75 +158,189 @@ impl http_body::Body for ByteBody {
 
     fn poll_data(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
-        let bytes = Bytes::copy_from_slice(&self.data);
-        Poll::Ready(Some(Ok(bytes)))
+        let this = self.get_mut();
+        if !this.done && !this.data.is_empty() {
+            this.done = true;
+            return Poll::Ready(Some(Ok(this.data.clone())));
+        }
+        this.done = true;
+        match &this.rest {
+            Some(rest) => Pin::new(&mut *rest.lock().unwrap()).poll_data(cx),
+            None => Poll::Ready(None),
+        }
     }
 
     fn poll_trailers(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
-        Poll::Ready(Ok(None))
+        match &self.rest {
+            Some(rest) => Pin::new(&mut *rest.lock().unwrap()).poll_trailers(cx),
+            None => Poll::Ready(Ok(self.trailers.clone())),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        (self.done || self.data.is_empty())
+            && self.trailers.is_none()
+            && self
+                .rest
+                .as_ref()
+                .is_none_or(|rest| rest.lock().unwrap().is_end_stream())
     }
 
     fn size_hint(&self) -> http_body::SizeHint {
         let length = self.data.len() as u64;
-        http_body::SizeHint::with_exact(length)
+        if let Some(rest) = &self.rest {
+            let rest = rest.lock().unwrap().size_hint();
+            let mut hint = http_body::SizeHint::new();
+            hint.set_lower(length + rest.lower());
+            if let Some(upper) = rest.upper() {
+                hint.set_upper(length + upper);
+            }
+            return hint;
+        }
+        if self.trailers.is_none() {
+            return http_body::SizeHint::with_exact(length);
+        }
+        // no exact length, so the body is sent chunked with its trailers
+        let mut hint = http_body::SizeHint::new();
+        hint.set_lower(length);
+        hint
     }
 }
 
+/// The client stopped sending the request body.
+#[derive(Debug)]
+pub struct BodyReadTimeout;
+
+impl fmt::Display for BodyReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out reading request body")
+    }
+}
+
+impl std::error::Error for BodyReadTimeout {}
+
+/// Wait for `fut`, failing once the body has been idle for `idle_timeout`.
+async fn idle<F: Future>(fut: F, idle_timeout: Option<Duration>) -> Result<F::Output, BoxError> {
+    match idle_timeout {
+        Some(idle_timeout) => Ok(tokio::time::timeout(idle_timeout, fut)
+            .await
+            .map_err(|_| BodyReadTimeout)?),
+        None => Ok(fut.await),
+    }
+}
+
+/// Read `body` into a pooled buffer, sized up front when its length is known.
+/// Past `max_buffered` bytes, the rest of it is streamed.
+async fn read_body(
+    mut body: hyper::Body,
+    idle_timeout: Option<Duration>,
+    max_buffered: Option<usize>,
+) -> Result<ByteBody, BoxError> {
+    let max_buffered = max_buffered.unwrap_or(usize::MAX);
+    let length = body.size_hint().lower() as usize;
+    if length > max_buffered {
+        return Ok(ByteBody::streaming(Bytes::new(), body));
+    }
+    let mut buf = POOL.take(length);
+    while let Some(chunk) = idle(body.data(), idle_timeout).await? {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > max_buffered {
+            return Ok(ByteBody::streaming(Bytes::from_owner(buf), body));
+        }
+    }
+    let trailers = idle(body.trailers(), idle_timeout).await??;
+    Ok(ByteBody::from(Bytes::from_owner(buf)).with_trailers(trailers))
+}
+
 #[derive(Clone)]
 pub struct ReadRequestBody<S> {
     inner: S,
+    idle_timeout: Option<Duration>,
+    max_buffered: Option<usize>,
 }
 
 impl<S> ReadRequestBody<S> {
     pub fn new(service: S) -> Self {
-        Self { inner: service }
+        Self {
+            inner: service,
+            idle_timeout: None,
+            max_buffered: None,
+        }
     }
 }
 
 impl<S> Service<Request<hyper::Body>> for ReadRequestBody<S>
 where
     S: Service<Request<ByteBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
     S::Future: Send,
 {
     type Response = S::Response;
 
-    type Error = S::Error;
+    type Error = BoxError;
 
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        self.inner.poll_ready(cx).map_err(Into::into)
     }
 
     fn call(&mut self, req: Request<hyper::Body>) -> Self::Future {
         let clone = self.inner.clone();
         // take the service that was ready
         let mut inner = std::mem::replace(&mut self.inner, clone);
+        let (idle_timeout, max_buffered) = (self.idle_timeout, self.max_buffered);
 
         Box::pin(async move {
-            let (parts, b) = req.into_parts();
-            let bytes = hyper::body::to_bytes(b).await;
-            let bytes = bytes.unwrap();
-            let req = Request::from_parts(parts, ByteBody::from(bytes));
-
-            inner.call(req).await
+            let (mut parts, b) = req.into_parts();
+            // a failed body read closes the connection
+            let body = if parts.method == Method::HEAD {
+                // HEAD requests carry no body worth waiting for
+                ByteBody::new(Vec::new())
+            } else {
+                read_body(b, idle_timeout, max_buffered).await?
+            };
+            if body.is_streamed() {
+                tracing::debug!("request body over the buffering cap, streaming it");
+                parts.extensions.insert(StreamedBody);
+            }
+            fix_length(&mut parts.headers, &body);
+            let req = Request::from_parts(parts, body);
+
+            inner.call(req).await.map_err(Into::into)
         })
     }
 }
 
 /// Enforces a rate limit on the number of requests the underlying
 /// service can handle over a period of time.
-#[derive(Debug, Clone)]
-pub struct ReadRequestLayer;
+#[derive(Debug, Clone, Default)]
+pub struct ReadRequestLayer {
+    idle_timeout: Option<Duration>,
+    max_buffered: Option<usize>,
+}
 
 impl ReadRequestLayer {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    /// Fail requests whose body stalls for longer than `timeout` while it is
+    /// buffered.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Stream the bodies longer than `max` bytes rather than buffering them.
+    pub fn max_buffered(mut self, max: Option<usize>) -> Self {
+        self.max_buffered = max;
+        self
     }
 }
 
@@ -120,7 +348,11 @@ impl<S> Layer<S> for ReadRequestLayer {
     type Service = ReadRequestBody<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        ReadRequestBody::new(service)
+        ReadRequestBody {
+            inner: service,
+            idle_timeout: self.idle_timeout,
+            max_buffered: self.max_buffered,
+        }
     }
 }
 
@@ -130,10 +362,10 @@ mod tests {
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::https::HttpsConnector;
     use http::Request;
     use httpmock::prelude::*;
     use hyper::Client;
-    use hyper_tls::HttpsConnector;
     use serde_json::json;
     use tower::{ServiceBuilder, ServiceExt};
 
@@ -184,7 +416,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_read_request() -> Result<(), Box<dyn Error>> {
+    async fn test_read_request() -> Result<(), BoxError> {
         // Arrange
         // let _ = env_logger::try_init();
         let server = MockServer::start();
@@ -222,4 +454,126 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_read_request_idle_timeout() -> Result<(), BoxError> {
+        let (mut sender, body) = hyper::Body::channel();
+        sender.send_data(Bytes::from_static(b"{")).await?;
+        let request = Request::builder().method("POST").uri("/").body(body)?;
+
+        let https_client = Client::builder().build::<_, ByteBody>(HttpsConnector::new());
+        let mut client = ServiceBuilder::new()
+            .layer(ReadRequestLayer::new().idle_timeout(Some(Duration::from_millis(50))))
+            .service(https_client);
+
+        // the sender is kept open but never sends the rest
+        let err = client.ready().await?.call(request).await.unwrap_err();
+        assert!(err.is::<BodyReadTimeout>());
+        drop(sender);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trailers_preserved() -> Result<(), BoxError> {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse()?);
+
+        // request trailers reach the services behind the layer
+        let (mut sender, body) = hyper::Body::channel();
+        sender.send_data(Bytes::from_static(b"data")).await?;
+        sender.send_trailers(trailers.clone()).await?;
+        drop(sender);
+        let service =
+            ReadRequestLayer::new().layer(tower::service_fn(|req: Request<ByteBody>| async move {
+                Ok::<_, BoxError>(req.body().trailers().cloned())
+            }));
+        let request = Request::post("/").body(body)?;
+        let received = tower::ServiceExt::oneshot(service, request).await?;
+        assert_eq!(received.as_ref(), Some(&trailers));
+
+        // and survive buffering of response bodies
+        let body = hyper::Body::from_buffered(Bytes::from_static(b"data"), Some(trailers.clone()));
+        let (data, received) = buffer(body).await?;
+        assert_eq!(data, "data");
+        assert_eq!(received, Some(trailers));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_body_over_cap_streamed() -> Result<(), BoxError> {
+        let service = ReadRequestLayer::new()
+            .max_buffered(Some(4))
+            .layer(tower::service_fn(|req: Request<ByteBody>| async move {
+                let streamed = req.extensions().get::<StreamedBody>().is_some();
+                let length = req.headers().get(CONTENT_LENGTH).cloned();
+                let (data, _) = buffer(req.into_body()).await?;
+                Ok::<_, BoxError>((streamed, length, data))
+            }));
+
+        // with a length over the cap, nothing is buffered
+        let request = Request::post("/").body(hyper::Body::from("streamed body"))?;
+        let (streamed, length, data) = service.clone().oneshot(request).await?;
+        assert!(streamed);
+        assert_eq!(length, None);
+        assert_eq!(data, "streamed body");
+
+        // without, the start is buffered until it goes over the cap
+        let (mut sender, body) = hyper::Body::channel();
+        sender.send_data(Bytes::from_static(b"abc")).await?;
+        let request = Request::post("/")
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(body)?;
+        let response = tokio::spawn(service.clone().oneshot(request));
+        for chunk in ["de", "fgh"] {
+            sender
+                .send_data(Bytes::from_static(chunk.as_bytes()))
+                .await?;
+        }
+        drop(sender);
+        let (streamed, _, data) = response.await??;
+        assert!(streamed);
+        assert_eq!(data, "abcdefgh");
+
+        // under it, the body is buffered as usual
+        let request = Request::post("/").body(hyper::Body::from("abc"))?;
+        let (streamed, length, _) = service.oneshot(request).await?;
+        assert!(!streamed);
+        assert_eq!(length.unwrap(), "3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_fix_length() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(TRANSFER_ENCODING, "chunked".parse().unwrap());
+        fix_length(&mut headers, &ByteBody::new(b"hello".to_vec()));
+        assert_eq!(headers[CONTENT_LENGTH], "5");
+        assert!(!headers.contains_key(TRANSFER_ENCODING));
+
+        // bodyless requests get no length
+        let mut headers = http::HeaderMap::new();
+        fix_length(&mut headers, &ByteBody::new(Vec::new()));
+        assert!(headers.is_empty());
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, "5".parse().unwrap());
+        let body = ByteBody::new(b"hello".to_vec()).with_trailers(Some(http::HeaderMap::new()));
+        fix_length(&mut headers, &body);
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn test_head_body_skipped() -> Result<(), BoxError> {
+        // a body that never ends would time out if it were read
+        let (_sender, body) = hyper::Body::channel();
+        let service = ReadRequestLayer::new()
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .layer(tower::service_fn(|req: Request<ByteBody>| async move {
+                Ok::<_, BoxError>(req.body().as_bytes().len())
+            }));
+        let request = Request::head("/v6/device").body(body)?;
+        assert_eq!(tower::ServiceExt::oneshot(service, request).await?, 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,635 @@
+//! Traffic recording.
+//!
+//! [`RecordLayer`] buffers the response of a sampled share of the requests
+//! and writes the request/response pair to a file, either as newline-delimited
+//! JSON or as a HAR archive. Configured headers and JSON body fields are
+//! redacted before anything reaches the disk. The file is written by a
+//! background thread, so a slow disk never blocks the request path. While it
+//! is behind, records past a bounded queue are dropped and counted rather
+//! than held in memory. Dropping the last handle to the layer waits for it to
+//! write the queued records.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    gzip,
+    metrics::Metric,
+    read_request_body::{buffer, ByteBody, FromBuffered},
+    rng::{HasherRng, Rng},
+    route::RouteMatcher,
+    secret::SENSITIVE_HEADERS,
+};
+
+/// Replacement for redacted header values and body fields.
+pub const REDACTED: &str = "[redacted]";
+
+const DROPPED: Metric = Metric::counter(
+    "proxy_recording_dropped_total",
+    "Sampled records dropped as the recording writer fell behind.",
+);
+
+/// Records waiting for the writer, later ones are dropped until it catches
+/// up.
+const MAX_QUEUED_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: RecordFormat,
+    /// Fraction of the matching requests recorded.
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    /// Only requests matching one of these routes are recorded, all of them
+    /// when empty.
+    #[serde(default)]
+    pub routes: Vec<RouteMatcher>,
+    /// Headers whose values are replaced, matched case-insensitively.
+    #[serde(default = "default_redact_headers")]
+    pub redact_headers: Vec<String>,
+    /// JSON object keys whose values are replaced, at any depth.
+    #[serde(default)]
+    pub redact_fields: Vec<String>,
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+fn default_redact_headers() -> Vec<String> {
+    SENSITIVE_HEADERS.map(String::from).to_vec()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// One [`Record`] per line.
+    #[default]
+    Ndjson,
+    /// HTTP Archive 1.2, readable by browser dev tools.
+    Har,
+}
+
+/// A recorded request/response pair, one line of an NDJSON recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// RFC 3339 time the request was received.
+    pub started: String,
+    pub duration_ms: u64,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+impl Record {
+    /// Read a recording, either NDJSON or a HAR archive.
+    pub fn read_all(data: &[u8]) -> Result<Vec<Record>, BoxError> {
+        if let Ok(har) = serde_json::from_slice::<serde_json::Value>(data) {
+            if let Some(entries) = har["log"]["entries"].as_array() {
+                return entries
+                    .iter()
+                    .map(|entry| Record::from_har_entry(entry).ok_or("invalid HAR entry".into()))
+                    .collect();
+            }
+        }
+        data.split(|&b| b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).map_err(Into::into))
+            .collect()
+    }
+
+    fn from_har_entry(entry: &serde_json::Value) -> Option<Record> {
+        let pairs = |headers: &serde_json::Value| {
+            headers
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|h| Some((h["name"].as_str()?.into(), h["value"].as_str()?.into())))
+                .collect::<Vec<_>>()
+        };
+        let request = &entry["request"];
+        let response = &entry["response"];
+        Some(Record {
+            started: entry["startedDateTime"].as_str()?.into(),
+            duration_ms: entry["time"].as_f64()? as u64,
+            request: RecordedRequest {
+                method: request["method"].as_str()?.into(),
+                uri: request["url"].as_str()?.into(),
+                headers: pairs(&request["headers"]),
+                body: request["postData"]["text"].as_str().map(Into::into),
+            },
+            response: RecordedResponse {
+                status: response["status"].as_u64()?.try_into().ok()?,
+                headers: pairs(&response["headers"]),
+                body: response["content"]["text"]
+                    .as_str()
+                    .filter(|text| !text.is_empty())
+                    .map(Into::into),
+            },
+        })
+    }
+
+    /// The record as a HAR `entry` object.
+    fn to_har_entry(&self) -> serde_json::Value {
+        let pairs = |headers: &[(String, String)]| {
+            headers
+                .iter()
+                .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>()
+        };
+        let mut request = serde_json::json!({
+            "method": self.request.method,
+            "url": self.request.uri,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": pairs(&self.request.headers),
+            "queryString": [],
+            "headersSize": -1,
+            "bodySize": self.request.body.as_ref().map_or(0, |b| b.len()),
+        });
+        if let Some(body) = &self.request.body {
+            request["postData"] = serde_json::json!({
+                "mimeType": header(&self.request.headers, "content-type").unwrap_or_default(),
+                "text": body,
+            });
+        }
+        serde_json::json!({
+            "startedDateTime": self.started,
+            "time": self.duration_ms,
+            "request": request,
+            "response": {
+                "status": self.response.status,
+                "statusText": "",
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": pairs(&self.response.headers),
+                "content": {
+                    "size": self.response.body.as_ref().map_or(0, |b| b.len()),
+                    "mimeType": header(&self.response.headers, "content-type").unwrap_or_default(),
+                    "text": self.response.body.clone().unwrap_or_default(),
+                },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            },
+            "cache": {},
+            "timings": { "send": 0, "wait": self.duration_ms, "receive": 0 },
+        })
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+struct Redactor {
+    headers: Vec<String>,
+    fields: Vec<String>,
+}
+
+impl Redactor {
+    fn headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self
+                    .headers
+                    .iter()
+                    .any(|h| name.as_str().eq_ignore_ascii_case(h))
+                {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn body(&self, body: &[u8]) -> Option<String> {
+        if body.is_empty() {
+            return None;
+        }
+        if !self.fields.is_empty() {
+            if let Ok(mut value) = serde_json::from_slice::<serde_json::Value>(body) {
+                self.redact_value(&mut value);
+                return Some(value.to_string());
+            }
+        }
+        Some(String::from_utf8_lossy(body).into_owned())
+    }
+
+    fn redact_value(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.fields.contains(key) {
+                        *value = serde_json::Value::from(REDACTED);
+                    } else {
+                        self.redact_value(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => {
+                items.iter_mut().for_each(|v| self.redact_value(v));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Appends records to the recording file.
+struct RecordWriter {
+    out: BufWriter<File>,
+    format: RecordFormat,
+    entries: usize,
+}
+
+/// Closing brackets of a HAR file, rewritten after every entry.
+const HAR_TAIL: &[u8] = b"\n]}}\n";
+
+impl RecordWriter {
+    fn open(path: &Path, format: RecordFormat) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = match format {
+            RecordFormat::Ndjson => OpenOptions::new().create(true).append(true).open(path)?,
+            // a HAR file is a single JSON document, start a new one
+            RecordFormat::Har => File::create(path)?,
+        };
+        let mut writer = Self {
+            out: BufWriter::new(file),
+            format,
+            entries: 0,
+        };
+        if format == RecordFormat::Har {
+            let creator = serde_json::json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            });
+            write!(
+                writer.out,
+                r#"{{"log":{{"version":"1.2","creator":{},"entries":["#,
+                creator
+            )?;
+            writer.out.write_all(HAR_TAIL)?;
+            writer.out.flush()?;
+        }
+        Ok(writer)
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            RecordFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, record)?;
+                self.out.write_all(b"\n")?;
+            }
+            RecordFormat::Har => {
+                // overwrite the closing brackets with the new entry
+                self.out.seek(SeekFrom::End(-(HAR_TAIL.len() as i64)))?;
+                if self.entries > 0 {
+                    self.out.write_all(b",")?;
+                }
+                self.out.write_all(b"\n")?;
+                serde_json::to_writer(&mut self.out, &record.to_har_entry())?;
+                self.out.write_all(HAR_TAIL)?;
+            }
+        }
+        self.entries += 1;
+        self.out.flush()
+    }
+}
+
+struct Recorder {
+    routes: Vec<RouteMatcher>,
+    rate: f64,
+    redactor: Redactor,
+    rng: Mutex<HasherRng>,
+    // taken on drop to stop the writer
+    sender: Mutex<Option<mpsc::SyncSender<Record>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    fn sample<B>(&self, req: &Request<B>) -> bool {
+        (self.routes.is_empty() || self.routes.iter().any(|r| r.matches(req)))
+            && (self.rate >= 1.0 || self.rng.lock().unwrap().next_f64() < self.rate)
+    }
+
+    fn send(&self, record: Record) {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return;
+        };
+        match sender.try_send(record) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(_)) => {
+                DROPPED.increment(&[]);
+                tracing::debug!("recording writer is behind, dropping record");
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                tracing::warn!("recording writer is gone, dropping record")
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /// Wait for the writer to write the queued records out.
+    fn drop(&mut self) {
+        self.sender.get_mut().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RecordLayer {
+    recorder: Arc<Recorder>,
+}
+
+impl RecordLayer {
+    /// Open the recording file and start the writer thread.
+    pub fn new(config: RecordingConfig) -> Result<Self, BoxError> {
+        let mut writer = RecordWriter::open(&config.path, config.format)
+            .map_err(|err| format!("{}: {}", config.path.display(), err))?;
+        let (sender, receiver) = mpsc::sync_channel::<Record>(MAX_QUEUED_RECORDS);
+        let path = config.path.clone();
+        let writer = std::thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(err) = writer.write(&record) {
+                        tracing::error!(path = %path.display(), %err, "failed to write record");
+                    }
+                }
+            })?;
+
+        let redactor = Redactor {
+            headers: config.redact_headers,
+            fields: config.redact_fields,
+        };
+        Ok(Self {
+            recorder: Arc::new(Recorder {
+                routes: config.routes,
+                rate: config.rate,
+                redactor,
+                rng: Mutex::new(HasherRng::new()),
+                sender: Mutex::new(Some(sender)),
+                writer: Some(writer),
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for RecordLayer {
+    type Service = Recording<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Recording {
+            inner: service,
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Recording<S> {
+    inner: S,
+    recorder: Arc<Recorder>,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Recording<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ResBody: http_body::Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if !self.recorder.sample(&req) {
+            return Box::pin(async move { inner.call(req).await.map_err(Into::into) });
+        }
+
+        let recorder = self.recorder.clone();
+        let request = RecordedRequest {
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: recorder.redactor.headers(req.headers()),
+            body: recorder.redactor.body(req.body().as_bytes()),
+        };
+        let started = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let start = Instant::now();
+        let head = req.method() == Method::HEAD;
+
+        Box::pin(async move {
+            let res = inner.call(req).await.map_err(Into::into)?;
+            let (parts, body) = res.into_parts();
+            let record = |bytes: &[u8]| Record {
+                started,
+                duration_ms: duration_ms(start.elapsed()),
+                request,
+                response: RecordedResponse {
+                    status: parts.status.as_u16(),
+                    headers: recorder.redactor.headers(&parts.headers),
+                    body: recorder
+                        .redactor
+                        .body(&gzip::inspect(&parts.headers, bytes)),
+                },
+            };
+
+            // HEAD responses have no body worth buffering, and partial ones
+            // stream through untouched so downloads can resume
+            if head || parts.status == StatusCode::PARTIAL_CONTENT {
+                recorder.send(record(&[]));
+                return Ok(Response::from_parts(parts, body));
+            }
+            let (bytes, trailers) = buffer(body).await?;
+            recorder.send(record(&bytes));
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    fn config(path: PathBuf, format: RecordFormat) -> RecordingConfig {
+        serde_json::from_value(serde_json::json!({
+            "path": path,
+            "format": format!("{:?}", format).to_lowercase(),
+            "redact_fields": ["api_key"],
+        }))
+        .expect("valid config")
+    }
+
+    fn upstream() -> MockUpstream {
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "body": { "id": 1, "api_key": "secret" },
+        }))
+        .expect("valid fixture");
+        MockUpstream::new(vec![fixture])
+    }
+
+    async fn record(path: &Path, format: RecordFormat) -> Result<(), BoxError> {
+        let layer = RecordLayer::new(config(path.to_path_buf(), format))?;
+        let service = layer.layer(upstream());
+        for id in 0..2 {
+            let req = Request::post(format!("/v6/device/{}", id))
+                .header("authorization", "Bearer key")
+                .body(ByteBody::new(br#"{"api_key":"key","name":"dev"}"#.to_vec()))?;
+            let res = service.clone().oneshot(req).await?;
+            // the client still gets the full response
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            assert_eq!(body, r#"{"api_key":"secret","id":1}"#);
+        }
+        // dropping the last handle waits for the writer
+        drop(layer);
+        drop(service);
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_behind() {
+        let (sender, _receiver) = mpsc::sync_channel(1);
+        let recorder = Recorder {
+            routes: Vec::new(),
+            rate: 1.0,
+            redactor: Redactor {
+                headers: Vec::new(),
+                fields: Vec::new(),
+            },
+            rng: Mutex::new(HasherRng::new()),
+            sender: Mutex::new(Some(sender)),
+            writer: None,
+        };
+        let record = || Record {
+            started: String::new(),
+            duration_ms: 0,
+            request: RecordedRequest {
+                method: "GET".into(),
+                uri: "/".into(),
+                headers: Vec::new(),
+                body: None,
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: None,
+            },
+        };
+        let dropped = DROPPED.get(&[]);
+        recorder.send(record());
+        recorder.send(record());
+        assert_eq!(DROPPED.get(&[]) - dropped, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson() -> Result<(), BoxError> {
+        let path = std::env::temp_dir().join(format!("proxy-record-{}.ndjson", std::process::id()));
+        record(&path, RecordFormat::Ndjson).await?;
+        let data = fs::read_to_string(&path)?;
+        fs::remove_file(&path)?;
+
+        let records = Record::read_all(data.as_bytes())?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].request.uri, "/v6/device/1");
+        assert_eq!(
+            header(&records[0].request.headers, "authorization"),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            records[0].request.body.as_deref(),
+            Some(r#"{"api_key":"[redacted]","name":"dev"}"#)
+        );
+        assert_eq!(records[0].response.status, 200);
+        assert_eq!(
+            records[0].response.body.as_deref(),
+            Some(r#"{"api_key":"[redacted]","id":1}"#)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_har() -> Result<(), BoxError> {
+        let path = std::env::temp_dir().join(format!("proxy-record-{}.har", std::process::id()));
+        record(&path, RecordFormat::Har).await?;
+        let data = fs::read(&path)?;
+        fs::remove_file(&path)?;
+
+        let har: serde_json::Value = serde_json::from_slice(&data)?;
+        let entries = har["log"]["entries"].as_array().expect("entries");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["method"], "POST");
+
+        // HAR files can be read back for replay
+        let records = Record::read_all(&data)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].request.uri, "/v6/device/1");
+        assert_eq!(records[1].response.status, 200);
+        Ok(())
+    }
+}
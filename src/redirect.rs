@@ -0,0 +1,330 @@
+//! Upstream redirects followed by the proxy.
+//!
+//! Some device clients cannot follow redirects. For routes with a
+//! [`RedirectRule`], [`FollowRedirectLayer`] follows 301, 302, 303, 307 and
+//! 308 responses of the upstream itself, up to `max_hops` times, and answers
+//! with where they lead. 303s, and 301s and 302s to POSTs, are followed with
+//! a GET without a body, as browsers do; the others repeat the request,
+//! which streamed bodies cannot, so their redirects go back to the client.
+//! With `same_host`, the default, redirects to other hosts go back too.
+//! Otherwise they are followed without credentials: the client's own
+//! `Authorization` is not sent along, and requests marked [`ForeignHost`]
+//! get no pool key either, as the upstream picks where they go. The
+//! redirect the proxy stopped at is answered as it came.
+//! Each hop is forwarded as a request of its own, with its own timeout and
+//! retries.
+
+use std::{
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    Method, Request, Response, StatusCode, Uri,
+};
+use serde::Deserialize;
+use tower::{Layer, Service, ServiceExt};
+
+use crate::{
+    context::ProxyContext,
+    forward_request::{forward_uri, Upstream},
+    metrics::Metric,
+    read_request_body::{ByteBody, StreamedBody},
+    route::RouteMatcher,
+};
+
+const FOLLOWED: Metric = Metric::counter(
+    "proxy_redirects_followed_total",
+    "Upstream redirects followed by the proxy, by status.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Redirects followed per request.
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u32,
+    /// Follow redirects to the host the request went to only.
+    #[serde(default = "default_same_host")]
+    pub same_host: bool,
+}
+
+fn default_max_hops() -> u32 {
+    3
+}
+
+fn default_same_host() -> bool {
+    true
+}
+
+/// Marks a request following a redirect to another host than the one it was
+/// sent to, which no key of the pool is sent to.
+#[derive(Debug, Clone, Copy)]
+pub struct ForeignHost;
+
+/// `location` as an absolute URI, resolved against `from`.
+fn resolve(from: &Uri, location: &str) -> Option<Uri> {
+    let scheme = from.scheme_str()?;
+    let authority = from.authority()?;
+    let lower = location.to_ascii_lowercase();
+    let target = if lower.starts_with("http://") || lower.starts_with("https://") {
+        location.to_owned()
+    } else if location.starts_with("//") {
+        format!("{}:{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let path = from.path();
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    };
+    Uri::from_str(&target).ok()
+}
+
+/// A request for the same thing as `req`, to be sent again after a redirect.
+fn copy(req: &Request<ByteBody>) -> Request<ByteBody> {
+    let mut copy = Request::new(req.body().clone());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    if let Some(context) = ProxyContext::of(req) {
+        copy.extensions_mut().insert(context.clone());
+    }
+    if req.extensions().get::<StreamedBody>().is_some() {
+        copy.extensions_mut().insert(StreamedBody);
+    }
+    if req.extensions().get::<ForeignHost>().is_some() {
+        copy.extensions_mut().insert(ForeignHost);
+    }
+    copy
+}
+
+/// The request following the redirect of `res` to `req`, sent to `from`,
+/// and where it goes; `None` when the redirect goes back to the client.
+fn follow<B>(
+    rule: &RedirectRule,
+    req: &Request<ByteBody>,
+    from: &Uri,
+    res: &Response<B>,
+) -> Option<(Request<ByteBody>, Uri)> {
+    let status = res.status();
+    if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let location = res.headers().get(LOCATION)?.to_str().ok()?;
+    let target = resolve(from, location)?;
+    let same_host = target.scheme() == from.scheme() && target.authority() == from.authority();
+    if rule.same_host && !same_host {
+        return None;
+    }
+    let as_get = status == StatusCode::SEE_OTHER
+        || (matches!(status.as_u16(), 301 | 302) && req.method() == Method::POST);
+    if !as_get && req.extensions().get::<StreamedBody>().is_some() {
+        return None;
+    }
+
+    let mut next = copy(req);
+    if as_get {
+        *next.method_mut() = Method::GET;
+        *next.body_mut() = ByteBody::from(Bytes::new());
+        next.extensions_mut().remove::<StreamedBody>();
+        let headers = next.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(CONTENT_TYPE);
+    }
+    if !same_host {
+        next.headers_mut().remove(AUTHORIZATION);
+        next.extensions_mut().insert(ForeignHost);
+    }
+    let origin = format!("{}://{}", target.scheme_str()?, target.authority()?);
+    next.extensions_mut()
+        .insert(Upstream(Uri::from_str(&origin).ok()?));
+    *next.uri_mut() = Uri::from_str(target.path_and_query()?.as_str()).ok()?;
+    Some((next, target))
+}
+
+#[derive(Clone)]
+pub struct FollowRedirectLayer {
+    rules: Arc<Vec<RedirectRule>>,
+    upstream: Uri,
+}
+
+impl FollowRedirectLayer {
+    /// Follow redirects per `rules` for requests going to `upstream` unless
+    /// they say otherwise.
+    pub fn new(rules: Vec<RedirectRule>, upstream: Uri) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            upstream,
+        }
+    }
+}
+
+impl<S> Layer<S> for FollowRedirectLayer {
+    type Service = FollowRedirect<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FollowRedirect {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FollowRedirect<S> {
+    inner: S,
+    layer: FollowRedirectLayer,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for FollowRedirect<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let Some(rule) = self
+            .layer
+            .rules
+            .iter()
+            .find(|rule| rule.route.matches(&req))
+            .cloned()
+        else {
+            return Box::pin(self.inner.call(req));
+        };
+        let base = match req.extensions().get::<Upstream>() {
+            Some(Upstream(uri)) => uri,
+            None => &self.layer.upstream,
+        };
+        let mut from = forward_uri(base, req.uri());
+        let mut sent = copy(&req);
+        let fut = self.inner.call(req);
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let mut res = fut.await?;
+            for _ in 0..rule.max_hops {
+                let Some((next, target)) = follow(&rule, &sent, &from, &res) else {
+                    break;
+                };
+                FOLLOWED.increment(&[("status", res.status().as_str())]);
+                tracing::debug!(%from, to = %target, status = %res.status(), "following upstream redirect");
+                sent = copy(&next);
+                from = target;
+                res = inner.clone().oneshot(next).await?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::service_fn;
+
+    #[test]
+    fn test_resolve() {
+        let from = Uri::from_static("https://api.example/v6/device(1)?$select=id");
+        let resolve = |location| resolve(&from, location).unwrap().to_string();
+        assert_eq!(resolve("/v7/device"), "https://api.example/v7/device");
+        assert_eq!(resolve("device(2)"), "https://api.example/v6/device(2)");
+        assert_eq!(resolve("//cdn.example/a"), "https://cdn.example/a");
+        assert_eq!(resolve("HTTP://cdn.example/a"), "http://cdn.example/a");
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() -> Result<(), tower::BoxError> {
+        // redirects `/moved/<n>` to `/moved/<n - 1>`, `/moved/0` to `/done`,
+        // and `/away` to another host
+        let upstream = service_fn(|req: Request<ByteBody>| async move {
+            let base = req.extensions().get::<Upstream>().map(|u| u.0.clone());
+            let uri = forward_uri(
+                &base.unwrap_or(Uri::from_static("https://api.example")),
+                req.uri(),
+            );
+            let path = uri.path().to_owned();
+            let location = match path.strip_prefix("/moved/") {
+                Some("0") => Some("/done".to_owned()),
+                Some(n) => Some(format!("/moved/{}", n.parse::<u32>().unwrap() - 1)),
+                None if path == "/away" => Some("https://other.example/done".to_owned()),
+                None => None,
+            };
+            let mut res = Response::new(format!(
+                "{} {} {:?}",
+                req.method(),
+                uri,
+                req.headers().get(AUTHORIZATION)
+            ));
+            if let Some(location) = location {
+                *res.status_mut() = StatusCode::FOUND;
+                res.headers_mut()
+                    .insert(LOCATION, location.parse().unwrap());
+            }
+            Ok::<_, Infallible>(res)
+        });
+        let rules = serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/moved" }, "max_hops": 2 },
+            { "route": { "path": "/away", "methods": ["GET"] }, "same_host": false },
+        ]))?;
+        let layer = FollowRedirectLayer::new(rules, Uri::from_static("https://api.example"));
+        let service = layer.layer(upstream);
+        let send = |req: Request<ByteBody>| {
+            let service = service.clone();
+            async move {
+                let res = service.oneshot(req).await?;
+                Ok::<_, tower::BoxError>((res.status(), res.into_body()))
+            }
+        };
+
+        let post = Request::post("/moved/1")
+            .header(AUTHORIZATION, "Bearer own")
+            .body(ByteBody::new(b"{}".to_vec()))?;
+        assert_eq!(
+            send(post).await?,
+            (
+                StatusCode::OK,
+                "GET https://api.example/done Some(\"Bearer own\")".to_owned()
+            )
+        );
+        // no further than `max_hops`
+        let (status, _) =
+            send(Request::get("/moved/2").body(ByteBody::from(Bytes::new()))?).await?;
+        assert_eq!(status, StatusCode::FOUND);
+        // other hosts get no client key
+        let get = Request::get("/away")
+            .header(AUTHORIZATION, "Bearer own")
+            .body(ByteBody::from(Bytes::new()))?;
+        assert_eq!(
+            send(get).await?,
+            (
+                StatusCode::OK,
+                "GET https://other.example/done None".to_owned()
+            )
+        );
+        // other routes get the redirect
+        let head = Request::head("/away").body(ByteBody::from(Bytes::new()))?;
+        assert_eq!(send(head).await?.0, StatusCode::FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+//! Configuration hot reload.
+//!
+//! [`reloadable`] wraps the proxy stack in a [`Reloadable`] service whose
+//! stack can be swapped by a [`Reloader`]. A reload builds the whole stack
+//! from the current configuration first and only swaps it in when that
+//! succeeds, so an invalid file leaves the running stack in place. Requests
+//! in flight finish on the stack they started on, and the listener is never
+//! touched.
+//!
+//! Reloads are triggered by `SIGHUP`, by changes to the config file seen by
+//! [`watch`], and by the admin API. The version of the config serving, the
+//! number of reloads, is the `proxy_config_version` gauge.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use tower::{util::Oneshot, BoxError, Service, ServiceExt};
+
+use crate::metrics::Metric;
+
+const CONFIG_VERSION: Metric = Metric::gauge(
+    "proxy_config_version",
+    "Successful configuration reloads since startup.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Check the config file for changes this often, only on `SIGHUP` and
+    /// admin requests when unset.
+    pub watch_interval_secs: Option<u64>,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self {
+            watch_interval_secs: Some(5),
+        }
+    }
+}
+
+type Build = dyn Fn() -> Result<(), BoxError> + Send + Sync;
+
+/// Serves requests with the stack most recently swapped in.
+#[derive(Clone)]
+pub struct Reloadable<S> {
+    current: Arc<Mutex<S>>,
+}
+
+impl<S, Request> Service<Request> for Reloadable<S>
+where
+    S: Service<Request> + Clone,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Oneshot<S, Request>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // readiness is awaited on the stack the request ends up on
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let service = self.current.lock().unwrap().clone();
+        service.oneshot(req)
+    }
+}
+
+/// Rebuilds and swaps the stack of a [`Reloadable`].
+#[derive(Clone)]
+pub struct Reloader {
+    build: Arc<Build>,
+    version: Arc<AtomicU64>,
+    // one reload at a time, so versions follow the order of the swaps
+    reloading: Arc<Mutex<()>>,
+}
+
+impl Reloader {
+    /// Build and swap in a new stack, returning the new config version.
+    pub fn reload(&self) -> Result<u64, BoxError> {
+        let _reloading = self.reloading.lock().unwrap();
+        (self.build)()?;
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        CONFIG_VERSION.set(&[], version as f64);
+        tracing::info!(version, "configuration reloaded");
+        Ok(version)
+    }
+
+    /// Number of successful reloads since startup.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+}
+
+/// Serve `service` until `build` produces a replacement.
+pub fn reloadable<S, F>(service: S, build: F) -> (Reloadable<S>, Reloader)
+where
+    S: Send + 'static,
+    F: Fn() -> Result<S, BoxError> + Send + Sync + 'static,
+{
+    let current = Arc::new(Mutex::new(service));
+    CONFIG_VERSION.set(&[], 0.0);
+    let swap = current.clone();
+    let reloader = Reloader {
+        build: Arc::new(move || {
+            let service = build()?;
+            *swap.lock().unwrap() = service;
+            Ok(())
+        }),
+        version: Arc::new(AtomicU64::new(0)),
+        reloading: Arc::new(Mutex::new(())),
+    };
+    (Reloadable { current }, reloader)
+}
+
+/// Reload on `SIGHUP` and whenever the modification time of `path` changes,
+/// checked every `interval` if set.
+pub async fn watch(reloader: Reloader, path: Option<PathBuf>, interval: Option<Duration>) {
+    let modified = |path: &Option<PathBuf>| -> Option<SystemTime> {
+        std::fs::metadata(path.as_ref()?).ok()?.modified().ok()
+    };
+    let mut last_modified = modified(&path);
+    let mut ticks = interval.map(tokio::time::interval);
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+    loop {
+        #[cfg(unix)]
+        let hangup = async {
+            match hangup.as_mut() {
+                Some(signal) => signal.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<Option<()>>();
+
+        let tick = async {
+            match ticks.as_mut() {
+                Some(ticks) => ticks.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = hangup => tracing::info!("SIGHUP received"),
+            _ = tick => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                tracing::info!("configuration file changed");
+            }
+        }
+        let reloader = reloader.clone();
+        match tokio::task::spawn_blocking(move || reloader.reload()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => tracing::error!(%err, "configuration reload failed"),
+            Err(err) => tracing::error!(%err, "configuration reload panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn test_reload() -> Result<(), BoxError> {
+        let answer = Arc::new(AtomicU64::new(2));
+        let fail = Arc::new(AtomicBool::new(false));
+        let make = |n: u64| service_fn(move |x: u64| async move { Ok::<_, BoxError>(x * n) });
+        let (service, reloader) = reloadable(make(1), {
+            let answer = answer.clone();
+            let fail = fail.clone();
+            move || {
+                if fail.load(Ordering::SeqCst) {
+                    return Err("invalid config".into());
+                }
+                Ok(make(answer.load(Ordering::SeqCst)))
+            }
+        });
+
+        assert_eq!(service.clone().oneshot(21).await?, 21);
+        assert_eq!(reloader.reload()?, 1);
+        assert_eq!(service.clone().oneshot(21).await?, 42);
+        assert_eq!(CONFIG_VERSION.get(&[]), 1.0);
+
+        // a failed build keeps the running stack and version
+        fail.store(true, Ordering::SeqCst);
+        answer.store(3, Ordering::SeqCst);
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.version(), 1);
+        assert_eq!(CONFIG_VERSION.get(&[]), 1.0);
+        assert_eq!(service.oneshot(21).await?, 42);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! `proxy replay`: send recorded traffic to a target and compare the results.
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use crate::https::HttpsConnector;
+use clap::Parser;
+use http::{
+    header::{CONTENT_LENGTH, HOST},
+    HeaderName, HeaderValue, Method, Request, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use tokio::time::Instant;
+use tower::BoxError;
+
+use crate::record::{Record, REDACTED};
+
+#[derive(Debug, Parser)]
+pub struct ReplayArgs {
+    /// Recording to replay, NDJSON or HAR.
+    pub file: PathBuf,
+    /// Base URI the recorded paths are sent to.
+    #[arg(long, default_value = "http://127.0.0.1:3000")]
+    pub target: Uri,
+    /// Requests per second, as fast as possible when 0.
+    #[arg(long, default_value_t = 1.0)]
+    pub rate: f64,
+}
+
+/// Result of replaying one record.
+#[derive(Debug)]
+pub struct Outcome {
+    pub method: String,
+    pub uri: String,
+    pub recorded_status: u16,
+    pub recorded_ms: u64,
+    /// Status and latency of the replayed request, the error if it failed.
+    pub result: Result<(u16, u64), String>,
+}
+
+impl Outcome {
+    pub fn status_changed(&self) -> bool {
+        self.result
+            .as_ref()
+            .map_or(true, |(status, _)| *status != self.recorded_status)
+    }
+}
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Build the request for `record`, sent to `target`.
+fn request(record: &Record, target: &Uri) -> Result<Request<Body>, BoxError> {
+    // recorded URIs are the ones clients sent, usually path and query only
+    let recorded = Uri::from_str(&record.request.uri)?;
+    let path = recorded.path_and_query().map_or("/", |p| p.as_str());
+    let uri = format!("{}{}", target.to_string().trim_end_matches('/'), path);
+
+    let mut req = Request::builder()
+        .method(Method::from_str(&record.request.method)?)
+        .uri(uri);
+    for (name, value) in &record.request.headers {
+        let name = HeaderName::from_str(name)?;
+        // redacted credentials would only get the request rejected
+        if value == REDACTED || name == HOST || name == CONTENT_LENGTH {
+            continue;
+        }
+        req = req.header(name, HeaderValue::from_str(value)?);
+    }
+    let body = record
+        .request
+        .body
+        .clone()
+        .map_or_else(Body::empty, Body::from);
+    Ok(req.body(body)?)
+}
+
+async fn send(client: HttpsClient, req: Request<Body>) -> Result<(u16, u64), String> {
+    let start = Instant::now();
+    let res = client.request(req).await.map_err(|err| err.to_string())?;
+    let status = res.status().as_u16();
+    // latency includes reading the body, like the recorded one
+    hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok((status, start.elapsed().as_millis() as u64))
+}
+
+/// Replay `records` against `target` at `rate` requests per second.
+///
+/// Requests are started on schedule regardless of how long earlier ones
+/// take, so a slow target does not lower the offered load.
+pub async fn replay(records: Vec<Record>, target: &Uri, rate: f64) -> Vec<Outcome> {
+    let client = Client::builder().build(HttpsConnector::new());
+    let mut interval = (rate > 0.0).then(|| {
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+
+    let mut pending = Vec::with_capacity(records.len());
+    for record in records {
+        if let Some(interval) = interval.as_mut() {
+            interval.tick().await;
+        }
+        let handle = match request(&record, target) {
+            Ok(req) => Some(tokio::spawn(send(client.clone(), req))),
+            Err(err) => {
+                tracing::warn!(uri = %record.request.uri, %err, "skipping record");
+                None
+            }
+        };
+        pending.push((record, handle));
+    }
+
+    let mut outcomes = Vec::with_capacity(pending.len());
+    for (record, handle) in pending {
+        let result = match handle {
+            Some(handle) => handle.await.unwrap_or_else(|err| Err(err.to_string())),
+            None => Err("invalid record".into()),
+        };
+        outcomes.push(Outcome {
+            method: record.request.method,
+            uri: record.request.uri,
+            recorded_status: record.response.status,
+            recorded_ms: record.duration_ms,
+            result,
+        });
+    }
+    outcomes
+}
+
+pub fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+/// Run the `replay` subcommand, printing a line per request and a summary.
+pub async fn run(args: ReplayArgs) -> Result<(), BoxError> {
+    let data =
+        std::fs::read(&args.file).map_err(|err| format!("{}: {}", args.file.display(), err))?;
+    let records = Record::read_all(&data)?;
+    println!(
+        "replaying {} requests against {} at {} req/s",
+        records.len(),
+        args.target,
+        args.rate
+    );
+
+    let outcomes = replay(records, &args.target, args.rate).await;
+
+    let mut recorded = Vec::new();
+    let mut replayed = Vec::new();
+    for outcome in &outcomes {
+        let marker = if outcome.status_changed() { "!" } else { " " };
+        match &outcome.result {
+            Ok((status, ms)) => {
+                println!(
+                    "{} {} {}: status {} -> {}, latency {}ms -> {}ms",
+                    marker,
+                    outcome.method,
+                    outcome.uri,
+                    outcome.recorded_status,
+                    status,
+                    outcome.recorded_ms,
+                    ms
+                );
+                recorded.push(outcome.recorded_ms);
+                replayed.push(*ms);
+            }
+            Err(err) => println!("{} {} {}: {}", marker, outcome.method, outcome.uri, err),
+        }
+    }
+
+    recorded.sort_unstable();
+    replayed.sort_unstable();
+    let changed = outcomes.iter().filter(|o| o.status_changed()).count();
+    println!(
+        "{} requests, {} with a different status or failed",
+        outcomes.len(),
+        changed
+    );
+    for (name, p) in [("p50", 0.5), ("p99", 0.99)] {
+        println!(
+            "{} latency: {}ms recorded, {}ms replayed",
+            name,
+            percentile(&recorded, p),
+            percentile(&replayed, p)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    #[tokio::test]
+    async fn test_replay() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v6/device")
+                .query_param("$select", "id")
+                .header("content-type", "application/json")
+                .body(r#"{"name":"dev"}"#);
+            then.status(201);
+        });
+
+        let records = Record::read_all(concat!(
+            r#"{"started":"2026-01-01T00:00:00Z","duration_ms":12,"#,
+            r#""request":{"method":"POST","uri":"/v6/device?$select=id","#,
+            r#""headers":[["content-type","application/json"],["authorization","[redacted]"]],"#,
+            r#""body":"{\"name\":\"dev\"}"},"#,
+            r#""response":{"status":201,"headers":[],"body":null}}"#,
+            "\n",
+            r#"{"started":"2026-01-01T00:00:01Z","duration_ms":8,"#,
+            r#""request":{"method":"GET","uri":"/v6/missing","headers":[],"body":null},"#,
+            r#""response":{"status":200,"headers":[],"body":"{}"}}"#,
+            "\n",
+        ).as_bytes())?;
+        let target = Uri::from_str(&server.base_url())?;
+        let outcomes = replay(records, &target, 0.0).await;
+
+        m.assert();
+        assert_eq!(outcomes.len(), 2);
+        assert!(!outcomes[0].status_changed());
+        // httpmock answers unmatched requests with 404
+        assert!(outcomes[1].status_changed());
+        assert_eq!(outcomes[1].result.as_ref().map(|r| r.0), Ok(404));
+        Ok(())
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted = [1, 2, 3, 4, 100];
+        assert_eq!(percentile(&sorted, 0.5), 3);
+        assert_eq!(percentile(&sorted, 0.99), 100);
+        assert_eq!(percentile(&[], 0.5), 0);
+    }
+}
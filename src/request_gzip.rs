@@ -0,0 +1,172 @@
+//! Gzip of JSON request bodies.
+//!
+//! Devices on metered connections pay for every byte sent upstream. For
+//! upstreams that accept compressed requests, [`RequestGzipLayer`] compresses
+//! buffered JSON bodies of at least `min_bytes` and sets `Content-Encoding:
+//! gzip`, keeping the body as it is when compressing does not shrink it.
+//! Bodies already encoded or streamed are left alone. It runs after writes
+//! are queued for later delivery, which needs the body as text, and before
+//! requests are signed, so signatures cover what is sent.
+
+use std::task::{Context, Poll};
+
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    HeaderValue, Request,
+};
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::{
+    gzip,
+    metrics::Metric,
+    read_request_body::{fix_length, ByteBody},
+    route::RouteMatcher,
+};
+
+const SAVED: Metric = Metric::counter(
+    "proxy_request_gzip_saved_bytes_total",
+    "Bytes of request bodies saved by compressing them.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestGzipConfig {
+    /// Smallest body compressed.
+    pub min_bytes: usize,
+    /// Requests compressed, all when empty.
+    pub routes: Vec<RouteMatcher>,
+}
+
+impl Default for RequestGzipConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes: 1024,
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl RequestGzipConfig {
+    fn applies(&self, req: &Request<ByteBody>) -> bool {
+        let json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| {
+                let mime = mime.trim();
+                mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+            });
+        json && !req.body().is_streamed()
+            && req.body().as_bytes().len() >= self.min_bytes
+            && !req.headers().contains_key(CONTENT_ENCODING)
+            && (self.routes.is_empty() || self.routes.iter().any(|route| route.matches(req)))
+    }
+}
+
+/// Compress the body of `req` if the config applies and it shrinks.
+fn compress(config: &RequestGzipConfig, req: &mut Request<ByteBody>) {
+    if !config.applies(req) {
+        return;
+    }
+    let data = req.body().as_bytes();
+    let encoded = gzip::encode(data);
+    if encoded.len() >= data.len() {
+        return;
+    }
+    SAVED.add(&[], (data.len() - encoded.len()) as f64);
+    let body = ByteBody::new(encoded).with_trailers(req.body().trailers().cloned());
+    req.headers_mut()
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    fix_length(req.headers_mut(), &body);
+    *req.body_mut() = body;
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestGzipLayer {
+    config: RequestGzipConfig,
+}
+
+impl RequestGzipLayer {
+    pub fn new(config: RequestGzipConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for RequestGzipLayer {
+    type Service = RequestGzip<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RequestGzip {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestGzip<S> {
+    inner: S,
+    config: RequestGzipConfig,
+}
+
+impl<S> Service<Request<ByteBody>> for RequestGzip<S>
+where
+    S: Service<Request<ByteBody>>,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        compress(&self.config, &mut req);
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::CONTENT_LENGTH;
+
+    #[test]
+    fn test_request_gzip() {
+        let config = RequestGzipConfig {
+            min_bytes: 64,
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&serde_json::json!({ "note": "x".repeat(200) })).unwrap();
+        let request = |content_type: &str, body: &[u8]| {
+            let (mut parts, body) = Request::post("/v6/device(1)")
+                .header(CONTENT_TYPE, content_type)
+                .body(ByteBody::new(body.to_vec()))
+                .unwrap()
+                .into_parts();
+            fix_length(&mut parts.headers, &body);
+            Request::from_parts(parts, body)
+        };
+
+        let mut req = request("application/json; charset=utf-8", &json);
+        compress(&config, &mut req);
+        assert_eq!(req.headers()[CONTENT_ENCODING], "gzip");
+        let length = req.body().as_bytes().len();
+        assert!(length < json.len());
+        assert_eq!(req.headers()[CONTENT_LENGTH], length.to_string().as_str());
+        assert_eq!(gzip::decode(req.body().as_bytes()).unwrap(), json);
+
+        // small or other bodies are sent as they are
+        let mut req = request("application/json", b"{}");
+        compress(&config, &mut req);
+        assert!(!req.headers().contains_key(CONTENT_ENCODING));
+        let mut req = request("text/plain", &json);
+        compress(&config, &mut req);
+        assert_eq!(req.body().as_bytes(), json);
+    }
+}
@@ -24,4 +24,3 @@ impl MakeRequestId for MakeIntRequestId {
         Some(RequestId::new(request_id))
     }
 }
-
@@ -0,0 +1,650 @@
+//! In-memory response cache, with invalidation.
+//!
+//! [`CacheLayer`] keeps the 200 responses to GETs of the routes configured for
+//! `ttl_secs`, and answers repeats from memory with `X-Proxy-Cache: hit`,
+//! HEADs of them included, with the headers only, and `Range` requests of
+//! them with a 206 of the range.
+//! Responses are kept by the caller's key, if it brought its own, the
+//! upstream a gateway or environment rule sent the request to, if not the
+//! default one, and the path and query, and tagged with their OData resource, e.g. `device` of
+//! `/v6/device(123)`. A write passing through drops every response tagged
+//! with its resource, whoever asked for them, once it is answered, and GETs
+//! of the resource in flight meanwhile are not kept, so a read never outlives
+//! the write that changed it. The admin API drops responses by exact key, path
+//! prefix or tag through a [`ResponseCache`].
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LENGTH},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    context::caller,
+    forward_request::Upstream,
+    metrics::Metric,
+    odata::ODataQuery,
+    range,
+    read_request_body::{buffer, FromBuffered},
+    route::RouteMatcher,
+};
+
+const HITS: Metric = Metric::counter(
+    "proxy_cache_hits_total",
+    "GET requests answered from the response cache.",
+);
+
+const PURGED: Metric = Metric::counter(
+    "proxy_cache_purged_total",
+    "Cached responses dropped before they expired, by reason.",
+);
+
+/// Header added to responses answered from the cache.
+pub const X_PROXY_CACHE: &str = "x-proxy-cache";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Routes whose GET responses are cached.
+    pub routes: Vec<RouteMatcher>,
+    /// How long a response is served from the cache.
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Responses kept, those closest to expiring are dropped beyond this.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Larger bodies are passed on without being cached.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_ttl_secs() -> u64 {
+    60
+}
+
+fn default_max_entries() -> usize {
+    1_000
+}
+
+fn default_max_body_bytes() -> usize {
+    1 << 20
+}
+
+impl CacheConfig {
+    /// Check the routes, that they select GETs, and that responses are kept
+    /// at all.
+    pub fn validate(&self) -> Result<(), BoxError> {
+        if self.routes.is_empty() {
+            return Err("cache: no routes".into());
+        }
+        for route in &self.routes {
+            route.validate().map_err(|err| format!("cache: {}", err))?;
+            if !route.methods.is_empty()
+                && !route.methods.iter().any(|m| m.eq_ignore_ascii_case("GET"))
+            {
+                return Err(format!(
+                    "cache: route with methods {:?} selects no GETs",
+                    route.methods
+                )
+                .into());
+            }
+        }
+        if self.ttl_secs == 0 || self.max_entries == 0 {
+            return Err("cache: ttl_secs and max_entries must be over 0".into());
+        }
+        Ok(())
+    }
+}
+
+struct Entry {
+    /// Path and query the response is for.
+    path: String,
+    tag: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: Instant,
+}
+
+/// A cached response, as the admin API lists it.
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedResponse {
+    pub key: String,
+    pub tag: String,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// Bumped by each write to a resource, GETs that started before are not
+    /// kept.
+    generations: HashMap<String, u64>,
+}
+
+/// Responses cached by [`CacheLayer`], shared with the admin API.
+#[derive(Clone)]
+pub struct ResponseCache {
+    store: Arc<Mutex<Store>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    fn get(&self, key: &str) -> Option<Response<Bytes>> {
+        let store = self.store.lock().unwrap();
+        let entry = store
+            .entries
+            .get(key)
+            .filter(|entry| entry.expires > Instant::now())?;
+        let mut res = Response::new(entry.body.clone());
+        *res.status_mut() = entry.status;
+        *res.headers_mut() = entry.headers.clone();
+        Some(res)
+    }
+
+    fn generation(&self, tag: &str) -> u64 {
+        let store = self.store.lock().unwrap();
+        store.generations.get(tag).copied().unwrap_or_default()
+    }
+
+    /// Keep a response, unless its resource was written to since
+    /// `generation`.
+    fn put(&self, key: String, entry: Entry, generation: u64) {
+        let mut store = self.store.lock().unwrap();
+        if store
+            .generations
+            .get(&entry.tag)
+            .copied()
+            .unwrap_or_default()
+            != generation
+        {
+            return;
+        }
+        if !store.entries.contains_key(&key) && store.entries.len() >= self.max_entries {
+            let now = Instant::now();
+            store.entries.retain(|_, entry| entry.expires > now);
+            if store.entries.len() >= self.max_entries {
+                let soonest = store
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    store.entries.remove(&soonest);
+                }
+            }
+        }
+        store.entries.insert(key, entry);
+    }
+
+    fn purge(&self, reason: &'static str, purged: impl Fn(&str, &Entry) -> bool) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let before = store.entries.len();
+        store.entries.retain(|key, entry| !purged(key, entry));
+        let count = before - store.entries.len();
+        PURGED.add(&[("reason", reason)], count as f64);
+        count
+    }
+
+    /// The responses cached and not expired yet.
+    pub fn list(&self) -> Vec<CachedResponse> {
+        let now = Instant::now();
+        let store = self.store.lock().unwrap();
+        let mut list: Vec<_> = store
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .map(|(key, entry)| CachedResponse {
+                key: key.clone(),
+                tag: entry.tag.clone(),
+                expires_in_secs: (entry.expires - now).as_secs(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.key.cmp(&b.key));
+        list
+    }
+
+    /// Drop the response kept by `key`, returning how many were dropped.
+    pub fn purge_key(&self, key: &str) -> usize {
+        self.purge("key", |k, _| k == key)
+    }
+
+    /// Drop the responses to paths starting with `prefix`.
+    pub fn purge_prefix(&self, prefix: &str) -> usize {
+        self.purge("prefix", |_, entry| entry.path.starts_with(prefix))
+    }
+
+    /// Drop the responses of resource `tag`, and keep GETs of it in flight
+    /// from being cached.
+    pub fn purge_tag(&self, tag: &str) -> usize {
+        *self
+            .store
+            .lock()
+            .unwrap()
+            .generations
+            .entry(tag.to_owned())
+            .or_default() += 1;
+        self.purge("tag", |_, entry| entry.tag == tag)
+    }
+
+    pub fn purge_all(&self) -> usize {
+        self.purge("all", |_, _| true)
+    }
+}
+
+/// What a response to `req` is kept by: the caller's key, if it brought
+/// its own, the upstream it goes to, if not the default one, and the path
+/// and query.
+fn cache_key<B>(req: &Request<B>) -> String {
+    let caller = caller(req).unwrap_or_default();
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    match req.extensions().get::<Upstream>() {
+        Some(Upstream(upstream)) => format!("{} {} {}", caller, upstream, path),
+        None => format!("{} {}", caller, path),
+    }
+}
+
+/// Whether `headers` have a `Cache-Control` with `directive`.
+fn has_directive(headers: &HeaderMap, directive: &str) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case(directive))
+}
+
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: ResponseCache,
+    config: Arc<CacheConfig>,
+}
+
+impl CacheLayer {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            cache: ResponseCache {
+                store: Arc::new(Mutex::new(Store::default())),
+                ttl: Duration::from_secs(config.ttl_secs),
+                max_entries: config.max_entries.max(1),
+            },
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn cache(&self) -> ResponseCache {
+        self.cache.clone()
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = Cache<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Cache {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache<S> {
+    inner: S,
+    layer: CacheLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Cache<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: http_body::Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let cache = self.layer.cache.clone();
+        let tag = ODataQuery::parse(req.uri()).map(|query| query.resource);
+
+        if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+            // writes make what was read of their resource stale
+            let fut = self.inner.call(req);
+            return Box::pin(async move {
+                let res = fut.await.map_err(Into::into);
+                if let Some(tag) = tag {
+                    let purged = cache.purge_tag(&tag);
+                    tracing::debug!(%tag, purged, "write invalidated cached responses");
+                }
+                res
+            });
+        }
+
+        // HEADs select the GETs they would be answered from
+        let head = req.method() == Method::HEAD;
+        let cached = (req.method() == Method::GET || head)
+            && self.layer.config.routes.iter().any(|route| {
+                route.matches_parts(&Method::GET, req.uri().path())
+                    && route.matches_headers(req.headers())
+                    && route.matches_query(&Method::GET, req.uri())
+            });
+        let Some(tag) = tag.filter(|_| cached) else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+        let key = cache_key(&req);
+        if !has_directive(req.headers(), "no-cache") {
+            if let Some(res) = cache.get(&key) {
+                HITS.increment(&[]);
+                // ranges of the cached body, as clients resuming downloads ask
+                let mut res = range::serve_range(req.headers(), res);
+                let len = res.body().len();
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(len));
+                if head {
+                    *res.body_mut() = Bytes::new();
+                }
+                let mut res = res.map(ResBody::from);
+                res.headers_mut()
+                    .insert(X_PROXY_CACHE, HeaderValue::from_static("hit"));
+                return Box::pin(async move { Ok(res) });
+            }
+        }
+        // HEADs are answered from GETs, not kept
+        if head {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_owned();
+        let generation = cache.generation(&tag);
+        let max_body_bytes = self.layer.config.max_body_bytes;
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            if res.status() != StatusCode::OK || has_directive(res.headers(), "no-store") {
+                return Ok(res);
+            }
+            let (parts, body) = res.into_parts();
+            let (bytes, trailers) = buffer(body).await?;
+            if bytes.len() <= max_body_bytes {
+                let entry = Entry {
+                    path,
+                    tag,
+                    status: parts.status,
+                    headers: parts.headers.clone(),
+                    body: bytes.clone(),
+                    expires: Instant::now() + cache.ttl,
+                };
+                cache.put(key, entry, generation);
+            }
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::AUTHORIZATION;
+    use hyper::Body;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::{service_fn, ServiceExt};
+
+    fn layer() -> CacheLayer {
+        let config: CacheConfig = serde_json::from_value(serde_json::json!({
+            "routes": [{ "path_prefix": "/v6/device", "methods": ["GET"] }],
+        }))
+        .unwrap();
+        CacheLayer::new(config)
+    }
+
+    #[tokio::test]
+    async fn test_cache() -> Result<(), BoxError> {
+        let upstream = Arc::new(AtomicUsize::new(0));
+        let layer = layer();
+        let service = layer.layer(service_fn({
+            let upstream = upstream.clone();
+            move |req: Request<Body>| {
+                let n = upstream.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let mut res = Response::new(Body::from(n.to_string()));
+                    if req.method() == Method::PATCH {
+                        *res.status_mut() = StatusCode::NO_CONTENT;
+                    }
+                    Ok::<_, BoxError>(res)
+                }
+            }
+        }));
+        let send = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(AUTHORIZATION, "Bearer c1")
+                .body(Body::empty())
+                .unwrap();
+            service.clone().oneshot(req)
+        };
+        let body = |res: Response<Body>| async move {
+            let cached = res.headers().contains_key(X_PROXY_CACHE);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            (String::from_utf8(body.to_vec()).unwrap(), cached)
+        };
+
+        assert_eq!(
+            body(send(Method::GET, "/v6/device(1)").await?).await,
+            ("0".into(), false)
+        );
+        assert_eq!(
+            body(send(Method::GET, "/v6/device(1)").await?).await,
+            ("0".into(), true)
+        );
+        assert_eq!(
+            body(send(Method::GET, "/v6/device(2)").await?).await,
+            ("1".into(), false)
+        );
+        // routes not configured are not cached
+        send(Method::GET, "/v6/release").await?;
+        assert_eq!(layer.cache().list().len(), 2);
+
+        // a write drops every response of its resource
+        send(Method::PATCH, "/v6/device(2)").await?;
+        assert!(layer.cache().list().is_empty());
+        assert_eq!(
+            body(send(Method::GET, "/v6/device(1)").await?).await,
+            ("4".into(), false)
+        );
+        assert_eq!(upstream.load(Ordering::SeqCst), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_by_upstream() -> Result<(), BoxError> {
+        let layer = layer();
+        let gateway = crate::gateway::GatewayLayer::new(serde_json::from_value(
+            serde_json::json!({ "hosts": ["a.example", "b.example"] }),
+        )?)?;
+        let service = gateway.layer(layer.layer(service_fn(|req: Request<Body>| async move {
+            let upstream = req.extensions().get::<Upstream>().map(|u| u.0.to_string());
+            Ok::<_, BoxError>(Response::new(Body::from(upstream.unwrap_or_default())))
+        })));
+        let get = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let service = service.clone();
+            async move {
+                let res = service.oneshot(req).await?;
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>(String::from_utf8(body.to_vec())?)
+            }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(get("/a.example/v6/device(1)").await?, "https://a.example/");
+            assert_eq!(get("/b.example/v6/device(1)").await?, "https://b.example/");
+        }
+        assert_eq!(layer.cache().list().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_from_cached_get() -> Result<(), BoxError> {
+        let upstream = Arc::new(AtomicUsize::new(0));
+        let service = layer().layer(service_fn({
+            let upstream = upstream.clone();
+            move |req: Request<Body>| {
+                upstream.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let body = match *req.method() {
+                        Method::HEAD => Body::empty(),
+                        _ => Body::from("device"),
+                    };
+                    Ok::<_, BoxError>(Response::builder().header("etag", "\"v1\"").body(body)?)
+                }
+            }
+        }));
+        let send = |method: Method| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/v6/device(1)")
+                .body(Body::empty())
+                .unwrap();
+            service.clone().oneshot(req)
+        };
+
+        // not kept, nothing to answer from yet
+        let res = send(Method::HEAD).await?;
+        assert!(!res.headers().contains_key(X_PROXY_CACHE));
+        send(Method::GET).await?;
+        let res = send(Method::HEAD).await?;
+        assert_eq!(res.headers()[X_PROXY_CACHE], "hit");
+        assert_eq!(res.headers()["etag"], "\"v1\"");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "6");
+        assert!(hyper::body::to_bytes(res.into_body()).await?.is_empty());
+        assert_eq!(upstream.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_range_of_cached_get() -> Result<(), BoxError> {
+        let service = layer().layer(service_fn(|_: Request<Body>| async move {
+            Ok::<_, BoxError>(Response::new(Body::from("0123456789")))
+        }));
+        let get = |range: Option<&str>| {
+            let mut req = Request::get("/v6/device(1)");
+            if let Some(range) = range {
+                req = req.header(http::header::RANGE, range);
+            }
+            service.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        get(None).await?;
+        let res = get(Some("bytes=2-4")).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[X_PROXY_CACHE], "hit");
+        assert_eq!(res.headers()[http::header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "3");
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "234");
+        let res = get(Some("bytes=20-")).await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        let res = get(None).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "0123456789");
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let config =
+            |value: serde_json::Value| -> CacheConfig { serde_json::from_value(value).unwrap() };
+        assert!(layer().config.validate().is_ok());
+        let invalid = [
+            serde_json::json!({ "routes": [] }),
+            serde_json::json!({ "routes": [{ "path_prefix": "v6/device" }] }),
+            serde_json::json!({ "routes": [{ "methods": ["POST"] }] }),
+            serde_json::json!({ "routes": [{ "methods": ["G ET"] }] }),
+            serde_json::json!({ "routes": [{ "headers": [{ "name": "x y" }] }] }),
+            serde_json::json!({ "routes": [{}], "ttl_secs": 0 }),
+        ];
+        for value in invalid {
+            assert!(config(value.clone()).validate().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_purge() {
+        let cache = layer().cache();
+        let entry = |path: &str, tag: &str| Entry {
+            path: path.to_owned(),
+            tag: tag.to_owned(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            expires: Instant::now() + Duration::from_secs(60),
+        };
+        let fill = || {
+            cache.put(
+                "a /v6/device(1)".into(),
+                entry("/v6/device(1)", "device"),
+                0,
+            );
+            cache.put(
+                "b /v6/device(1)".into(),
+                entry("/v6/device(1)", "device"),
+                0,
+            );
+            cache.put("a /v6/release".into(), entry("/v6/release", "release"), 0);
+        };
+
+        fill();
+        assert_eq!(cache.purge_key("a /v6/device(1)"), 1);
+        assert_eq!(cache.purge_key("a /v6/device(1)"), 0);
+        assert_eq!(cache.purge_prefix("/v6/dev"), 1);
+        assert_eq!(cache.list().len(), 1);
+        assert_eq!(cache.purge_all(), 1);
+
+        fill();
+        assert_eq!(cache.purge_tag("device"), 2);
+        assert_eq!(cache.list()[0].tag, "release");
+        // GETs that started before the purge are not kept
+        cache.put(
+            "a /v6/device(1)".into(),
+            entry("/v6/device(1)", "device"),
+            0,
+        );
+        assert_eq!(cache.list().len(), 1);
+        cache.put(
+            "a /v6/device(1)".into(),
+            entry("/v6/device(1)", "device"),
+            1,
+        );
+        assert_eq!(cache.list().len(), 2);
+    }
+}
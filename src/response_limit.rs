@@ -0,0 +1,221 @@
+//! Response size limits.
+//!
+//! [`ResponseLimitLayer`] caps the size of upstream responses per route, so a
+//! small device cannot pull a huge OData result set through the buffering
+//! layers by accident. Responses announcing a larger `Content-Length` are
+//! refused before their body is read. Others are read up to the limit, and
+//! the upstream stream is dropped as soon as it goes over. Either way the
+//! client gets a 502 saying so.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_core::Future;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
+};
+use http_body::Body;
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::{read_request_body::FromBuffered, route::RouteMatcher};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseLimitRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Largest response body passed to the client.
+    pub max_bytes: u64,
+}
+
+/// Read `body` while it stays within `max` bytes, keeping its trailers.
+/// Returns `None`, dropping the rest of the body, once it goes over.
+async fn buffer_within<B>(body: B, max: u64) -> Result<Option<(Bytes, Option<HeaderMap>)>, BoxError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<BoxError>,
+{
+    let mut body = Box::pin(body);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk.map_err(Into::into)?);
+        if buf.len() as u64 > max {
+            return Ok(None);
+        }
+    }
+    let trailers = body.trailers().await.map_err(Into::into)?;
+    Ok(Some((buf.freeze(), trailers)))
+}
+
+fn too_large<B: From<Bytes>>(max: u64) -> Response<B> {
+    let body = serde_json::json!({
+        "error": "upstream response too large",
+        "max_bytes": max,
+    });
+    let mut res = Response::new(B::from(Bytes::from(body.to_string())));
+    *res.status_mut() = StatusCode::BAD_GATEWAY;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseLimitLayer {
+    rules: Vec<ResponseLimitRule>,
+}
+
+impl ResponseLimitLayer {
+    /// The first rule matching a request applies.
+    pub fn new(rules: Vec<ResponseLimitRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl<S> Layer<S> for ResponseLimitLayer {
+    type Service = ResponseLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ResponseLimit {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseLimit<S> {
+    inner: S,
+    rules: Vec<ResponseLimitRule>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ResponseLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let max = self
+            .rules
+            .iter()
+            .find(|rule| rule.route.matches(&req))
+            .map(|rule| rule.max_bytes);
+        let path = req.uri().path().to_owned();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            let Some(max) = max else {
+                return Ok(res);
+            };
+            let length = res
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if length.is_some_and(|length| length > max) {
+                tracing::warn!(path, max_bytes = max, "upstream response too large");
+                return Ok(too_large(max));
+            }
+            let (parts, body) = res.into_parts();
+            match buffer_within(body, max).await? {
+                Some((data, trailers)) => Ok(Response::from_parts(
+                    parts,
+                    ResBody::from_buffered(data, trailers),
+                )),
+                None => {
+                    tracing::warn!(
+                        path,
+                        max_bytes = max,
+                        "upstream response too large, cut off"
+                    );
+                    Ok(too_large(max))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body as HyperBody;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_response_limit() -> Result<(), BoxError> {
+        let layer = ResponseLimitLayer::new(vec![ResponseLimitRule {
+            route: RouteMatcher {
+                path_prefix: Some("/v6/device".to_owned()),
+                ..Default::default()
+            },
+            max_bytes: 8,
+        }]);
+        // streamed, without a length, unless the path says otherwise
+        let service = layer.layer(service_fn(|req: Request<()>| async move {
+            let (mut sender, body) = HyperBody::channel();
+            let chunks = req.uri().query().unwrap_or_default().to_owned();
+            tokio::spawn(async move {
+                for chunk in chunks.split(',') {
+                    if sender
+                        .send_data(Bytes::from(chunk.to_owned()))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+            Ok::<_, BoxError>(Response::new(body))
+        }));
+        let get = |uri: &'static str| {
+            let service = service.clone();
+            async move {
+                let res = service.oneshot(Request::get(uri).body(())?).await?;
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                Ok::<_, BoxError>((status, body))
+            }
+        };
+
+        assert_eq!(
+            get("/v6/device?1234,5678").await?,
+            (StatusCode::OK, "12345678".into())
+        );
+        let (status, body) = get("/v6/device?1234,5678,9").await?;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["max_bytes"], 8);
+        // other routes are not limited
+        assert_eq!(get("/v6/release?1234,5678,9").await?.0, StatusCode::OK);
+
+        // a long announced length is refused before reading the body
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            let mut res = Response::new(HyperBody::empty());
+            res.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(1_000_000));
+            Ok::<_, BoxError>(res)
+        }));
+        let res = service
+            .oneshot(Request::get("/v6/device").body(())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        Ok(())
+    }
+}
@@ -1,12 +1,108 @@
 use core::time;
+use std::error::Error;
 use std::pin::Pin;
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use futures_core::Future;
-use http::{Request, Response};
-use tower::retry::Policy;
+use http::{HeaderValue, Method, Request, Response};
+use serde::Deserialize;
+use tower::{retry::Policy, BoxError};
 
+use crate::context::ProxyContext;
+use crate::failure::FailureClass;
+use crate::idempotency::IDEMPOTENCY_KEY;
+use crate::read_request_body::StreamedBody;
+use crate::redirect::ForeignHost;
 use crate::rng::{HasherRng, Rng};
+use crate::shutdown::Shutdown;
+
+/// Attempt number, set on requests replayed by the retry policy.
+pub const X_PROXY_ATTEMPT: &str = "x-proxy-attempt";
+
+/// Attempt number of a request replayed by the retry policy, as a request
+/// extension clients cannot set.
+#[derive(Debug, Clone, Copy)]
+pub struct Attempt(pub u32);
+
+/// Drop the `X-Proxy-Attempt` clients send, which only the retry policy sets.
+pub fn without_attempt<B>(mut req: Request<B>) -> Request<B> {
+    req.headers_mut().remove(X_PROXY_ATTEMPT);
+    req
+}
+
+/// Add an `Idempotency-Key` to writes that don't carry one, so the upstream
+/// can deduplicate the attempts of a retried request.
+///
+/// The key is derived from the id the proxy gave the request in its
+/// [`ProxyContext`], never from the client's `X-Request-Id`, which two
+/// clients could share. That id is only unique within a process, so it is
+/// prefixed with a random per-process value.
+pub fn with_idempotency_key<B>(mut req: Request<B>) -> Request<B> {
+    static INSTANCE: OnceLock<u64> = OnceLock::new();
+
+    if !matches!(*req.method(), Method::POST | Method::PATCH)
+        || req.headers().contains_key(IDEMPOTENCY_KEY)
+    {
+        return req;
+    }
+    let request_id = match ProxyContext::of(&req) {
+        Some(context) => context.get().id,
+        None => return req,
+    };
+    let instance = INSTANCE.get_or_init(|| HasherRng::new().next_u64());
+    let key = format!("proxy-{:016x}-{}", instance, request_id);
+    if let Ok(value) = HeaderValue::from_str(&key) {
+        req.headers_mut().insert(IDEMPOTENCY_KEY, value);
+    }
+    req
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retries of a failed request, after the first attempt.
+    pub attempts: u32,
+    /// Backoff before the first retry, doubled for each next one.
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// `Retry-After` of the 503s answering retries cut short by shutdown.
+    pub shutdown_retry_after_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            min_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            shutdown_retry_after_secs: 5,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The policy for this config, no longer waiting once `shutdown` is
+    /// triggered.
+    pub fn policy(&self, shutdown: Shutdown) -> WithBackoff<ExponentialBackoff> {
+        let min = Duration::from_millis(self.min_backoff_ms);
+        let max = Duration::from_millis(self.max_backoff_ms).max(min);
+        WithBackoff::new(self.attempts, ExponentialBackoff::new(min, max, 2.0))
+            .with_shutdown(shutdown)
+    }
+
+    /// Check the backoff bounds are in order.
+    pub fn validate(&self) -> Result<(), BoxError> {
+        if self.min_backoff_ms > self.max_backoff_ms {
+            return Err(format!(
+                "retry: min_backoff_ms {} is over max_backoff_ms {}",
+                self.min_backoff_ms, self.max_backoff_ms
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
 
 pub trait Backoff {
     type Future: Future<Output = Self> + Send;
@@ -129,11 +225,23 @@ impl Backoff for ExponentialBackoff {
 pub struct WithBackoff<B> {
     attempts: u32,
     backoff: B,
+    shutdown: Shutdown,
 }
 
 impl<B> WithBackoff<B> {
     pub fn new(attempts: u32, backoff: B) -> Self {
-        Self { attempts, backoff }
+        Self {
+            attempts,
+            backoff,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// Stop backing off once `shutdown` is triggered: the attempt waited
+    /// for is the last, and answered by the `ShutdownLayer` below.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 }
 
@@ -141,6 +249,7 @@ impl<B, ReqBody, ResBody, E> Policy<Request<ReqBody>, Response<ResBody>, E> for
 where
     ReqBody: http_body::Body + Clone,
     B: Backoff + Clone + Send + Sync + 'static,
+    E: AsRef<dyn Error + Send + Sync>,
 {
     type Future = Pin<Box<dyn Future<Output = Self> + Send>>;
 
@@ -149,9 +258,19 @@ where
         _req: &Request<ReqBody>,
         result: Result<&Response<ResBody>, &E>,
     ) -> Option<Self::Future> {
-        if let Ok(res) = result {
-            if res.status().is_success() {
-                return None;
+        match result {
+            Ok(res) if res.status().is_success() => return None,
+            // the connection was handed over to the tunnel, see `crate::websocket`
+            Ok(res) if res.status() == http::StatusCode::SWITCHING_PROTOCOLS => return None,
+            Ok(_) => {}
+            // hosts that do not exist and untrusted certificates stay so
+            Err(err) => {
+                let class = FailureClass::of(err.as_ref());
+                class.record();
+                if !class.is_retryable() {
+                    tracing::warn!(class = class.as_str(), "not retrying failed request");
+                    return None;
+                }
             }
         }
 
@@ -159,27 +278,211 @@ where
             return None;
         }
 
+        match result {
+            Ok(res) => {
+                tracing::warn!(status = %res.status(), attempts_left = self.attempts, "retrying request")
+            }
+            Err(_) => tracing::warn!(attempts_left = self.attempts, "retrying failed request"),
+        }
+
         let mut this = self.clone();
         let fut = async move {
-            this.backoff = this.backoff.next().await;
-            this.attempts -= 1;
+            let shutdown = this.shutdown.clone();
+            tokio::select! {
+                backoff = this.backoff.next() => {
+                    this.backoff = backoff;
+                    this.attempts -= 1;
+                }
+                _ = shutdown.triggered() => this.attempts = 0,
+            }
             this
         };
 
         Some(Box::pin(fut))
     }
 
+    /// Clone `req` for the next attempt. The header map is copied whole,
+    /// its values sharing their bytes with the original, and buffered bodies
+    /// share their data too, as the clone shares the context.
     fn clone_request(&self, req: &Request<ReqBody>) -> Option<Request<ReqBody>> {
-        let mut b = Request::builder();
-        b = b.uri(req.uri().clone());
-        b = b.method(req.method().clone());
-        for (k, v) in req.headers() {
-            if k != http::header::AUTHORIZATION {
-                b = b.header(k, v);
-            }
+        // the body is sent as it arrives and cannot be sent again
+        if req.extensions().get::<StreamedBody>().is_some() {
+            return None;
         }
-        let req = b.body(req.body().clone());
-        let req = req.expect("request cloned");
-        Some(req)
+        // the original request is attempt 1, every clone is the next attempt
+        let attempt = req
+            .extensions()
+            .get::<Attempt>()
+            .map_or(1, |Attempt(attempt)| *attempt)
+            .saturating_add(1);
+        let mut clone = Request::new(req.body().clone());
+        *clone.method_mut() = req.method().clone();
+        *clone.uri_mut() = req.uri().clone();
+        *clone.version_mut() = req.version();
+        let headers = clone.headers_mut();
+        *headers = req.headers().clone();
+        headers.insert(X_PROXY_ATTEMPT, HeaderValue::from(attempt));
+        clone.extensions_mut().insert(Attempt(attempt));
+        let context = ProxyContext::of(req);
+        // callers keep their own key, pool keys are assigned again by the
+        // auth layer
+        if context.is_none_or(|context| context.get().caller.is_none()) {
+            clone.headers_mut().remove(http::header::AUTHORIZATION);
+        }
+        if let Some(context) = context {
+            context.update(|annotations| annotations.attempt = attempt);
+            clone.extensions_mut().insert(context.clone());
+        }
+        if req.extensions().get::<ForeignHost>().is_some() {
+            clone.extensions_mut().insert(ForeignHost);
+        }
+        Some(clone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Body;
+
+    impl http_body::Body for Body {
+        type Data = bytes::Bytes;
+
+        type Error = std::convert::Infallible;
+
+        fn poll_data(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            std::task::Poll::Ready(None)
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            std::task::Poll::Ready(Ok(None))
+        }
+    }
+
+    #[test]
+    fn test_clone_request_attempts() {
+        let policy = WithBackoff::new(3, LinearBackoff::new(Duration::ZERO));
+        let clone = |req: &Request<Body>| {
+            Policy::<_, Response<()>, tower::BoxError>::clone_request(&policy, req).expect("cloned")
+        };
+        let req = Request::post("/v6/device")
+            .version(http::Version::HTTP_2)
+            .header(http::header::AUTHORIZATION, "Bearer key")
+            .header("x-tag", "a")
+            .header("x-tag", "b")
+            .extension(ProxyContext::new(crate::context::Annotations {
+                id: 7,
+                attempt: 1,
+                ..Default::default()
+            }))
+            .body(Body)
+            .expect("request");
+        let req = with_idempotency_key(req);
+        let key = req.headers()[IDEMPOTENCY_KEY].clone();
+        assert!(key.to_str().unwrap().ends_with("-7"));
+
+        let second = clone(&req);
+        let third = clone(&second);
+        assert_eq!(second.headers()[X_PROXY_ATTEMPT], "2");
+        assert_eq!(third.headers()[X_PROXY_ATTEMPT], "3");
+        assert!(matches!(third.extensions().get(), Some(Attempt(3))));
+        // the attempt clients claim is none of the policy's business
+        let mut claimed = Request::new(Body);
+        claimed
+            .headers_mut()
+            .insert(X_PROXY_ATTEMPT, HeaderValue::from(u32::MAX));
+        assert_eq!(
+            clone(&without_attempt(claimed)).headers()[X_PROXY_ATTEMPT],
+            "2"
+        );
+        assert_eq!(third.headers()[IDEMPOTENCY_KEY], key);
+        assert_eq!(third.headers().get_all("x-tag").iter().count(), 2);
+        assert_eq!(third.version(), http::Version::HTTP_2);
+        assert_eq!(third.uri(), "/v6/device");
+        // the key is assigned again by the auth layer
+        assert!(!second.headers().contains_key(http::header::AUTHORIZATION));
+
+        // unless the context says it is the caller's own
+        let mut req = req;
+        let context = ProxyContext::new(crate::context::Annotations {
+            caller: Some("own".to_owned()),
+            attempt: 1,
+            ..Default::default()
+        });
+        req.extensions_mut().insert(context.clone());
+        let second = clone(&req);
+        assert_eq!(second.headers()[http::header::AUTHORIZATION], "Bearer key");
+        assert_eq!(context.get().attempt, 2);
+        assert!(ProxyContext::of(&second).is_some());
+
+        // streamed bodies cannot be sent again
+        req.extensions_mut().insert(StreamedBody);
+        assert!(Policy::<_, Response<()>, tower::BoxError>::clone_request(&policy, &req).is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RetryConfig::default().validate().is_ok());
+        let config = RetryConfig {
+            min_backoff_ms: 5000,
+            max_backoff_ms: 100,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_idempotency_key_only_for_writes() {
+        let with_id = |id: u64| {
+            ProxyContext::new(crate::context::Annotations {
+                id,
+                ..Default::default()
+            })
+        };
+        let get = Request::get("/v6/device")
+            .header("x-request-id", "1")
+            .extension(with_id(1))
+            .body(())
+            .expect("request");
+        assert!(!with_idempotency_key(get)
+            .headers()
+            .contains_key(IDEMPOTENCY_KEY));
+
+        let own = Request::patch("/v6/device")
+            .extension(with_id(1))
+            .header(IDEMPOTENCY_KEY, "client")
+            .body(())
+            .expect("request");
+        assert_eq!(
+            with_idempotency_key(own).headers()[IDEMPOTENCY_KEY],
+            "client"
+        );
+
+        // writes sharing the client's request id get keys of their own
+        let post = |id: u64| {
+            let req = Request::post("/v6/device")
+                .header("x-request-id", "1")
+                .extension(with_id(id))
+                .body(())
+                .expect("request");
+            with_idempotency_key(req).headers()[IDEMPOTENCY_KEY].clone()
+        };
+        assert_ne!(post(2), post(3));
+        // without a context there is no id to derive a key from
+        let bare = Request::post("/v6/device")
+            .header("x-request-id", "1")
+            .body(())
+            .expect("request");
+        assert!(!with_idempotency_key(bare)
+            .headers()
+            .contains_key(IDEMPOTENCY_KEY));
     }
 }
@@ -0,0 +1,187 @@
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Uri};
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::odata::ODataMatcher;
+
+/// Selects requests by path, method, headers and OData query shape.
+///
+/// An empty matcher selects every request.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteMatcher {
+    /// Exact path.
+    pub path: Option<String>,
+    pub path_prefix: Option<String>,
+    /// Allowed methods, any method when empty.
+    pub methods: Vec<String>,
+    /// Headers that must all be present.
+    pub headers: Vec<HeaderMatcher>,
+    /// OData query shape, of GETs only, any request when unset.
+    pub odata: Option<ODataMatcher>,
+}
+
+/// The routes of [`crate::config::Config`] sections, as embedders build them
+/// in code, e.g. `RouteConfig::prefix("/v6/device").method(Method::GET)`.
+pub type RouteConfig = RouteMatcher;
+
+/// Requires a header, optionally with an exact value.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderMatcher {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl RouteMatcher {
+    /// Select the exact path `path`.
+    pub fn path(path: impl Into<String>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Select the paths starting with `prefix`.
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            path_prefix: Some(prefix.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Allow `method` as well, only the methods allowed so far selecting
+    /// any at all.
+    pub fn method(mut self, method: Method) -> Self {
+        self.methods.push(method.as_str().to_owned());
+        self
+    }
+
+    /// Require the header `name`, with `value` if any.
+    pub fn header(mut self, name: HeaderName, value: Option<HeaderValue>) -> Self {
+        self.headers.push(HeaderMatcher {
+            name: name.as_str().to_owned(),
+            value: value.and_then(|value| value.to_str().ok().map(str::to_owned)),
+        });
+        self
+    }
+
+    /// Check paths are absolute, and that methods and headers are valid, as
+    /// a matcher with a typo in them silently matches nothing.
+    pub fn validate(&self) -> Result<(), BoxError> {
+        for path in [&self.path, &self.path_prefix].into_iter().flatten() {
+            if !path.starts_with('/') {
+                return Err(format!("route: path {:?} does not start with /", path).into());
+            }
+        }
+        for method in &self.methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|err| format!("route: method {:?}: {}", method, err))?;
+        }
+        for header in &self.headers {
+            HeaderName::try_from(header.name.as_str())
+                .map_err(|err| format!("route: header {:?}: {}", header.name, err))?;
+            if let Some(value) = &header.value {
+                HeaderValue::from_str(value)
+                    .map_err(|err| format!("route: header {:?}: {}", header.name, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches<B>(&self, req: &Request<B>) -> bool {
+        self.matches_parts(req.method(), req.uri().path())
+            && self.matches_headers(req.headers())
+            && self.matches_query(req.method(), req.uri())
+    }
+
+    /// Match on the OData query shape only.
+    pub fn matches_query(&self, method: &Method, uri: &Uri) -> bool {
+        self.odata
+            .as_ref()
+            .is_none_or(|odata| odata.matches(method, uri))
+    }
+
+    /// Match on header requirements only.
+    pub fn matches_headers(&self, headers: &HeaderMap) -> bool {
+        self.headers.iter().all(|h| {
+            headers
+                .get_all(h.name.as_str())
+                .iter()
+                .any(|v| h.value.as_ref().is_none_or(|value| v == value.as_str()))
+        })
+    }
+
+    /// Match on method and path only, ignoring header requirements.
+    pub fn matches_parts(&self, method: &Method, path: &str) -> bool {
+        if self.path.as_ref().is_some_and(|p| p != path) {
+            return false;
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(value: serde_json::Value) -> RouteMatcher {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = RouteConfig::prefix("/v6/device")
+            .method(Method::GET)
+            .header(HeaderName::from_static("x-fleet"), None);
+        assert!(valid.validate().is_ok());
+        assert!(RouteMatcher::default().validate().is_ok());
+
+        let invalid = [
+            serde_json::json!({ "path": "v6/device" }),
+            serde_json::json!({ "path_prefix": "device" }),
+            serde_json::json!({ "methods": ["G ET"] }),
+            serde_json::json!({ "methods": [""] }),
+            serde_json::json!({ "headers": [{ "name": "x y" }] }),
+            serde_json::json!({ "headers": [{ "name": "x-fleet", "value": "a\nb" }] }),
+        ];
+        for value in invalid {
+            assert!(matcher(value.clone()).validate().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let route = RouteConfig::prefix("/v6/device")
+            .method(Method::GET)
+            .header(
+                HeaderName::from_static("x-fleet"),
+                Some(HeaderValue::from_static("a")),
+            );
+        let req = |method: &str, path: &str, fleet: Option<&str>| {
+            let mut req = Request::builder().method(method).uri(path);
+            if let Some(fleet) = fleet {
+                req = req.header("x-fleet", fleet);
+            }
+            req.body(()).unwrap()
+        };
+        assert!(route.matches(&req("GET", "/v6/device(1)", Some("a"))));
+        // methods are compared case-insensitively
+        assert!(route.matches(&req("get", "/v6/device", Some("a"))));
+        assert!(!route.matches(&req("POST", "/v6/device", Some("a"))));
+        assert!(!route.matches(&req("GET", "/v6/release", Some("a"))));
+        assert!(!route.matches(&req("GET", "/v6/device", Some("b"))));
+        assert!(!route.matches(&req("GET", "/v6/device", None)));
+        assert!(RouteConfig::path("/ping").matches(&req("HEAD", "/ping", None)));
+        assert!(!RouteConfig::path("/ping").matches(&req("HEAD", "/ping/", None)));
+    }
+}
@@ -0,0 +1,194 @@
+//! Request URI sanitization.
+//!
+//! [`SanitizeLayer`] normalizes the request path before routes are matched
+//! and `ForwardRequest` builds the upstream URI: duplicate slashes are
+//! collapsed and `.` and `..` segments resolved, percent-encoded ones
+//! included. Paths climbing above the root, paths with percent-encoded
+//! separators, which upstreams may decode into segments of their own, and
+//! paths carrying null bytes or control characters are rejected with a 400.
+//! Queries are only checked for raw control characters: encoded ones are
+//! data, such as a newline matched by an OData `$filter`.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, uri::PathAndQuery, HeaderValue, Request, Response, StatusCode};
+use tower::{BoxError, Layer, Service};
+
+/// Decode the percent-encoded bytes of `s`, leaving invalid escapes as they are.
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn has_control_chars(s: &str) -> bool {
+    percent_decode(s).iter().any(|b| b.is_ascii_control())
+}
+
+/// Collapse duplicate slashes and resolve dot segments of `path`.
+pub fn normalize_path(path: &str) -> Result<String, &'static str> {
+    if has_control_chars(path) {
+        return Err("control character in path");
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        let decoded = percent_decode(segment);
+        if decoded.iter().any(|b| matches!(b, b'/' | b'\\')) {
+            return Err("encoded separator in path");
+        }
+        match decoded.as_slice() {
+            b"" | b"." => {}
+            b".." => {
+                if segments.pop().is_none() {
+                    return Err("path escapes the root");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    // keep the trailing slash of directory-like paths
+    if !segments.is_empty() && (path.ends_with('/') || path.ends_with("/.")) {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+fn sanitize<B>(req: &mut Request<B>) -> Result<(), &'static str> {
+    let path = normalize_path(req.uri().path())?;
+    let query = req.uri().query();
+    if query.is_some_and(|query| query.bytes().any(|b| b.is_ascii_control())) {
+        return Err("control character in query");
+    }
+    if path == req.uri().path() {
+        return Ok(());
+    }
+
+    let path_and_query = match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query =
+        Some(PathAndQuery::try_from(path_and_query).map_err(|_| "invalid path")?);
+    *req.uri_mut() = parts.try_into().map_err(|_| "invalid path")?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SanitizeLayer;
+
+impl SanitizeLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for SanitizeLayer {
+    type Service = Sanitize<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Sanitize { inner: service }
+    }
+}
+
+#[derive(Clone)]
+pub struct Sanitize<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Sanitize<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let error = match sanitize(&mut req) {
+            Ok(()) => {
+                let fut = self.inner.call(req);
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+            Err(error) => error,
+        };
+
+        tracing::warn!(uri = %req.uri(), error, "rejected request uri");
+        let body = serde_json::json!({ "error": error });
+        let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+        *res.status_mut() = StatusCode::BAD_REQUEST;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/v6//device").unwrap(), "/v6/device");
+        assert_eq!(normalize_path("/v6/./x/../device").unwrap(), "/v6/device");
+        assert_eq!(
+            normalize_path("/v6/x/%2e%2E/device/").unwrap(),
+            "/v6/device/"
+        );
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert!(normalize_path("/v6/../../etc/passwd").is_err());
+        assert!(normalize_path("/v6/%2e%2e/%2e%2e/admin").is_err());
+        assert!(normalize_path("/v6/device%00.json").is_err());
+        assert!(normalize_path("/v6/..%2f..%2fadmin").is_err());
+        assert!(normalize_path("/v6/x/%2E%2E%2Fdevice").is_err());
+        assert!(normalize_path("/v6/%5c..%5cadmin").is_err());
+    }
+
+    #[test]
+    fn test_sanitize_request() {
+        let mut req = Request::get("/v6//x/../device?$filter=id%20eq%201")
+            .body(())
+            .unwrap();
+        sanitize(&mut req).unwrap();
+        assert_eq!(req.uri(), "/v6/device?$filter=id%20eq%201");
+
+        // encoded control characters are left to the upstream in queries
+        let mut req = Request::get("/v6/device?$filter=note%20eq%20'%0A'")
+            .body(())
+            .unwrap();
+        sanitize(&mut req).unwrap();
+        assert_eq!(req.uri(), "/v6/device?$filter=note%20eq%20'%0A'");
+    }
+}
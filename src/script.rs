@@ -0,0 +1,359 @@
+//! Rhai scripting hooks.
+//!
+//! Each configured script may define `on_request(req)` and `on_response(res)`
+//! for one-off transformations that do not deserve a layer of their own.
+//! Scripts run as a [`ProxyPlugin`] and only see request and response heads:
+//!
+//! ```rhai
+//! fn on_request(req) {
+//!     if req.headers["x-legacy-client"] == () { return; }
+//!     req.path.replace("/v5/", "/v6/");
+//!     req
+//! }
+//! ```
+//!
+//! `req` holds `method`, `path`, `query` and `headers`; returning it applies
+//! the changes to `path`, `query` and `headers`, returning a map with a
+//! `status` (and optionally `headers` and `body`) answers the request without
+//! forwarding it, returning nothing leaves the request as is. `res` holds
+//! `method`, `path`, `status` and `headers`, changes to `status` and
+//! `headers` apply when it is returned.
+
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use http::{
+    request, response, uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, Method, Response,
+    StatusCode, Uri,
+};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::{plugin::ProxyPlugin, route::RouteMatcher};
+
+/// Bound on the work a single hook call may do, so a runaway script cannot
+/// stall requests.
+const MAX_OPERATIONS: u64 = 100_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptHook {
+    /// Requests the script applies to. Header requirements are only checked
+    /// for `on_request`, responses are selected by method and path.
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Rhai file defining `on_request(req)` and/or `on_response(res)`.
+    pub path: PathBuf,
+}
+
+struct Script {
+    route: RouteMatcher,
+    name: String,
+    ast: AST,
+    on_request: bool,
+    on_response: bool,
+}
+
+pub struct ScriptPlugin {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+impl ScriptPlugin {
+    /// Compile every hook script.
+    pub fn new(hooks: Vec<ScriptHook>) -> Result<Self, BoxError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let scripts = hooks
+            .into_iter()
+            .map(|hook| {
+                let name = hook.path.display().to_string();
+                let source = std::fs::read_to_string(&hook.path)
+                    .map_err(|err| format!("{}: {}", name, err))?;
+                let ast = engine
+                    .compile(source)
+                    .map_err(|err| format!("{}: {}", name, err))?;
+                let defines = |f: &str| ast.iter_functions().any(|meta| meta.name == f);
+                Ok(Script {
+                    on_request: defines("on_request"),
+                    on_response: defines("on_response"),
+                    route: hook.route,
+                    name,
+                    ast,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self { engine, scripts })
+    }
+
+    fn call(&self, script: &Script, hook: &str, arg: Map) -> Option<Map> {
+        let result = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &script.ast,
+            hook,
+            (Dynamic::from(arg),),
+        );
+        match result {
+            Ok(value) if value.is_unit() => None,
+            Ok(value) => match value.try_cast::<Map>() {
+                Some(map) => Some(map),
+                None => {
+                    tracing::warn!(script = %script.name, hook, "script returned no map");
+                    None
+                }
+            },
+            Err(err) => {
+                tracing::warn!(script = %script.name, hook, %err, "script failed");
+                None
+            }
+        }
+    }
+}
+
+impl ProxyPlugin for ScriptPlugin {
+    fn name(&self) -> &str {
+        "script"
+    }
+
+    fn on_request(&self, req: &mut request::Parts) -> Option<Response<Bytes>> {
+        for script in &self.scripts {
+            if !script.on_request
+                || !script.route.matches_parts(&req.method, req.uri.path())
+                || !script.route.matches_headers(&req.headers)
+                || !script.route.matches_query(&req.method, &req.uri)
+            {
+                continue;
+            }
+            let mut arg = Map::new();
+            arg.insert("method".into(), req.method.as_str().into());
+            arg.insert("path".into(), req.uri.path().into());
+            arg.insert("query".into(), req.uri.query().unwrap_or_default().into());
+            arg.insert("headers".into(), headers_to_map(&req.headers).into());
+            let Some(mut result) = self.call(script, "on_request", arg) else {
+                continue;
+            };
+
+            if let Some(status) = result.get("status") {
+                let status = status
+                    .as_int()
+                    .ok()
+                    .and_then(|code| u16::try_from(code).ok())
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let body = result
+                    .get("body")
+                    .map(|body| Bytes::from(body.to_string()))
+                    .unwrap_or_default();
+                let mut res = Response::new(body);
+                *res.status_mut() = status;
+                if let Some(headers) = result.remove("headers").and_then(|h| h.try_cast()) {
+                    apply_headers(res.headers_mut(), &headers);
+                }
+                tracing::debug!(script = %script.name, %status, "script answered request");
+                return Some(res);
+            }
+
+            if let Some(headers) = result.remove("headers").and_then(|h| h.try_cast()) {
+                apply_headers(&mut req.headers, &headers);
+            }
+            let path = result
+                .get("path")
+                .map(Dynamic::to_string)
+                .unwrap_or_else(|| req.uri.path().to_owned());
+            let query = result
+                .get("query")
+                .map(Dynamic::to_string)
+                .or_else(|| req.uri.query().map(str::to_owned))
+                .unwrap_or_default();
+            let path_and_query = if query.is_empty() {
+                path
+            } else {
+                format!("{}?{}", path, query)
+            };
+            if Some(path_and_query.as_str()) != req.uri.path_and_query().map(PathAndQuery::as_str) {
+                let mut parts = req.uri.clone().into_parts();
+                match path_and_query.parse() {
+                    Ok(path_and_query) => {
+                        parts.path_and_query = Some(path_and_query);
+                        if let Ok(uri) = Uri::from_parts(parts) {
+                            req.uri = uri;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!(script = %script.name, %err, "script set an invalid path")
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn on_response(&self, method: &Method, uri: &Uri, res: &mut response::Parts) {
+        for script in self.scripts.iter().rev() {
+            if !script.on_response
+                || !script.route.matches_parts(method, uri.path())
+                || !script.route.matches_query(method, uri)
+            {
+                continue;
+            }
+            let mut arg = Map::new();
+            arg.insert("method".into(), method.as_str().into());
+            arg.insert("path".into(), uri.path().into());
+            arg.insert(
+                "status".into(),
+                Dynamic::from_int(res.status.as_u16().into()),
+            );
+            arg.insert("headers".into(), headers_to_map(&res.headers).into());
+            let Some(mut result) = self.call(script, "on_response", arg) else {
+                continue;
+            };
+
+            if let Some(status) = result
+                .get("status")
+                .and_then(|status| status.as_int().ok())
+                .and_then(|code| u16::try_from(code).ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+            {
+                res.status = status;
+            }
+            if let Some(headers) = result.remove("headers").and_then(|h| h.try_cast()) {
+                apply_headers(&mut res.headers, &headers);
+            }
+        }
+    }
+}
+
+/// Headers as a map of lowercase names, repeated values joined with `, `.
+fn headers_to_map(headers: &HeaderMap) -> Map {
+    let mut map = Map::new();
+    for name in headers.keys() {
+        let value = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(", ");
+        map.insert(name.as_str().into(), value.into());
+    }
+    map
+}
+
+/// Apply the headers a script returned: names it dropped are removed, values
+/// it changed replace all previous values, unchanged headers are untouched.
+fn apply_headers(headers: &mut HeaderMap, map: &Map) {
+    let before = headers_to_map(headers);
+    for name in before.keys() {
+        if !map.contains_key(name) {
+            headers.remove(name.as_str());
+        }
+    }
+    for (name, value) in map {
+        let value = value.to_string();
+        if before.get(name).is_some_and(|v| v.to_string() == value) {
+            continue;
+        }
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => tracing::warn!(header = %name, "script set an invalid header"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use crate::plugin::PluginLayer;
+    use http::Request;
+    use hyper::Body;
+    use tower::{Layer, ServiceExt};
+
+    const SCRIPT: &str = r#"
+        fn on_request(req) {
+            if req.headers["x-api-token"] == () {
+                return #{ status: 401, body: "token required" };
+            }
+            req.headers.remove("x-api-token");
+            req.headers["x-scripted"] = "yes";
+            req.path.replace("/v5/", "/v6/");
+            req
+        }
+
+        fn on_response(res) {
+            if res.status == 404 {
+                res.status = 410;
+                res.headers["x-gone"] = res.path;
+            }
+            res
+        }
+    "#;
+
+    #[tokio::test]
+    async fn test_script() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("proxy-script-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("hook.rhai");
+        std::fs::write(&path, SCRIPT)?;
+        let plugin = ScriptPlugin::new(vec![ScriptHook {
+            route: serde_json::from_value(serde_json::json!({ "path_prefix": "/v5" }))?,
+            path,
+        }])?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let fixtures: Vec<Fixture> = serde_json::from_value(serde_json::json!([
+            { "route": { "path": "/v6/device", "headers": [{ "name": "x-scripted" }] },
+              "status": 200 },
+        ]))?;
+        let service = PluginLayer::new()
+            .register(plugin)
+            .layer(MockUpstream::new(fixtures));
+
+        let req = Request::get("/v5/device")
+            .header("x-api-token", "secret")
+            .body(Body::empty())?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = service
+            .clone()
+            .oneshot(Request::get("/v5/device").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            hyper::body::to_bytes(res.into_body()).await?,
+            "token required"
+        );
+
+        let req = Request::get("/v5/missing")
+            .header("x-api-token", "secret")
+            .body(Body::empty())?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::GONE);
+        assert_eq!(res.headers()["x-gone"], "/v5/missing");
+
+        // other routes are left alone
+        let res = service
+            .oneshot(Request::get("/v6/device").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_script() {
+        let path = std::env::temp_dir().join(format!("proxy-invalid-{}.rhai", std::process::id()));
+        std::fs::write(&path, "fn on_request(req) {").unwrap();
+        let hooks = vec![ScriptHook {
+            route: RouteMatcher::default(),
+            path: path.clone(),
+        }];
+        assert!(ScriptPlugin::new(hooks).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+//! API keys that clear their memory.
+//!
+//! Some deployments must not leave credentials lying around in freed memory
+//! or in logs. [`ApiKey`] holds a key for the pool and for the requests it is
+//! used in: its bytes are zeroed when it is dropped, `Debug` shows its
+//! [`key_id`] rather than the key, and it has no `Display`, so it takes an
+//! explicit dereference to format one. [`ApiKey::bearer`] makes the
+//! `Authorization` value, marked sensitive so HTTP/2 never indexes it.
+
+use std::{borrow::Borrow, fmt, ops::Deref};
+
+use http::HeaderValue;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::context::key_id;
+
+/// Headers carrying credentials, which are never written to disk as they
+/// are: recordings redact them by default and queued requests leave them out.
+pub const SENSITIVE_HEADERS: [&str; 8] = [
+    "authorization",
+    "x-balena-authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-signature",
+    "x-amz-security-token",
+];
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    /// `Bearer <key>`, as a sensitive header value.
+    pub fn bearer(&self) -> Option<HeaderValue> {
+        let value = Zeroizing::new(format!("Bearer {}", self.0));
+        let mut value = HeaderValue::from_str(&value).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+}
+
+impl Drop for ApiKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl From<String> for ApiKey {
+    fn from(key: String) -> Self {
+        Self(key)
+    }
+}
+
+impl From<&str> for ApiKey {
+    fn from(key: &str) -> Self {
+        Self(key.to_owned())
+    }
+}
+
+impl Deref for ApiKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for ApiKey {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for ApiKey {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for ApiKey {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ApiKey").field(&key_id(&self.0)).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key() {
+        let key = ApiKey::from("secret-key");
+        assert!(!format!("{:?}", key).contains("secret"));
+        assert_eq!(
+            format!("{:?}", key),
+            format!("ApiKey({:?})", key_id("secret-key"))
+        );
+        let value = key.bearer().unwrap();
+        assert!(value.is_sensitive());
+        assert_eq!(value, "Bearer secret-key");
+        assert!(key == "secret-key");
+    }
+}
@@ -0,0 +1,636 @@
+//! Listener and connection handling.
+//!
+//! Accepted, active and closed connections, the requests they served, and
+//! failed accepts and TLS handshakes are counted in the `proxy_connection*`,
+//! `proxy_accept_errors_total` and `proxy_tls_handshake_failures_total`
+//! metrics. On shutdown the listener closes and open connections get
+//! `drain_timeout_secs` to finish, see [`crate::shutdown`].
+//!
+//! Interim responses other than `100 Continue` are not forwarded, see
+//! [`crate::expect`].
+
+use std::{
+    future::{pending, Future},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::{Request, Response};
+use http_body::Body;
+use hyper::server::conn::Http;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify, Semaphore},
+    time::Instant,
+};
+use tower::{BoxError, Service};
+
+use crate::{metrics::Metric, shutdown::Shutdown};
+
+const ACCEPTED: Metric = Metric::counter(
+    "proxy_connections_accepted_total",
+    "Connections accepted by the listener.",
+);
+pub const ACTIVE: Metric = Metric::gauge("proxy_connections_active", "Connections open.");
+const CLOSED: Metric = Metric::counter("proxy_connections_closed_total", "Connections closed.");
+const CONNECTION_REQUESTS: Metric = Metric::counter(
+    "proxy_connection_requests_total",
+    "Requests served by closed connections; per connection once divided by the closed ones.",
+);
+const ACCEPT_ERRORS: Metric = Metric::counter(
+    "proxy_accept_errors_total",
+    "Connections that failed to be accepted.",
+);
+#[cfg(feature = "tls")]
+const HANDSHAKE_FAILURES: Metric = Metric::counter(
+    "proxy_tls_handshake_failures_total",
+    "Connections dropped as their TLS handshake failed or timed out.",
+);
+
+/// Pauses after failed accepts, doubling from the first to the last, as
+/// hyper's own listener does.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether a failed accept was about that one connection only, rather than
+/// the listener.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// Size of hyper's read buffer, which bounds the request head. Requests
+    /// overflowing it get a 431. At least 8192, hyper's default when unset.
+    pub max_buf_bytes: Option<usize>,
+    /// Close connections that take longer to send the request headers.
+    /// HTTP/1 only: hyper has no such timeout for HTTP/2 connections.
+    pub header_read_timeout_ms: Option<u64>,
+    /// Fail requests whose body stalls for longer between two chunks.
+    pub body_read_timeout_ms: Option<u64>,
+    /// Stream request bodies over this size instead of buffering them, all
+    /// are buffered when unset. Streamed requests are never retried.
+    pub max_buffered_body_bytes: Option<usize>,
+    /// Gracefully close connections older than this, after their current
+    /// request.
+    pub max_connection_lifetime_secs: Option<u64>,
+    /// Connections served at once; further ones wait in the accept backlog.
+    pub max_connections: Option<usize>,
+    /// Gracefully close connections after this many requests.
+    pub max_requests_per_connection: Option<u64>,
+    /// Close keep-alive connections left without a request for this long.
+    pub idle_timeout_secs: Option<u64>,
+    /// Send small responses right away instead of coalescing them (Nagle).
+    pub tcp_nodelay: bool,
+    /// Let several processes listen on the same port, the kernel spreading
+    /// connections between them (`SO_REUSEPORT`, unix only).
+    pub reuse_port: bool,
+    /// Probe connections idle for this long at the TCP level.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Time between keep-alive probes, the system default when unset.
+    pub tcp_keepalive_interval_secs: Option<u64>,
+    /// Unanswered probes before the connection is dropped, the system
+    /// default when unset.
+    pub tcp_keepalive_retries: Option<u32>,
+    /// Connections waiting to be accepted.
+    pub backlog: u32,
+    /// How long open connections get to finish their requests on shutdown.
+    pub drain_timeout_secs: u64,
+    /// Tell the build in an `X-Proxy-Version` header on every response.
+    pub version_header: bool,
+    /// Answer requests expecting anything but `100-continue` with a 417
+    /// instead of forwarding them, see [`crate::expect`].
+    pub refuse_expectations: bool,
+    /// TLS termination, plain HTTP when unset.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            max_buf_bytes: None,
+            header_read_timeout_ms: Some(30_000),
+            body_read_timeout_ms: Some(30_000),
+            max_buffered_body_bytes: None,
+            max_connection_lifetime_secs: None,
+            max_connections: None,
+            max_requests_per_connection: None,
+            idle_timeout_secs: None,
+            tcp_nodelay: false,
+            reuse_port: false,
+            tcp_keepalive_secs: None,
+            tcp_keepalive_interval_secs: None,
+            tcp_keepalive_retries: None,
+            backlog: 1024,
+            drain_timeout_secs: 30,
+            version_header: false,
+            refuse_expectations: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn body_read_timeout(&self) -> Option<Duration> {
+        self.body_read_timeout_ms.map(Duration::from_millis)
+    }
+
+    fn bind(&self) -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(self.listen),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        #[cfg(unix)]
+        {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(self.reuse_port)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&self.listen.into())?;
+        socket.listen(self.backlog.min(i32::MAX as u32) as i32)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Apply the per-connection socket options to `stream`.
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay)?;
+        if let Some(time) = self.tcp_keepalive_secs {
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(time));
+            if let Some(interval) = self.tcp_keepalive_interval_secs {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval));
+            }
+            #[cfg(unix)]
+            if let Some(retries) = self.tcp_keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Requests seen on one connection.
+struct Activity {
+    requests: AtomicU64,
+    in_flight: AtomicUsize,
+    last_active: Mutex<Instant>,
+    limit_reached: Notify,
+}
+
+impl Activity {
+    /// Resolve once the connection was idle for `idle`.
+    async fn idle(&self, idle: Duration) {
+        loop {
+            let at = *self.last_active.lock().unwrap() + idle;
+            if self.in_flight.load(Ordering::Relaxed) == 0 && Instant::now() >= at {
+                return;
+            }
+            tokio::time::sleep_until(at.max(Instant::now() + idle / 4)).await;
+        }
+    }
+}
+
+/// An open connection, counted as active until dropped.
+struct Open(Arc<Activity>);
+
+impl Open {
+    fn new(activity: Arc<Activity>) -> Self {
+        ACCEPTED.increment(&[]);
+        ACTIVE.add(&[], 1.0);
+        Self(activity)
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        ACTIVE.add(&[], -1.0);
+        CLOSED.increment(&[]);
+        CONNECTION_REQUESTS.add(&[], self.0.requests.load(Ordering::Relaxed) as f64);
+    }
+}
+
+/// Connection service keeping track of its [`Activity`].
+#[derive(Clone)]
+struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+    max_requests: Option<u64>,
+}
+
+impl<S, R> Service<R> for Tracked<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let requests = self.activity.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        self.activity.in_flight.fetch_add(1, Ordering::Relaxed);
+        if self.max_requests == Some(requests) {
+            self.activity.limit_reached.notify_one();
+        }
+        let fut = self.inner.call(req);
+        let activity = self.activity.clone();
+        Box::pin(async move {
+            let result = fut.await;
+            *activity.last_active.lock().unwrap() = Instant::now();
+            activity.in_flight.fetch_sub(1, Ordering::Relaxed);
+            result
+        })
+    }
+}
+
+/// Why a connection should be closed, once it should.
+async fn close_reason(
+    activity: &Activity,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    shutdown: &Shutdown,
+) -> &'static str {
+    tokio::select! {
+        _ = shutdown.triggered() => "shutting down",
+        _ = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => pending().await,
+            }
+        } => "connection lifetime exceeded",
+        _ = activity.limit_reached.notified() => "connection request limit reached",
+        _ = async {
+            match idle {
+                Some(idle) => activity.idle(idle).await,
+                None => pending().await,
+            }
+        } => "connection idle",
+    }
+}
+
+/// Serve one connection until it closes, or should be closed.
+async fn serve_connection<I, S, B>(
+    http: Http,
+    io: I,
+    service: Tracked<S>,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    shutdown: Shutdown,
+    peer: SocketAddr,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<hyper::Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let activity = service.activity.clone();
+    let conn = http.serve_connection(io, service);
+    // the connection is handed over to the tunnel, see `crate::websocket`
+    #[cfg(feature = "websocket")]
+    let conn = conn.with_upgrades();
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        reason = close_reason(&activity, deadline, idle, &shutdown) => {
+            tracing::debug!(%peer, reason, "closing connection");
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(err) = result {
+        tracing::debug!(%peer, %err, "connection closed with error");
+    }
+}
+
+/// Accept connections and serve them with clones of `service`, until
+/// `shutdown` is triggered and they are closed.
+pub async fn serve<S, B>(
+    config: &ServerConfig,
+    service: S,
+    shutdown: Shutdown,
+) -> Result<(), BoxError>
+where
+    S: Service<Request<hyper::Body>, Response = Response<B>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    #[cfg(feature = "tls")]
+    let tls = match &config.tls {
+        Some(tls) => Some(crate::tls::Acceptor::new(tls).await?),
+        None => None,
+    };
+    let listener = config
+        .bind()
+        .map_err(|err| format!("{}: {}", config.listen, err))?;
+    tracing::info!(addr = %config.listen, "listening");
+
+    let mut http = Http::new();
+    if let Some(timeout) = config.header_read_timeout_ms {
+        http.http1_header_read_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(size) = config.max_buf_bytes {
+        http.max_buf_size(size.max(8192));
+    }
+    let lifetime = config.max_connection_lifetime_secs.map(Duration::from_secs);
+    let idle = config.idle_timeout_secs.map(Duration::from_secs);
+    let connections = config
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max.max(1))));
+
+    // every connection task holds a sender, so the channel closes with the last
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown.triggered() => break,
+            accepted = async {
+                let permit = match &connections {
+                    Some(connections) => Some(connections.clone().acquire_owned().await?),
+                    None => None,
+                };
+                Ok::<_, BoxError>((permit, listener.accept().await))
+            } => accepted?,
+        };
+        let (stream, peer, permit) = match accepted {
+            (permit, Ok((stream, peer))) => {
+                backoff = MIN_ACCEPT_BACKOFF;
+                (stream, peer, permit)
+            }
+            (_, Err(err)) => {
+                ACCEPT_ERRORS.increment(&[]);
+                // the peer gave up before we got to it, next
+                if is_connection_error(&err) {
+                    tracing::debug!(%err, "accept failed");
+                    continue;
+                }
+                // e.g. out of file descriptors, give connections time to close
+                tracing::warn!(%err, backoff_ms = backoff.as_millis() as u64, "accept failed");
+                tokio::select! {
+                    _ = shutdown.triggered() => break,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        if let Err(err) = config.configure(&stream) {
+            tracing::warn!(%peer, %err, "cannot set socket options");
+        }
+        let service = Tracked {
+            inner: service.clone(),
+            activity: Arc::new(Activity {
+                requests: AtomicU64::new(0),
+                in_flight: AtomicUsize::new(0),
+                last_active: Mutex::new(Instant::now()),
+                limit_reached: Notify::new(),
+            }),
+            max_requests: config.max_requests_per_connection,
+        };
+        let open = Open::new(service.activity.clone());
+        let http = http.clone();
+        let deadline = lifetime.map(|lifetime| Instant::now() + lifetime);
+        let shutdown = shutdown.clone();
+        let open_tx = open_tx.clone();
+        #[cfg(feature = "tls")]
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            // the connection counts until it is closed
+            let _permit = permit;
+            let _open = open;
+            let _open_tx = open_tx;
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                match tls.accept(stream).await {
+                    Ok(stream) => {
+                        serve_connection(http, stream, service, deadline, idle, shutdown, peer)
+                            .await
+                    }
+                    Err(err) => {
+                        HANDSHAKE_FAILURES.increment(&[]);
+                        tracing::warn!(%peer, %err, "TLS handshake failed");
+                    }
+                }
+                return;
+            }
+            serve_connection(http, stream, service, deadline, idle, shutdown, peer).await;
+        });
+    }
+
+    drop(listener);
+    drop(open_tx);
+    let drain = Duration::from_secs(config.drain_timeout_secs);
+    tracing::info!(drain_timeout = ?drain, "waiting for open connections");
+    if tokio::time::timeout(drain, open_rx.recv()).await.is_err() {
+        tracing::warn!(
+            open = ACTIVE.total(),
+            "drain timeout passed, dropping open connections"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::conn::{handshake, SendRequest};
+    use tokio::task::JoinHandle;
+
+    /// Serve `config` with a service answering every request, returning the
+    /// address it listens on.
+    async fn listen(mut config: ServerConfig, shutdown: Shutdown) -> SocketAddr {
+        // a free port, released for the listener to take over
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        config.listen = addr;
+        let service = tower::service_fn(|_: Request<hyper::Body>| async {
+            Ok::<_, BoxError>(Response::new(hyper::Body::from("ok")))
+        });
+        tokio::spawn(async move { serve(&config, service, shutdown).await.unwrap() });
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        addr
+    }
+
+    /// An HTTP/1 client connection to `addr`, and the task driving it, which
+    /// finishes once the connection is closed.
+    async fn connect(addr: SocketAddr) -> (SendRequest<hyper::Body>, JoinHandle<()>) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (sender, conn) = handshake(stream).await.unwrap();
+        let conn = tokio::spawn(async move {
+            let _ = conn.await;
+        });
+        (sender, conn)
+    }
+
+    async fn get(sender: &mut SendRequest<hyper::Body>) -> Response<hyper::Body> {
+        let req = Request::get("/").body(hyper::Body::empty()).unwrap();
+        futures_util::future::poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .unwrap();
+        sender.send_request(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let config = ServerConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let addr = listen(config, Shutdown::default()).await;
+
+        let (mut first, first_conn) = connect(addr).await;
+        assert_eq!(get(&mut first).await.status(), 200);
+
+        // waits in the backlog while the first is open
+        let (mut second, _second_conn) = connect(addr).await;
+        let pending = tokio::spawn(async move { get(&mut second).await.status() });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!pending.is_finished());
+
+        drop(first);
+        first_conn.await.unwrap();
+        let status = tokio::time::timeout(Duration::from_secs(5), pending)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status, 200);
+    }
+
+    #[tokio::test]
+    async fn test_max_requests_per_connection() {
+        let config = ServerConfig {
+            max_requests_per_connection: Some(2),
+            ..Default::default()
+        };
+        let addr = listen(config, Shutdown::default()).await;
+
+        let (mut sender, conn) = connect(addr).await;
+        assert_eq!(get(&mut sender).await.status(), 200);
+        assert_eq!(get(&mut sender).await.status(), 200);
+        // closed after the second, without idling
+        tokio::time::timeout(Duration::from_secs(5), conn)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let addr = listen(config, Shutdown::default()).await;
+
+        let (mut sender, conn) = connect(addr).await;
+        assert_eq!(get(&mut sender).await.status(), 200);
+        let started = std::time::Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let config = ServerConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            tcp_nodelay: true,
+            tcp_keepalive_secs: Some(30),
+            tcp_keepalive_interval_secs: Some(5),
+            tcp_keepalive_retries: Some(3),
+            ..Default::default()
+        };
+        let listener = config.bind().unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        config.configure(&stream).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        }
+        drop(client);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let mut config = ServerConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = config.bind().unwrap();
+        config.listen = first.local_addr().unwrap();
+        let second = config.bind().unwrap();
+        assert_eq!(second.local_addr().unwrap(), config.listen);
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let config = ServerConfig {
+            header_read_timeout_ms: Some(200),
+            ..Default::default()
+        };
+        let addr = listen(config, Shutdown::default()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut buf);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+//! Rate limiting shared by proxy replicas.
+//!
+//! Balena limits requests per API key, whatever the number of proxies using
+//! it. [`SharedLimitLayer`] keeps one token bucket per key in Redis, so
+//! horizontally scaled proxies collectively stay within the key's quota
+//! instead of each assuming all of it. Requests wait for their token, up to
+//! `max_wait_ms`, and get a 429 beyond that. Buckets refill on the Redis
+//! clock (Redis 5 or later), so replicas with skewed clocks still agree.
+//!
+//! Keys are hashed before they are used in Redis key names. When Redis is
+//! unreachable or slow, requests are forwarded unlimited rather than failed.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+    HeaderValue, Request, Response, StatusCode,
+};
+use redis::{aio::ConnectionManager, Script};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tower::{BoxError, Layer, Service};
+
+use crate::secret::ApiKey;
+
+/// Take a token from the bucket in `KEYS[1]`, refilled at `ARGV[1]` tokens
+/// per millisecond up to `ARGV[2]`. Returns the milliseconds to wait for it,
+/// or -1, taking nothing, when that is more than `ARGV[3]`.
+const TAKE: &str = r"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local max_wait = tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local wait = math.ceil(math.max(0, 1 - tokens) / rate)
+if wait > max_wait then
+  return -1
+end
+redis.call('HSET', KEYS[1], 'tokens', tokens - 1, 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate) + wait + 1000)
+return wait
+";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedLimitConfig {
+    /// Redis holding the buckets, e.g. `redis://limits.internal:6379/0`.
+    pub redis_url: String,
+    /// Requests per minute allowed per API key across all replicas.
+    pub requests_per_minute: u64,
+    /// Requests that may pass at once after an idle period, one second worth
+    /// of requests when unset.
+    pub burst: Option<u64>,
+    /// Prefix of the bucket names in Redis.
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// Longest a request waits for its token before getting a 429.
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+    /// Longest a Redis round trip may take before the request goes unlimited.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_key_prefix() -> String {
+    "proxy:limit".to_owned()
+}
+
+fn default_max_wait_ms() -> u64 {
+    10_000
+}
+
+fn default_timeout_ms() -> u64 {
+    200
+}
+
+/// What a request may do, as decided by its bucket.
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Wait(Duration),
+    Reject,
+}
+
+struct Limiter {
+    client: redis::Client,
+    // connected on first use, and again after a failed attempt
+    connection: OnceCell<ConnectionManager>,
+    script: Script,
+    key_prefix: String,
+    // tokens per millisecond
+    rate: f64,
+    burst: u64,
+    max_wait: Duration,
+    timeout: Duration,
+}
+
+impl Limiter {
+    /// The Redis key of the bucket of `api_key`.
+    fn bucket(&self, api_key: &str) -> String {
+        let digest = Sha256::digest(api_key.as_bytes());
+        format!("{}:{}", self.key_prefix, hex::encode(&digest[..16]))
+    }
+
+    async fn take(&self, api_key: &str) -> Result<Decision, BoxError> {
+        let take = async {
+            let connection = self
+                .connection
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await?;
+            let wait: i64 = self
+                .script
+                .key(self.bucket(api_key))
+                .arg(self.rate)
+                .arg(self.burst)
+                .arg(self.max_wait.as_millis() as u64)
+                .invoke_async(&mut connection.clone())
+                .await?;
+            Ok::<_, BoxError>(wait)
+        };
+        let wait = tokio::time::timeout(self.timeout, take)
+            .await
+            .map_err(|_| "shared limit: redis timed out")??;
+        Ok(match u64::try_from(wait) {
+            Ok(wait) => Decision::Wait(Duration::from_millis(wait)),
+            Err(_) => Decision::Reject,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl SharedLimitLayer {
+    /// Check the Redis URL of `config`; the connection is made on first use.
+    pub fn new(config: SharedLimitConfig) -> Result<Self, BoxError> {
+        let client = redis::Client::open(config.redis_url.as_str())
+            .map_err(|err| format!("shared limit redis_url: {}", err))?;
+        let rate = config.requests_per_minute.max(1) as f64 / 60_000.0;
+        let burst = config
+            .burst
+            .unwrap_or_else(|| config.requests_per_minute.div_ceil(60))
+            .max(1);
+        Ok(Self {
+            limiter: Arc::new(Limiter {
+                client,
+                connection: OnceCell::new(),
+                script: Script::new(TAKE),
+                key_prefix: config.key_prefix,
+                rate,
+                burst,
+                max_wait: Duration::from_millis(config.max_wait_ms),
+                timeout: Duration::from_millis(config.timeout_ms),
+            }),
+        })
+    }
+}
+
+impl<S> Layer<S> for SharedLimitLayer {
+    type Service = SharedLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SharedLimit {
+            inner: service,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+fn api_key<B>(req: &Request<B>) -> Option<ApiKey> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (_, key) = value.split_once(' ')?;
+    Some(key.trim())
+        .filter(|key| !key.is_empty())
+        .map(ApiKey::from)
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for SharedLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let Some(api_key) = api_key(&req) else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        // the ready service is used once the token is taken
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            match limiter.take(&api_key).await {
+                Ok(Decision::Wait(wait)) => {
+                    if !wait.is_zero() {
+                        tracing::debug!(
+                            wait_ms = wait.as_millis() as u64,
+                            "waiting for shared limit"
+                        );
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+                Ok(Decision::Reject) => {
+                    tracing::warn!("shared limit exceeded");
+                    let retry_after = limiter.max_wait.as_secs().max(1);
+                    let body = serde_json::json!({ "error": "rate limit exceeded" });
+                    let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+                    *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                    res.headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                    return Ok(res);
+                }
+                Err(err) => tracing::warn!(%err, "shared limit unavailable, not limiting"),
+            }
+            inner.call(req).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_upstream::MockUpstream, read_request_body::ByteBody};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_unreachable_redis_does_not_limit() -> Result<(), BoxError> {
+        let layer = SharedLimitLayer::new(serde_json::from_value(serde_json::json!({
+            "redis_url": "redis://127.0.0.1:1/",
+            "requests_per_minute": 60,
+        }))?)?;
+        assert_eq!(layer.limiter.burst, 1);
+        // keys do not leak into bucket names
+        let bucket = layer.limiter.bucket("secret-key");
+        assert!(bucket.starts_with("proxy:limit:") && !bucket.contains("secret"));
+        assert_eq!(bucket, layer.limiter.bucket("secret-key"));
+        assert_ne!(bucket, layer.limiter.bucket("other-key"));
+
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+        let req = Request::get("/v6/device")
+            .header(AUTHORIZATION, "Bearer secret-key")
+            .body(ByteBody::new(Vec::new()))?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
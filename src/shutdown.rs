@@ -0,0 +1,235 @@
+//! Graceful shutdown.
+//!
+//! On SIGTERM or ctrl-c the listener stops accepting, open connections are
+//! closed after their current request, and the proxy exits once all are, or
+//! after `drain_timeout_secs`. A request waiting out the backoff before its
+//! next attempt would keep its connection open for up to `max_backoff_ms`
+//! per retry, so once [`Shutdown`] is triggered the retry policy stops
+//! waiting, and [`ShutdownLayer`] answers the attempt it would have sent with
+//! a 503 and a `Retry-After` instead, for the client to try again elsewhere.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    HeaderValue, Request, Response, StatusCode,
+};
+use tokio::sync::Notify;
+use tower::{BoxError, Layer, Service};
+
+use crate::{metrics::Metric, retry::Attempt};
+
+const ABANDONED: Metric = Metric::counter(
+    "proxy_shutdown_abandoned_retries_total",
+    "Retries answered with a 503 rather than sent, as the proxy was shutting down.",
+);
+
+#[derive(Default)]
+struct State {
+    triggered: AtomicBool,
+    notify: Notify,
+}
+
+/// The shutdown switch, triggered once.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<State>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        if !self.0.triggered.swap(true, Ordering::SeqCst) {
+            tracing::warn!("shutting down");
+            self.0.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once shutdown is triggered.
+    pub async fn triggered(&self) {
+        // registered before the check, so a trigger in between is not missed
+        let notified = self.0.notify.notified();
+        if self.is_triggered() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Trigger shutdown on SIGTERM or ctrl-c.
+    pub fn watch_signals(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            signal().await;
+            this.trigger();
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            tracing::warn!(%err, "cannot watch SIGTERM");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[derive(Clone)]
+pub struct ShutdownLayer {
+    shutdown: Shutdown,
+    retry_after: HeaderValue,
+}
+
+impl ShutdownLayer {
+    /// Answer retries with a 503 once `shutdown` is triggered, asking clients
+    /// to try again after `retry_after_secs`.
+    pub fn new(shutdown: Shutdown, retry_after_secs: u64) -> Self {
+        Self {
+            shutdown,
+            retry_after: HeaderValue::from(retry_after_secs),
+        }
+    }
+}
+
+impl<S> Layer<S> for ShutdownLayer {
+    type Service = AbandonRetries<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AbandonRetries {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AbandonRetries<S> {
+    inner: S,
+    layer: ShutdownLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for AbandonRetries<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // first attempts are sent, connections close after them anyway
+        if !self.layer.shutdown.is_triggered() || req.extensions().get::<Attempt>().is_none() {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        ABANDONED.increment(&[]);
+        let body = serde_json::json!({ "error": "shutting down, retry later" }).to_string();
+        let mut res = Response::new(ResBody::from(Bytes::from(body)));
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res.headers_mut()
+            .insert(RETRY_AFTER, self.layer.retry_after.clone());
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_request_body::ByteBody, retry::RetryConfig};
+    use hyper::Body;
+    use std::{
+        sync::atomic::AtomicUsize,
+        time::{Duration, Instant},
+    };
+    use tower::{retry::RetryLayer, service_fn, ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn test_shutdown_cuts_backoff_short() -> Result<(), BoxError> {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let upstream = service_fn({
+            let sent = sent.clone();
+            move |_: Request<ByteBody>| {
+                sent.fetch_add(1, Ordering::SeqCst);
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = StatusCode::BAD_GATEWAY;
+                async { Ok::<_, BoxError>(res) }
+            }
+        });
+        let upstream_clone = upstream.clone();
+        let config = RetryConfig {
+            min_backoff_ms: 60_000,
+            ..Default::default()
+        };
+        let shutdown = Shutdown::default();
+        let service = ServiceBuilder::new()
+            .layer(RetryLayer::new(config.policy(shutdown.clone())))
+            .layer(ShutdownLayer::new(shutdown.clone(), 5))
+            .service(upstream);
+
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                shutdown.trigger();
+            }
+        });
+        let started = Instant::now();
+        let req = Request::get("/v6/device").body(ByteBody::from(Bytes::new()))?;
+        let res = service.oneshot(req).await?;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "5");
+        // only the first attempt went upstream
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert!(shutdown.is_triggered());
+        shutdown.triggered().await;
+
+        // first attempts claiming to be retries are still sent
+        let req = Request::get("/v6/device")
+            .header(crate::retry::X_PROXY_ATTEMPT, "2")
+            .body(ByteBody::from(Bytes::new()))?;
+        let res = ShutdownLayer::new(shutdown, 5)
+            .layer(upstream_clone)
+            .oneshot(req)
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,509 @@
+//! AWS Signature Version 4 request signing.
+//!
+//! [`SigV4Layer`] is an alternative to `AuthLayer` for upstreams on AWS
+//! (S3, API Gateway), usually reached through an environment rule. Requests
+//! to a configured host are signed over their buffered body, which also keeps
+//! the key pool from adding a Balena key. Bodies streamed for being over the
+//! buffering cap are sent to S3 as `UNSIGNED-PAYLOAD`; other services need
+//! the payload hash, and such requests fail. Credentials come from
+//! `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, or
+//! else from the instance metadata service (IMDSv2).
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_core::Future;
+use hmac::{Hmac, Mac};
+use http::{
+    header::{AUTHORIZATION, HOST},
+    HeaderValue, Request,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tower::{BoxError, Layer, Service};
+
+use crate::{read_request_body::ByteBody, sanitize::percent_decode};
+
+const X_AMZ_DATE: &str = "x-amz-date";
+/// Payload hash of requests whose body is not signed, accepted by S3 only.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const X_AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+const X_AMZ_SECURITY_TOKEN: &str = "x-amz-security-token";
+
+const IMDS: &str = "http://169.254.169.254/latest";
+/// Instance credentials are rotated hourly, well ahead of their expiry.
+const IMDS_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigV4Rule {
+    /// Upstream hosts whose requests are signed, e.g.
+    /// `my-bucket.s3.eu-west-1.amazonaws.com`.
+    pub hosts: Vec<String>,
+    pub region: String,
+    /// Signing name of the service, e.g. `s3` or `execute-api`.
+    pub service: String,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ImdsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+}
+
+enum CredentialSource {
+    Static(Credentials),
+    Imds {
+        client: Box<Client<HttpConnector>>,
+        cached: Mutex<Option<(Credentials, Instant)>>,
+    },
+}
+
+impl CredentialSource {
+    /// Credentials from the environment, or the instance metadata service
+    /// when unset.
+    fn ambient() -> Self {
+        match Credentials::from_env() {
+            Some(credentials) => Self::Static(credentials),
+            None => Self::Imds {
+                client: Box::new(Client::new()),
+                cached: Mutex::new(None),
+            },
+        }
+    }
+
+    async fn get(&self) -> Result<Credentials, BoxError> {
+        let (client, cached) = match self {
+            Self::Static(credentials) => return Ok(credentials.clone()),
+            Self::Imds { client, cached } => (client, cached),
+        };
+        let mut cached = cached.lock().await;
+        if let Some((credentials, fetched)) = cached.as_ref() {
+            if fetched.elapsed() < IMDS_REFRESH {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = fetch_imds(client)
+            .await
+            .map_err(|err| format!("instance metadata credentials: {}", err))?;
+        *cached = Some((credentials.clone(), Instant::now()));
+        Ok(credentials)
+    }
+}
+
+async fn fetch_imds(client: &Client<HttpConnector>) -> Result<Credentials, BoxError> {
+    async fn text(client: &Client<HttpConnector>, req: Request<Body>) -> Result<String, BoxError> {
+        let res = client.request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("status {}", res.status()).into());
+        }
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok(String::from_utf8(body.to_vec())?)
+    }
+
+    let token = text(
+        client,
+        Request::put(format!("{}/api/token", IMDS))
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "21600")
+            .body(Body::empty())?,
+    )
+    .await?;
+    let get = |path: String| {
+        Request::get(format!(
+            "{}/meta-data/iam/security-credentials/{}",
+            IMDS, path
+        ))
+        .header("x-aws-ec2-metadata-token", token.as_str())
+        .body(Body::empty())
+    };
+    let roles = text(client, get(String::new())?).await?;
+    let role = roles.lines().next().ok_or("no instance role")?;
+    let credentials: ImdsCredentials =
+        serde_json::from_str(&text(client, get(role.to_owned())?).await?)?;
+    Ok(Credentials {
+        access_key_id: credentials.access_key_id,
+        secret_access_key: credentials.secret_access_key,
+        session_token: Some(credentials.token),
+    })
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters, and `/` if asked.
+fn uri_encode(s: &[u8], keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &byte in s {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn canonical_query(query: Option<&str>) -> String {
+    let mut params: Vec<(String, String)> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (
+                uri_encode(&percent_decode(key), false),
+                uri_encode(&percent_decode(value), false),
+            )
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+struct Signer {
+    region: String,
+    service: String,
+}
+
+impl Signer {
+    /// Add the signature headers to `req`, signed at `now`.
+    fn sign(
+        &self,
+        req: &mut Request<ByteBody>,
+        credentials: &Credentials,
+        now: OffsetDateTime,
+    ) -> Result<(), BoxError> {
+        let date = format!(
+            "{:04}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day()
+        );
+        let timestamp = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let host = req.uri().authority().ok_or("no upstream host")?.to_string();
+        // S3 wants the payload hash as a header and paths encoded once
+        let s3 = self.service == "s3";
+        // only the start of a streamed body is at hand to hash
+        let payload = match (req.body().is_streamed(), s3) {
+            (false, _) => sha256_hex(req.body().as_bytes()),
+            (true, true) => UNSIGNED_PAYLOAD.to_owned(),
+            (true, false) => return Err("request body too large to sign".into()),
+        };
+        let mut headers = vec![("host", host.clone()), (X_AMZ_DATE, timestamp.clone())];
+        if s3 {
+            headers.push((X_AMZ_CONTENT_SHA256, payload.clone()));
+        }
+        if let Some(token) = &credentials.session_token {
+            headers.push((X_AMZ_SECURITY_TOKEN, token.clone()));
+        }
+        headers.sort();
+
+        let path = if s3 {
+            uri_encode(&percent_decode(req.uri().path()), true)
+        } else {
+            uri_encode(req.uri().path().as_bytes(), true)
+        };
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            req.method(),
+            path,
+            canonical_query(req.uri().query()),
+            headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+                .collect::<String>(),
+            signed_headers,
+            payload,
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key = [
+            date.as_str(),
+            self.region.as_str(),
+            self.service.as_str(),
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+            |key, part| hmac(&key, part),
+        );
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        );
+        let req_headers = req.headers_mut();
+        req_headers.insert(HOST, HeaderValue::from_str(&host)?);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            req_headers.insert(name, HeaderValue::from_str(&value)?);
+        }
+        req_headers.insert(AUTHORIZATION, HeaderValue::from_str(&authorization)?);
+        Ok(())
+    }
+}
+
+/// Signs the proxy's own calls to an AWS service, such as KMS at startup.
+pub struct AwsSigner {
+    signer: Signer,
+    credentials: CredentialSource,
+}
+
+impl AwsSigner {
+    /// Sign with the credentials from the environment, or the instance
+    /// metadata service when unset.
+    pub fn new(region: String, service: String) -> Self {
+        Self {
+            signer: Signer { region, service },
+            credentials: CredentialSource::ambient(),
+        }
+    }
+
+    pub async fn sign(&self, req: &mut Request<ByteBody>) -> Result<(), BoxError> {
+        let credentials = self.credentials.get().await?;
+        self.signer
+            .sign(req, &credentials, OffsetDateTime::now_utc())
+    }
+}
+
+struct Signers {
+    rules: Vec<(Vec<String>, Signer)>,
+    credentials: CredentialSource,
+}
+
+impl Signers {
+    fn find(&self, host: &str) -> Option<&Signer> {
+        self.rules
+            .iter()
+            .find(|(hosts, _)| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|(_, signer)| signer)
+    }
+}
+
+#[derive(Clone)]
+pub struct SigV4Layer {
+    signers: Arc<Signers>,
+}
+
+impl SigV4Layer {
+    /// Sign with the credentials from the environment, or the instance
+    /// metadata service when unset.
+    pub fn new(rules: Vec<SigV4Rule>) -> Self {
+        Self::with_credentials(rules, CredentialSource::ambient())
+    }
+
+    fn with_credentials(rules: Vec<SigV4Rule>, credentials: CredentialSource) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let signer = Signer {
+                    region: rule.region,
+                    service: rule.service,
+                };
+                (rule.hosts, signer)
+            })
+            .collect();
+        Self {
+            signers: Arc::new(Signers { rules, credentials }),
+        }
+    }
+}
+
+impl<S> Layer<S> for SigV4Layer {
+    type Service = SigV4<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SigV4 {
+            inner: service,
+            signers: self.signers.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SigV4<S> {
+    inner: S,
+    signers: Arc<Signers>,
+}
+
+impl<S> Service<Request<ByteBody>> for SigV4<S>
+where
+    S: Service<Request<ByteBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let signers = self.signers.clone();
+        Box::pin(async move {
+            let host = req.uri().host().unwrap_or_default().to_owned();
+            if let Some(signer) = signers.find(&host) {
+                let credentials = signers.credentials.get().await?;
+                // drop whatever the client or earlier layers meant for Balena
+                req.headers_mut().remove(AUTHORIZATION);
+                signer.sign(&mut req, &credentials, OffsetDateTime::now_utc())?;
+            }
+            inner.call(req).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_time() -> OffsetDateTime {
+        // 2015-08-30T12:36:00Z
+        OffsetDateTime::from_unix_timestamp(1_440_938_160).unwrap()
+    }
+
+    fn example() -> (Signer, Credentials) {
+        (
+            Signer {
+                region: "us-east-1".to_owned(),
+                service: "service".to_owned(),
+            },
+            Credentials {
+                access_key_id: "AKIDEXAMPLE".to_owned(),
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+                session_token: None,
+            },
+        )
+    }
+
+    // from the AWS SigV4 test suite
+    #[test]
+    fn test_get_vanilla_query_order() -> Result<(), BoxError> {
+        let (signer, credentials) = example();
+        let mut req = Request::get("https://example.amazonaws.com/?Param2=value2&Param1=value1")
+            .body(ByteBody::new(Vec::new()))?;
+        signer.sign(&mut req, &credentials, example_time())?;
+        assert_eq!(req.headers()[X_AMZ_DATE], "20150830T123600Z");
+        assert_eq!(
+            req.headers()[AUTHORIZATION],
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_s3_payload_header() -> Result<(), BoxError> {
+        let (mut signer, credentials) = example();
+        signer.service = "s3".to_owned();
+        let mut req = Request::put("https://bucket.s3.amazonaws.com/logs/a%20b.txt")
+            .body(ByteBody::new(b"hello".to_vec()))?;
+        signer.sign(&mut req, &credentials, example_time())?;
+        assert_eq!(
+            req.headers()[X_AMZ_CONTENT_SHA256],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        let authorization = req.headers()[AUTHORIZATION].to_str()?;
+        assert!(authorization.contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date,"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sign_streamed() -> Result<(), BoxError> {
+        use crate::read_request_body::ReadRequestLayer;
+        use tower::ServiceExt;
+
+        let sign = |service: &'static str| {
+            ReadRequestLayer::new()
+                .max_buffered(Some(4))
+                .layer(tower::service_fn(
+                    move |mut req: Request<ByteBody>| async move {
+                        let (mut signer, credentials) = example();
+                        signer.service = service.to_owned();
+                        signer.sign(&mut req, &credentials, example_time())?;
+                        Ok::<_, BoxError>(req.headers().get(X_AMZ_CONTENT_SHA256).cloned())
+                    },
+                ))
+        };
+        let req = || {
+            Request::put("https://bucket.s3.amazonaws.com/logs/a.txt")
+                .body(Body::from("hello world"))
+                .unwrap()
+        };
+        let payload = sign("s3").oneshot(req()).await?;
+        assert_eq!(payload.unwrap(), UNSIGNED_PAYLOAD);
+        assert!(sign("execute-api").oneshot(req()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_query() {
+        assert_eq!(canonical_query(Some("b=2&a=%7e x&c")), "a=~%20x&b=2&c=");
+        assert_eq!(canonical_query(None), "");
+    }
+}
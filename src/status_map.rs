@@ -0,0 +1,185 @@
+//! Upstream status mapping.
+//!
+//! [`StatusMapLayer`] rewrites upstream statuses the client should not act
+//! on as they are. An upstream 401 means our API key is wrong, not the
+//! client's credentials, and is better surfaced as a 502; an upstream 429
+//! throttles the proxy as a whole and is better surfaced as a 503 with our
+//! own `Retry-After`.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use http::{header::RETRY_AFTER, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::route::RouteMatcher;
+
+/// The status the upstream answered, as a response extension of responses
+/// whose status was rewritten.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamStatus(pub StatusCode);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Upstream status.
+    pub from: u16,
+    /// Status returned to the client.
+    pub to: u16,
+    /// Replace the upstream `Retry-After` header.
+    pub retry_after_secs: Option<u64>,
+}
+
+struct Rule {
+    route: RouteMatcher,
+    from: StatusCode,
+    to: StatusCode,
+    retry_after: Option<HeaderValue>,
+}
+
+#[derive(Clone)]
+pub struct StatusMapLayer {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl StatusMapLayer {
+    /// Check the statuses of `rules`.
+    pub fn new(rules: Vec<StatusRule>) -> Result<Self, BoxError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let status = |code| {
+                    StatusCode::from_u16(code).map_err(|_| format!("invalid status {}", code))
+                };
+                Ok(Rule {
+                    from: status(rule.from)?,
+                    to: status(rule.to)?,
+                    retry_after: rule.retry_after_secs.map(HeaderValue::from),
+                    route: rule.route,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+}
+
+impl<S> Layer<S> for StatusMapLayer {
+    type Service = StatusMap<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        StatusMap {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusMap<S> {
+    inner: S,
+    rules: Arc<Vec<Rule>>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for StatusMap<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let rules = self.rules.clone();
+        let matching: Vec<usize> = (0..rules.len())
+            .filter(|&i| rules[i].route.matches(&req))
+            .collect();
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await.map_err(Into::into)?;
+            let rule = matching
+                .into_iter()
+                .map(|i| &rules[i])
+                .find(|rule| rule.from == res.status());
+            if let Some(rule) = rule {
+                tracing::debug!(from = %rule.from, to = %rule.to, "mapping upstream status");
+                res.extensions_mut().insert(UpstreamStatus(rule.from));
+                *res.status_mut() = rule.to;
+                if let Some(retry_after) = &rule.retry_after {
+                    res.headers_mut().insert(RETRY_AFTER, retry_after.clone());
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_map_status() -> Result<(), BoxError> {
+        let fixtures: Vec<Fixture> = serde_json::from_value(serde_json::json!([
+            { "route": { "path": "/v6/busy" }, "status": 429,
+              "headers": { "retry-after": "3600" } },
+            { "route": { "path": "/v6/auth" }, "status": 401 },
+        ]))?;
+        let layer = StatusMapLayer::new(serde_json::from_value(serde_json::json!([
+            { "from": 429, "to": 503, "retry_after_secs": 5 },
+            { "route": { "path_prefix": "/v6/auth" }, "from": 401, "to": 502 },
+        ]))?)?;
+        let service = layer.layer(MockUpstream::new(fixtures));
+
+        let res = service
+            .clone()
+            .oneshot(Request::get("/v6/busy").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "5");
+
+        let res = service
+            .clone()
+            .oneshot(Request::get("/v6/auth").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        // statuses without a rule pass through
+        let res = service
+            .oneshot(Request::get("/v6/other").body(Body::empty())?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_status() {
+        let rules = vec![StatusRule {
+            route: RouteMatcher::default(),
+            from: 1000,
+            to: 502,
+            retry_after_secs: None,
+        }];
+        assert!(StatusMapLayer::new(rules).is_err());
+    }
+}
@@ -0,0 +1,697 @@
+//! Store and forward for writes on flaky connections.
+//!
+//! [`StoreForwardLayer`] sits above the timeout and retry layers. When a POST
+//! or PATCH on a configured route still fails once the in-memory retries are
+//! exhausted, or times out, the request is written to a [`DurableQueue`] on disk and the client gets a
+//! 202. A background task replays the queue in order once the upstream
+//! answers again, so device state reports survive outages and reboots, with
+//! the stack most recently built, reloads included. Requests the upstream
+//! still fails to take after `max_attempts` replays are logged, moved to the
+//! dead-letter file if any, and dropped, so they don't hold up those queued
+//! after them.
+//!
+//! Requests are replayed with a pool key. Those of callers with a key of
+//! their own are not queued, as their key would have to be kept on disk.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::future::BoxFuture;
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, Method, Request, Response,
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::{read_request_body::ByteBody, route::RouteMatcher, secret::SENSITIVE_HEADERS};
+
+/// Header carrying the queue id of a stored request.
+pub const X_PROXY_QUEUED: &str = "x-proxy-queued";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoreForwardConfig {
+    pub directory: PathBuf,
+    /// Routes whose failed writes are queued.
+    pub routes: Vec<RouteMatcher>,
+    /// Pause between attempts to drain the queue.
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval_secs: u64,
+    /// Requests beyond this are failed as usual instead of queued.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Replays of a request before it is dropped.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// NDJSON file receiving the requests dropped after `max_attempts`.
+    pub dead_letter: Option<PathBuf>,
+}
+
+fn default_retry_interval() -> u64 {
+    30
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+fn default_max_attempts() -> u32 {
+    100
+}
+
+/// A request waiting in the queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedRequest {
+    pub id: u64,
+    pub queued_at: String,
+    pub attempts: u32,
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// The body is base64, as it is not UTF-8.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+/// What the admin API shows of a queued request.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedSummary {
+    pub id: u64,
+    pub queued_at: String,
+    pub attempts: u32,
+    pub method: String,
+    pub uri: String,
+}
+
+impl QueuedRequest {
+    /// Snapshot a request. Credentials, the [`SENSITIVE_HEADERS`], are left
+    /// out: keys are assigned and requests signed again on replay.
+    pub fn new<B>(req: &Request<B>, body: &[u8]) -> Self {
+        let headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| !SENSITIVE_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let (body, base64) = match std::str::from_utf8(body) {
+            Ok(body) => (body.to_owned(), false),
+            Err(_) => (STANDARD.encode(body), true),
+        };
+        Self {
+            id: 0,
+            queued_at: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            attempts: 0,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers,
+            body,
+            base64,
+        }
+    }
+
+    pub fn to_request(&self) -> Result<Request<ByteBody>, BoxError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.append(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+        }
+        let body = if self.base64 {
+            STANDARD.decode(&self.body)?
+        } else {
+            self.body.clone().into_bytes()
+        };
+        let mut req = Request::builder()
+            .method(Method::from_str(&self.method)?)
+            .uri(self.uri.as_str())
+            .body(ByteBody::new(body))?;
+        *req.headers_mut() = headers;
+        Ok(req)
+    }
+
+    fn summary(&self) -> QueuedSummary {
+        QueuedSummary {
+            id: self.id,
+            queued_at: self.queued_at.clone(),
+            attempts: self.attempts,
+            method: self.method.clone(),
+            uri: self.uri.clone(),
+        }
+    }
+}
+
+struct QueueState {
+    next_id: u64,
+    entries: BTreeMap<u64, QueuedSummary>,
+}
+
+/// FIFO of requests persisted as one JSON file each in a directory.
+#[derive(Clone)]
+pub struct DurableQueue {
+    dir: Arc<PathBuf>,
+    max_entries: usize,
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl DurableQueue {
+    /// Open the queue, picking up the requests left by a previous run.
+    pub fn open(dir: &Path, max_entries: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut entries = BTreeMap::new();
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match fs::read(&path).map(|data| serde_json::from_slice::<QueuedRequest>(&data)) {
+                Ok(Ok(entry)) => {
+                    entries.insert(entry.id, entry.summary());
+                }
+                _ => tracing::warn!(path = %path.display(), "skipping unreadable queue entry"),
+            }
+        }
+        if !entries.is_empty() {
+            tracing::info!(entries = entries.len(), dir = %dir.display(), "loaded queue");
+        }
+        let next_id = entries.keys().next_back().map_or(1, |id| id + 1);
+        Ok(Self {
+            dir: Arc::new(dir.to_path_buf()),
+            max_entries,
+            state: Arc::new(Mutex::new(QueueState { next_id, entries })),
+        })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", id))
+    }
+
+    fn write(&self, entry: &QueuedRequest) -> io::Result<()> {
+        // write then rename, a crash never leaves a truncated entry behind
+        let path = self.path(entry.id);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(entry)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Append a request, returning its id.
+    pub fn push(&self, mut entry: QueuedRequest) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= self.max_entries {
+            return Err(io::Error::other("queue is full"));
+        }
+        entry.id = state.next_id;
+        self.write(&entry)?;
+        state.next_id += 1;
+        state.entries.insert(entry.id, entry.summary());
+        Ok(entry.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<QueuedSummary> {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// The oldest request in the queue.
+    pub fn front(&self) -> Option<QueuedRequest> {
+        let id = *self.state.lock().unwrap().entries.keys().next()?;
+        match fs::read(self.path(id)).map(|data| serde_json::from_slice(&data)) {
+            Ok(Ok(entry)) => Some(entry),
+            _ => {
+                tracing::warn!(id, "dropping unreadable queue entry");
+                self.remove(id);
+                self.front()
+            }
+        }
+    }
+
+    pub fn remove(&self, id: u64) -> bool {
+        let removed = self.state.lock().unwrap().entries.remove(&id).is_some();
+        if removed {
+            if let Err(err) = fs::remove_file(self.path(id)) {
+                tracing::warn!(id, %err, "failed to remove queue entry");
+            }
+        }
+        removed
+    }
+
+    /// Count a failed delivery attempt.
+    pub fn record_attempt(&self, mut entry: QueuedRequest) {
+        entry.attempts += 1;
+        let mut state = self.state.lock().unwrap();
+        if let Some(summary) = state.entries.get_mut(&entry.id) {
+            summary.attempts = entry.attempts;
+            if let Err(err) = self.write(&entry) {
+                tracing::warn!(id = entry.id, %err, "failed to update queue entry");
+            }
+        }
+    }
+}
+
+/// Whether the upstream is unavailable, rather than rejecting the request.
+fn is_unavailable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Append `entry` to the NDJSON file at `path`.
+pub fn dead_letter(path: &Path, entry: &QueuedRequest) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    serde_json::to_writer(&mut file, entry)?;
+    file.write_all(b"\n")
+}
+
+/// Run `f`, which does file I/O, on the blocking pool.
+async fn off_runtime<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// What [`drain`] does with requests that keep failing.
+#[derive(Clone)]
+struct Retries {
+    max_attempts: u32,
+    dead_letter: Option<Arc<PathBuf>>,
+}
+
+/// Replay queued requests in order, stopping at the first one the upstream
+/// still fails to take, unless that was its last attempt.
+async fn drain<S, ResBody>(queue: &DurableQueue, retries: &Retries, mut service: S)
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone,
+    S::Error: Into<BoxError>,
+{
+    loop {
+        let front = queue.clone();
+        let Some(entry) = off_runtime(move || front.front()).await else {
+            return;
+        };
+        let id = entry.id;
+        let req = match entry.to_request() {
+            Ok(req) => req,
+            Err(err) => {
+                tracing::error!(id, %err, "dropping invalid queue entry");
+                let queue = queue.clone();
+                off_runtime(move || queue.remove(id)).await;
+                continue;
+            }
+        };
+        let result: Result<_, BoxError> = match service.ready().await {
+            Ok(service) => service.call(req).await.map_err(Into::into),
+            Err(err) => Err(err.into()),
+        };
+        let failure = match result {
+            Ok(res) if !is_unavailable(res.status()) => {
+                if res.status().is_client_error() {
+                    tracing::error!(id, status = %res.status(), uri = entry.uri, "queued request rejected");
+                } else {
+                    tracing::info!(id, status = %res.status(), "queued request delivered");
+                }
+                let queue = queue.clone();
+                off_runtime(move || queue.remove(id)).await;
+                continue;
+            }
+            Ok(res) => res.status().to_string(),
+            Err(err) => err.to_string(),
+        };
+
+        if entry.attempts + 1 < retries.max_attempts {
+            tracing::debug!(id, %failure, "upstream still unavailable");
+            let queue = queue.clone();
+            off_runtime(move || queue.record_attempt(entry)).await;
+            return;
+        }
+        tracing::error!(id, uri = entry.uri, %failure, attempts = entry.attempts + 1, "queued request dead-lettered");
+        let queue = queue.clone();
+        let file = retries.dead_letter.clone();
+        off_runtime(move || {
+            if let Some(path) = file {
+                if let Err(err) = dead_letter(&path, &entry) {
+                    tracing::error!(path = %path.display(), %err, "failed to write dead letter");
+                }
+            }
+            queue.remove(id);
+        })
+        .await;
+    }
+}
+
+/// Replays the queue through the stack it was made with.
+type Replay = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct StoreForwardLayer {
+    queue: DurableQueue,
+    routes: Arc<Vec<RouteMatcher>>,
+    retry_interval: Duration,
+    retries: Retries,
+    worker_started: Arc<AtomicBool>,
+    // swapped for one with the new stack whenever the layer is applied
+    replay: Arc<Mutex<Option<Replay>>>,
+}
+
+impl StoreForwardLayer {
+    pub fn new(config: StoreForwardConfig) -> Result<Self, BoxError> {
+        let queue = DurableQueue::open(&config.directory, config.max_entries)
+            .map_err(|err| format!("{}: {}", config.directory.display(), err))?;
+        Ok(Self {
+            queue,
+            routes: Arc::new(config.routes),
+            retry_interval: Duration::from_secs(config.retry_interval_secs),
+            retries: Retries {
+                max_attempts: config.max_attempts.max(1),
+                dead_letter: config.dead_letter.map(Arc::new),
+            },
+            worker_started: Arc::new(AtomicBool::new(false)),
+            replay: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn queue(&self) -> DurableQueue {
+        self.queue.clone()
+    }
+
+    /// Replay the queue through the stack the layer was last applied to.
+    async fn drain(&self) {
+        let replay = self.replay.lock().unwrap().clone();
+        if let Some(replay) = replay {
+            replay().await;
+        }
+    }
+}
+
+impl<S, ResBody> Layer<S> for StoreForwardLayer
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError> + Send,
+    S::Future: Send,
+    ResBody: Send + 'static,
+{
+    type Service = StoreForward<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let queue = self.queue.clone();
+        let retries = self.retries.clone();
+        // shared with the worker task, so held where only `Send` is needed
+        let worker = Mutex::new(service.clone());
+        let replay: Replay = Arc::new(move || {
+            let (queue, retries) = (queue.clone(), retries.clone());
+            let worker = worker.lock().unwrap().clone();
+            Box::pin(async move { drain(&queue, &retries, worker).await })
+        });
+        *self.replay.lock().unwrap() = Some(replay);
+        // one worker drains the queue, however often the layer is applied
+        if !self.worker_started.swap(true, Ordering::SeqCst) {
+            let layer = self.clone();
+            let interval = self.retry_interval;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    layer.drain().await;
+                }
+            });
+        }
+        StoreForward {
+            inner: service,
+            queue: self.queue.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StoreForward<S> {
+    inner: S,
+    queue: DurableQueue,
+    routes: Arc<Vec<RouteMatcher>>,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for StoreForward<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        // streamed bodies are only read once, while forwarding them, and
+        // callers' own keys are not kept on disk
+        let entry = (matches!(*req.method(), Method::POST | Method::PATCH)
+            && !req.body().is_streamed()
+            && !req.headers().contains_key(AUTHORIZATION)
+            && self.routes.iter().any(|route| route.matches(&req)))
+        .then(|| QueuedRequest::new(&req, req.body().as_bytes()));
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Box::pin(async move { inner.call(req).await.map_err(Into::into) }),
+        };
+
+        let queue = self.queue.clone();
+        Box::pin(async move {
+            let result = inner.call(req).await.map_err(Into::into);
+            let failure = match &result {
+                Ok(res) if !is_unavailable(res.status()) => return result,
+                Ok(res) => res.status().to_string(),
+                Err(err) => err.to_string(),
+            };
+
+            // off the runtime, as outages fail many writes at once
+            let pushed = tokio::task::spawn_blocking(move || queue.push(entry))
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err)));
+            match pushed {
+                Ok(id) => {
+                    tracing::warn!(id, %failure, "write failed, queued for later delivery");
+                    let body = serde_json::json!({ "queued": id }).to_string();
+                    let mut res = Response::new(ResBody::from(Bytes::from(body)));
+                    *res.status_mut() = StatusCode::ACCEPTED;
+                    res.headers_mut()
+                        .insert(X_PROXY_QUEUED, HeaderValue::from(id));
+                    Ok(res)
+                }
+                Err(err) => {
+                    tracing::error!(%err, "failed to queue request");
+                    result
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use hyper::Client;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("proxy-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_queue_and_replay() -> Result<(), BoxError> {
+        let dir = temp_dir("store-forward");
+        let server = MockServer::start();
+        let mut down = server.mock(|when, then| {
+            when.method(httpmock::Method::PATCH).path("/v6/device");
+            then.status(503);
+        });
+
+        let layer = StoreForwardLayer::new(serde_json::from_value(serde_json::json!({
+            "directory": dir,
+            "routes": [{ "path_prefix": "/v6/device" }],
+            "retry_interval_secs": 3600,
+        }))?)?;
+        let upstream = Client::builder().build_http::<ByteBody>();
+        let client = layer.layer(upstream.clone());
+
+        let req = Request::patch(format!("http://{}/v6/device", server.address()))
+            .body(ByteBody::new(br#"{"status":"Idle"}"#.to_vec()))?;
+        let res = client.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers()[X_PROXY_QUEUED], "1");
+
+        // callers with their own key get the failure
+        let req = Request::patch(format!("http://{}/v6/device", server.address()))
+            .header(AUTHORIZATION, "Bearer key")
+            .body(ByteBody::new(br#"{"status":"Idle"}"#.to_vec()))?;
+        let res = client.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // the queue survives a restart
+        let queue = DurableQueue::open(&dir, 10)?;
+        let retries = Retries {
+            max_attempts: 10,
+            dead_letter: None,
+        };
+        assert_eq!(queue.len(), 1);
+
+        drain(&queue, &retries, upstream.clone()).await;
+        assert_eq!(queue.list()[0].attempts, 1);
+
+        down.delete();
+        let up = server.mock(|when, then| {
+            when.method(httpmock::Method::PATCH)
+                .path("/v6/device")
+                .body(r#"{"status":"Idle"}"#);
+            then.status(200);
+        });
+        drain(&queue, &retries, upstream.clone()).await;
+        up.assert();
+        assert_eq!(queue.len(), 0);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_entry_dead_lettered() -> Result<(), BoxError> {
+        let dir = temp_dir("store-forward-poison");
+        let dead = dir.join("dead.ndjson");
+        let queue = DurableQueue::open(&dir, 10)?;
+        for path in ["/v6/device/poison", "/v6/device"] {
+            let req = Request::post(path).body(())?;
+            queue.push(QueuedRequest::new(&req, b"{}"))?;
+        }
+        let retries = Retries {
+            max_attempts: 2,
+            dead_letter: Some(Arc::new(dead.clone())),
+        };
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let upstream = tower::service_fn({
+            let delivered = delivered.clone();
+            move |req: Request<ByteBody>| {
+                let path = req.uri().path().to_owned();
+                let status = if path.ends_with("poison") { 500 } else { 200 };
+                if status == 200 {
+                    delivered.lock().unwrap().push(path);
+                }
+                async move {
+                    Ok::<_, BoxError>(
+                        Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())?,
+                    )
+                }
+            }
+        });
+
+        // held up by the poisoned entry until its last attempt
+        drain(&queue, &retries, upstream.clone()).await;
+        assert_eq!(queue.len(), 2);
+        assert!(delivered.lock().unwrap().is_empty());
+        drain(&queue, &retries, upstream).await;
+        assert_eq!(queue.len(), 0);
+        assert_eq!(*delivered.lock().unwrap(), ["/v6/device"]);
+        let dead = fs::read_to_string(&dead)?;
+        assert_eq!(dead.lines().count(), 1);
+        assert!(dead.contains("/v6/device/poison"));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_left_out() -> Result<(), BoxError> {
+        let req = Request::post("/v6/device")
+            .header("cookie", "session=1")
+            .header("x-api-key", "key")
+            .header("x-signature", "sig")
+            .header("content-type", "application/json")
+            .body(())?;
+        let entry = QueuedRequest::new(&req, b"{}");
+        let names: Vec<&str> = entry
+            .headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["content-type"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_body() -> Result<(), BoxError> {
+        let body = [0xff, 0x00, 0xfe];
+        let req = Request::post("/v6/image").body(())?;
+        let entry = QueuedRequest::new(&req, &body);
+        assert!(entry.base64);
+        assert_eq!(entry.to_request()?.body().as_bytes(), body);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_through_latest_stack() -> Result<(), BoxError> {
+        let dir = temp_dir("store-forward-reload");
+        let layer = StoreForwardLayer::new(serde_json::from_value(serde_json::json!({
+            "directory": dir,
+            "routes": [{ "path_prefix": "/v6/device" }],
+            "retry_interval_secs": 3600,
+        }))?)?;
+        let stack = |name: &'static str| {
+            tower::service_fn(move |_: Request<ByteBody>| async move {
+                let status = if name == "old" { 503 } else { 200 };
+                Ok::<_, BoxError>(
+                    Response::builder()
+                        .status(status)
+                        .body(hyper::Body::empty())?,
+                )
+            })
+        };
+        let client = layer.layer(stack("old"));
+        let req = Request::post("/v6/device").body(ByteBody::new(b"{}".to_vec()))?;
+        assert_eq!(client.oneshot(req).await?.status(), StatusCode::ACCEPTED);
+
+        // a reload applies the layer to the new stack
+        let _ = layer.layer(stack("new"));
+        layer.drain().await;
+        assert_eq!(layer.queue().len(), 0);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Summaries of huge OData collections.
+//!
+//! A device asking for every device of a large fleet gets megabytes of JSON
+//! it may not have the memory to parse. [`SummarizeLayer`] reads successful
+//! GET responses of the routes configured and, when one is larger than
+//! `max_bytes`, passes on the first `items` records of its `{"d": [...]}`
+//! collection only, with `__count`, how many records it had, and `__next`,
+//! the request to make through the proxy for the following ones: the same
+//! query with `$skip` moved past them and `$top` of `items`, so pages after
+//! the first stay small. Responses under the threshold, and bodies other
+//! than collections, are passed on as they came.
+
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri,
+};
+use http_body::Body;
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    gzip,
+    metrics::Metric,
+    range,
+    read_request_body::{buffer, FromBuffered},
+    route::RouteMatcher,
+    sanitize::percent_decode,
+};
+
+const SUMMARIZED: Metric = Metric::counter(
+    "proxy_summarized_responses_total",
+    "Collection responses cut down to a summary for being too large.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SummarizeRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    /// Responses larger than this are summarized, measured decoded.
+    pub max_bytes: u64,
+    /// Records kept in a summary, and asked for by its `__next` link.
+    #[serde(default = "default_items")]
+    pub items: usize,
+}
+
+fn default_items() -> usize {
+    100
+}
+
+/// The value of the query parameter `name` of `uri`, decoded.
+fn param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        (percent_decode(key) == name.as_bytes())
+            .then(|| String::from_utf8_lossy(&percent_decode(value)).into_owned())
+    })
+}
+
+/// The request for the `items` records after the first `items` answered to
+/// `uri`, or `None` when its `$top` asked for no more.
+fn next_link(uri: &Uri, items: usize) -> Option<String> {
+    let number = |name| param(uri, name).and_then(|value| value.trim().parse::<usize>().ok());
+    let skip = number("$skip").unwrap_or(0) + items;
+    let top = match number("$top") {
+        Some(top) if top <= items => return None,
+        Some(top) => (top - items).min(items),
+        None => items,
+    };
+    let mut query: Vec<Cow<str>> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let key = param.split_once('=').map_or(*param, |(key, _)| key);
+            !param.is_empty() && !matches!(&percent_decode(key)[..], b"$skip" | b"$top")
+        })
+        .map(Cow::Borrowed)
+        .collect();
+    query.push(format!("$skip={}", skip).into());
+    query.push(format!("$top={}", top).into());
+    Some(format!("{}?{}", uri.path(), query.join("&")))
+}
+
+/// `doc` cut down to its first `items` records, if it is a collection of
+/// more.
+fn summarize(doc: &mut Value, uri: &Uri, items: usize) -> bool {
+    let Some(Value::Array(records)) = doc.get_mut("d") else {
+        return false;
+    };
+    let count = records.len();
+    if count <= items {
+        return false;
+    }
+    records.truncate(items);
+    let object = doc.as_object_mut().expect("has a `d`");
+    object.insert("__count".to_owned(), count.into());
+    object.insert("__next".to_owned(), next_link(uri, items).into());
+    true
+}
+
+/// `bytes` summarized for `uri`, if they are a collection larger than
+/// `rule` allows.
+fn summarize_body(
+    rule: &SummarizeRule,
+    uri: &Uri,
+    headers: &mut HeaderMap,
+    bytes: &[u8],
+) -> Option<Bytes> {
+    let decoded = gzip::inspect(headers, bytes);
+    if decoded.len() as u64 <= rule.max_bytes {
+        return None;
+    }
+    let mut doc: Value = serde_json::from_slice(&decoded).ok()?;
+    if !summarize(&mut doc, uri, rule.items) {
+        return None;
+    }
+    let mut body = serde_json::to_vec(&doc).expect("serializable value");
+    if gzip::is_gzip(headers) {
+        body = gzip::encode(&body);
+    }
+    if headers.contains_key(CONTENT_LENGTH) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Some(Bytes::from(body))
+}
+
+#[derive(Debug, Clone)]
+pub struct SummarizeLayer {
+    rules: Vec<SummarizeRule>,
+}
+
+impl SummarizeLayer {
+    /// The first rule matching a request applies.
+    pub fn new(rules: Vec<SummarizeRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl<S> Layer<S> for SummarizeLayer {
+    type Service = Summarize<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Summarize {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Summarize<S> {
+    inner: S,
+    rules: Vec<SummarizeRule>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Summarize<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| req.method() == Method::GET && rule.route.matches(&req))
+            .cloned();
+        let uri = req.uri().clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            let Some(rule) = rule else {
+                return Ok(res);
+            };
+            let length = res
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            // small enough as announced, or not ours to rewrite
+            if res.status() != StatusCode::OK
+                || range::is_partial(&res)
+                || length.is_some_and(|length| length <= rule.max_bytes)
+                || (res.headers().contains_key(CONTENT_ENCODING) && !gzip::is_gzip(res.headers()))
+            {
+                return Ok(res);
+            }
+            let (mut parts, body) = res.into_parts();
+            let (bytes, trailers) = buffer(body).await?;
+            let bytes = match summarize_body(&rule, &uri, &mut parts.headers, &bytes) {
+                Some(summary) => {
+                    SUMMARIZED.increment(&[]);
+                    tracing::debug!(path = uri.path(), "response summarized");
+                    summary
+                }
+                None => bytes,
+            };
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_next_link() {
+        let uri = Uri::from_static("/v6/device?$select=id&$skip=10");
+        assert_eq!(
+            next_link(&uri, 5).as_deref(),
+            Some("/v6/device?$select=id&$skip=15&$top=5")
+        );
+        let uri = Uri::from_static("/v6/device?%24top=12&$filter=a%20eq%201");
+        assert_eq!(
+            next_link(&uri, 5).as_deref(),
+            Some("/v6/device?$filter=a%20eq%201&$skip=5&$top=5")
+        );
+        let uri = Uri::from_static("/v6/device?$top=7");
+        assert_eq!(
+            next_link(&uri, 5).as_deref(),
+            Some("/v6/device?$skip=5&$top=2")
+        );
+        assert_eq!(next_link(&Uri::from_static("/v6/device?$top=5"), 5), None);
+    }
+
+    #[tokio::test]
+    async fn test_summarize() -> Result<(), BoxError> {
+        let records: Vec<Value> = (0..50)
+            .map(|id| serde_json::json!({ "id": id, "device_name": "pi" }))
+            .collect();
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/v6/device" },
+            "body": { "d": records },
+        }))?;
+        let rules: Vec<SummarizeRule> = serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/v6/device" }, "max_bytes": 512, "items": 3 },
+        ]))?;
+        let service = SummarizeLayer::new(rules).layer(MockUpstream::new(vec![fixture]));
+        let get = |uri: &str| {
+            let req = Request::get(uri).body(hyper::Body::empty()).unwrap();
+            service.clone().oneshot(req)
+        };
+
+        let res = get("/v6/device?$select=id").await?;
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["d"].as_array().unwrap().len(), 3);
+        assert_eq!(body["__count"], 50);
+        assert_eq!(body["__next"], "/v6/device?$select=id&$skip=3&$top=3");
+
+        // small enough
+        let rules = vec![SummarizeRule {
+            route: RouteMatcher::default(),
+            max_bytes: 1 << 20,
+            items: 3,
+        }];
+        let res = SummarizeLayer::new(rules)
+            .layer(MockUpstream::new(vec![serde_json::from_value(
+                serde_json::json!({ "body": { "d": [1, 2, 3, 4] } }),
+            )?]))
+            .oneshot(Request::get("/v6/device").body(hyper::Body::empty())?)
+            .await?;
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body, serde_json::json!({ "d": [1, 2, 3, 4] }));
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! Balena supervisor API proxy mode.
+//!
+//! [`SupervisorLayer`] gives apps on the device a second routing domain:
+//! requests under `path_prefix` are sent, without the prefix, to the local
+//! supervisor instead of the cloud API. They share the logging, access rules
+//! and transforms of the cloud requests but skip the cloud-only layers (key
+//! rotation, retries, store-and-forward), and carry the supervisor's own API
+//! key from `BALENA_SUPERVISOR_API_KEY` unless the client sent one.
+
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use http::{
+    header::{AUTHORIZATION, HOST},
+    HeaderValue, Request, Response, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::read_request_body::ByteBody;
+
+/// Environment variable holding the supervisor API key, set by balena in
+/// containers labelled `io.balena.features.supervisor-api`.
+pub const BALENA_SUPERVISOR_API_KEY: &str = "BALENA_SUPERVISOR_API_KEY";
+/// Environment variable holding the supervisor address, set alongside the key.
+pub const BALENA_SUPERVISOR_ADDRESS: &str = "BALENA_SUPERVISOR_ADDRESS";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorConfig {
+    /// Requests under this prefix go to the supervisor.
+    pub path_prefix: String,
+    /// Supervisor address, `BALENA_SUPERVISOR_ADDRESS` or
+    /// `http://127.0.0.1:48484` when unset.
+    pub address: Option<String>,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            path_prefix: "/supervisor".to_owned(),
+            address: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SupervisorLayer {
+    prefix: String,
+    // without a trailing slash, so request paths can be appended
+    address: String,
+    authorization: Option<HeaderValue>,
+    client: Client<HttpConnector, ByteBody>,
+}
+
+impl SupervisorLayer {
+    /// Check the supervisor address; requests are authorized with `api_key`
+    /// when given.
+    pub fn new(config: SupervisorConfig, api_key: Option<String>) -> Result<Self, BoxError> {
+        let address = config
+            .address
+            .or_else(|| std::env::var(BALENA_SUPERVISOR_ADDRESS).ok())
+            .unwrap_or_else(|| "http://127.0.0.1:48484".to_owned());
+        Uri::from_str(&address)
+            .map_err(|err| format!("supervisor address {}: {}", address, err))?;
+        let authorization = api_key
+            .filter(|key| !key.is_empty())
+            .map(|key| HeaderValue::from_str(&format!("Bearer {}", key)))
+            .transpose()
+            .map_err(|_| format!("invalid {}", BALENA_SUPERVISOR_API_KEY))?;
+        Ok(Self {
+            prefix: config.path_prefix.trim_end_matches('/').to_owned(),
+            address: address.trim_end_matches('/').to_owned(),
+            authorization,
+            client: Client::builder().build_http(),
+        })
+    }
+
+    /// The supervisor URI of a request path under the prefix.
+    fn target(&self, uri: &Uri) -> Option<Uri> {
+        let path = uri.path().strip_prefix(self.prefix.as_str())?;
+        if !(path.is_empty() || path.starts_with('/')) {
+            return None;
+        }
+        let path = if path.is_empty() { "/" } else { path };
+        let target = match uri.query() {
+            Some(query) => format!("{}{}?{}", self.address, path, query),
+            None => format!("{}{}", self.address, path),
+        };
+        Uri::from_str(&target).ok()
+    }
+}
+
+impl<S> Layer<S> for SupervisorLayer {
+    type Service = Supervisor<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Supervisor {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Supervisor<S> {
+    inner: S,
+    layer: SupervisorLayer,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Supervisor<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Body> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        let Some(target) = self.layer.target(req.uri()) else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        tracing::debug!(%target, "forwarding to the supervisor");
+        *req.uri_mut() = target;
+        req.headers_mut().remove(HOST);
+        if let Some(authorization) = &self.layer.authorization {
+            if !req.headers().contains_key(AUTHORIZATION) {
+                req.headers_mut()
+                    .insert(AUTHORIZATION, authorization.clone());
+            }
+        }
+        let fut = self.layer.client.request(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res.map(ResBody::from))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::MockUpstream;
+    use http::StatusCode;
+    use httpmock::prelude::*;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_supervisor_routing() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        let ping = server.mock(|when, then| {
+            when.method(GET)
+                .path("/v1/device")
+                .query_param("x", "1")
+                .header("authorization", "Bearer local-key");
+            then.status(200).body("supervisor");
+        });
+
+        let layer = SupervisorLayer::new(
+            SupervisorConfig {
+                address: Some(format!("http://{}/", server.address())),
+                ..Default::default()
+            },
+            Some("local-key".to_owned()),
+        )?;
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+
+        let req = Request::get("/supervisor/v1/device?x=1").body(ByteBody::new(Vec::new()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(res.into_body()).await?, "supervisor");
+        ping.assert();
+
+        // everything else goes to the cloud stack
+        for path in ["/v6/device", "/supervisorx/v1/device"] {
+            let req = Request::get(path).body(ByteBody::new(Vec::new()))?;
+            let res = service.clone().oneshot(req).await?;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! The whole proxy in-process, for integration tests.
+//!
+//! [`TestProxy`] builds the stack from a [`Config`] the way `main` does, with
+//! the upstream pointed at a local server such as an `httpmock` one, and
+//! takes requests without a listener. Tests assert retry counts and header
+//! rewrites with the upstream's mocks, matching on the attempt header the
+//! retry policy sets and on [`bearer`] keys, and key rotation with the pool
+//! helpers. Built for the crate's tests, and with the `testkit` feature.
+
+use bytes::Bytes;
+use http::{Request, Response};
+use hyper::Body;
+use tower::{BoxError, ServiceExt};
+
+#[cfg(feature = "auth")]
+use crate::{auth::KeyPool, secret::ApiKey};
+use crate::{build_service, cli::Args, config::Config, Durable, ProxyService};
+
+/// `Authorization` sent upstream with a key of the pool.
+pub fn bearer(key: &str) -> String {
+    format!("Bearer {}", key)
+}
+
+pub struct TestProxy {
+    service: ProxyService,
+    #[cfg(feature = "auth")]
+    keys: KeyPool,
+}
+
+impl TestProxy {
+    /// The stack of `config`, forwarding to `upstream`, e.g.
+    /// `server.url("/v6")`, with `keys` in the pool.
+    pub fn new(mut config: Config, upstream: &str, keys: &[&str]) -> Result<Self, BoxError> {
+        config.upstream = Some(upstream.to_owned());
+        #[cfg(feature = "auth")]
+        let pool = KeyPool::from(keys.to_vec()).with_config(&config.key_pool);
+        #[cfg(not(feature = "auth"))]
+        let _ = keys;
+        let durable = Durable::new(
+            &config,
+            #[cfg(feature = "auth")]
+            pool.clone(),
+        )?;
+        let args = Args {
+            mock_upstream: false,
+            dry_run: false,
+            command: None,
+        };
+        Ok(TestProxy {
+            service: build_service(&args, &config, &durable)?,
+            #[cfg(feature = "auth")]
+            keys: pool,
+        })
+    }
+
+    /// The stack, e.g. to serve it on a listener.
+    pub fn service(&self) -> ProxyService {
+        self.service.clone()
+    }
+
+    /// Send `req` through the stack, reading the whole response.
+    pub async fn send(&self, req: Request<Body>) -> Result<Response<Bytes>, BoxError> {
+        let res = self.service.clone().oneshot(req).await?;
+        let (parts, body) = res.into_parts();
+        Ok(Response::from_parts(
+            parts,
+            hyper::body::to_bytes(body).await?,
+        ))
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Response<Bytes>, BoxError> {
+        self.send(Request::get(path).body(Body::empty())?).await
+    }
+
+    #[cfg(feature = "auth")]
+    pub fn keys(&self) -> &KeyPool {
+        &self.keys
+    }
+
+    /// The key the next pooled request goes out with.
+    #[cfg(feature = "auth")]
+    pub fn active_key(&self) -> Option<ApiKey> {
+        self.keys.active_key()
+    }
+
+    /// Requests sent upstream with `key`, retries included.
+    #[cfg(feature = "auth")]
+    pub fn key_requests(&self, key: &str) -> u64 {
+        self.keys.stats(key).map_or(0, |stats| stats.requests)
+    }
+}
+
+#[cfg(all(test, feature = "auth", feature = "retry"))]
+mod tests {
+    use super::*;
+    use crate::retry::{RetryConfig, X_PROXY_ATTEMPT};
+    use httpmock::prelude::*;
+
+    fn config() -> Config {
+        Config {
+            retry: RetryConfig {
+                attempts: 2,
+                min_backoff_ms: 1,
+                max_backoff_ms: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_and_retries() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        // the first key is rate limited, the retry goes out with the next
+        let limited = server.mock(|when, then| {
+            when.path("/v6/device").header("authorization", bearer("a"));
+            then.status(429).header("retry-after", "60");
+        });
+        let served = server.mock(|when, then| {
+            when.path("/v6/device")
+                .header("authorization", bearer("b"))
+                .header(X_PROXY_ATTEMPT, "2");
+            then.status(200).body("[]");
+        });
+        let proxy = TestProxy::new(config(), &server.url("/v6"), &["a", "b"])?;
+        assert_eq!(proxy.active_key().as_deref(), Some("a"));
+
+        let res = proxy.get("/device").await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "[]");
+        limited.assert_hits(1);
+        served.assert_hits(1);
+        assert_eq!(proxy.active_key().as_deref(), Some("b"));
+        assert_eq!((proxy.key_requests("a"), proxy.key_requests("b")), (1, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_header_rewrites() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        // the client's own key, under the header browsers leave alone
+        let mock = server.mock(|when, then| {
+            when.path("/v6/application")
+                .header("authorization", bearer("own"))
+                .matches(|req| {
+                    !req.headers
+                        .iter()
+                        .flatten()
+                        .any(|(name, _)| name.eq_ignore_ascii_case("x-balena-authorization"))
+                });
+            then.status(200);
+        });
+        let proxy = TestProxy::new(config(), &server.url("/v6"), &["a"])?;
+        let req = Request::get("/application")
+            .header("x-balena-authorization", bearer("own"))
+            .body(Body::empty())?;
+        assert_eq!(proxy.send(req).await?.status(), 200);
+        mock.assert();
+        assert_eq!(proxy.key_requests("a"), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redirects_elsewhere_get_no_pool_key() -> Result<(), BoxError> {
+        let other = MockServer::start();
+        let done = other.mock(|when, then| {
+            when.path("/done").matches(|req| {
+                !req.headers
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            });
+            then.status(200).body("done");
+        });
+        let server = MockServer::start();
+        let moved = server.mock(|when, then| {
+            when.path("/v6/device").header("authorization", bearer("a"));
+            then.status(302).header("location", other.url("/done"));
+        });
+        let mut config = config();
+        // the redirect is followed rather than retried
+        config.retry.attempts = 0;
+        config.redirects = serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/device" }, "same_host": false },
+        ]))?;
+        let proxy = TestProxy::new(config, &server.url("/v6"), &["a"])?;
+
+        let res = proxy.get("/device").await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "done");
+        moved.assert_hits(1);
+        done.assert_hits(1);
+        assert_eq!(proxy.key_requests("a"), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_writes_queued() -> Result<(), BoxError> {
+        // an upstream taking connections and never answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = format!("http://{}/v6", listener.local_addr()?);
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+        let dir = std::env::temp_dir().join(format!("proxy-timed-out-{}", std::process::id()));
+        let mut config = config();
+        config.timeout = serde_json::from_value(serde_json::json!({ "default_ms": 200 }))?;
+        config.store_forward = Some(serde_json::from_value(serde_json::json!({
+            "directory": dir,
+            "routes": [{ "path_prefix": "/v6/device" }],
+        }))?);
+        let proxy = TestProxy::new(config, &upstream, &["a"])?;
+
+        let req = Request::patch("/device(1)").body(Body::from(r#"{"status":"Idle"}"#))?;
+        let res = proxy.send(req).await?;
+        assert_eq!(res.status(), 202);
+        assert!(res
+            .headers()
+            .contains_key(crate::store_forward::X_PROXY_QUEUED));
+        let _ = std::fs::remove_dir_all(&dir);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routes_match_normalized_paths() -> Result<(), BoxError> {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/v6/device");
+            then.status(200).body(vec![b'x'; 10_000]);
+        });
+        let mut config = config();
+        config.throttle = serde_json::from_value(serde_json::json!({
+            "rules": [{
+                "route": { "path_prefix": "/device" },
+                "download_bytes_per_sec": 20_000,
+                "burst_bytes": 2_000,
+            }],
+        }))?;
+        let proxy = TestProxy::new(config, &server.url("/v6"), &["a"])?;
+        for path in ["//device", "/./device", "/x/../device"] {
+            let started = std::time::Instant::now();
+            let res = proxy.get(path).await?;
+            assert_eq!(res.body().len(), 10_000);
+            assert!(
+                started.elapsed() >= std::time::Duration::from_millis(300),
+                "{}",
+                path
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,521 @@
+//! Bandwidth throttling.
+//!
+//! [`ThrottleLayer`] applies token buckets, in bytes per second, per route or
+//! per caller. Downloads are paced by [`ThrottledBody`] as the response body
+//! streams to the client. Uploads are paced the same way as the request body
+//! streams to the upstream: the layer only picks the bucket, and
+//! [`ThrottleUpload`], wrapped around the upstream client, applies it.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_core::{ready, Future};
+use http::{HeaderMap, Request, Response};
+use http_body::Body;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use tokio::time::{Instant, Sleep};
+use tower::{BoxError, Layer, Service};
+
+use crate::{context::key_id, read_request_body::ByteBody, route::RouteMatcher};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleRule {
+    #[serde(default)]
+    pub route: RouteMatcher,
+    pub upload_bytes_per_sec: Option<u64>,
+    pub download_bytes_per_sec: Option<u64>,
+    /// Bytes that may pass at once after an idle period, one second worth
+    /// of traffic when unset.
+    pub burst_bytes: Option<u64>,
+    /// Give every caller a bucket of its own instead of sharing one per rule.
+    #[serde(default)]
+    pub per_caller: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// The first matching rule applies.
+    pub rules: Vec<ThrottleRule>,
+    /// Header identifying the caller for `per_caller` rules.
+    pub caller_header: String,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            caller_header: "x-balena-authorization".into(),
+        }
+    }
+}
+
+/// Token bucket that may go into debt: taking more than is available
+/// succeeds, and the caller waits for the returned duration.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            burst: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take `n` tokens, returning how long to wait before using them.
+    pub fn take(&mut self, n: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn max_chunk(&self) -> usize {
+        self.burst as usize
+    }
+}
+
+type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+pin_project! {
+    /// Body releasing its data no faster than its bucket allows.
+    pub struct ThrottledBody<B> {
+        #[pin]
+        inner: B,
+        bucket: Option<SharedBucket>,
+        // data read from `inner` but not yet released
+        pending: Option<Bytes>,
+        // data released once `sleep` completes
+        delayed: Option<Bytes>,
+        sleep: Option<Pin<Box<Sleep>>>,
+    }
+}
+
+impl<B> ThrottledBody<B> {
+    pub fn new(inner: B, bucket: Option<SharedBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            pending: None,
+            delayed: None,
+            sleep: None,
+        }
+    }
+}
+
+impl<B: From<Bytes>> From<Bytes> for ThrottledBody<B> {
+    fn from(data: Bytes) -> Self {
+        Self::new(B::from(data), None)
+    }
+}
+
+impl<B> Body for ThrottledBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        let bucket = match this.bucket {
+            Some(bucket) => bucket,
+            None => return this.inner.poll_data(cx),
+        };
+
+        if let Some(sleep) = this.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            *this.sleep = None;
+            return Poll::Ready(this.delayed.take().map(Ok));
+        }
+
+        let mut chunk = match this.pending.take() {
+            Some(chunk) => chunk,
+            None => match ready!(this.inner.as_mut().poll_data(cx)) {
+                Some(Ok(chunk)) => chunk,
+                other => return Poll::Ready(other),
+            },
+        };
+
+        let mut bucket = bucket.lock().unwrap();
+        // release big chunks in burst sized pieces to keep the pace smooth
+        if chunk.len() > bucket.max_chunk() {
+            *this.pending = Some(chunk.split_off(bucket.max_chunk()));
+        }
+        let wait = bucket.take(chunk.len() as u64);
+        drop(bucket);
+
+        if wait.is_zero() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        let mut sleep = Box::pin(tokio::time::sleep(wait));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Ok(chunk))),
+            Poll::Pending => {
+                *this.sleep = Some(sleep);
+                *this.delayed = Some(chunk);
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_none() && self.delayed.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Bucket pacing the upload of a request, picked by [`Throttle`].
+#[derive(Clone)]
+struct UploadBucket(SharedBucket);
+
+#[derive(Default)]
+struct Buckets {
+    upload: Option<SharedBucket>,
+    download: Option<SharedBucket>,
+    // the use of the throttles they were last picked for
+    used: AtomicU64,
+}
+
+struct Throttles {
+    config: ThrottleConfig,
+    // per rule, keyed by the caller's key id for `per_caller` rules, which
+    // keeps their credentials out of the map
+    buckets: Mutex<HashMap<(usize, String), Arc<Buckets>>>,
+    uses: AtomicU64,
+}
+
+/// Callers tracked before idle buckets are dropped, then the least recently
+/// used ones.
+const MAX_BUCKETS: usize = 1024;
+
+impl Throttles {
+    fn buckets<B>(&self, req: &Request<B>) -> Option<Arc<Buckets>> {
+        let (index, rule) = self
+            .config
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.route.matches(req))?;
+        let caller = if rule.per_caller {
+            req.headers()
+                .get(self.config.caller_header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(key_id)
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let key = (index, caller);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            buckets.retain(|_, b| Arc::strong_count(b) > 1);
+            if buckets.len() >= MAX_BUCKETS {
+                // requests holding the evicted bucket keep being paced by it
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.used.load(Ordering::Relaxed))
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+        let bucket = |rate: Option<u64>| {
+            rate.map(|rate| {
+                Arc::new(Mutex::new(TokenBucket::new(
+                    rate,
+                    rule.burst_bytes.unwrap_or(rate),
+                )))
+            })
+        };
+        let entry = buckets.entry(key).or_insert_with(|| {
+            Arc::new(Buckets {
+                upload: bucket(rule.upload_bytes_per_sec),
+                download: bucket(rule.download_bytes_per_sec),
+                used: AtomicU64::new(0),
+            })
+        });
+        let used = self.uses.fetch_add(1, Ordering::Relaxed);
+        entry.used.store(used, Ordering::Relaxed);
+        Some(entry.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct ThrottleLayer {
+    throttles: Arc<Throttles>,
+}
+
+impl ThrottleLayer {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            throttles: Arc::new(Throttles {
+                config,
+                buckets: Mutex::new(HashMap::new()),
+                uses: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for ThrottleLayer {
+    type Service = Throttle<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Throttle {
+            inner: service,
+            throttles: self.throttles.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Throttle<S> {
+    inner: S,
+    throttles: Arc<Throttles>,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Throttle<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    type Response = Response<ThrottledBody<ResBody>>;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        let clone = self.inner.clone();
+        // take the service that was ready
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let buckets = self.throttles.buckets(&req).unwrap_or_default();
+        if let Some(bucket) = &buckets.upload {
+            req.extensions_mut().insert(UploadBucket(bucket.clone()));
+        }
+
+        Box::pin(async move {
+            let res = inner.call(req).await.map_err(Into::into)?;
+            Ok(res.map(|body| ThrottledBody::new(body, buckets.download.clone())))
+        })
+    }
+}
+
+/// Sends request bodies to `inner` paced by the upload bucket [`Throttle`]
+/// picked for them, if any.
+#[derive(Clone)]
+pub struct ThrottleUpload<S> {
+    inner: S,
+}
+
+impl<S> ThrottleUpload<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, B> Service<Request<B>> for ThrottleUpload<S>
+where
+    S: Service<Request<ThrottledBody<B>>>,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let bucket = req
+            .extensions()
+            .get::<UploadBucket>()
+            .map(|UploadBucket(bucket)| bucket.clone());
+        self.inner
+            .call(req.map(|body| ThrottledBody::new(body, bucket)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000, 500);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        let wait = bucket.take(250);
+        assert!(wait > Duration::from_millis(240) && wait <= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn test_download_paced() -> Result<(), BoxError> {
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/image" },
+            "body": "x".repeat(3000),
+        }))?;
+        let layer = ThrottleLayer::new(serde_json::from_value(serde_json::json!({
+            "rules": [{ "route": { "path_prefix": "/image" }, "download_bytes_per_sec": 10000, "burst_bytes": 1000 }],
+        }))?);
+        let service = layer.layer(MockUpstream::new(vec![fixture]));
+
+        let start = tokio::time::Instant::now();
+        let req = Request::get("/image").body(ByteBody::new(Vec::new()))?;
+        let res = service.clone().oneshot(req).await?;
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(body.len(), 3000);
+        // the first 1000 bytes are the burst
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+
+        // unmatched routes are not throttled
+        let start = tokio::time::Instant::now();
+        let req = Request::get("/other").body(ByteBody::new(Vec::new()))?;
+        let res = service.oneshot(req).await?;
+        hyper::body::to_bytes(res.into_body()).await?;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_caller_buckets() -> Result<(), BoxError> {
+        let layer = ThrottleLayer::new(serde_json::from_value(serde_json::json!({
+            "rules": [{ "download_bytes_per_sec": 1000, "per_caller": true }],
+        }))?);
+        let throttles = &layer.throttles;
+        let request = |caller: &str| {
+            Request::get("/v6/device")
+                .header("x-balena-authorization", format!("Bearer {}", caller))
+                .body(())
+        };
+        let a = throttles.buckets(&request("a")?).expect("matched");
+        let b = throttles.buckets(&request("b")?).expect("matched");
+        assert!(!Arc::ptr_eq(&a, &b));
+        let again = throttles.buckets(&request("a")?).expect("matched");
+        assert!(Arc::ptr_eq(&a, &again));
+        // callers are told apart without keeping their credentials
+        let buckets = throttles.buckets.lock().unwrap();
+        assert!(buckets.keys().all(|(_, caller)| !caller.contains("Bearer")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_buckets_bounded() -> Result<(), BoxError> {
+        let layer = ThrottleLayer::new(serde_json::from_value(serde_json::json!({
+            "rules": [{ "download_bytes_per_sec": 1000, "per_caller": true }],
+        }))?);
+        let throttles = &layer.throttles;
+        let request = |caller: usize| {
+            Request::get("/v6/device")
+                .header("x-balena-authorization", format!("Bearer {}", caller))
+                .body(())
+        };
+        // every bucket stays in use
+        let first = throttles.buckets(&request(0)?).expect("matched");
+        let mut held = Vec::new();
+        for caller in 1..MAX_BUCKETS + 10 {
+            held.push(throttles.buckets(&request(caller)?).expect("matched"));
+        }
+        assert_eq!(throttles.buckets.lock().unwrap().len(), MAX_BUCKETS);
+        // the least recently used caller went first
+        let again = throttles.buckets(&request(0)?).expect("matched");
+        assert!(!Arc::ptr_eq(&first, &again));
+        let last = held.last().expect("held");
+        let again = throttles
+            .buckets(&request(MAX_BUCKETS + 9)?)
+            .expect("matched");
+        assert!(Arc::ptr_eq(last, &again));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_paced() -> Result<(), BoxError> {
+        let layer = ThrottleLayer::new(serde_json::from_value(serde_json::json!({
+            "rules": [{ "route": { "path_prefix": "/image" }, "upload_bytes_per_sec": 10000, "burst_bytes": 1000 }],
+        }))?);
+        let upstream = ThrottleUpload::new(tower::service_fn(
+            |req: Request<ThrottledBody<ByteBody>>| async move {
+                // when each chunk reaches the upstream
+                let start = tokio::time::Instant::now();
+                let mut body = Box::pin(req.into_body());
+                let mut arrivals = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    arrivals.push((chunk?.len(), start.elapsed()));
+                }
+                Ok::<_, BoxError>(Response::new(arrivals))
+            },
+        ));
+        let service = layer.layer(upstream);
+
+        let req = Request::put("/image").body(ByteBody::new(vec![b'x'; 3000]))?;
+        let arrivals = service.clone().oneshot(req).await?.into_body().inner;
+        let sizes: Vec<usize> = arrivals.iter().map(|(len, _)| *len).collect();
+        assert_eq!(sizes, [1000, 1000, 1000]);
+        // the first 1000 bytes are the burst, the rest follow at the rate
+        assert!(arrivals[0].1 < Duration::from_millis(50), "{:?}", arrivals);
+        assert!(arrivals[1].1 >= Duration::from_millis(90), "{:?}", arrivals);
+        assert!(
+            arrivals[2].1 >= Duration::from_millis(190),
+            "{:?}",
+            arrivals
+        );
+
+        // unmatched routes are not throttled
+        let req = Request::put("/other").body(ByteBody::new(vec![b'x'; 3000]))?;
+        let arrivals = service.oneshot(req).await?.into_body().inner;
+        assert_eq!(arrivals.len(), 1);
+        assert!(arrivals[0].1 < Duration::from_millis(50));
+        Ok(())
+    }
+}
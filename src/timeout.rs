@@ -0,0 +1,167 @@
+//! Per-request timeouts.
+//!
+//! [`TimeoutLayer`] bounds the time a request takes, retries included, and
+//! answers a 504 when it runs out. Callers may pick their own timeout with
+//! `x-proxy-timeout-ms`, up to `max_ms`, so interactive calls can fail fast
+//! while batch jobs wait longer. The header is not sent upstream.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderName, HeaderValue, Request, Response, StatusCode};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::context::ProxyContext;
+
+pub const X_PROXY_TIMEOUT_MS: HeaderName = HeaderName::from_static("x-proxy-timeout-ms");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Timeout of requests without `x-proxy-timeout-ms`, none when unset.
+    pub default_ms: Option<u64>,
+    /// Longest timeout callers may ask for.
+    pub max_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            default_ms: None,
+            max_ms: 300_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeoutLayer {
+    config: TimeoutConfig,
+}
+
+impl TimeoutLayer {
+    pub fn new(config: TimeoutConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Timeout {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    config: TimeoutConfig,
+}
+
+impl<S> Timeout<S> {
+    /// The timeout of a request asking for `requested`, in milliseconds.
+    fn timeout(&self, requested: Option<&HeaderValue>) -> Option<Duration> {
+        let requested = requested
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        requested
+            .or(self.config.default_ms)
+            .map(|ms| Duration::from_millis(ms.min(self.config.max_ms)))
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Timeout<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let requested = req.headers_mut().remove(X_PROXY_TIMEOUT_MS);
+        let timeout = self.timeout(requested.as_ref());
+        if let (Some(context), Some(timeout)) = (ProxyContext::of(&req), timeout) {
+            context.update(|annotations| annotations.deadline = Some(Instant::now() + timeout));
+        }
+        let fut = self.inner.call(req);
+        let Some(timeout) = timeout else {
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, fut).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => {
+                    tracing::warn!(timeout_ms = timeout.as_millis() as u64, "request timed out");
+                    let body = serde_json::json!({ "error": "request timed out" });
+                    let mut res = Response::new(ResBody::from(Bytes::from(body.to_string())));
+                    *res.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                    res.headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    Ok(res)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_timeout() -> Result<(), BoxError> {
+        let layer = TimeoutLayer::new(TimeoutConfig {
+            default_ms: Some(50),
+            max_ms: 5_000,
+        });
+        // the upstream takes 200ms, and never sees the header
+        let service = layer.layer(service_fn(|req: Request<Bytes>| async move {
+            assert!(!req.headers().contains_key(X_PROXY_TIMEOUT_MS));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, Infallible>(Response::new(Bytes::new()))
+        }));
+        let status = |timeout: Option<&'static str>| {
+            let mut req = Request::get("/v6/device");
+            if let Some(timeout) = timeout {
+                req = req.header(X_PROXY_TIMEOUT_MS, timeout);
+            }
+            let req = req.body(Bytes::new()).unwrap();
+            let service = service.clone();
+            async move { service.oneshot(req).await.map(|res| res.status()) }
+        };
+
+        assert_eq!(status(None).await?, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(status(Some("1000")).await?, StatusCode::OK);
+        assert_eq!(status(Some("10")).await?, StatusCode::GATEWAY_TIMEOUT);
+        // capped at the maximum, and invalid values use the default
+        assert_eq!(
+            layer
+                .layer(())
+                .timeout(Some(&HeaderValue::from_static("60000"))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(status(Some("soon")).await?, StatusCode::GATEWAY_TIMEOUT);
+        Ok(())
+    }
+}
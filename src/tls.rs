@@ -0,0 +1,125 @@
+//! TLS termination for the listener.
+//!
+//! The certificate comes either from PEM files or from an ACME directory
+//! (see [`crate::acme`]). ACME certificates are checked twice a day and
+//! renewed as they near expiry; new connections then use the new
+//! certificate while established ones keep theirs.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_native_tls::{
+    native_tls::{self, Identity},
+    TlsAcceptor, TlsStream,
+};
+use tower::BoxError;
+
+use crate::acme::{Acme, AcmeConfig, Certificate};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, unless `acme` provides it.
+    pub cert_path: Option<PathBuf>,
+    /// PEM (PKCS #8) private key of the certificate.
+    pub key_path: Option<PathBuf>,
+    /// Certificates obtained and renewed automatically.
+    pub acme: Option<AcmeConfig>,
+    /// Drop connections that take longer to complete the handshake.
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10_000
+}
+
+fn acceptor(certificate: &Certificate) -> Result<TlsAcceptor, BoxError> {
+    let identity = Identity::from_pkcs8(&certificate.chain, &certificate.key)?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+#[derive(Clone)]
+pub struct Acceptor {
+    current: Arc<RwLock<TlsAcceptor>>,
+    handshake_timeout: Duration,
+}
+
+impl Acceptor {
+    /// Load the certificate of `config`, ordering one first if need be.
+    pub async fn new(config: &TlsConfig) -> Result<Self, BoxError> {
+        let handshake_timeout = Duration::from_millis(config.handshake_timeout_ms);
+        let Some(acme) = config.acme.clone() else {
+            let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+                return Err("tls: cert_path and key_path, or acme, are required".into());
+            };
+            let certificate = Certificate {
+                chain: std::fs::read(cert_path)
+                    .map_err(|err| format!("{}: {}", cert_path.display(), err))?,
+                key: std::fs::read(key_path)
+                    .map_err(|err| format!("{}: {}", key_path.display(), err))?,
+            };
+            return Ok(Self {
+                current: Arc::new(RwLock::new(acceptor(&certificate)?)),
+                handshake_timeout,
+            });
+        };
+
+        let acme = Arc::new(Acme::new(acme)?);
+        tokio::spawn({
+            let acme = acme.clone();
+            async move {
+                if let Err(err) = acme.serve_challenges().await {
+                    tracing::error!(%err, "ACME challenge listener failed");
+                }
+            }
+        });
+        let certificate = match acme.cached() {
+            Some(certificate) if !acme.needs_renewal(Some(&certificate)) => certificate,
+            _ => acme.order().await?,
+        };
+        let acceptor = Self {
+            current: Arc::new(RwLock::new(acceptor(&certificate)?)),
+            handshake_timeout,
+        };
+        tokio::spawn(acceptor.clone().renew(acme, certificate));
+        Ok(acceptor)
+    }
+
+    async fn renew(self, acme: Arc<Acme>, mut certificate: Certificate) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(12 * 60 * 60)).await;
+            if !acme.needs_renewal(Some(&certificate)) {
+                continue;
+            }
+            let renewed = match acme.order().await {
+                Ok(renewed) => renewed,
+                Err(err) => {
+                    tracing::error!(%err, "ACME renewal failed");
+                    continue;
+                }
+            };
+            match acceptor(&renewed) {
+                Ok(acceptor) => {
+                    *self.current.write().unwrap() = acceptor;
+                    certificate = renewed;
+                }
+                Err(err) => tracing::error!(%err, "renewed certificate unusable"),
+            }
+        }
+    }
+
+    /// Complete the handshake of `stream`.
+    pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, BoxError> {
+        let acceptor = self.current.read().unwrap().clone();
+        tokio::time::timeout(self.handshake_timeout, acceptor.accept(stream))
+            .await
+            .map_err(|_| "TLS handshake timed out")?
+            .map_err(Into::into)
+    }
+}
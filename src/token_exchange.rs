@@ -0,0 +1,394 @@
+//! Short-lived session tokens in place of the pool keys.
+//!
+//! Every upstream request used to carry a long-lived key, valid until it is
+//! revoked by hand. With `token_exchange`, [`TokenExchangeLayer`] trades each
+//! pool key for a session token at the upstream's `path`, a JWT, and sends
+//! that instead. Tokens are kept per key and upstream host and exchanged
+//! again `refresh_before_secs` before the `exp` of their claims, or after
+//! `ttl_secs` for tokens without one, by one request at a time. A 401 to a
+//! request sent with a token drops it, and the request is sent again once
+//! with a fresh one, unless its body was streamed. A 401 to the exchange
+//! itself is answered as the upstream's, for the auth layer to remove the
+//! key; when the exchange fails otherwise, the request goes with the key as
+//! before. Keys of clients bringing their own are left alone. The layer sits
+//! below pacing and the shared limits, which count requests by key.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::AUTHORIZATION, HeaderValue, Request, Response, StatusCode, Uri};
+use http_body::Body as HttpBody;
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service, ServiceExt};
+use zeroize::Zeroizing;
+
+use crate::{
+    auth::KeyPool,
+    context::{key_id, ProxyContext},
+    metrics::Metric,
+    read_request_body::{ByteBody, StreamedBody},
+    secret::ApiKey,
+};
+
+const EXCHANGES: Metric = Metric::counter(
+    "proxy_token_exchanges_total",
+    "Pool keys exchanged for session tokens, by result.",
+);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenExchangeConfig {
+    /// Endpoint answering a key with a session token, on the upstream's host.
+    pub path: String,
+    /// Tokens are exchanged again this long before they expire.
+    pub refresh_before_secs: u64,
+    /// Lifetime of tokens without an `exp` claim.
+    pub ttl_secs: u64,
+}
+
+impl Default for TokenExchangeConfig {
+    fn default() -> Self {
+        Self {
+            path: "/user/v1/refresh-token".to_owned(),
+            refresh_before_secs: 300,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+struct Token {
+    bearer: HeaderValue,
+    refresh_at: Instant,
+}
+
+/// The token of one key on one host, exchanged by one request at a time.
+type Slot = Arc<tokio::sync::Mutex<Option<Token>>>;
+
+#[derive(Clone)]
+pub struct TokenExchangeLayer {
+    config: Arc<TokenExchangeConfig>,
+    keys: KeyPool,
+    /// Tokens by upstream origin and key id.
+    tokens: Arc<Mutex<HashMap<(String, String), Slot>>>,
+}
+
+impl TokenExchangeLayer {
+    pub fn new(config: TokenExchangeConfig, keys: KeyPool) -> Self {
+        Self {
+            config: Arc::new(config),
+            keys,
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn slot(&self, origin: &str, key: &str) -> Slot {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens
+            .entry((origin.to_owned(), key_id(key)))
+            .or_default()
+            .clone()
+    }
+
+    /// When a token issued now with `jwt` is exchanged again.
+    fn refresh_at(&self, jwt: &str) -> Instant {
+        let now = Instant::now();
+        let ttl = expiry(jwt)
+            .map(|exp| exp.duration_since(SystemTime::now()).unwrap_or_default())
+            .unwrap_or(Duration::from_secs(self.config.ttl_secs));
+        now + ttl.saturating_sub(Duration::from_secs(self.config.refresh_before_secs))
+    }
+}
+
+/// When `jwt` expires, from its `exp` claim.
+fn expiry(jwt: &str) -> Option<SystemTime> {
+    let claims = jwt.split('.').nth(1)?;
+    let claims = URL_SAFE_NO_PAD.decode(claims.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&claims).ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+impl<S> Layer<S> for TokenExchangeLayer {
+    type Service = TokenExchange<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TokenExchange {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TokenExchange<S> {
+    inner: S,
+    layer: TokenExchangeLayer,
+}
+
+/// What came of an exchange.
+enum Exchanged<B> {
+    Token(HeaderValue),
+    /// The upstream refused the key.
+    Rejected(Response<B>),
+    /// No token, the key is sent instead.
+    Failed,
+}
+
+/// The pool key `req` is sent with, if any.
+fn pool_key<B>(keys: &KeyPool, req: &Request<B>) -> Option<ApiKey> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let key = value.strip_prefix("Bearer ")?.trim();
+    keys.keys().into_iter().find(|k| k == key)
+}
+
+/// Trade `key` for a token at `origin`.
+async fn exchange<S, B>(
+    config: &TokenExchangeConfig,
+    inner: S,
+    origin: &str,
+    key: &str,
+) -> Result<Exchanged<B>, BoxError>
+where
+    S: Service<Request<ByteBody>, Response = Response<B>>,
+    S::Error: Into<BoxError>,
+    B: HttpBody,
+    B::Error: Into<BoxError>,
+{
+    let uri = Uri::from_str(&format!("{}{}", origin, config.path))?;
+    let mut req = Request::get(uri).body(ByteBody::from(Bytes::new()))?;
+    let mut bearer = HeaderValue::from_str(&Zeroizing::new(format!("Bearer {}", key)))?;
+    bearer.set_sensitive(true);
+    req.headers_mut().insert(AUTHORIZATION, bearer);
+
+    let res = inner.oneshot(req).await.map_err(Into::into)?;
+    if res.status() == StatusCode::UNAUTHORIZED {
+        EXCHANGES.increment(&[("result", "rejected")]);
+        return Ok(Exchanged::Rejected(res));
+    }
+    if !res.status().is_success() {
+        return Err(format!("token exchange answered {}", res.status()).into());
+    }
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(Into::into)?;
+    let jwt = Zeroizing::new(String::from_utf8(body.to_vec())?);
+    // answered as a JSON string or as it is
+    let jwt = jwt.trim().trim_matches('"');
+    if jwt.is_empty() {
+        return Err("token exchange answered no token".into());
+    }
+    let mut bearer = HeaderValue::from_str(&Zeroizing::new(format!("Bearer {}", jwt)))?;
+    bearer.set_sensitive(true);
+    EXCHANGES.increment(&[("result", "ok")]);
+    Ok(Exchanged::Token(bearer))
+}
+
+impl TokenExchangeLayer {
+    /// The token of `key` on `origin`, exchanged if there is none or it is
+    /// due, or if it is `stale`.
+    async fn token<S, B>(
+        &self,
+        inner: S,
+        origin: &str,
+        key: &str,
+        stale: Option<&HeaderValue>,
+    ) -> Exchanged<B>
+    where
+        S: Service<Request<ByteBody>, Response = Response<B>>,
+        S::Error: Into<BoxError>,
+        B: HttpBody,
+        B::Error: Into<BoxError>,
+    {
+        let slot = self.slot(origin, key);
+        let mut token = slot.lock().await;
+        if let Some(current) = token.as_ref() {
+            let is_stale = stale.is_some_and(|stale| *stale == current.bearer);
+            if !is_stale && current.refresh_at > Instant::now() {
+                return Exchanged::Token(current.bearer.clone());
+            }
+        }
+        match exchange(&self.config, inner, origin, key).await {
+            Ok(Exchanged::Token(bearer)) => {
+                let jwt = bearer.to_str().unwrap_or_default();
+                let jwt = jwt.strip_prefix("Bearer ").unwrap_or(jwt);
+                let refresh_at = self.refresh_at(jwt);
+                tracing::debug!(key = %self.keys.label(key), refresh_in = ?refresh_at - Instant::now(), "key exchanged for a session token");
+                *token = Some(Token {
+                    refresh_at,
+                    bearer: bearer.clone(),
+                });
+                Exchanged::Token(bearer)
+            }
+            Ok(exchanged) => {
+                *token = None;
+                exchanged
+            }
+            Err(err) => {
+                EXCHANGES.increment(&[("result", "failed")]);
+                tracing::warn!(key = %self.keys.label(key), %err, "token exchange failed, sending the key");
+                *token = None;
+                Exchanged::Failed
+            }
+        }
+    }
+}
+
+/// A copy of `req` to send again, unless its body was streamed.
+fn resendable(req: &Request<ByteBody>) -> Option<Request<ByteBody>> {
+    if req.extensions().get::<StreamedBody>().is_some() {
+        return None;
+    }
+    let mut copy = Request::new(req.body().clone());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    if let Some(context) = ProxyContext::of(req) {
+        copy.extensions_mut().insert(context.clone());
+    }
+    Some(copy)
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for TokenExchange<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: HttpBody + Send + 'static,
+    ResBody::Data: Send,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let origin = match (req.uri().scheme(), req.uri().authority()) {
+            (Some(scheme), Some(authority)) => format!("{}://{}", scheme, authority),
+            _ => String::new(),
+        };
+        let key = pool_key(&self.layer.keys, &req).filter(|_| !origin.is_empty());
+        let Some(key) = key else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        // the ready service, leaving a clone in its place
+        let inner = self.inner.clone();
+        let ready = std::mem::replace(&mut self.inner, inner.clone());
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let mut req = req;
+            let bearer = match layer.token(inner.clone(), &origin, &key, None).await {
+                Exchanged::Token(bearer) => bearer,
+                Exchanged::Rejected(res) => return Ok(res),
+                Exchanged::Failed => return ready.oneshot(req).await.map_err(Into::into),
+            };
+            req.headers_mut().insert(AUTHORIZATION, bearer.clone());
+            let resend = resendable(&req);
+            let res = ready.oneshot(req).await.map_err(Into::into)?;
+            let Some(mut req) = resend.filter(|_| res.status() == StatusCode::UNAUTHORIZED) else {
+                return Ok(res);
+            };
+
+            // revoked or expired early, once more with a fresh token
+            tracing::debug!(key = %layer.keys.label(&key), "session token refused, exchanging again");
+            match layer
+                .token(inner.clone(), &origin, &key, Some(&bearer))
+                .await
+            {
+                Exchanged::Token(bearer) => {
+                    req.headers_mut().insert(AUTHORIZATION, bearer);
+                }
+                Exchanged::Rejected(res) => return Ok(res),
+                Exchanged::Failed => {}
+            }
+            inner.oneshot(req).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::service_fn;
+
+    fn jwt(exp: u64) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::json!({ "exp": exp }).to_string());
+        format!("e30.{}.c2ln", claims)
+    }
+
+    #[test]
+    fn test_expiry() {
+        assert_eq!(
+            expiry(&jwt(1_800_000_000)),
+            Some(UNIX_EPOCH + Duration::from_secs(1_800_000_000))
+        );
+        assert_eq!(expiry("not-a-jwt"), None);
+    }
+
+    #[tokio::test]
+    async fn test_token_exchange() -> Result<(), BoxError> {
+        let exchanges = Arc::new(AtomicUsize::new(0));
+        // tokens for `good`, none for `revoked`, the first token is refused
+        let upstream = service_fn({
+            let exchanges = exchanges.clone();
+            move |req: Request<ByteBody>| {
+                let auth = req.headers()[AUTHORIZATION].to_str().unwrap().to_owned();
+                let mut res = Response::new(Body::empty());
+                if req.uri().path() == "/user/v1/refresh-token" {
+                    match auth.as_str() {
+                        "Bearer good" => {
+                            let n = exchanges.fetch_add(1, Ordering::SeqCst);
+                            *res.body_mut() =
+                                Body::from(format!("\"{}\"", jwt(4_000_000_000 + n as u64)));
+                        }
+                        _ => *res.status_mut() = StatusCode::UNAUTHORIZED,
+                    }
+                } else {
+                    assert_eq!(req.uri().path(), "/v6/device");
+                    if auth != format!("Bearer {}", jwt(4_000_000_001)) {
+                        *res.status_mut() = StatusCode::UNAUTHORIZED;
+                    }
+                }
+                async { Ok::<_, BoxError>(res) }
+            }
+        });
+        let keys = KeyPool::from(vec!["good", "revoked"]);
+        let layer = TokenExchangeLayer::new(TokenExchangeConfig::default(), keys);
+        let service = layer.layer(upstream);
+        let send = |key: &str| {
+            let req = Request::get("https://api.example/v6/device")
+                .header(AUTHORIZATION, format!("Bearer {}", key))
+                .body(ByteBody::from(Bytes::new()))
+                .unwrap();
+            service.clone().oneshot(req)
+        };
+
+        // the first token is refused, the second kept
+        assert_eq!(send("good").await?.status(), StatusCode::OK);
+        assert_eq!(send("good").await?.status(), StatusCode::OK);
+        assert_eq!(exchanges.load(Ordering::SeqCst), 2);
+        assert_eq!(send("revoked").await?.status(), StatusCode::UNAUTHORIZED);
+        // keys not in the pool are sent as they are
+        assert_eq!(send("client").await?.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
@@ -0,0 +1,555 @@
+//! JSON body transformations.
+//!
+//! [`TransformLayer`] rewrites the buffered JSON body of requests matching a
+//! rule with a list of [`JsonOp`]s, so clients with frozen payload formats
+//! keep working as the API changes. Responses to requests matching a
+//! response rule are buffered and filtered the same way before they reach
+//! the client. Bodies that are not JSON are passed on untouched, as are
+//! request bodies too large to buffer, which are streamed.
+//!
+//! The upstream's `ETag` does not describe a filtered body, so successful
+//! responses rewritten this way carry a strong `ETag` of their own, a hash
+//! of the bytes sent, and `If-None-Match` is answered here with a 304
+//! instead of being forwarded, sparing polling clients the body.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    gzip, range,
+    read_request_body::{buffer, fix_length, ByteBody, FromBuffered},
+    route::RouteMatcher,
+};
+
+/// An edit of a JSON document. Paths are JSON pointers, e.g.
+/// `/belongs_to__application`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum JsonOp {
+    /// Set `path` to `value`, creating missing objects on the way.
+    Set {
+        path: String,
+        value: Value,
+    },
+    /// Set `path` to `value` unless it is already present.
+    Default {
+        path: String,
+        value: Value,
+    },
+    Remove {
+        path: String,
+    },
+    /// Move the value at `from` to `to`.
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Request body rewrites, every matching rule applies in order.
+    pub requests: Vec<TransformRule>,
+    /// Response body rewrites, every matching rule applies in order.
+    pub responses: Vec<ResponseTransformRule>,
+}
+
+impl TransformConfig {
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty() && self.responses.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformRule {
+    pub route: RouteMatcher,
+    pub ops: Vec<JsonOp>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseTransformRule {
+    /// Matched against the request the response answers.
+    pub route: RouteMatcher,
+    #[serde(default)]
+    pub ops: Vec<JsonOp>,
+    /// Field names removed at any depth, e.g. `api_key` or `__metadata`.
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Fields kept in each record, all of them when unset. Records are the
+    /// elements of a `{"d": [...]}` collection or of a top-level array, or
+    /// the top-level object itself.
+    pub allow: Option<Vec<String>>,
+}
+
+impl ResponseTransformRule {
+    fn apply(&self, doc: &mut Value) {
+        for op in &self.ops {
+            op.apply(doc);
+        }
+        if !self.strip.is_empty() {
+            strip(doc, &self.strip);
+        }
+        if let Some(allow) = &self.allow {
+            let records = match doc {
+                Value::Object(object) if object.get("d").is_some_and(Value::is_array) => {
+                    object.get_mut("d").expect("checked above")
+                }
+                doc => doc,
+            };
+            let keep = |record: &mut Value| {
+                if let Value::Object(record) = record {
+                    record.retain(|name, _| allow.contains(name));
+                }
+            };
+            match records {
+                Value::Array(items) => items.iter_mut().for_each(keep),
+                record => keep(record),
+            }
+        }
+    }
+}
+
+fn strip(doc: &mut Value, fields: &[String]) {
+    match doc {
+        Value::Object(object) => {
+            object.retain(|name, _| !fields.contains(name));
+            object.values_mut().for_each(|value| strip(value, fields));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| strip(item, fields)),
+        _ => {}
+    }
+}
+
+fn tokens(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|t| t.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn set(doc: &mut Value, pointer: &str, value: Value) {
+    let tokens = tokens(pointer);
+    let (last, parents) = match tokens.split_last() {
+        Some(split) => split,
+        None => return *doc = value,
+    };
+    let mut target = doc;
+    for token in parents {
+        target = match target {
+            Value::Object(object) => object
+                .entry(token.as_str())
+                .or_insert_with(|| Value::Object(Default::default())),
+            Value::Array(items) => match token.parse::<usize>().ok().and_then(|i| items.get_mut(i))
+            {
+                Some(item) => item,
+                None => return,
+            },
+            _ => return,
+        };
+    }
+    match target {
+        Value::Object(object) => {
+            object.insert(last.clone(), value);
+        }
+        Value::Array(items) => {
+            if let Some(item) = last.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                *item = value;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn remove(doc: &mut Value, pointer: &str) -> Option<Value> {
+    let (parent, last) = pointer.rsplit_once('/')?;
+    let last = last.replace("~1", "/").replace("~0", "~");
+    match doc.pointer_mut(parent)? {
+        Value::Object(object) => object.remove(&last),
+        Value::Array(items) => {
+            let index = last.parse::<usize>().ok().filter(|&i| i < items.len())?;
+            Some(items.remove(index))
+        }
+        _ => None,
+    }
+}
+
+impl JsonOp {
+    pub fn apply(&self, doc: &mut Value) {
+        match self {
+            JsonOp::Set { path, value } => set(doc, path, value.clone()),
+            JsonOp::Default { path, value } => {
+                if doc.pointer(path).is_none() {
+                    set(doc, path, value.clone());
+                }
+            }
+            JsonOp::Remove { path } => {
+                remove(doc, path);
+            }
+            JsonOp::Rename { from, to } => {
+                if let Some(value) = remove(doc, from) {
+                    set(doc, to, value);
+                }
+            }
+        }
+    }
+}
+
+/// Replace `body` with `doc`, keeping a `Content-Length` header in sync.
+fn encode(headers: &mut HeaderMap, doc: &Value) -> Vec<u8> {
+    let body = serde_json::to_vec(doc).expect("serializable value");
+    if headers.contains_key(CONTENT_LENGTH) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    body
+}
+
+/// Apply the ops of every rule matching `req` to its body.
+fn transform(rules: &[TransformRule], req: &mut Request<ByteBody>) {
+    let mut ops = rules
+        .iter()
+        .filter(|rule| rule.route.matches(req))
+        .flat_map(|rule| &rule.ops)
+        .peekable();
+    if ops.peek().is_none() {
+        return;
+    }
+    // only the start of a streamed body is at hand
+    if req.body().is_streamed() {
+        tracing::warn!(method = %req.method(), path = req.uri().path(), "request body too large to transform");
+        return;
+    }
+    let mut doc: Value = match serde_json::from_slice(req.body().as_bytes()) {
+        Ok(doc) => doc,
+        Err(_) => return,
+    };
+    for op in ops {
+        op.apply(&mut doc);
+    }
+
+    let body = serde_json::to_vec(&doc).expect("serializable value");
+    let body = ByteBody::new(body).with_trailers(req.body().trailers().cloned());
+    fix_length(req.headers_mut(), &body);
+    *req.body_mut() = body;
+}
+
+/// Apply `rules` to a buffered response body, returning the new body.
+/// Gzip bodies are decoded to apply them, and encoded again unless they
+/// came out untouched, in which case the original bytes go on.
+fn transform_response(
+    rules: &[ResponseTransformRule],
+    headers: &mut HeaderMap,
+    bytes: Bytes,
+) -> Bytes {
+    let gzip = gzip::is_gzip(headers);
+    let mut doc: Value = match serde_json::from_slice(&gzip::inspect(headers, &bytes)) {
+        Ok(doc) => doc,
+        Err(_) => return bytes,
+    };
+    let original = gzip.then(|| doc.clone());
+    for rule in rules {
+        rule.apply(&mut doc);
+    }
+    match original {
+        Some(original) if original == doc => bytes,
+        Some(_) => {
+            let body = gzip::encode(&serde_json::to_vec(&doc).expect("serializable value"));
+            if headers.contains_key(CONTENT_LENGTH) {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            }
+            Bytes::from(body)
+        }
+        None => Bytes::from(encode(headers, &doc)),
+    }
+}
+
+/// A strong `ETag` for `body`.
+fn etag(body: &[u8]) -> HeaderValue {
+    let digest = Sha256::digest(body);
+    HeaderValue::from_str(&format!("\"{}\"", hex::encode(&digest[..16])))
+        .expect("hex is a valid header value")
+}
+
+/// Whether an `If-None-Match` of `condition` holds for `etag`, compared
+/// weakly as RFC 9110 has it.
+fn none_match(condition: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(condition) = condition.to_str() else {
+        return true;
+    };
+    let etag = etag.to_str().unwrap_or_default();
+    !condition
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+#[derive(Debug, Clone)]
+pub struct TransformLayer {
+    config: Arc<TransformConfig>,
+}
+
+impl TransformLayer {
+    pub fn new(config: TransformConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for TransformLayer {
+    type Service = Transform<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Transform {
+            inner: service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Transform<S> {
+    inner: S,
+    config: Arc<TransformConfig>,
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Transform<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: http_body::Body<Data = Bytes> + FromBuffered + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<ByteBody>) -> Self::Future {
+        let responses: Vec<ResponseTransformRule> = self
+            .config
+            .responses
+            .iter()
+            .filter(|rule| req.method() != Method::HEAD && rule.route.matches(&req))
+            .cloned()
+            .collect();
+        // validators of rewritten bodies are ours, not the upstream's
+        let if_none_match = if responses.is_empty() {
+            None
+        } else {
+            req.headers_mut().remove(IF_NONE_MATCH)
+        };
+        transform(&self.config.requests, &mut req);
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = fut.await.map_err(Into::into)?;
+            // bodies compressed other than with gzip are not ours to rewrite
+            // neither are parts of bodies
+            if responses.is_empty()
+                || range::is_partial(&res)
+                || (res.headers().contains_key(CONTENT_ENCODING) && !gzip::is_gzip(res.headers()))
+            {
+                return Ok(res);
+            }
+            let (mut parts, body) = res.into_parts();
+            let (bytes, mut trailers) = buffer(body).await?;
+            let mut bytes = transform_response(&responses, &mut parts.headers, bytes);
+            if parts.status == StatusCode::OK {
+                let etag = etag(&bytes);
+                if if_none_match.is_some_and(|condition| !none_match(&condition, &etag)) {
+                    parts.status = StatusCode::NOT_MODIFIED;
+                    parts.headers.remove(CONTENT_LENGTH);
+                    bytes = Bytes::new();
+                    trailers = None;
+                }
+                parts.headers.insert(ETAG, etag);
+            }
+            Ok(Response::from_parts(
+                parts,
+                ResBody::from_buffered(bytes, trailers),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::{Fixture, MockUpstream};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_ops() {
+        let mut doc = serde_json::json!({ "name": "pi", "app": 12, "meta": { "a": 1 } });
+        let ops: Vec<JsonOp> = serde_json::from_value(serde_json::json!([
+            { "op": "rename", "from": "/name", "to": "/device_name" },
+            { "op": "rename", "from": "/app", "to": "/belongs_to__application" },
+            { "op": "default", "path": "/is_online", "value": false },
+            { "op": "default", "path": "/device_name", "value": "unused" },
+            { "op": "set", "path": "/location/city", "value": "Paris" },
+            { "op": "remove", "path": "/meta/a" },
+        ]))
+        .unwrap();
+        for op in &ops {
+            op.apply(&mut doc);
+        }
+        assert_eq!(
+            doc,
+            serde_json::json!({
+                "device_name": "pi",
+                "belongs_to__application": 12,
+                "is_online": false,
+                "location": { "city": "Paris" },
+                "meta": {},
+            })
+        );
+    }
+
+    #[test]
+    fn test_transform_request() {
+        let rules: Vec<TransformRule> = serde_json::from_value(serde_json::json!([
+            { "route": { "path_prefix": "/v6/device", "methods": ["POST"] },
+              "ops": [{ "op": "default", "path": "/belongs_to__application", "value": 7 }] },
+        ]))
+        .unwrap();
+
+        let mut req = Request::post("/v6/device")
+            .header(CONTENT_LENGTH, 2)
+            .body(ByteBody::new(b"{}".to_vec()))
+            .unwrap();
+        transform(&rules, &mut req);
+        assert_eq!(req.body().as_bytes(), br#"{"belongs_to__application":7}"#);
+        assert_eq!(req.headers()[CONTENT_LENGTH], "29");
+
+        // not JSON, left alone
+        let mut req = Request::post("/v6/device")
+            .body(ByteBody::new(b"raw".to_vec()))
+            .unwrap();
+        transform(&rules, &mut req);
+        assert_eq!(req.body().as_bytes(), b"raw");
+
+        // streamed, its start parsing as JSON does not make it whole
+        let body = ByteBody::streaming(Bytes::from_static(b"{}"), hyper::Body::from("{}"));
+        let mut req = Request::post("/v6/device")
+            .header(CONTENT_LENGTH, 4)
+            .body(body)
+            .unwrap();
+        transform(&rules, &mut req);
+        assert!(req.body().is_streamed());
+        assert_eq!(req.body().as_bytes(), b"{}");
+        assert_eq!(req.headers()[CONTENT_LENGTH], "4");
+    }
+
+    #[test]
+    fn test_transform_gzip_response() {
+        let rules: Vec<ResponseTransformRule> = serde_json::from_value(serde_json::json!([
+            { "route": {}, "strip": ["api_key"] },
+        ]))
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let body = Bytes::from(gzip::encode(br#"{"api_key":"secret","id":1}"#));
+        let body = transform_response(&rules, &mut headers, body);
+        assert_eq!(gzip::decode(&body).unwrap(), br#"{"id":1}"#);
+
+        // untouched bodies go on as they came
+        let body = Bytes::from(gzip::encode(br#"{"id":1}"#));
+        assert_eq!(transform_response(&rules, &mut headers, body.clone()), body);
+    }
+
+    #[tokio::test]
+    async fn test_transform_response() -> Result<(), BoxError> {
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/v6/device" },
+            "body": {
+                "d": [
+                    { "id": 1, "api_key": "secret", "__metadata": { "uri": "/x" },
+                      "device_name": "pi", "note": "n" },
+                ],
+            },
+        }))?;
+        let layer = TransformLayer::new(serde_json::from_value(serde_json::json!({
+            "responses": [{
+                "route": { "path_prefix": "/v6/device" },
+                "ops": [{ "op": "rename", "from": "/d/0/note", "to": "/d/0/comment" }],
+                "strip": ["api_key", "__metadata"],
+                "allow": ["id", "device_name", "api_key", "comment"],
+            }],
+        }))?);
+        let service = layer.layer(MockUpstream::new(vec![fixture]));
+
+        let req = Request::get("/v6/device").body(ByteBody::new(Vec::new()))?;
+        let res = service.oneshot(req).await?;
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(
+            body,
+            serde_json::json!({ "d": [{ "id": 1, "device_name": "pi", "comment": "n" }] })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_etag() -> Result<(), BoxError> {
+        let fixture: Fixture = serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/v6/device" },
+            "headers": { "etag": "\"upstream\"" },
+            "body": { "d": [{ "id": 1, "api_key": "secret" }] },
+        }))?;
+        let layer = TransformLayer::new(serde_json::from_value(serde_json::json!({
+            "responses": [{ "route": { "path_prefix": "/v6/device" }, "strip": ["api_key"] }],
+        }))?);
+        let service = layer.layer(MockUpstream::new(vec![fixture]));
+        let send = |if_none_match: Option<&HeaderValue>| {
+            let mut req = Request::get("/v6/device")
+                .body(ByteBody::new(Vec::new()))
+                .unwrap();
+            if let Some(value) = if_none_match {
+                req.headers_mut().insert(IF_NONE_MATCH, value.clone());
+            }
+            service.clone().oneshot(req)
+        };
+
+        let res = send(None).await?;
+        let tag = res.headers()[ETAG].clone();
+        assert_ne!(tag, "\"upstream\"");
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(tag, etag(&body));
+
+        let res = send(Some(&tag)).await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], tag);
+        assert!(hyper::body::to_bytes(res.into_body()).await?.is_empty());
+
+        let weak = HeaderValue::from_str(&format!("\"other\", W/{}", tag.to_str()?))?;
+        let res = send(Some(&weak)).await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = send(Some(&HeaderValue::from_static("\"other\""))).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Upstream request ids next to ours.
+//!
+//! Every request gets an `x-request-id` of ours, which is sent upstream, but
+//! Balena answers with an id of its own that support asks for. The id of the
+//! upstream response is recorded in the request span and the line logged
+//! when the request is done, so either id finds the other. With `expose`,
+//! clients get it as `x-upstream-request-id`, while `x-request-id` stays
+//! ours. It sits below the layer propagating our id to responses, so only
+//! ids the upstream sent are taken.
+
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use http::{HeaderName, HeaderValue, Request, Response};
+use serde::Deserialize;
+use tower::{BoxError, Layer, Service};
+
+use crate::context::ProxyContext;
+
+/// Response header carrying the upstream id to clients.
+pub const X_UPSTREAM_REQUEST_ID: &str = "x-upstream-request-id";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamRequestIdConfig {
+    /// Response header the upstream sends its id in.
+    pub header: String,
+    /// Return the upstream id to clients as `x-upstream-request-id`.
+    pub expose: bool,
+}
+
+impl Default for UpstreamRequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".to_owned(),
+            expose: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamRequestIdLayer {
+    header: HeaderName,
+    expose: bool,
+}
+
+impl UpstreamRequestIdLayer {
+    /// Check the header name of `config`.
+    pub fn new(config: UpstreamRequestIdConfig) -> Result<Self, BoxError> {
+        Ok(Self {
+            header: HeaderName::from_str(&config.header)?,
+            expose: config.expose,
+        })
+    }
+
+    /// The upstream id of `res`, taken off it when exposed under our name.
+    fn header_value<B>(&self, res: &mut Response<B>) -> Option<HeaderValue> {
+        if self.expose {
+            res.headers_mut().remove(&self.header)
+        } else {
+            res.headers().get(&self.header).cloned()
+        }
+    }
+}
+
+impl<S> Layer<S> for UpstreamRequestIdLayer {
+    type Service = UpstreamRequestId<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        UpstreamRequestId {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UpstreamRequestId<S> {
+    inner: S,
+    layer: UpstreamRequestIdLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for UpstreamRequestId<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let context = ProxyContext::of(&req).cloned();
+        let layer = self.layer.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let value = match layer.header_value(&mut res) {
+                Some(value) => value,
+                None => return Ok(res),
+            };
+            if let Ok(id) = value.to_str() {
+                tracing::Span::current().record("upstream_request_id", id);
+                if let Some(context) = &context {
+                    context.update(|annotations| {
+                        annotations.upstream_request_id = Some(id.to_owned())
+                    });
+                }
+            }
+            if layer.expose {
+                res.headers_mut().insert(X_UPSTREAM_REQUEST_ID, value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Annotations;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+    use tower_http::ServiceBuilderExt;
+
+    #[tokio::test]
+    async fn test_upstream_request_id() -> Result<(), BoxError> {
+        let upstream = service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() != "/local" {
+                res.headers_mut()
+                    .insert("x-request-id", "balena-1".parse().unwrap());
+            }
+            Ok::<_, Infallible>(res)
+        });
+        let service = |expose: bool| {
+            let config = UpstreamRequestIdConfig {
+                expose,
+                ..Default::default()
+            };
+            ServiceBuilder::new()
+                .propagate_x_request_id()
+                .layer(UpstreamRequestIdLayer::new(config).unwrap())
+                .service(upstream)
+        };
+        let request = |path: &str| {
+            let context = ProxyContext::new(Annotations::default());
+            let req = Request::get(path)
+                .header("x-request-id", "7")
+                .extension(context.clone())
+                .body(())
+                .unwrap();
+            (req, context)
+        };
+
+        let (req, context) = request("/v6/device");
+        let res = service(true).oneshot(req).await?;
+        assert_eq!(res.headers()["x-request-id"], "7");
+        assert_eq!(res.headers()[X_UPSTREAM_REQUEST_ID], "balena-1");
+        assert_eq!(
+            context.get().upstream_request_id.as_deref(),
+            Some("balena-1")
+        );
+
+        // without `expose`, responses are left as the upstream sent them
+        let (req, context) = request("/v6/device");
+        let res = service(false).oneshot(req).await?;
+        assert_eq!(res.headers()["x-request-id"], "balena-1");
+        assert!(!res.headers().contains_key(X_UPSTREAM_REQUEST_ID));
+        assert_eq!(
+            context.get().upstream_request_id.as_deref(),
+            Some("balena-1")
+        );
+
+        // our own id is not taken for the upstream's
+        let (req, context) = request("/local");
+        let res = service(true).oneshot(req).await?;
+        assert_eq!(res.headers()["x-request-id"], "7");
+        assert_eq!(context.get().upstream_request_id, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,541 @@
+//! Request body validation against JSON Schema.
+//!
+//! [`ValidateLayer`] checks the buffered body of requests matching a rule
+//! against the rule's schema and answers invalid payloads itself with a 422
+//! listing every violation. Bodies over the buffering cap, streamed rather
+//! than read whole, cannot be checked and get a 413. Schemas are loaded from files; `pointer` selects
+//! one inside a larger document, such as
+//! `/components/schemas/Device` in an OpenAPI file, and local `$ref`s are
+//! resolved against that document.
+//!
+//! The common validation keywords are supported: `type`, `enum`, `const`,
+//! `required`, `properties`, `additionalProperties`, `items`, `minItems`,
+//! `maxItems`, `minLength`, `maxLength`, `pattern`, `minimum`, `maximum`,
+//! `allOf`, `anyOf`, `oneOf` and `nullable`. Other keywords are ignored.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Future;
+use http::{header::CONTENT_TYPE, HeaderValue, Request, Response, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower::{BoxError, Layer, Service};
+
+use crate::{read_request_body::ByteBody, route::RouteMatcher};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationRule {
+    pub route: RouteMatcher,
+    /// JSON file holding the schema.
+    pub schema: PathBuf,
+    /// JSON pointer to the schema inside the file, the whole file when unset.
+    pub pointer: Option<String>,
+}
+
+/// A place in the body that breaks the schema.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Violation {
+    /// JSON pointer into the body.
+    pub path: String,
+    pub message: String,
+}
+
+/// A schema and the document its `$ref`s resolve against.
+pub struct Schema {
+    document: Value,
+    pointer: String,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    pub fn new(document: Value, pointer: Option<String>) -> Result<Self, BoxError> {
+        let pointer = pointer.unwrap_or_default();
+        if document.pointer(&pointer).is_none() {
+            return Err(format!("no schema at {}", pointer).into());
+        }
+        let mut patterns = HashMap::new();
+        collect_patterns(&document, &mut patterns)?;
+        Ok(Self {
+            document,
+            pointer,
+            patterns,
+        })
+    }
+
+    /// The schema at `pointer` in the JSON file at `path`.
+    pub fn load(path: &Path, pointer: Option<String>) -> Result<Self, BoxError> {
+        let context = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        let file = std::fs::read(path).map_err(|err| context(&err))?;
+        let document = serde_json::from_slice(&file).map_err(|err| context(&err))?;
+        Schema::new(document, pointer).map_err(|err| context(&err).into())
+    }
+
+    /// Check `value`, returning every violation found.
+    pub fn validate(&self, value: &Value) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let schema = self
+            .document
+            .pointer(&self.pointer)
+            .expect("checked in new");
+        self.check(schema, value, String::new(), &mut violations, 0);
+        violations
+    }
+
+    fn resolve<'a>(&'a self, schema: &'a Value) -> &'a Value {
+        // follow chains of references, giving up on cycles
+        let mut schema = schema;
+        for _ in 0..32 {
+            match schema.get("$ref").and_then(Value::as_str) {
+                Some(reference) => match reference
+                    .strip_prefix('#')
+                    .and_then(|p| self.document.pointer(p))
+                {
+                    Some(target) => schema = target,
+                    None => return &Value::Null,
+                },
+                None => return schema,
+            }
+        }
+        &Value::Null
+    }
+
+    fn check(
+        &self,
+        schema: &Value,
+        value: &Value,
+        path: String,
+        violations: &mut Vec<Violation>,
+        depth: usize,
+    ) {
+        let schema = self.resolve(schema);
+        let schema = match schema.as_object() {
+            Some(schema) if depth < 64 => schema,
+            _ => return,
+        };
+        let mut violation = |path: &str, message: String| {
+            violations.push(Violation {
+                path: path.to_owned(),
+                message,
+            })
+        };
+
+        let nullable = schema.get("nullable") == Some(&Value::Bool(true));
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            let matches =
+                types.iter().any(|t| type_matches(t, value)) || (nullable && value.is_null());
+            if !types.is_empty() && !matches {
+                violation(
+                    &path,
+                    format!(
+                        "expected {}, found {}",
+                        types.join(" or "),
+                        type_name(value)
+                    ),
+                );
+                return;
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(value) {
+                violation(
+                    &path,
+                    format!("must be one of {}", Value::from(options.clone())),
+                );
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                violation(&path, format!("must be {}", expected));
+            }
+        }
+
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !object.contains_key(name) {
+                            violation(&path, format!("missing required property {}", name));
+                        }
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, item) in object {
+                    let item_path = format!("{}/{}", path, escape(name));
+                    match (
+                        properties.and_then(|p| p.get(name)),
+                        schema.get("additionalProperties"),
+                    ) {
+                        (Some(property), _) => {
+                            self.check(property, item, item_path, violations, depth + 1)
+                        }
+                        (None, Some(Value::Bool(false))) => violations.push(Violation {
+                            path: item_path,
+                            message: "unexpected property".into(),
+                        }),
+                        (None, Some(additional)) => {
+                            self.check(additional, item, item_path, violations, depth + 1)
+                        }
+                        (None, None) => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                    if len < min {
+                        violation(&path, format!("must have at least {} items", min));
+                    }
+                }
+                if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                    if len > max {
+                        violation(&path, format!("must have at most {} items", max));
+                    }
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        let item_path = format!("{}/{}", path, i);
+                        self.check(item_schema, item, item_path, violations, depth + 1);
+                    }
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                    if len < min {
+                        violation(&path, format!("must be at least {} characters", min));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                    if len > max {
+                        violation(&path, format!("must be at most {} characters", max));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    if !self.patterns[pattern].is_match(s) {
+                        violation(&path, format!("must match {}", pattern));
+                    }
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                    if n < min {
+                        violation(&path, format!("must be at least {}", min));
+                    }
+                }
+                if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                    if n > max {
+                        violation(&path, format!("must be at most {}", max));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for sub in all {
+                self.check(sub, value, path.clone(), violations, depth + 1);
+            }
+        }
+        for (keyword, exactly_one) in [("anyOf", false), ("oneOf", true)] {
+            if let Some(Value::Array(subs)) = schema.get(keyword) {
+                let matching = subs
+                    .iter()
+                    .filter(|sub| {
+                        let mut found = Vec::new();
+                        self.check(sub, value, path.clone(), &mut found, depth + 1);
+                        found.is_empty()
+                    })
+                    .count();
+                if matching == 0 || (exactly_one && matching > 1) {
+                    violations.push(Violation {
+                        path: path.clone(),
+                        message: format!(
+                            "must match {} of the {} schemas",
+                            if exactly_one {
+                                "exactly one"
+                            } else {
+                                "at least one"
+                            },
+                            keyword
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Compile every `pattern` of `schema` up front, failing on invalid ones.
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> Result<(), BoxError> {
+    match schema {
+        Value::Object(object) => {
+            if let Some(Value::String(pattern)) = object.get("pattern") {
+                if !patterns.contains_key(pattern) {
+                    let regex = Regex::new(pattern)
+                        .map_err(|err| format!("schema pattern {}: {}", pattern, err))?;
+                    patterns.insert(pattern.clone(), regex);
+                }
+            }
+            object
+                .values()
+                .try_for_each(|value| collect_patterns(value, patterns))
+        }
+        Value::Array(values) => values
+            .iter()
+            .try_for_each(|value| collect_patterns(value, patterns)),
+        _ => Ok(()),
+    }
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        other => other == type_name(value),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Escape a property name for use in a JSON pointer.
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+struct Rule {
+    route: RouteMatcher,
+    schema: Schema,
+}
+
+#[derive(Clone)]
+pub struct ValidateLayer {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl ValidateLayer {
+    /// Load the schema of every rule.
+    pub fn new(rules: Vec<ValidationRule>) -> Result<Self, BoxError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                Ok(Rule {
+                    schema: Schema::load(&rule.schema, rule.pointer)?,
+                    route: rule.route,
+                })
+            })
+            .collect::<Result<_, BoxError>>()?;
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+}
+
+impl<S> Layer<S> for ValidateLayer {
+    type Service = Validate<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Validate {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Validate<S> {
+    inner: S,
+    rules: Arc<Vec<Rule>>,
+}
+
+impl<S> Validate<S> {
+    /// The body of `req` is invalid, the status to answer with and why.
+    fn invalid(&self, req: &Request<ByteBody>) -> Option<(StatusCode, serde_json::Value)> {
+        let rule = self.rules.iter().find(|rule| rule.route.matches(req))?;
+        // only the start of a streamed body is at hand to check
+        if req.body().is_streamed() {
+            return Some((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                serde_json::json!({ "error": "request body too large to validate" }),
+            ));
+        }
+        let body: Value = match serde_json::from_slice(req.body().as_bytes()) {
+            Ok(body) => body,
+            Err(err) => {
+                return Some((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    serde_json::json!({
+                        "error": "request body is not valid JSON",
+                        "details": [{ "path": "", "message": err.to_string() }],
+                    }),
+                ))
+            }
+        };
+        let violations = rule.schema.validate(&body);
+        (!violations.is_empty()).then(|| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                serde_json::json!({
+                    "error": "request body does not match schema",
+                    "details": violations,
+                }),
+            )
+        })
+    }
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for Validate<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        let (status, error) = match self.invalid(&req) {
+            Some(invalid) => invalid,
+            None => {
+                let fut = self.inner.call(req);
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+        };
+
+        tracing::info!(path = req.uri().path(), "rejected invalid request body");
+        let mut res = Response::new(ResBody::from(Bytes::from(error.to_string())));
+        *res.status_mut() = status;
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::MockUpstream;
+    use tower::ServiceExt;
+
+    fn openapi() -> Value {
+        serde_json::json!({
+            "components": { "schemas": {
+                "Device": {
+                    "type": "object",
+                    "required": ["device_name"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "device_name": { "type": "string", "minLength": 1, "pattern": "^[a-z0-9-]+$" },
+                        "note": { "type": "string", "nullable": true },
+                        "tags": { "type": "array", "items": { "$ref": "#/components/schemas/Tag" } },
+                    },
+                },
+                "Tag": { "type": "object", "properties": { "value": { "type": "integer", "minimum": 0 } } },
+            } },
+        })
+    }
+
+    #[test]
+    fn test_validate() -> Result<(), BoxError> {
+        let schema = Schema::new(openapi(), Some("/components/schemas/Device".into()))?;
+        let valid =
+            serde_json::json!({ "device_name": "pi-1", "note": null, "tags": [{ "value": 1 }] });
+        assert_eq!(schema.validate(&valid), Vec::new());
+
+        let invalid =
+            serde_json::json!({ "device_name": "Pi 1", "tags": [{ "value": -1 }], "x": 1 });
+        let paths: Vec<String> = schema
+            .validate(&invalid)
+            .into_iter()
+            .map(|v| v.path)
+            .collect();
+        assert_eq!(paths, ["/device_name", "/tags/0/value", "/x"]);
+
+        let missing = schema.validate(&serde_json::json!({}));
+        assert_eq!(missing[0].message, "missing required property device_name");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_body() -> Result<(), BoxError> {
+        let path = std::env::temp_dir().join(format!("proxy-schema-{}.json", std::process::id()));
+        std::fs::write(&path, openapi().to_string())?;
+        let layer = ValidateLayer::new(vec![serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/v6/device", "methods": ["PATCH"] },
+            "schema": path,
+            "pointer": "/components/schemas/Device",
+        }))?])?;
+        std::fs::remove_file(&path)?;
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+
+        let req = Request::patch("/v6/device(1)")
+            .body(ByteBody::new(br#"{"device_name": 1}"#.to_vec()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(body["details"][0]["path"], "/device_name");
+
+        // valid bodies and other routes go through
+        let req = Request::patch("/v6/device(1)")
+            .body(ByteBody::new(br#"{"device_name": "pi"}"#.to_vec()))?;
+        assert_eq!(
+            service.clone().oneshot(req).await?.status(),
+            StatusCode::NOT_FOUND
+        );
+        let req = Request::post("/v6/release").body(ByteBody::new(b"not json".to_vec()))?;
+        assert_eq!(service.oneshot(req).await?.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body() -> Result<(), BoxError> {
+        use crate::read_request_body::ReadRequestLayer;
+
+        let rules = vec![Rule {
+            route: serde_json::from_value(serde_json::json!({ "path_prefix": "/v6/device" }))?,
+            schema: Schema::new(openapi(), Some("/components/schemas/Device".into()))?,
+        }];
+        let service = ReadRequestLayer::new().max_buffered(Some(4)).layer(
+            ValidateLayer {
+                rules: Arc::new(rules),
+            }
+            .layer(MockUpstream::new(Vec::new())),
+        );
+        let req =
+            Request::patch("/v6/device(1)").body(hyper::Body::from(r#"{"device_name": "pi"}"#))?;
+        let res = service.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+}
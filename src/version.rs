@@ -0,0 +1,62 @@
+//! The build handling traffic.
+//!
+//! During an incident it helps to know which build answered: `/version` on
+//! the admin API tells the crate version, the git commit and build time
+//! recorded by `build.rs`, and the features compiled in. With
+//! `server.version_header`, every response also carries
+//! `X-Proxy-Version: <version>+<commit>`.
+
+use http::HeaderValue;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub const X_PROXY_VERSION: &str = "x-proxy-version";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short hash of the commit built, `unknown` outside a checkout.
+pub const GIT_SHA: &str = env!("PROXY_GIT_SHA");
+
+const BUILD_EPOCH: &str = env!("PROXY_BUILD_EPOCH");
+
+/// The cargo features compiled in.
+pub fn features() -> Vec<&'static str> {
+    [
+        ("auth", cfg!(feature = "auth")),
+        ("retry", cfg!(feature = "retry")),
+        ("cache", cfg!(feature = "cache")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("scripting", cfg!(feature = "scripting")),
+        ("tls", cfg!(feature = "tls")),
+        ("websocket", cfg!(feature = "websocket")),
+        ("bench", cfg!(feature = "bench")),
+        ("testkit", cfg!(feature = "testkit")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// When the build was made, as RFC 3339.
+fn built_at() -> Option<String> {
+    let epoch = BUILD_EPOCH.parse().ok()?;
+    OffsetDateTime::from_unix_timestamp(epoch)
+        .ok()?
+        .format(&Rfc3339)
+        .ok()
+}
+
+/// The body of `/version`.
+pub fn info() -> serde_json::Value {
+    serde_json::json!({
+        "version": VERSION,
+        "git_sha": GIT_SHA,
+        "built_at": built_at(),
+        "features": features(),
+    })
+}
+
+/// The value of `X-Proxy-Version`.
+pub fn header_value() -> HeaderValue {
+    HeaderValue::from_str(&format!("{}+{}", VERSION, GIT_SHA))
+        .unwrap_or(HeaderValue::from_static(VERSION))
+}
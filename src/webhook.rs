@@ -0,0 +1,318 @@
+//! Webhook relay with at-least-once delivery.
+//!
+//! [`WebhookLayer`] answers requests on the webhook route with a 202 as soon
+//! as they are persisted in a [`DurableQueue`], and a background task delivers
+//! them to the configured target in order, backing off while the target
+//! fails. Webhooks that still fail after `max_attempts` are logged and moved
+//! to the dead-letter file.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::https::HttpsConnector;
+use bytes::Bytes;
+use futures_core::Future;
+use http::{
+    header::{CONTENT_LENGTH, HOST},
+    HeaderValue, Request, Response, StatusCode, Uri,
+};
+use hyper::Client;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    read_request_body::ByteBody,
+    retry::{Backoff, ExponentialBackoff},
+    route::RouteMatcher,
+    store_forward::{dead_letter, DurableQueue, QueuedRequest, X_PROXY_QUEUED},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Requests accepted as webhooks, e.g. `{"path_prefix": "/webhooks/"}`.
+    pub route: RouteMatcher,
+    /// Where webhooks are delivered, the request path is appended.
+    pub target: String,
+    /// Directory persisting undelivered webhooks.
+    pub directory: PathBuf,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// NDJSON file receiving webhooks that could not be delivered.
+    pub dead_letter: Option<PathBuf>,
+    #[serde(default = "default_backoff_min_ms")]
+    pub backoff_min_ms: u64,
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+fn default_backoff_min_ms() -> u64 {
+    1000
+}
+
+fn default_backoff_max_ms() -> u64 {
+    60_000
+}
+
+struct Relay {
+    queue: DurableQueue,
+    notify: Notify,
+    target: Uri,
+}
+
+impl Relay {
+    fn target_uri(&self, entry: &QueuedRequest) -> Result<Uri, BoxError> {
+        let uri = Uri::from_str(&entry.uri)?;
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        let target = self.target.to_string();
+        Ok(Uri::from_str(&format!(
+            "{}{}",
+            target.trim_end_matches('/'),
+            path
+        ))?)
+    }
+}
+
+/// Deliver queued webhooks until the process exits.
+async fn deliver(relay: Arc<Relay>, config: WebhookConfig) {
+    let client = Client::builder().build::<_, ByteBody>(HttpsConnector::new());
+    let initial_backoff = ExponentialBackoff::new(
+        Duration::from_millis(config.backoff_min_ms),
+        Duration::from_millis(config.backoff_max_ms.max(config.backoff_min_ms.max(1))),
+        1.0,
+    );
+    let mut backoff = initial_backoff.clone();
+
+    loop {
+        let entry = match relay.queue.front() {
+            Some(entry) => entry,
+            None => {
+                relay.notify.notified().await;
+                continue;
+            }
+        };
+
+        let result = match entry.to_request() {
+            Ok(mut req) => match relay.target_uri(&entry) {
+                Ok(uri) => {
+                    *req.uri_mut() = uri;
+                    req.headers_mut().remove(HOST);
+                    req.headers_mut().remove(CONTENT_LENGTH);
+                    client
+                        .request(req)
+                        .await
+                        .map_err(|err| err.to_string())
+                        .and_then(|res| {
+                            let status = res.status();
+                            (!status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS)
+                                .then_some(status)
+                                .ok_or_else(|| status.to_string())
+                        })
+                }
+                Err(err) => Err(err.to_string()),
+            },
+            Err(err) => Err(err.to_string()),
+        };
+
+        match result {
+            Ok(status) => {
+                tracing::info!(id = entry.id, %status, "webhook delivered");
+                relay.queue.remove(entry.id);
+                backoff = initial_backoff.clone();
+            }
+            Err(err) if entry.attempts + 1 >= config.max_attempts => {
+                tracing::error!(id = entry.id, uri = entry.uri, %err, attempts = entry.attempts + 1, "webhook dead-lettered");
+                if let Some(path) = &config.dead_letter {
+                    if let Err(err) = dead_letter(path, &entry) {
+                        tracing::error!(path = %path.display(), %err, "failed to write dead letter");
+                    }
+                }
+                relay.queue.remove(entry.id);
+                backoff = initial_backoff.clone();
+            }
+            Err(err) => {
+                tracing::warn!(id = entry.id, %err, attempts = entry.attempts + 1, "webhook delivery failed");
+                relay.queue.record_attempt(entry);
+                backoff = backoff.next().await;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookLayer {
+    relay: Arc<Relay>,
+    route: Arc<RouteMatcher>,
+}
+
+impl WebhookLayer {
+    /// Open the webhook queue and start delivering it.
+    pub fn new(config: WebhookConfig) -> Result<Self, BoxError> {
+        let queue = DurableQueue::open(&config.directory, config.max_entries)
+            .map_err(|err| format!("{}: {}", config.directory.display(), err))?;
+        let target = Uri::from_str(&config.target)
+            .map_err(|err| format!("webhook target {}: {}", config.target, err))?;
+        let relay = Arc::new(Relay {
+            queue,
+            notify: Notify::new(),
+            target,
+        });
+        let route = Arc::new(config.route.clone());
+        tokio::spawn(deliver(relay.clone(), config));
+        Ok(Self { relay, route })
+    }
+
+    pub fn queue(&self) -> DurableQueue {
+        self.relay.queue.clone()
+    }
+}
+
+impl<S> Layer<S> for WebhookLayer {
+    type Service = WebhookRelay<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        WebhookRelay {
+            inner: service,
+            relay: self.relay.clone(),
+            route: self.route.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookRelay<S> {
+    inner: S,
+    relay: Arc<Relay>,
+    route: Arc<RouteMatcher>,
+}
+
+fn response<B: From<Bytes>>(status: StatusCode, body: serde_json::Value) -> Response<B> {
+    let mut res = Response::new(B::from(Bytes::from(body.to_string())));
+    *res.status_mut() = status;
+    res
+}
+
+impl<S, ResBody> Service<Request<ByteBody>> for WebhookRelay<S>
+where
+    S: Service<Request<ByteBody>, Response = Response<ResBody>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+    ResBody: From<Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<ByteBody>) -> Self::Future {
+        if !self.route.matches(&req) {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        if req.body().is_streamed() {
+            let res = response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                serde_json::json!({ "error": "webhook body too large to queue" }),
+            );
+            return Box::pin(async move { Ok(res) });
+        }
+        let entry = QueuedRequest::new(&req, req.body().as_bytes());
+        let res = match self.relay.queue.push(entry) {
+            Ok(id) => {
+                self.relay.notify.notify_one();
+                let mut res = response(StatusCode::ACCEPTED, serde_json::json!({ "queued": id }));
+                res.headers_mut()
+                    .insert(X_PROXY_QUEUED, HeaderValue::from(id));
+                res
+            }
+            Err(err) => {
+                tracing::error!(%err, "failed to queue webhook");
+                response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    serde_json::json!({ "error": "webhook queue unavailable" }),
+                )
+            }
+        };
+        Box::pin(async move { Ok(res) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream::MockUpstream;
+    use httpmock::prelude::*;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_relay() -> Result<(), BoxError> {
+        let dir = std::env::temp_dir().join(format!("proxy-webhook-{}", std::process::id()));
+        let dead_letter = dir.join("dead.ndjson");
+        let server = MockServer::start();
+        let m = server.mock(|when, then| {
+            when.method(POST).path("/webhooks/ok").body("ping");
+            then.status(200);
+        });
+        let failing = server.mock(|when, then| {
+            when.method(POST).path("/webhooks/fail");
+            then.status(500);
+        });
+
+        let layer = WebhookLayer::new(serde_json::from_value(serde_json::json!({
+            "route": { "path_prefix": "/webhooks/" },
+            "target": server.base_url(),
+            "directory": dir.join("queue"),
+            "max_attempts": 2,
+            "dead_letter": dead_letter,
+            "backoff_min_ms": 10,
+        }))?)?;
+        let service = layer.layer(MockUpstream::new(Vec::new()));
+
+        for path in ["/webhooks/fail", "/webhooks/ok"] {
+            let req = Request::post(path).body(ByteBody::new(b"ping".to_vec()))?;
+            let res = service.clone().oneshot(req).await?;
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+        }
+        // other routes are not relayed
+        let req = Request::get("/v6/device").body(ByteBody::new(Vec::new()))?;
+        let res = service.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        for _ in 0..100 {
+            if layer.queue().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        failing.assert_hits(2);
+        m.assert();
+        let dead = std::fs::read_to_string(&dead_letter)?;
+        assert!(dead.contains("/webhooks/fail"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! WebSocket tunnels to the upstream.
+//!
+//! Requests to switch to WebSocket go down the stack like any other, so they
+//! reach the upstream of their route with a key of the pool. When the
+//! upstream answers `101 Switching Protocols`, [`WebSocketLayer`] joins the
+//! client's connection to the upstream's once both are upgraded, and copies
+//! bytes both ways until either side closes. The tunnels outlive their
+//! request: they are not counted as open connections, and are not waited for
+//! on shutdown.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Future;
+use http::{
+    header::{CONNECTION, UPGRADE},
+    HeaderName, Request, Response, StatusCode,
+};
+use hyper::upgrade::OnUpgrade;
+use tower::{Layer, Service};
+
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketLayer;
+
+impl<S> Layer<S> for WebSocketLayer {
+    type Service = WebSocket<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        WebSocket { inner: service }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSocket<S> {
+    inner: S,
+}
+
+/// Whether `req` asks to switch to the WebSocket protocol.
+fn is_upgrade<B>(req: &Request<B>) -> bool {
+    let lists = |name: HeaderName, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    lists(CONNECTION, "upgrade") && lists(UPGRADE, "websocket")
+}
+
+/// Copy bytes between both connections once upgraded, until either closes.
+async fn tunnel(client: OnUpgrade, upstream: OnUpgrade) {
+    let (mut client, mut upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            tracing::warn!(%err, "websocket upgrade failed");
+            return;
+        }
+    };
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => tracing::debug!(sent, received, "websocket closed"),
+        Err(err) => tracing::debug!(%err, "websocket closed with error"),
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for WebSocket<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = S::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // hyper only upgrades connections serving with upgrades
+        let client = match is_upgrade(&req) {
+            true => req.extensions_mut().remove::<OnUpgrade>(),
+            false => None,
+        };
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(client) = client {
+                match res.extensions_mut().remove::<OnUpgrade>() {
+                    Some(upstream) if res.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                        tokio::spawn(tunnel(client, upstream));
+                    }
+                    _ => tracing::debug!(status = %res.status(), "websocket not accepted"),
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{config::Config, server::ServerConfig, shutdown::Shutdown, testkit::TestProxy};
+
+    /// Read from `stream` up to the end of a message head.
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// An upstream accepting one WebSocket and echoing what it gets,
+    /// returning its address and the request head it got.
+    async fn echo_upstream() -> (SocketAddr, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            let _ = tx.send(head);
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                      connection: upgrade\r\nupgrade: websocket\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let (mut read, mut write) = stream.split();
+            let _ = tokio::io::copy(&mut read, &mut write).await;
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let req = |connection: &str, upgrade: &str| {
+            Request::get("/")
+                .header(CONNECTION, connection)
+                .header(UPGRADE, upgrade)
+                .body(())
+                .unwrap()
+        };
+        assert!(is_upgrade(&req("Upgrade", "websocket")));
+        assert!(is_upgrade(&req("keep-alive, upgrade", "WebSocket")));
+        assert!(!is_upgrade(&req("keep-alive", "websocket")));
+        assert!(!is_upgrade(&req("upgrade", "h2c")));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let (upstream, head) = echo_upstream().await;
+        let proxy = TestProxy::new(
+            Config::default(),
+            &format!("http://{}/v6", upstream),
+            &["key1"],
+        )
+        .unwrap();
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = ServerConfig {
+            listen: addr,
+            ..Default::default()
+        };
+        let service = proxy.service();
+        tokio::spawn(
+            async move { crate::server::serve(&config, service, Shutdown::default()).await },
+        );
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+
+        stream
+            .write_all(
+                b"GET /logs HTTP/1.1\r\nhost: proxy\r\n\
+                  connection: upgrade\r\nupgrade: websocket\r\n\
+                  sec-websocket-version: 13\r\n\
+                  sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let res = read_head(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 101"), "{}", res);
+        let head = head.await.unwrap().to_lowercase();
+        assert!(head.starts_with("get /v6/logs http/1.1"), "{}", head);
+        assert!(head.contains("upgrade: websocket"), "{}", head);
+        #[cfg(feature = "auth")]
+        assert!(head.contains("authorization: bearer key1"), "{}", head);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+}